@@ -0,0 +1,105 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use elizadb::{query::Query, smallset::Smallset, Database, Key};
+
+fn key(value: u64) -> Key {
+    Key::try_from(value).unwrap()
+}
+
+/// Database of `keys` records, each carrying `terms_per_key` of `terms` terms
+fn populated(keys: u64, terms: u64, terms_per_key: u64) -> Database<8> {
+    let mut db = Database::<8>::default();
+    for k in 1..=keys {
+        for i in 0..terms_per_key {
+            let term = (k * 7 + i * 13) % terms;
+            db.set_flag(key(k), &term.to_string()).unwrap();
+        }
+    }
+    db
+}
+
+fn smallset(c: &mut Criterion) {
+    let mut group = c.benchmark_group("smallset");
+    group.bench_function("insert_remove", |b| {
+        b.iter(|| {
+            let mut set = Smallset::<u16, 8>::new_empty();
+            for item in 1..=8u16 {
+                set.insert(item.try_into().unwrap()).unwrap();
+            }
+            for item in 1..=8u16 {
+                set.remove(item.try_into().unwrap());
+            }
+            black_box(set)
+        })
+    });
+    let mut set = Smallset::<u16, 8>::new_empty();
+    for item in [3u16, 11, 19, 27] {
+        set.insert(item.try_into().unwrap()).unwrap();
+    }
+    group.bench_function("contains_chained", |b| {
+        b.iter(|| black_box(set.contains(black_box(27u16).try_into().unwrap())))
+    });
+    group.finish();
+}
+
+fn set_flag(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_flag");
+    group.bench_function("fresh_keys", |b| {
+        b.iter_batched(
+            Database::<8>::default,
+            |mut db| {
+                for k in 1..=1000 {
+                    db.set_flag(key(k), "term").unwrap();
+                }
+                db
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    // single record growing through every tier up to big storage
+    group.bench_function("promotion", |b| {
+        b.iter_batched(
+            Database::<8>::default,
+            |mut db| {
+                for term in 0..100 {
+                    db.set_flag(key(1), &term.to_string()).unwrap();
+                }
+                db
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn vertical_query(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vertical_query");
+    let simple = Query::Simple {
+        term: "3".to_string(),
+    };
+    let k_of_n = Query::KofN {
+        terms: (0..8).map(|term| term.to_string()).collect(),
+        bound: 3,
+    };
+
+    for keys in [10_000, 100_000] {
+        let mut db = populated(keys, 64, 6);
+        group.bench_with_input(BenchmarkId::new("simple", keys), &db, |b, db| {
+            b.iter(|| black_box(db.vertical_query(&simple).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("k_of_n", keys), &db, |b, db| {
+            b.iter(|| black_box(db.vertical_query(&k_of_n).unwrap()))
+        });
+
+        db.enable_term_columns().unwrap();
+        group.bench_with_input(BenchmarkId::new("simple_columnar", keys), &db, |b, db| {
+            b.iter(|| black_box(db.vertical_query(&simple).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("k_of_n_columnar", keys), &db, |b, db| {
+            b.iter(|| black_box(db.vertical_query(&k_of_n).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, smallset, set_flag, vertical_query);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+
+use elizadb::Database;
+use libfuzzer_sys::fuzz_target;
+
+// malformed snapshots must be rejected with an error, never panic
+fuzz_target!(|data: &[u8]| {
+    let _ = Database::<8>::load(&mut &data[..]);
+});
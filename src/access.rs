@@ -0,0 +1,342 @@
+//! Term-based access policy restricting which API keys may read or write records
+//!
+//! Rules name term prefixes along with roles allowed to read or write records carrying such
+//! terms. API keys are given as `Authorization: Bearer <key>`; with no rules everything is allowed.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use serde::Deserialize;
+
+use crate::{
+    error::ApiError,
+    query::{KeyRange, Query},
+    storage::{Database, Key, StorageCorruption},
+    tenants::Tenant,
+    term_locks::{LockCheck, TermLocked, TermLocks},
+};
+
+/// Role of API keys allowed to override term locks
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Contents of `--access-policy` file
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Roles granted to every API key
+    #[serde(default)]
+    pub api_keys: HashMap<String, BTreeSet<String>>,
+    #[serde(default)]
+    pub rules: Vec<TermRule>,
+    /// Namespaces API keys are confined to, such keys need not be listed in `api_keys`
+    #[serde(default)]
+    pub tenants: HashMap<String, Tenant>,
+    /// Set when loaded from file, admin routes are reserved for [`ADMIN_ROLE`] then
+    #[serde(skip)]
+    pub loaded: bool,
+}
+
+/// Restricts records carrying terms starting with `prefix` to API keys holding one of the roles
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TermRule {
+    pub prefix: String,
+    /// Roles allowed to read such records, anyone if absent
+    #[serde(default)]
+    pub read: Option<BTreeSet<String>>,
+    /// Roles allowed to write such records, anyone if absent
+    #[serde(default)]
+    pub write: Option<BTreeSet<String>>,
+}
+
+impl AccessPolicy {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let policy: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Self {
+            loaded: true,
+            ..policy
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccessError {
+    #[error("API key is not known")]
+    UnknownApiKey,
+    #[error("API key may not {action} records carrying term {term}")]
+    Denied { action: Action, term: String },
+    #[error("key {key} is outside of namespace {namespace}")]
+    OutsideNamespace { key: Key, namespace: String },
+    #[error("route {0} is not available to tenants")]
+    NotForTenants(String),
+    #[error("API key does not hold role {ADMIN_ROLE}")]
+    AdminRequired,
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+/// API key given as `Authorization: Bearer <key>`
+pub fn bearer_api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Extractor holding policy and term locks along with roles and tenant of the calling API key
+#[derive(Clone)]
+pub struct Access {
+    policy: Arc<AccessPolicy>,
+    locks: Arc<TermLocks>,
+    roles: BTreeSet<String>,
+    tenant: Option<Tenant>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Access
+where
+    Arc<AccessPolicy>: FromRef<S>,
+    Arc<TermLocks>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::for_api_key(
+            Arc::from_ref(state),
+            Arc::from_ref(state),
+            bearer_api_key(&parts.headers),
+        )?)
+    }
+}
+
+impl Access {
+    /// Access of caller presenting `api_key`, anonymous without one
+    pub fn for_api_key(
+        policy: Arc<AccessPolicy>,
+        locks: Arc<TermLocks>,
+        api_key: Option<&str>,
+    ) -> Result<Self, AccessError> {
+        let roles = match api_key {
+            Some(api_key) => match policy.api_keys.get(api_key) {
+                Some(roles) => roles.clone(),
+                None if policy.tenants.contains_key(api_key) => BTreeSet::new(),
+                None => return Err(AccessError::UnknownApiKey),
+            },
+            None => BTreeSet::new(),
+        };
+        let tenant = api_key.and_then(|api_key| policy.tenants.get(api_key).cloned());
+        Ok(Self {
+            policy,
+            locks,
+            roles,
+            tenant,
+        })
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.roles.contains(ADMIN_ROLE)
+    }
+
+    pub fn check_admin(&self) -> Result<(), AccessError> {
+        match self.is_admin() {
+            true => Ok(()),
+            false => Err(AccessError::AdminRequired),
+        }
+    }
+
+    pub fn tenant(&self) -> Option<&Tenant> {
+        self.tenant.as_ref()
+    }
+
+    /// Fails if `key` is outside of namespace of tenant
+    pub fn check_key(&self, key: Key) -> Result<(), AccessError> {
+        match &self.tenant {
+            Some(tenant) => tenant.check_key(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `key` is visible to caller, which it is unless outside of namespace of tenant
+    pub fn sees(&self, key: Key) -> bool {
+        self.check_key(key).is_ok()
+    }
+
+    /// Part of `range` caller may look into
+    pub fn confine(&self, range: KeyRange) -> KeyRange {
+        match &self.tenant {
+            Some(tenant) => tenant.confine(range),
+            None => range,
+        }
+    }
+
+    /// Term locks as they apply to caller, for work outliving the request
+    pub fn lock_check(&self) -> LockCheck {
+        LockCheck::new(self.locks.clone(), self.is_admin())
+    }
+
+    /// Fails if flags of any of `terms` are locked against caller
+    pub fn check_unlocked<'t, const SMALLSIZE: usize>(
+        &self,
+        db: &'t Database<SMALLSIZE>,
+        terms: impl IntoIterator<Item = &'t str>,
+    ) -> Result<(), TermLocked> {
+        self.lock_check().check(db, terms)
+    }
+
+    /// Whether every rule matching `term` lets caller's roles perform `action`
+    pub fn allows(&self, action: Action, term: &str) -> bool {
+        self.policy
+            .rules
+            .iter()
+            .filter(|rule| term.starts_with(&rule.prefix))
+            .all(|rule| {
+                let allowed = match action {
+                    Action::Read => &rule.read,
+                    Action::Write => &rule.write,
+                };
+                allowed
+                    .as_ref()
+                    .is_none_or(|allowed| !allowed.is_disjoint(&self.roles))
+            })
+    }
+
+    /// Terms given by alias are checked under their canonical name as well
+    pub fn check_terms<'t, const SMALLSIZE: usize>(
+        &self,
+        db: &'t Database<SMALLSIZE>,
+        action: Action,
+        terms: impl IntoIterator<Item = &'t str>,
+    ) -> Result<(), AccessError> {
+        if self.policy.rules.is_empty() {
+            return Ok(());
+        }
+        let mut names = terms
+            .into_iter()
+            .flat_map(|term| [term, db.canonical_name(term)]);
+        match names.find(|term| !self.allows(action, term)) {
+            Some(term) => Err(AccessError::Denied {
+                action,
+                term: term.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Check namespace of `key` and terms it carries, keys without a record only have the former
+    /// checked
+    pub fn check_record<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        key: &Key,
+        action: Action,
+    ) -> Result<(), AccessError> {
+        self.check_key(*key)?;
+        if self.policy.rules.is_empty() {
+            return Ok(());
+        }
+        let terms = db.horizontal_query(key)?.unwrap_or_default();
+        self.check_terms(db, action, terms)
+    }
+
+    /// Queries naming terms caller may not read are rejected, since they reveal who carries them.
+    /// Group queries are checked against current members of the group
+    pub fn check_query<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        query: &Query,
+    ) -> Result<(), AccessError> {
+        match query {
+            Query::Simple { term } => self.check_terms(db, Action::Read, [term.as_str()]),
+            Query::KofN { terms, .. } => {
+                self.check_terms(db, Action::Read, terms.iter().map(String::as_str))
+            }
+            Query::Group { group, .. } => {
+                self.check_terms(db, Action::Read, db.term_group(group).unwrap_or_default())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use crate::{
+        storage::{Database, Key},
+        term_locks::TermLocks,
+    };
+
+    use super::{Access, AccessPolicy, Action};
+
+    #[test]
+    fn protected_records_need_role() {
+        let policy: AccessPolicy = serde_json::from_str(
+            r#"{
+                "api_keys": {"secret": ["elevated"]},
+                "rules": [{"prefix": "pii:", "read": ["elevated"], "write": ["elevated"]}]
+            }"#,
+        )
+        .unwrap();
+        let policy = Arc::new(policy);
+        let locks = std::env::temp_dir().join(format!("elizadb-access-{}", std::process::id()));
+        let locks = Arc::new(TermLocks::open(locks).unwrap());
+        let anonymous = Access {
+            policy: policy.clone(),
+            locks: locks.clone(),
+            roles: BTreeSet::new(),
+            tenant: None,
+        };
+        let elevated = Access {
+            roles: policy.api_keys["secret"].clone(),
+            policy,
+            locks,
+            tenant: None,
+        };
+        let mut db = Database::<8>::default();
+        let (plain, sensitive) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(plain, "x").unwrap();
+        db.set_flag(sensitive, "pii:email").unwrap();
+        db.add_alias("pii:email", "contact").unwrap();
+
+        assert!(anonymous.check_record(&db, &plain, Action::Write).is_ok());
+        assert!(anonymous
+            .check_record(&db, &sensitive, Action::Read)
+            .is_err());
+        assert!(anonymous
+            .check_terms(&db, Action::Write, ["x", "pii:phone"])
+            .is_err());
+        assert!(anonymous
+            .check_terms(&db, Action::Read, ["contact"])
+            .is_err());
+        assert!(elevated.check_record(&db, &sensitive, Action::Read).is_ok());
+        assert!(elevated
+            .check_terms(&db, Action::Write, ["pii:phone", "contact"])
+            .is_ok());
+    }
+}
@@ -1,153 +1,2849 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post, Router},
-    Json,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, FromRef, FromRequest, Request, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put, Router},
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, RwLock};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tower_http::{catch_panic::CatchPanicLayer, compression::CompressionLayer, trace::TraceLayer};
 
 use crate::{
-    query::Query,
-    storage::{Database, Key},
+    access::{Access, AccessPolicy, Action},
+    archive::ArchivedSnapshot,
+    audit::{capture_actor, AuditEntry, AuditLog},
+    changes::{Change, ChangeBatch, ChangeLog},
+    compaction::CompactionReport,
+    config::{BigRecordDecoding, Config, KeyFormat},
+    consistency::ConsistencyReport,
+    deadline::{enforce_deadline, Deadline},
+    error::ApiError,
+    export::JsonExport,
+    extract::{ItemKey, Json, Path, Query as QueryParams},
+    history::FlagHistory,
+    idempotency::{replay_idempotent, IdempotencyCache},
+    import::{ImportProgress, Imports},
+    jobs::{JobError, JobId, JobInfo, JobKind, Jobs},
+    keys::{apply_key_format, ApiKey},
+    ndjson::{is_ndjson, NdjsonReader},
+    query::{
+        CountEstimate, FilteredQuery, FlagDiff, IdQuery, KeyRange, Query, QueryError, SimilarKey,
+        SimilarityMetric,
+    },
+    query_cache::QueryCache,
+    quotas::QuotaExceeded,
+    redis::RedisSource,
+    reload::{ConfigUpdate, Reloader, RuntimeConfig},
+    replication::{Standby, SNAPSHOT_SEQ_HEADER},
+    schedules::{Schedule, ScheduleInfo, Schedules},
+    serde::delta::{DeltaError, DeltaReport},
+    snapshots::Snapshotter,
+    soft_delete::{self, DeletedRecord},
+    stats::{ItemInfo, KeyListing, Stats, TermUsage},
+    storage::{Database, Key, SetFlagError, StorageCorruption, TermError, TermId, TermTableFull},
+    stored_queries::{Combination, CombineError, StoredQueries},
+    telemetry::LogFilter,
+    tenants::{confine_tenants, TenantMetrics},
+    term_locks::TermLocks,
+    time_travel,
+    transaction::{upsert_operations, DryRun, Operation, OperationResult},
+    triggers::{self, TriggerInfo},
+    views::{ViewDefinition, ViewInfo, Views},
+    write_queue::Writer,
 };
 
-type DBState = Arc<RwLock<Database<8>>>;
+pub type DBState = Arc<RwLock<Database<8>>>;
+
+#[derive(Clone)]
+pub struct AppState {
+    db: DBState,
+    changes: Arc<ChangeLog>,
+    idempotency: Arc<IdempotencyCache>,
+    read_only: Arc<AtomicBool>,
+    max_memory: Option<Arc<MemoryLimit>>,
+    concurrency: ConcurrencyLimits,
+    audit: Option<Arc<AuditLog>>,
+    writer: Writer,
+    key_format: KeyFormat,
+    max_body_bytes: usize,
+    imports: Arc<Imports>,
+    jobs: Arc<Jobs>,
+    stored_queries: Arc<StoredQueries>,
+    views: Arc<Views>,
+    schedules: Arc<Schedules>,
+    snapshotter: Snapshotter,
+    reloader: Reloader,
+    handler_panics: Arc<AtomicU64>,
+    standby: Arc<Mutex<Option<Standby>>>,
+    access: Arc<AccessPolicy>,
+    tenant_metrics: Arc<TenantMetrics>,
+    locks: Arc<TermLocks>,
+    deleted_retention: Duration,
+    query_cache: Option<Arc<QueryCache>>,
+    history: Option<Arc<FlagHistory>>,
+}
+
+impl AppState {
+    /// Fails if audit log is configured but cannot be opened, or access policy, term locks,
+    /// stored queries, views or schedules cannot be read
+    pub fn new(
+        db: DBState,
+        snapshotter: Snapshotter,
+        jobs: Arc<Jobs>,
+        log_filter: LogFilter,
+        config: &Config,
+    ) -> std::io::Result<Self> {
+        let audit = match &config.audit_log {
+            Some(path) => Some(Arc::new(AuditLog::open(path)?)),
+            None => None,
+        };
+        let access = match &config.access_policy {
+            Some(path) => AccessPolicy::load(path)?,
+            None => AccessPolicy::default(),
+        };
+        let views = Arc::new(Views::open(Views::path_for(&config.data_file))?);
+        let schedules = Arc::new(Schedules::open(
+            Schedules::path_for(&config.data_file),
+            db.clone(),
+        )?);
+        let query_cache = config
+            .query_cache_capacity
+            .map(|capacity| Arc::new(QueryCache::new(capacity)));
+        let locks = Arc::new(TermLocks::open(TermLocks::path_for(&config.data_file))?);
+        // nothing else takes the database lock before state is set up
+        db.try_write()
+            .map_err(|_| std::io::Error::other("database is locked while setting up state"))?
+            .set_eviction_guard(locks.clone());
+        let standby = config
+            .follow
+            .clone()
+            .map(|leader| Standby::start(leader, db.clone(), views.clone(), query_cache.clone()));
+        let standby = Arc::new(Mutex::new(standby));
+        let read_only = Arc::new(AtomicBool::new(config.read_only));
+        let mut changes = ChangeLog::new(config.changelog_capacity).with_views(views.clone());
+        if let Some(query_cache) = &query_cache {
+            changes = changes.with_query_cache(query_cache.clone());
+        }
+        if let Some(audit) = &audit {
+            changes = changes.with_audit(audit.clone());
+        }
+        let history = config
+            .flag_history
+            .map(|per_key| Arc::new(FlagHistory::new(per_key)));
+        if let Some(history) = &history {
+            changes = changes.with_history(history.clone());
+        }
+        let changes = Arc::new(changes);
+        let deleted_retention = Duration::from_secs(config.deleted_retention_secs);
+        let paused = {
+            let (read_only, standby) = (read_only.clone(), standby.clone());
+            move || read_only.load(Ordering::Relaxed) || standby.lock().unwrap().is_some()
+        };
+        tokio::spawn(soft_delete::purge_periodically(
+            db.clone(),
+            changes.clone(),
+            deleted_retention,
+            Duration::from_secs(config.purge_interval_secs),
+            paused,
+        ));
+
+        let writer = if config.write_batching {
+            Writer::batching(
+                db.clone(),
+                config.write_queue_capacity,
+                config.write_batch_size,
+            )
+        } else {
+            Writer::direct(db.clone())
+        };
+
+        let concurrency = ConcurrencyLimits {
+            reads: Arc::new(ConcurrencyLimit::new(config.max_concurrent_reads)),
+            writes: Arc::new(ConcurrencyLimit::new(config.max_concurrent_writes)),
+            admin: Arc::new(ConcurrencyLimit::new(config.max_concurrent_admin)),
+        };
+        let reloader = Reloader::new(
+            log_filter,
+            snapshotter.clone(),
+            read_only.clone(),
+            concurrency.clone(),
+            config.reload_file.clone(),
+        );
+
+        Ok(Self {
+            db,
+            changes,
+            idempotency: Arc::new(IdempotencyCache::new(
+                config.idempotency_capacity,
+                Duration::from_secs(config.idempotency_ttl_secs),
+            )),
+            read_only,
+            max_memory: config
+                .max_memory_bytes
+                .map(|limit| Arc::new(MemoryLimit::new(limit))),
+            concurrency,
+            audit,
+            writer,
+            key_format: config.key_format,
+            max_body_bytes: config.max_body_bytes,
+            imports: Arc::new(Imports::new(config.data_file.clone(), jobs.clone())),
+            jobs,
+            stored_queries: Arc::new(StoredQueries::open(StoredQueries::path_for(
+                &config.data_file,
+            ))?),
+            views,
+            schedules,
+            snapshotter,
+            reloader,
+            handler_panics: Arc::default(),
+            standby,
+            access: Arc::new(access),
+            tenant_metrics: Arc::default(),
+            locks,
+            deleted_retention,
+            query_cache,
+            history,
+        })
+    }
+
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+}
+
+impl FromRef<AppState> for DBState {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ChangeLog> {
+    fn from_ref(state: &AppState) -> Self {
+        state.changes.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<QueryCache>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.query_cache.clone()
+    }
+}
+
+impl FromRef<AppState> for Writer {
+    fn from_ref(state: &AppState) -> Self {
+        state.writer.clone()
+    }
+}
+
+impl FromRef<AppState> for KeyFormat {
+    fn from_ref(state: &AppState) -> Self {
+        state.key_format
+    }
+}
+
+impl FromRef<AppState> for Arc<AccessPolicy> {
+    fn from_ref(state: &AppState) -> Self {
+        state.access.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<TenantMetrics> {
+    fn from_ref(state: &AppState) -> Self {
+        state.tenant_metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<TermLocks> {
+    fn from_ref(state: &AppState) -> Self {
+        state.locks.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<IdempotencyCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.idempotency.clone()
+    }
+}
+
+/// Requests served at once by each route group, changed at runtime through [`Reloader`]
+#[derive(Clone, Debug, Default)]
+pub struct ConcurrencyLimits {
+    pub reads: Arc<ConcurrencyLimit>,
+    pub writes: Arc<ConcurrencyLimit>,
+    pub admin: Arc<ConcurrencyLimit>,
+}
+
+/// Limit of requests of one route group in flight, zero meaning unlimited
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimit {
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit.unwrap_or(0)),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self) -> Option<usize> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|&limit| limit > 0)
+    }
+
+    /// Requests already in flight keep being served when limit is lowered below their count
+    pub fn set(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Count request in until returned guard is dropped, or give back limit it would exceed
+    fn enter(&self) -> Result<InFlight<'_>, usize> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let guard = InFlight(&self.in_flight);
+        let before = self.in_flight.fetch_add(1, Ordering::AcqRel);
+        if limit > 0 && before >= limit {
+            return Err(limit);
+        }
+        Ok(guard)
+    }
+}
+
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Let at most limit of route group's requests in at once, shedding the rest with 503 instead of
+/// queueing them. Applied as route layer sharing one counter, so the limit covers whole group
+fn limit_concurrency(
+    router: Router<AppState>,
+    group: &'static str,
+    limit: Arc<ConcurrencyLimit>,
+) -> Router<AppState> {
+    router.route_layer(middleware::from_fn_with_state(
+        (group, limit),
+        shed_over_limit,
+    ))
+}
+
+async fn shed_over_limit(
+    State((group, limit)): State<(&'static str, Arc<ConcurrencyLimit>)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limit.enter() {
+        Ok(_in_flight) => next.run(request).await,
+        Err(limit) => overloaded(group, limit),
+    }
+}
+
+fn overloaded(group: &str, limit: usize) -> Response {
+    let mut response = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "overloaded",
+        format!("{limit} {group} requests are already being served, retry later"),
+    )
+    .with_detail(serde_json::json!({ "group": group, "limit": limit }))
+    .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, header::HeaderValue::from_static("1"));
+    response
+}
 
-pub fn build_router(state: DBState) -> axum::Router {
-    Router::new()
-        .route("/terms", get(list_terms).post(create_term))
-        .route("/items", get(list_items).post(create_item))
+pub fn build_router(state: AppState) -> axum::Router {
+    let reads = Router::new()
+        .route("/terms", get(list_terms))
+        .route("/terms/detailed", get(list_terms_detailed))
+        .route("/terms/least-used", get(list_least_used_terms))
+        .route("/terms/locked", get(list_locked_terms))
+        .route("/aliases", get(list_aliases))
+        .route("/term-groups", get(list_term_groups))
+        .route("/term-groups/:name", get(get_term_group))
+        .route("/triggers", get(list_triggers))
+        .route("/triggers/:name", get(get_trigger))
+        .route("/items", get(list_items))
         .route(
             "/items/:key",
-            get(make_horizontal_query).post(add_term_to_key),
+            get(make_horizontal_query).head(check_item_exists),
+        )
+        .route("/items/filtered", post(get_items_filtered))
+        .route("/items/:key/similar", post(find_similar_items))
+        .route("/items/:key/info", get(get_item_info))
+        .route("/items/:key/history", get(get_item_history))
+        .route("/items/:key/by-id", get(make_horizontal_query_by_id))
+        .route("/items/:key/diff/:other", get(diff_items))
+        .route(
+            "/items/by-alias/:name",
+            get(make_horizontal_query).head(check_item_exists),
         )
+        .route("/items/by-alias/:name/similar", post(find_similar_items))
+        .route("/items/by-alias/:name/info", get(get_item_info))
+        .route("/items/by-alias/:name/history", get(get_item_history))
+        .route("/key-aliases", get(list_key_aliases))
         .route("/query", post(make_vertical_query))
+        .route("/query/by-id", post(make_vertical_query_by_id))
+        .route("/query/facets", post(make_facet_query))
+        .route("/query/count", post(count_vertical_query))
+        .route("/query/combine", post(combine_stored_queries))
+        .route("/queries", get(list_stored_queries))
+        .route("/queries/:name", get(get_stored_query))
+        .route("/views", get(list_views))
+        .route("/schedules", get(list_schedules))
+        .route("/schedules/:name", get(get_schedule))
+        .route("/views/:name/keys", get(get_view_keys))
+        .route("/views/:name/count", get(get_view_count))
+        .route("/bulk/query", post(make_vertical_query_bulk))
+        .route("/bulk/import/:job_id", get(get_import_progress));
+    #[cfg(feature = "graphql")]
+    let reads = reads.route("/graphql", post(crate::graphql::execute));
+    let reads = reads.route_layer(middleware::from_fn(enforce_deadline));
+
+    let writes = Router::new()
+        .route("/terms", post(create_term))
+        .route("/terms/:term", patch(rename_term))
+        .route("/terms/:term/merge-into/:into", post(merge_term))
+        .route("/terms/:term/lock", post(lock_term).delete(unlock_term))
+        .route("/aliases", post(create_alias))
+        .route("/aliases/:alias", delete(remove_alias))
+        .route("/term-groups", post(set_term_group))
+        .route("/term-groups/:name", delete(remove_term_group))
+        .route("/triggers", post(set_trigger))
+        .route("/triggers/:name", delete(remove_trigger))
+        .route("/items", post(create_item))
+        .route(
+            "/items/:key",
+            post(add_term_to_key)
+                .put(replace_item_flags)
+                .delete(delete_item),
+        )
+        .route(
+            "/items/by-alias/:name",
+            post(add_term_to_key)
+                .put(replace_item_flags)
+                .delete(delete_item),
+        )
+        .route("/items/:key/by-id", post(add_term_id_to_key))
+        .route("/items/:key/flags", post(upsert_item_flags))
+        .route("/items/by-alias/:name/flags", post(upsert_item_flags))
+        .route("/items/:key/restore", post(restore_item))
+        .route(
+            "/items/:key/alias",
+            put(set_key_alias).delete(remove_key_alias),
+        )
+        .route(
+            "/items/by-alias/:name/alias",
+            put(set_key_alias).delete(remove_key_alias),
+        )
         .route("/bulk/items", post(allocate_items_bulk))
         .route("/bulk/keys", post(set_keys_bulk))
+        .route("/bulk/import", post(start_import))
+        .route("/bulk/import/redis", post(start_redis_import))
+        .route("/transactions", post(run_transaction))
+        .route("/batch", post(run_batch))
+        .route(
+            "/queries/:name",
+            put(put_stored_query).delete(remove_stored_query),
+        )
+        .route("/views", post(create_view))
+        .route(
+            "/schedules/:name",
+            put(put_schedule).delete(remove_schedule),
+        )
+        .route("/views/:name", delete(remove_view))
+        .route_layer(middleware::from_fn(capture_actor))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_when_memory_exceeded,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            replay_idempotent,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_when_read_only,
+        ));
+
+    let admin_writes = Router::new()
+        .route("/admin/compact", post(compact_storage))
+        .route("/admin/deleted/purge", post(purge_deleted_items))
+        .route(
+            "/admin/restore",
+            post(restore_snapshot).layer(DefaultBodyLimit::disable()),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_when_read_only,
+        ));
+
+    let admin = Router::new()
         .route("/service/save", post(save_state))
+        .route("/service/save/delta", post(save_delta))
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .route("/admin/snapshot", post(save_state))
+        .route("/admin/export", get(export_json))
+        .route("/admin/deleted", get(list_deleted_items))
+        .route("/admin/jobs", get(list_jobs))
+        .route("/admin/jobs/:job_id", get(get_job).delete(cancel_job))
+        .route("/admin/check", get(check_consistency))
+        .route("/admin/readonly", get(get_read_only).post(set_read_only))
+        .route("/admin/promote", post(promote_standby))
+        .route("/admin/config", get(get_config).post(update_config))
+        .route("/admin/audit", get(list_audit_entries))
+        .route("/replication/changes", get(list_changes))
+        .route("/admin/snapshot/stream", get(stream_snapshot))
+        .route("/admin/snapshots", get(list_archived_snapshots))
+        .merge(admin_writes)
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+
+    let limits = state.concurrency.clone();
+    let handler_panics = state.handler_panics.clone();
+    let router = Router::new()
+        .merge(limit_concurrency(reads, "read", limits.reads))
+        .merge(limit_concurrency(writes, "write", limits.writes))
+        .merge(limit_concurrency(admin, "admin", limits.admin))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            confine_tenants,
+        ))
+        .fallback(route_not_found)
+        .layer(middleware::from_fn_with_state(
+            state.key_format,
+            apply_key_format,
+        ))
+        .layer(DefaultBodyLimit::max(state.max_body_bytes));
+    catch_panics(router, handler_panics)
+        .layer(CompressionLayer::new())
+        .layer(TraceLayer::new_for_http())
         .with_state(state)
 }
 
-async fn create_term(
-    State(db): State<DBState>,
-    term: Json<String>,
-) -> Result<(StatusCode, Json<impl Serialize>), (StatusCode, Json<impl Serialize>)> {
-    let mut db = db.write().await;
+/// Answer requests whose handler panicked with 500, counting them in `panics`
+fn catch_panics<S: Clone + Send + Sync + 'static>(
+    router: Router<S>,
+    panics: Arc<AtomicU64>,
+) -> Router<S> {
+    router.layer(CatchPanicLayer::custom(move |panic| {
+        handler_panicked(panic, &panics)
+    }))
+}
+
+/// Answer request whose handler panicked with 500, the backtrace is logged by panic hook
+fn handler_panicked(panic: Box<dyn std::any::Any + Send>, panics: &AtomicU64) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown cause");
+    panics.fetch_add(1, Ordering::Relaxed);
+    tracing::error!("request handler panicked: {message}");
+    ApiError::internal("request handler panicked").into_response()
+}
+
+async fn route_not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "route_not_found", "no such route")
+}
 
-    if db.get_term_id(&term).is_some() {
-        return Err((StatusCode::CONFLICT, Json("term already exists")));
+/// With access policy loaded, admin routes are reserved for API keys holding admin role
+async fn require_admin(
+    State(policy): State<Arc<AccessPolicy>>,
+    access: Access,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if policy.loaded {
+        access.check_admin()?;
     }
+    Ok(next.run(request).await)
+}
 
-    match db.add_term(&term) {
-        Ok(new_index) => Ok((StatusCode::CREATED, Json(u8::from(new_index)))),
-        Err(_) => Err((StatusCode::BAD_REQUEST, Json("term database is full"))),
+async fn reject_when_read_only(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(standby) = &*state.standby.lock().unwrap() {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "standby",
+            "instance is a standby, promote it to take writes",
+        )
+        .with_detail(serde_json::json!({ "leader": standby.leader() }))
+        .into_response();
+    }
+    if state.read_only.load(Ordering::Relaxed) {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "database is in read-only mode",
+        )
+        .into_response();
     }
+    next.run(request).await
 }
 
-async fn list_terms(State(db): State<DBState>) -> Json<Vec<String>> {
-    let db = db.read().await;
-    Json(db.terms.left_keys().cloned().collect())
+/// How long memory usage estimated for the limit is trusted before writes estimate it again
+const MEMORY_ESTIMATE_TTL: Duration = Duration::from_secs(1);
+
+/// `--max-memory-bytes` along with usage estimated lately, as estimating it walks every record
+struct MemoryLimit {
+    limit: usize,
+    estimate: Mutex<Option<(Instant, usize)>>,
 }
 
-async fn create_item(State(db): State<DBState>, Json(key): Json<Key>) -> StatusCode {
-    let mut db = db.write().await;
-    if db.create_record(key) {
-        StatusCode::CREATED
-    } else {
-        StatusCode::CONFLICT
+impl MemoryLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            estimate: Mutex::new(None),
+        }
     }
-}
 
-async fn allocate_items_bulk(
-    State(db): State<DBState>,
-    Json(items): Json<Vec<Key>>,
-) -> Result<StatusCode, (StatusCode, Json<Vec<Key>>)> {
-    let mut db = db.write().await;
-    let mut existing_keys = vec![];
-    for item in items {
-        if !db.create_record(item) {
-            existing_keys.push(item);
+    /// Usage estimated within [`MEMORY_ESTIMATE_TTL`], estimated anew once it gets older
+    async fn usage(&self, db: &DBState) -> usize {
+        let cached = *self.estimate.lock().unwrap();
+        if let Some((_, usage)) = cached.filter(|(at, _)| at.elapsed() < MEMORY_ESTIMATE_TTL) {
+            return usage;
         }
+        let usage = db.read().await.memory_usage().total;
+        *self.estimate.lock().unwrap() = Some((Instant::now(), usage));
+        usage
     }
-    if existing_keys.is_empty() {
-        Ok(StatusCode::CREATED)
-    } else {
-        Err((StatusCode::CONFLICT, Json(existing_keys)))
+}
+
+/// Deletions are let through since they are the way out of this state
+async fn reject_when_memory_exceeded(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let memory = state
+        .max_memory
+        .as_ref()
+        .filter(|_| request.method() != Method::DELETE);
+    if let Some(memory) = memory {
+        let limit = memory.limit;
+        let usage = memory.usage(&state.db).await;
+        if usage > limit {
+            return ApiError::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "memory_limit_exceeded",
+                format!("estimated memory usage of {usage} bytes exceeds limit of {limit} bytes"),
+            )
+            .with_detail(serde_json::json!({ "usage": usage, "limit": limit }))
+            .into_response();
+        }
     }
+    next.run(request).await
 }
 
-async fn list_items(State(db): State<DBState>) -> Json<Vec<Key>> {
-    let db = db.read().await;
+async fn get_read_only(State(state): State<AppState>) -> Json<bool> {
+    Json(state.read_only.load(Ordering::Relaxed))
+}
 
-    Json(db.list_keys().collect())
+async fn set_read_only(State(state): State<AppState>, Json(read_only): Json<bool>) -> StatusCode {
+    state.read_only.store(read_only, Ordering::Relaxed);
+    StatusCode::OK
 }
 
-async fn add_term_to_key(
-    State(db): State<DBState>,
-    Path(key): Path<Key>,
-    Json(term): Json<String>,
-) -> Result<StatusCode, (StatusCode, Json<&'static str>)> {
-    let mut db = db.write().await;
+#[derive(Clone, Debug, Serialize)]
+struct Promotion {
+    /// Leader the instance stopped following
+    previous_leader: String,
+}
 
-    match db.set_flag(key, &term) {
-        Ok(_) => Ok(StatusCode::CREATED),
-        Err(_) => Err((
+/// Stop following leader and start taking writes, unless read-only mode is on
+async fn promote_standby(State(state): State<AppState>) -> Result<Json<Promotion>, ApiError> {
+    let standby = state.standby.lock().unwrap().take().ok_or_else(|| {
+        ApiError::new(
             StatusCode::CONFLICT,
-            Json("term database is full and cannot take more terms"),
-        )),
+            "not_standby",
+            "instance is not a standby",
+        )
+    })?;
+    let previous_leader = standby.promote();
+    state
+        .db
+        .write()
+        .await
+        .set_trigger_webhooks(triggers::deliver_webhooks());
+    tracing::info!(previous_leader, "promoted standby, taking writes");
+    Ok(Json(Promotion { previous_leader }))
+}
+
+async fn get_config(State(state): State<AppState>) -> Json<RuntimeConfig> {
+    Json(state.reloader.current())
+}
+
+async fn update_config(
+    State(state): State<AppState>,
+    Json(update): Json<ConfigUpdate>,
+) -> Result<Json<RuntimeConfig>, ApiError> {
+    Ok(Json(state.reloader.apply(update)?))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ChangesParams {
+    since: u64,
+    #[serde(default)]
+    wait_ms: u64,
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+}
+
+fn default_changes_limit() -> usize {
+    10_000
+}
+
+const MAX_CHANGES_WAIT: Duration = Duration::from_secs(60);
+
+async fn list_changes(
+    State(changes): State<Arc<ChangeLog>>,
+    QueryParams(params): QueryParams<ChangesParams>,
+) -> Result<Json<ChangeBatch>, ApiError> {
+    let wait = Duration::from_millis(params.wait_ms).min(MAX_CHANGES_WAIT);
+    let batch = changes.wait_since(params.since, params.limit, wait).await?;
+    Ok(Json(ChangeBatch {
+        next: batch.last().map_or(params.since, |change| change.seq + 1),
+        changes: batch,
+    }))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct AuditParams {
+    #[serde(default)]
+    since: u64,
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+}
+
+async fn list_audit_entries(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams<AuditParams>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let Some(audit) = &state.audit else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "audit_disabled",
+            "audit log is not enabled",
+        ));
+    };
+    match audit.read_since(params.since, params.limit).await {
+        Ok(entries) => Ok(Json(entries)),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+/// Sink forwarding everything written into it as body chunks
+struct ChunkWriter(mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+async fn list_archived_snapshots(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ArchivedSnapshot>>, ApiError> {
+    let Some(archive) = state.snapshotter.archive() else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "snapshot_archive_disabled",
+            "snapshot archive is not enabled",
+        ));
+    };
+    archive
+        .list()
+        .map(Json)
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+async fn stream_snapshot(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    // copy is taken under a brief read lock, so that slow clients do not hold up writers
+    let (db, seq) = {
+        let db = state.db.read().await;
+        let copy = db
+            .frozen()
+            .map_err(|e| ApiError::internal(format!("failed to copy snapshot: {e}")))?;
+        (copy, state.changes.next_seq())
+    };
+    let (sender, receiver) = mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BufWriter::with_capacity(SNAPSHOT_CHUNK_SIZE, ChunkWriter(sender.clone()));
+        let result = db
+            .dump(&mut writer)
+            .map_err(std::io::Error::other)
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/msgpack".to_string()),
+            (
+                HeaderName::from_static(SNAPSHOT_SEQ_HEADER),
+                seq.to_string(),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    ))
+}
+
+/// Snapshot to restore from when it is not uploaded in request body
 #[derive(Clone, Debug, Deserialize)]
-struct SetKeysBulk {
-    term: String,
-    keys: Vec<Key>,
+#[serde(rename_all = "snake_case")]
+enum RestoreSource {
+    /// Slot file or bare dump, or data file whose slots and deltas should be loaded
+    Path(PathBuf),
+    /// Location serving slot file or bare dump, e.g. `/admin/snapshot/stream` of a peer
+    Url(String),
 }
 
-async fn set_keys_bulk(State(db): State<DBState>, Json(request): Json<SetKeysBulk>) -> StatusCode {
-    let mut db = db.write().await;
-    if db.add_term(&request.term).is_err() {
-        return StatusCode::CONFLICT;
+#[derive(Clone, Debug, Serialize)]
+struct RestoreReport {
+    keys: usize,
+    terms: usize,
+}
+
+fn invalid_snapshot(e: impl ToString) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_snapshot",
+        e.to_string(),
+    )
+}
+
+async fn load_restore_source(source: RestoreSource) -> Result<Database<8>, ApiError> {
+    let bytes = match source {
+        RestoreSource::Path(path) if path.is_file() => tokio::fs::read(&path)
+            .await
+            .map(Bytes::from)
+            .map_err(|e| ApiError::internal(e.to_string()))?,
+        RestoreSource::Path(path) => {
+            if crate::serde::rotation::slots_newest_first(&path).is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "snapshot_not_found",
+                    format!("no snapshot at {}", path.display()),
+                ));
+            }
+            return tokio::task::spawn_blocking(move || {
+                crate::serde::load_possibly_missing(path, BigRecordDecoding::Eager)
+                    .map_err(invalid_snapshot)
+            })
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        }
+        RestoreSource::Url(url) => {
+            let fetched = async { reqwest::get(&url).await?.error_for_status()?.bytes().await };
+            fetched.await.map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "snapshot_fetch_failed",
+                    format!("failed to fetch {url}: {e}"),
+                )
+            })?
+        }
+    };
+    tokio::task::spawn_blocking(move || crate::serde::load_bytes(&bytes).map_err(invalid_snapshot))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+}
+
+/// Replace whole state with snapshot from request body, or from location given as JSON
+async fn restore_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<RestoreReport>, ApiError> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let restored = if is_json {
+        let source = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_restore_source",
+                e.to_string(),
+            )
+        })?;
+        load_restore_source(source).await?
+    } else {
+        tokio::task::spawn_blocking(move || {
+            crate::serde::load_bytes(&body).map_err(invalid_snapshot)
+        })
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))??
+    };
+
+    let check = restored.check_consistency();
+    if !check.consistent {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "inconsistent_snapshot",
+            "snapshot does not pass consistency check",
+        )
+        .with_detail(check.problems));
     }
-    for key in request.keys {
-        db.set_flag(key, &request.term).unwrap();
+    let report = RestoreReport {
+        keys: check.checked_keys,
+        terms: check.checked_terms,
+    };
+
+    let mut db = state.db.write().await;
+    db.replace_with(restored)
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    // followers have to bootstrap again, their feed positions refer to replaced state
+    state.changes.discard_all();
+    Ok(Json(report))
+}
+
+/// Body of `POST /terms`, either bare name or `{name, id}` asking for a particular id.
+/// Ids of removed terms are only reused when asked for this way
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum TermRequest {
+    Name(String),
+    WithId {
+        name: String,
+        #[serde(default)]
+        id: Option<TermId>,
+    },
+}
+
+/// Names unused term whose id was reused for new term, as terms are evicted only when the term
+/// table is full and `--term-eviction` allows it
+const EVICTED_TERM_HEADER: &str = "x-elizadb-evicted-term";
+
+fn with_evicted_term(db: &Database<8>, term_id: TermId, mut response: Response) -> Response {
+    let evicted = db
+        .evicted_term(term_id)
+        .and_then(|name| header::HeaderValue::from_str(name).ok());
+    if let Some(evicted) = evicted {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(EVICTED_TERM_HEADER), evicted);
     }
+    response
+}
 
-    StatusCode::OK
+async fn create_term(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    Json(request): Json<TermRequest>,
+) -> Result<Response, ApiError> {
+    let (term, id) = match request {
+        TermRequest::Name(name) => (name, None),
+        TermRequest::WithId { name, id } => (name, id),
+    };
+    writer
+        .run(move |db| {
+            if db.get_term_id(&term).is_some() {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "term_exists",
+                    "term already exists",
+                ));
+            }
+            if let Some(id) = id {
+                let new_index = db.add_term_with_id(&term, id)?;
+                changes.record(Change::AddTermWithId { term, id });
+                return Ok((StatusCode::CREATED, Json(TermId::from(new_index))).into_response());
+            }
+
+            match db.add_term(&term) {
+                Ok(new_index) => {
+                    changes.record(Change::AddTerm { term });
+                    let term_id = TermId::from(new_index);
+                    let response = (StatusCode::CREATED, Json(term_id)).into_response();
+                    Ok(with_evicted_term(db, term_id, response))
+                }
+                Err(e) => Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "term_table_full",
+                    e.to_string(),
+                )),
+            }
+        })
+        .await
 }
 
-async fn make_horizontal_query(
+async fn rename_term(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    State(locks): State<Arc<TermLocks>>,
+    access: Access,
+    Path(term): Path<String>,
+    Json(new_name): Json<String>,
+) -> Result<Json<TermId>, ApiError> {
+    writer
+        .run(move |db| {
+            access.check_terms(db, Action::Write, [term.as_str(), new_name.as_str()])?;
+            access.check_unlocked(db, [term.as_str()])?;
+            let term_id = db.rename_term(&term, &new_name)?;
+            if let Err(e) = locks.rename(&term, &new_name) {
+                tracing::warn!("failed to move lock of renamed term {term}: {e}");
+            }
+            changes.record(Change::RenameTerm { term, new_name });
+            Ok(Json(term_id))
+        })
+        .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CreateAlias {
+    alias: String,
+    term: String,
+}
+
+/// Aliases reach flags of their term, so caller has to be allowed to write it
+async fn create_alias(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    Json(request): Json<CreateAlias>,
+) -> Result<(StatusCode, Json<TermId>), ApiError> {
+    writer
+        .run(move |db| {
+            access.check_terms(
+                db,
+                Action::Write,
+                [request.term.as_str(), request.alias.as_str()],
+            )?;
+            let term_id = db.add_alias(&request.term, &request.alias)?;
+            changes.record(Change::AddAlias {
+                alias: request.alias,
+                term: request.term,
+            });
+            Ok((StatusCode::CREATED, Json(term_id)))
+        })
+        .await
+}
+
+async fn list_aliases(State(db): State<DBState>) -> Json<HashMap<String, String>> {
+    let db = db.read().await;
+    Json(
+        db.list_aliases()
+            .map(|(alias, term)| (alias.to_string(), term.to_string()))
+            .collect(),
+    )
+}
+
+async fn remove_alias(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    Path(alias): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            if db.remove_alias(&alias) {
+                changes.record(Change::RemoveAlias { alias });
+                Ok(StatusCode::NO_CONTENT)
+            } else {
+                Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "unknown_alias",
+                    format!("alias {alias} does not exist"),
+                ))
+            }
+        })
+        .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SetTermGroup {
+    name: String,
+    terms: Vec<String>,
+    /// Records may carry at most one of the terms, setting one clears the others
+    #[serde(default)]
+    exclusive: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct TermGroupInfo {
+    terms: Vec<String>,
+    exclusive: bool,
+    /// Given as `prefix*`, terms added with them join the group
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    prefixes: Vec<String>,
+}
+
+fn term_group_info(db: &Database<8>, name: &str) -> Option<TermGroupInfo> {
+    Some(TermGroupInfo {
+        terms: db.term_group(name)?.into_iter().map(String::from).collect(),
+        exclusive: db.is_exclusive_term_group(name),
+        prefixes: db
+            .term_group_prefixes(name)?
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Create group or replace its terms, responds with canonical names of terms given by alias
+async fn set_term_group(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    Json(request): Json<SetTermGroup>,
+) -> Result<(StatusCode, Json<TermGroupInfo>), ApiError> {
+    writer
+        .run(move |db| {
+            db.set_term_group(&request.name, &request.terms, request.exclusive)?;
+            let info = term_group_info(db, &request.name).expect("group was just set");
+            changes.record(Change::SetTermGroup {
+                name: request.name,
+                terms: request.terms,
+                exclusive: request.exclusive,
+            });
+            Ok((StatusCode::CREATED, Json(info)))
+        })
+        .await
+}
+
+async fn list_term_groups(State(db): State<DBState>) -> Json<BTreeMap<String, TermGroupInfo>> {
+    let db = db.read().await;
+    Json(
+        db.list_term_groups()
+            .filter_map(|(name, _)| Some((name.to_string(), term_group_info(&db, name)?)))
+            .collect(),
+    )
+}
+
+fn unknown_term_group(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "unknown_term_group",
+        format!("term group {name} does not exist"),
+    )
+}
+
+async fn get_term_group(
     State(db): State<DBState>,
-    Path(key): Path<Key>,
-) -> Result<(StatusCode, Json<Vec<String>>), (StatusCode, Json<&'static str>)> {
+    Path(name): Path<String>,
+) -> Result<Json<TermGroupInfo>, ApiError> {
     let db = db.read().await;
-    match db.horizontal_query(&key) {
-        Some(items) => Ok((
-            StatusCode::OK,
-            Json(items.into_iter().map(String::from).collect()),
-        )),
-        None => Err((StatusCode::NOT_FOUND, Json("key does not exist"))),
-    }
+    term_group_info(&db, &name)
+        .map(Json)
+        .ok_or_else(|| unknown_term_group(&name))
 }
 
-async fn make_vertical_query(
+async fn remove_term_group(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            if db.remove_term_group(&name) {
+                changes.record(Change::RemoveTermGroup { name });
+                Ok(StatusCode::NO_CONTENT)
+            } else {
+                Err(unknown_term_group(&name))
+            }
+        })
+        .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SetTrigger {
+    name: String,
+    #[serde(flatten)]
+    trigger: TriggerInfo,
+}
+
+/// Create trigger or replace the one of the same name, responds with canonical names of terms
+/// given by alias
+async fn set_trigger(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    Json(SetTrigger { name, trigger }): Json<SetTrigger>,
+) -> Result<(StatusCode, Json<TriggerInfo>), ApiError> {
+    writer
+        .run(move |db| {
+            // the trigger writes `set` on behalf of whoever sets `when` later, so the caller must
+            // be allowed to write both
+            let terms = std::iter::once(&trigger.when)
+                .chain(&trigger.set)
+                .map(String::as_str);
+            access.check_terms(db, Action::Write, terms.clone())?;
+            access.check_unlocked(db, terms)?;
+            db.set_trigger(
+                &name,
+                &trigger.when,
+                &trigger.set,
+                trigger.webhook.as_deref(),
+            )?;
+            let info = db.trigger(&name).expect("trigger was just set");
+            changes.record(Change::SetTrigger {
+                name,
+                when: trigger.when,
+                set: trigger.set,
+                webhook: trigger.webhook,
+            });
+            Ok((StatusCode::CREATED, Json(info)))
+        })
+        .await
+}
+
+async fn list_triggers(State(db): State<DBState>) -> Json<BTreeMap<String, TriggerInfo>> {
+    let db = db.read().await;
+    Json(
+        db.list_triggers()
+            .map(|(name, trigger)| (name.to_string(), trigger))
+            .collect(),
+    )
+}
+
+fn unknown_trigger(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "unknown_trigger",
+        format!("trigger {name} does not exist"),
+    )
+}
+
+async fn get_trigger(
     State(db): State<DBState>,
-    Json(query): Json<Query>,
-) -> (StatusCode, Result<Json<Vec<Key>>, Json<String>>) {
+    Path(name): Path<String>,
+) -> Result<Json<TriggerInfo>, ApiError> {
+    db.read()
+        .await
+        .trigger(&name)
+        .map(Json)
+        .ok_or_else(|| unknown_trigger(&name))
+}
+
+async fn remove_trigger(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            if db.remove_trigger(&name) {
+                changes.record(Change::RemoveTrigger { name });
+                Ok(StatusCode::NO_CONTENT)
+            } else {
+                Err(unknown_trigger(&name))
+            }
+        })
+        .await
+}
+
+async fn list_terms(State(db): State<DBState>) -> Json<Vec<String>> {
     let db = db.read().await;
-    match db.vertical_query(&query) {
-        Ok(items) => (StatusCode::OK, Ok(Json(items))),
-        Err(message) => (StatusCode::BAD_REQUEST, Err(Json(message))),
+    Json(db.terms.left_keys().cloned().collect())
+}
+
+async fn list_terms_detailed(State(db): State<DBState>) -> Result<Json<Vec<TermUsage>>, ApiError> {
+    Ok(Json(db.read().await.term_usage()?))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct LeastUsedParams {
+    #[serde(default = "default_least_used_limit")]
+    limit: usize,
+}
+
+fn default_least_used_limit() -> usize {
+    10
+}
+
+async fn list_least_used_terms(
+    State(db): State<DBState>,
+    QueryParams(params): QueryParams<LeastUsedParams>,
+) -> Result<Json<Vec<TermUsage>>, ApiError> {
+    Ok(Json(db.read().await.least_used_terms(params.limit)?))
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct TermMerge {
+    /// Number of keys that carried merged term
+    keys: usize,
+    /// Id of the term merged into, which may change as merged term's id is freed
+    into: TermId,
+}
+
+async fn merge_term(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    Path((term, into)): Path<(String, String)>,
+) -> Result<Json<TermMerge>, ApiError> {
+    writer
+        .run(move |db| {
+            access.check_terms(db, Action::Write, [term.as_str(), into.as_str()])?;
+            access.check_unlocked(db, [term.as_str(), into.as_str()])?;
+            let keys = db.merge_term(&term, &into)?;
+            let into_id = db.get_term_id(&into).unwrap();
+            changes.record(Change::MergeTerm { term, into });
+            Ok(Json(TermMerge {
+                keys,
+                into: into_id,
+            }))
+        })
+        .await
+}
+
+async fn list_locked_terms(State(locks): State<Arc<TermLocks>>) -> Json<BTreeSet<String>> {
+    Json(locks.list())
+}
+
+/// Answers 201 when term gets locked and 200 when it already was. Terms given by alias get locked
+/// under their canonical name
+async fn lock_term(
+    State(db): State<DBState>,
+    State(locks): State<Arc<TermLocks>>,
+    access: Access,
+    Path(term): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    access.check_admin()?;
+    let term = {
+        let db = db.read().await;
+        if db.get_term_id(&term).is_none() {
+            return Err(TermError::UnknownTerm(term).into());
+        }
+        db.canonical_name(&term).to_string()
+    };
+    match locks.lock(&term) {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Ok(StatusCode::OK),
+        Err(e) => Err(ApiError::internal(format!(
+            "failed to save term locks: {e}"
+        ))),
     }
 }
 
-async fn save_state(State(db): State<DBState>) -> Result<StatusCode, (StatusCode, Json<String>)> {
-    let db = db.read().await;
-    match crate::serde::two_phase_save(&db, crate::serde::DEFAULT_SAVE_PATH) {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(e.to_string()))),
+async fn unlock_term(
+    State(db): State<DBState>,
+    State(locks): State<Arc<TermLocks>>,
+    access: Access,
+    Path(term): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    access.check_admin()?;
+    let term = db.read().await.canonical_name(&term).to_string();
+    match locks.unlock(&term) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "term_not_locked",
+            format!("term {term} is not locked"),
+        )),
+        Err(e) => Err(ApiError::internal(format!(
+            "failed to save term locks: {e}"
+        ))),
+    }
+}
+
+async fn create_item(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    State(db): State<DBState>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    Json(ApiKey(key)): Json<ApiKey>,
+) -> Result<Response, ApiError> {
+    access.check_key(key)?;
+    if params.dry_run {
+        let db = db.read().await;
+        if db.contains_key(&key) {
+            return Err(key_exists(key));
+        }
+        let report = dry_run(&db, [Operation::CreateRecord { key }])?.report(&db);
+        return Ok(Json(report).into_response());
+    }
+    writer
+        .run(move |db| {
+            if db.create_record(key)? {
+                changes.record(Change::CreateRecord { key });
+                Ok(StatusCode::CREATED.into_response())
+            } else {
+                Err(key_exists(key))
+            }
+        })
+        .await
+}
+
+/// Lines of newline-delimited bulk bodies applied under a single lock acquisition
+const BULK_CHUNK_LINES: usize = 1024;
+
+/// Bulk request payload, either parsed whole from JSON or read from newline-delimited body
+/// a chunk at a time
+enum BulkBody<T> {
+    Whole(Option<Vec<T>>),
+    Lines(NdjsonReader),
+}
+
+impl<T: serde::de::DeserializeOwned> BulkBody<T> {
+    /// Empty once payload is exhausted
+    async fn next_chunk(&mut self) -> Result<Vec<T>, ApiError> {
+        match self {
+            Self::Whole(values) => Ok(values.take().unwrap_or_default()),
+            Self::Lines(reader) => Ok(reader.next_chunk(BULK_CHUNK_LINES).await?),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct DryRunParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Accepts JSON array of keys or, with `Content-Type: application/x-ndjson`, one key per line.
+/// Newline-delimited bodies are applied chunk by chunk, so chunks before a malformed line stay
+/// applied, as do keys created before running out of quota
+async fn allocate_items_bulk(
+    State(db): State<DBState>,
+    State(changes): State<Arc<ChangeLog>>,
+    QueryParams(params): QueryParams<DryRunParams>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let mut items = if is_ndjson(request.headers()) {
+        BulkBody::Lines(NdjsonReader::new(request.into_body()))
+    } else {
+        let Json(items) = Json::from_request(request, &()).await?;
+        BulkBody::Whole(Some(items))
+    };
+
+    let mut dry_run = params.dry_run.then(DryRun::default);
+    let mut existing_keys = vec![];
+    loop {
+        let chunk = items.next_chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        match &mut dry_run {
+            Some(dry_run) => {
+                let db = db.read().await;
+                for ApiKey(key) in chunk {
+                    let result = dry_run.apply(&db, Operation::CreateRecord { key })?;
+                    if result == OperationResult::AlreadyExists {
+                        existing_keys.push(ApiKey(key));
+                    }
+                }
+            }
+            None => create_records(&mut *db.write().await, &changes, chunk, &mut existing_keys)?,
+        }
+    }
+
+    if !existing_keys.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "key_exists",
+            format!("{} of the keys already exist", existing_keys.len()),
+        )
+        .with_detail(existing_keys));
+    }
+    Ok(match dry_run {
+        Some(dry_run) => Json(dry_run.report(&*db.read().await)).into_response(),
+        None => StatusCode::CREATED.into_response(),
+    })
+}
+
+fn create_records(
+    db: &mut Database<8>,
+    changes: &ChangeLog,
+    items: Vec<ApiKey>,
+    existing_keys: &mut Vec<ApiKey>,
+) -> Result<(), QuotaExceeded> {
+    for ApiKey(item) in items {
+        if db.create_record(item)? {
+            changes.record(Change::CreateRecord { key: item });
+        } else {
+            existing_keys.push(ApiKey(item));
+        }
+    }
+    Ok(())
+}
+
+fn key_exists(key: Key) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "key_exists",
+        format!("key {key} already exists"),
+    )
+}
+
+fn key_not_found(key: Key) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "key_not_found",
+        format!("key {key} does not exist"),
+    )
+}
+
+async fn list_items(
+    State(db): State<DBState>,
+    access: Access,
+    QueryParams(mut listing): QueryParams<KeyListing>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let db = db.read().await;
+    if let Some(term) = &listing.has_term {
+        access.check_terms(&db, Action::Read, [term.as_str()])?;
+    }
+    listing.within = access.confine(listing.within);
+
+    Ok(Json(
+        db.list_keys_filtered(&listing)?
+            .into_iter()
+            .map(ApiKey)
+            .collect(),
+    ))
+}
+
+async fn list_key_aliases(State(db): State<DBState>) -> Json<HashMap<String, ApiKey>> {
+    let db = db.read().await;
+    Json(
+        db.list_key_aliases()
+            .map(|(alias, key)| (alias.to_string(), ApiKey(key)))
+            .collect(),
+    )
+}
+
+async fn set_key_alias(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    ItemKey(key): ItemKey,
+    Json(alias): Json<String>,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            if db.key_alias(key) == Some(alias.as_str()) {
+                return Ok(StatusCode::OK);
+            }
+            db.set_key_alias(key, &alias)?;
+            changes.record(Change::SetKeyAlias { key, alias });
+            Ok(StatusCode::CREATED)
+        })
+        .await
+}
+
+async fn remove_key_alias(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    ItemKey(key): ItemKey,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| match db.remove_key_alias(key) {
+            Some(_) => {
+                changes.record(Change::RemoveKeyAlias { key });
+                Ok(StatusCode::NO_CONTENT)
+            }
+            None => Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_key_alias",
+                format!("key {key} has no alias"),
+            )),
+        })
+        .await
+}
+
+async fn delete_item(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    ItemKey(key): ItemKey,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            access.check_record(db, &key, Action::Write)?;
+            access.check_unlocked(db, db.horizontal_query(&key)?.unwrap_or_default())?;
+            let deleted_at = soft_delete::now();
+            if !db.delete_record(key, deleted_at)? {
+                return Err(key_not_found(key));
+            }
+            changes.record(Change::DeleteRecord { key, deleted_at });
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn restore_item(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    ItemKey(key): ItemKey,
+) -> Result<Json<DeletedRecord>, ApiError> {
+    writer
+        .run(move |db| {
+            if let Some(deleted) = db.deleted_record(key) {
+                access.check_terms(db, Action::Write, deleted.terms.iter().map(String::as_str))?;
+                access.check_unlocked(db, deleted.terms.iter().map(String::as_str))?;
+            }
+            let restored = db.restore_record(key)?;
+            changes.record(Change::RestoreRecord { key });
+            Ok(Json(restored))
+        })
+        .await
+}
+
+async fn add_term_to_key(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    State(db): State<DBState>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    ItemKey(key): ItemKey,
+    Json(term): Json<String>,
+) -> Result<Response, ApiError> {
+    {
+        let db = db.read().await;
+        access.check_terms(&db, Action::Write, [term.as_str()])?;
+        access.check_unlocked(&db, [term.as_str()])?;
+    }
+    if params.dry_run {
+        let db = db.read().await;
+        access.check_record(&db, &key, Action::Write)?;
+        let report = dry_run(&db, [Operation::SetFlag { key, term }])?.report(&db);
+        if report.term_capacity_exceeded {
+            return Err(TermTableFull.into());
+        }
+        return Ok(Json(report).into_response());
+    }
+    writer
+        .run(move |db| {
+            access.check_record(db, &key, Action::Write)?;
+            access.check_unlocked(db, db.carried_exclusive_companions(key, &term)?)?;
+            let term_is_new = db.get_term_id(&term).is_none();
+            db.set_flag(key, &term)?;
+            let term_id = db.get_term_id(&term).filter(|_| term_is_new);
+            changes.record(Change::SetFlag { key, term });
+            let response = StatusCode::CREATED.into_response();
+            Ok(match term_id {
+                Some(term_id) => with_evicted_term(db, term_id, response),
+                None => response,
+            })
+        })
+        .await
+}
+
+/// Same as `POST /items/:key` with term given by id, which has to exist already
+async fn add_term_id_to_key(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    ItemKey(key): ItemKey,
+    Json(term_id): Json<TermId>,
+) -> Result<StatusCode, ApiError> {
+    writer
+        .run(move |db| {
+            let term = db
+                .explain_term_id(term_id)
+                .ok_or(SetFlagError::UnknownTermId(term_id))?
+                .to_string();
+            access.check_terms(db, Action::Write, [term.as_str()])?;
+            access.check_unlocked(db, [term.as_str()])?;
+            access.check_record(db, &key, Action::Write)?;
+            access.check_unlocked(db, db.carried_exclusive_companions(key, &term)?)?;
+            db.set_flag_by_id(key, term_id)?;
+            // change feed stays by name, so that its consumers need not know ids
+            changes.record(Change::SetFlag { key, term });
+            Ok(StatusCode::CREATED)
+        })
+        .await
+}
+
+/// Body of `PUT /items/:key`, bare array of terms or object that may also name version record is
+/// expected to be at, which is rejected with 409 otherwise
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ReplacementRequest {
+    Terms(Vec<String>),
+    Versioned {
+        terms: Vec<String>,
+        #[serde(default)]
+        expected_version: Option<u64>,
+    },
+}
+
+async fn replace_item_flags(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    State(db): State<DBState>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    ItemKey(key): ItemKey,
+    Json(replacement): Json<ReplacementRequest>,
+) -> Result<Response, ApiError> {
+    let (terms, expected_version) = match replacement {
+        ReplacementRequest::Terms(terms) => (terms, None),
+        ReplacementRequest::Versioned {
+            terms,
+            expected_version,
+        } => (terms, expected_version),
+    };
+    let given = terms.iter().map(String::as_str);
+    access.check_terms(&*db.read().await, Action::Write, given)?;
+    if params.dry_run {
+        let db = db.read().await;
+        access.check_record(&db, &key, Action::Write)?;
+        if let Some(expected) = expected_version {
+            db.check_version(key, expected)?;
+        }
+        check_replacement_unlocked(&access, &db, key, &terms)?;
+        let dry_run = dry_run(&db, db.replacement_operations(key, &terms)?)?;
+        dry_run.check_term_capacity(&db)?;
+        return Ok(Json(dry_run.report(&db)).into_response());
+    }
+    writer
+        .run(move |db| {
+            access.check_record(db, &key, Action::Write)?;
+            if let Some(expected) = expected_version {
+                db.check_version(key, expected)?;
+            }
+            check_replacement_unlocked(&access, db, key, &terms)?;
+            let replacement = db.replace_flags(key, &terms)?;
+            for change in replacement.changes(key) {
+                changes.record(change);
+            }
+            Ok(Json(replacement).into_response())
+        })
+        .await
+}
+
+/// Set all of listed terms on record under one lock, creating the record and terms as needed,
+/// and report which flags were not set before
+async fn upsert_item_flags(
+    State(writer): State<Writer>,
+    State(changes): State<Arc<ChangeLog>>,
+    State(db): State<DBState>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    ItemKey(key): ItemKey,
+    Json(terms): Json<Vec<String>>,
+) -> Result<Response, ApiError> {
+    {
+        let db = db.read().await;
+        access.check_terms(&db, Action::Write, terms.iter().map(String::as_str))?;
+        access.check_unlocked(&db, terms.iter().map(String::as_str))?;
+    }
+    if params.dry_run {
+        let db = db.read().await;
+        access.check_record(&db, &key, Action::Write)?;
+        check_companions_unlocked(&access, &db, key, &terms)?;
+        let dry_run = dry_run(&db, upsert_operations(key, &terms))?;
+        dry_run.check_term_capacity(&db)?;
+        return Ok(Json(dry_run.report(&db)).into_response());
+    }
+    writer
+        .run(move |db| {
+            access.check_record(db, &key, Action::Write)?;
+            check_companions_unlocked(&access, db, key, &terms)?;
+            let upsert = db.upsert_flags(key, &terms)?;
+            for change in upsert.changes(key) {
+                changes.record(change);
+            }
+            Ok(Json(upsert).into_response())
+        })
+        .await
+}
+
+/// Fails if setting `terms` on `key` would clear a locked exclusive companion
+fn check_companions_unlocked(
+    access: &Access,
+    db: &Database<8>,
+    key: Key,
+    terms: &[String],
+) -> Result<(), ApiError> {
+    for term in terms {
+        access.check_unlocked(db, db.carried_exclusive_companions(key, term)?)?;
+    }
+    Ok(())
+}
+
+/// Fails if replacing flags of `key` with `terms` would set or unset a locked term
+fn check_replacement_unlocked(
+    access: &Access,
+    db: &Database<8>,
+    key: Key,
+    terms: &[String],
+) -> Result<(), ApiError> {
+    let current = db.horizontal_query(&key)?.unwrap_or_default();
+    let desired = terms
+        .iter()
+        .map(|term| db.canonical_name(term))
+        .collect::<HashSet<_>>();
+    Ok(access.check_unlocked(db, current.symmetric_difference(&desired).copied())?)
+}
+
+fn dry_run(
+    db: &Database<8>,
+    operations: impl IntoIterator<Item = Operation>,
+) -> Result<DryRun, StorageCorruption> {
+    let mut dry_run = DryRun::default();
+    for operation in operations {
+        dry_run.apply(db, operation)?;
+    }
+    Ok(dry_run)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SetKeysBulk {
+    term: String,
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum BulkFlagResult {
+    Applied,
+    AlreadySet,
+    Failed { reason: String },
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct BulkFlagReport {
+    #[serde(with = "crate::keys::flexible")]
+    key: Key,
+    #[serde(flatten)]
+    result: BulkFlagResult,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BulkKeysParams {
+    term: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Outcome of newline-delimited `/bulk/keys`, only keys that failed are reported individually
+#[derive(Clone, Debug, Default, Serialize)]
+struct BulkFlagSummary {
+    applied: usize,
+    already_set: usize,
+    failed: Vec<BulkFlagReport>,
+}
+
+/// Accepts [`SetKeysBulk`] or, with `Content-Type: application/x-ndjson`, one key per line
+/// and the term in `term` query parameter, answered with [`BulkFlagSummary`]
+async fn set_keys_bulk(
+    State(db): State<DBState>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    QueryParams(params): QueryParams<BulkKeysParams>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let ndjson = is_ndjson(request.headers());
+    let (term, mut keys) = if ndjson {
+        let term = params.term.ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "missing_term",
+                "newline-delimited bulk request takes term in `term` query parameter",
+            )
+        })?;
+        (
+            term,
+            BulkBody::Lines(NdjsonReader::new(request.into_body())),
+        )
+    } else {
+        let Json(request) = Json::<SetKeysBulk>::from_request(request, &()).await?;
+        (request.term, BulkBody::Whole(Some(request.keys)))
+    };
+    {
+        let db = db.read().await;
+        access.check_terms(&db, Action::Write, [term.as_str()])?;
+        access.check_unlocked(&db, [term.as_str()])?;
+    }
+
+    if params.dry_run {
+        let mut dry_run = DryRun::default();
+        loop {
+            let chunk = keys.next_chunk().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let db = db.read().await;
+            for ApiKey(key) in chunk {
+                let term = term.clone();
+                dry_run.apply(&db, Operation::SetFlag { key, term })?;
+            }
+        }
+        return Ok(Json(dry_run.report(&*db.read().await)).into_response());
+    }
+
+    if !ndjson {
+        let keys = keys.next_chunk().await?;
+        let mut db = db.write().await;
+        if add_bulk_term(&mut db, &changes, &term).is_err() {
+            let report: Vec<_> = keys
+                .into_iter()
+                .map(|ApiKey(key)| BulkFlagReport {
+                    key,
+                    result: BulkFlagResult::Failed {
+                        reason: "term database is full and cannot take more terms".to_string(),
+                    },
+                })
+                .collect();
+            return Ok(Json(report).into_response());
+        }
+        let report: Vec<_> = flag_keys(&mut db, &changes, &access, &term, keys).collect();
+        return Ok(Json(report).into_response());
+    }
+
+    add_bulk_term(&mut *db.write().await, &changes, &term)?;
+    let mut summary = BulkFlagSummary::default();
+    loop {
+        let chunk = keys.next_chunk().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let mut db = db.write().await;
+        for report in flag_keys(&mut db, &changes, &access, &term, chunk) {
+            match report.result {
+                BulkFlagResult::Applied => summary.applied += 1,
+                BulkFlagResult::AlreadySet => summary.already_set += 1,
+                BulkFlagResult::Failed { .. } => summary.failed.push(report),
+            }
+        }
+    }
+    Ok(Json(summary).into_response())
+}
+
+/// Spool uploaded rows and apply them in background, answering with 202 once upload is stored
+async fn start_import(
+    State(state): State<AppState>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    if params.dry_run {
+        let report = crate::import::dry_run(&state.db, body).await?;
+        return Ok(Json(report).into_response());
+    }
+    let spool = state.imports.prepare();
+    if let Err(e) = spool_body(body, &spool).await {
+        let _ = tokio::fs::remove_file(&spool).await;
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "import_upload_failed",
+            format!("failed to store uploaded dataset: {e}"),
+        ));
+    }
+    let progress = state
+        .imports
+        .spawn(spool, state.db.clone(), state.changes.clone(), access);
+    Ok((StatusCode::ACCEPTED, Json(progress)).into_response())
+}
+
+/// Import sets from Redis, progress is looked up like that of uploaded imports
+async fn start_redis_import(
+    State(state): State<AppState>,
+    access: Access,
+    Json(source): Json<RedisSource>,
+) -> Result<(StatusCode, Json<ImportProgress>), ApiError> {
+    source
+        .validate()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_redis_url", e.to_string()))?;
+    let progress =
+        state
+            .imports
+            .spawn_from_redis(source, state.db.clone(), state.changes.clone(), access);
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+async fn spool_body(body: Body, path: &std::path::Path) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    while let Some(data) = stream.next().await {
+        file.write_all(&data.map_err(std::io::Error::other)?)
+            .await?;
+    }
+    file.flush().await
+}
+
+async fn get_import_progress(
+    State(state): State<AppState>,
+    Path(job_id): Path<u64>,
+) -> Result<Json<ImportProgress>, ApiError> {
+    state.imports.progress(job_id).map(Json).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "import_not_found",
+            format!("no import job {job_id}"),
+        )
+    })
+}
+
+fn add_bulk_term(
+    db: &mut Database<8>,
+    changes: &ChangeLog,
+    term: &str,
+) -> Result<(), TermTableFull> {
+    let term_is_new = db.get_term_id(term).is_none();
+    db.add_term(term)?;
+    if term_is_new {
+        changes.record(Change::AddTerm {
+            term: term.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn flag_keys<'a>(
+    db: &'a mut Database<8>,
+    changes: &'a ChangeLog,
+    access: &'a Access,
+    term: &'a str,
+    keys: Vec<ApiKey>,
+) -> impl Iterator<Item = BulkFlagReport> + 'a {
+    keys.into_iter().map(move |ApiKey(key)| {
+        let checked = access
+            .check_record(db, &key, Action::Write)
+            .map_err(|e| e.to_string())
+            .and_then(|()| {
+                let companions = db
+                    .carried_exclusive_companions(key, term)
+                    .map_err(|e| e.to_string())?;
+                access
+                    .check_unlocked(&*db, companions)
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(reason) = checked {
+            return BulkFlagReport {
+                key,
+                result: BulkFlagResult::Failed { reason },
+            };
+        }
+        let result = match db.set_flag(key, term) {
+            Ok(true) => {
+                changes.record(Change::SetFlag {
+                    key,
+                    term: term.to_string(),
+                });
+                BulkFlagResult::Applied
+            }
+            Ok(false) => BulkFlagResult::AlreadySet,
+            Err(e) => BulkFlagResult::Failed {
+                reason: e.to_string(),
+            },
+        };
+        BulkFlagReport { key, result }
+    })
+}
+
+/// Every record touched by transaction, and every term it sets or unsets, has to be writable and
+/// unlocked
+fn check_operations(
+    access: &Access,
+    db: &Database<8>,
+    operations: &[Operation],
+) -> Result<(), ApiError> {
+    for operation in operations {
+        match operation {
+            Operation::CreateRecord { key } => access.check_record(db, key, Action::Write)?,
+            Operation::SetFlag { key, term } => {
+                access.check_terms(db, Action::Write, [term.as_str()])?;
+                access.check_unlocked(db, [term.as_str()])?;
+                access.check_record(db, key, Action::Write)?;
+                // flags of exclusive companions are cleared along the way
+                access.check_unlocked(db, db.carried_exclusive_companions(*key, term)?)?;
+            }
+            Operation::UnsetFlag { key, term } => {
+                access.check_terms(db, Action::Write, [term.as_str()])?;
+                access.check_unlocked(db, [term.as_str()])?;
+                access.check_record(db, key, Action::Write)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_transaction(
+    State(db): State<DBState>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    QueryParams(params): QueryParams<DryRunParams>,
+    Json(operations): Json<Vec<Operation>>,
+) -> Result<Response, ApiError> {
+    if params.dry_run {
+        let db = db.read().await;
+        check_operations(&access, &db, &operations)?;
+        let dry_run = dry_run(&db, operations)?;
+        dry_run.check_term_capacity(&db)?;
+        return Ok(Json(dry_run.report(&db)).into_response());
+    }
+    let mut db = db.write().await;
+    check_operations(&access, &db, &operations)?;
+    let results = db.apply_transaction(&operations)?;
+    for (operation, result) in operations.into_iter().zip(results.iter()) {
+        if result.changed_state() {
+            changes.record(operation.into());
+        }
+    }
+    Ok(Json(results).into_response())
+}
+
+/// Outcome of a single operation of `/batch`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BatchResult {
+    Ok { result: OperationResult },
+    Failed { code: &'static str, reason: String },
+}
+
+/// Unlike transactions, operations of a batch succeed or fail one by one, in order and under a
+/// single lock acquisition
+async fn run_batch(
+    State(db): State<DBState>,
+    State(changes): State<Arc<ChangeLog>>,
+    access: Access,
+    Json(operations): Json<Vec<Operation>>,
+) -> Json<Vec<BatchResult>> {
+    let mut db = db.write().await;
+    let results = operations
+        .into_iter()
+        .map(
+            |operation| match apply_batch_operation(&access, &mut db, &operation) {
+                Ok(result) => {
+                    if result.changed_state() {
+                        changes.record(operation.into());
+                    }
+                    BatchResult::Ok { result }
+                }
+                Err(e) => BatchResult::Failed {
+                    code: e.code(),
+                    reason: e.message().to_string(),
+                },
+            },
+        )
+        .collect();
+    Json(results)
+}
+
+fn apply_batch_operation(
+    access: &Access,
+    db: &mut Database<8>,
+    operation: &Operation,
+) -> Result<OperationResult, ApiError> {
+    check_operations(access, db, std::slice::from_ref(operation))?;
+    Ok(db.apply_operation(operation)?)
+}
+
+async fn make_horizontal_query(
+    State(db): State<DBState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let db = db.read().await;
+    access.check_record(&db, &key, Action::Read)?;
+    match db.horizontal_query(&key)? {
+        Some(items) => Ok(Json(items.into_iter().map(String::from).collect())),
+        None => Err(key_not_found(key)),
+    }
+}
+
+/// Ids of terms key carries, in ascending order
+async fn make_horizontal_query_by_id(
+    State(db): State<DBState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+) -> Result<Json<Vec<TermId>>, ApiError> {
+    let db = db.read().await;
+    access.check_record(&db, &key, Action::Read)?;
+    let mut term_ids = db
+        .record_term_ids(&key)?
+        .ok_or_else(|| key_not_found(key))?;
+    term_ids.sort_unstable();
+    Ok(Json(term_ids))
+}
+
+async fn get_item_info(
+    State(db): State<DBState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+) -> Result<Json<ItemInfo>, ApiError> {
+    let db = db.read().await;
+    access.check_record(&db, &key, Action::Read)?;
+    match db.item_info(key) {
+        Some(info) => Ok(Json(info)),
+        None => Err(key_not_found(key)),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct HistoryParams {
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+}
+
+/// Recent changes of record, from in-memory history if enabled and audit log otherwise
+async fn get_item_history(
+    State(state): State<AppState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+    QueryParams(params): QueryParams<HistoryParams>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    access.check_record(&*state.db.read().await, &key, Action::Read)?;
+    if let Some(history) = &state.history {
+        return Ok(Json(history.get(&key, params.limit)));
+    }
+    let Some(audit) = &state.audit else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "history_disabled",
+            "neither flag history nor audit log is enabled",
+        ));
+    };
+    match audit.read_for_key(key, params.limit).await {
+        Ok(entries) => Ok(Json(entries)),
+        Err(e) => Err(ApiError::internal(format!("failed to read audit log: {e}"))),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DiffPath {
+    #[serde(with = "crate::keys::flexible")]
+    other: Key,
+}
+
+async fn diff_items(
+    State(db): State<DBState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+    Path(DiffPath { other }): Path<DiffPath>,
+) -> Result<Json<FlagDiff>, ApiError> {
+    let db = db.read().await;
+    access.check_record(&db, &key, Action::Read)?;
+    access.check_record(&db, &other, Action::Read)?;
+    let a = db
+        .horizontal_query(&key)?
+        .ok_or_else(|| key_not_found(key))?;
+    let b = db
+        .horizontal_query(&other)?
+        .ok_or_else(|| key_not_found(other))?;
+    Ok(Json(FlagDiff::new(&a, &b)))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct FilteredItemsRequest {
+    keys: Vec<ApiKey>,
+    terms: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct FilteredItem {
+    #[serde(with = "crate::keys::flexible")]
+    key: Key,
+    exists: bool,
+    /// Requested terms that key carries
+    terms: Vec<String>,
+}
+
+async fn get_items_filtered(
+    State(db): State<DBState>,
+    access: Access,
+    Json(request): Json<FilteredItemsRequest>,
+) -> Result<Json<Vec<FilteredItem>>, ApiError> {
+    let db = db.read().await;
+    access.check_terms(&db, Action::Read, request.terms.iter().map(String::as_str))?;
+    let keys = request.keys.into_iter().map(Key::from).collect::<Vec<_>>();
+    for &key in &keys {
+        access.check_key(key)?;
+    }
+    let found = db.filtered_multi_get(&keys, &request.terms)?;
+    Ok(Json(
+        keys.into_iter()
+            .zip(found)
+            .map(|(key, terms)| FilteredItem {
+                key,
+                exists: terms.is_some(),
+                terms: terms
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SimilarityRequest {
+    #[serde(default = "default_similarity_limit")]
+    limit: usize,
+    #[serde(default)]
+    metric: SimilarityMetric,
+}
+
+fn default_similarity_limit() -> usize {
+    10
+}
+
+async fn find_similar_items(
+    State(db): State<DBState>,
+    access: Access,
+    ItemKey(key): ItemKey,
+    Json(request): Json<SimilarityRequest>,
+) -> Result<Json<Vec<SimilarKey>>, ApiError> {
+    let db = db.read().await;
+    access.check_record(&db, &key, Action::Read)?;
+    // tenants get the most similar keys of their namespace rather than fewer than asked for
+    let limit = match access.tenant() {
+        Some(_) => usize::MAX,
+        None => request.limit,
+    };
+    match db.similar_keys(&key, limit, request.metric)? {
+        Some(items) => Ok(Json(
+            items
+                .into_iter()
+                .filter(|item| access.sees(item.key))
+                .take(request.limit)
+                .collect(),
+        )),
+        None => Err(key_not_found(key)),
+    }
+}
+
+async fn check_item_exists(State(db): State<DBState>, ItemKey(key): ItemKey) -> StatusCode {
+    let db = db.read().await;
+    if db.contains_key(&key) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct AsOfParams {
+    /// Evaluate against state as of this many milliseconds since unix epoch
+    as_of: Option<u64>,
+}
+
+async fn make_vertical_query(
+    State(state): State<AppState>,
+    State(db): State<DBState>,
+    State(query_cache): State<Option<Arc<QueryCache>>>,
+    access: Access,
+    deadline: Deadline,
+    QueryParams(params): QueryParams<AsOfParams>,
+    Json(mut query): Json<FilteredQuery>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    access.check_query(&*db.read().await, &query.query)?;
+    query.range = access.confine(query.range);
+    query.deadline = deadline;
+    if let Some(as_of) = params.as_of {
+        let Some(audit) = &state.audit else {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "time_travel_unavailable",
+                "queries of past state replay audit log, which is not enabled",
+            ));
+        };
+        let past =
+            time_travel::state_as_of::<8>(state.snapshotter.data_file(), audit, as_of).await?;
+        let keys = past.filtered_vertical_query(&query)?;
+        deadline.check()?;
+        return Ok(Json(keys.into_iter().map(ApiKey).collect()));
+    }
+    let db = db.read().await;
+    let keys = match &query_cache {
+        Some(query_cache) => query_cache
+            .query(&db, &query)
+            .map(|keys| keys.iter().copied().map(ApiKey).collect()),
+        None => db
+            .filtered_vertical_query(&query)
+            .map(|keys| keys.into_iter().map(ApiKey).collect()),
+    }?;
+    deadline.check()?;
+    Ok(Json(keys))
+}
+
+async fn make_vertical_query_by_id(
+    State(db): State<DBState>,
+    access: Access,
+    deadline: Deadline,
+    Json(mut query): Json<FilteredQuery<IdQuery>>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    query.range = access.confine(query.range);
+    query.deadline = deadline;
+    let db = db.read().await;
+    // unknown ids are left for the query to reject
+    let terms = query
+        .query
+        .term_ids()
+        .iter()
+        .filter_map(|&term_id| db.explain_term_id(term_id));
+    access.check_terms(&db, Action::Read, terms)?;
+    let keys = db.filtered_vertical_query_by_id(&query)?;
+    deadline.check()?;
+    Ok(Json(keys.into_iter().map(ApiKey).collect()))
+}
+
+/// Records sampled by approximate counts unless asked otherwise
+const DEFAULT_COUNT_SAMPLE_SIZE: usize = 10_000;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct CountParams {
+    #[serde(default)]
+    approximate: bool,
+    sample_size: Option<usize>,
+}
+
+async fn count_vertical_query(
+    State(db): State<DBState>,
+    access: Access,
+    deadline: Deadline,
+    QueryParams(params): QueryParams<CountParams>,
+    Json(mut query): Json<FilteredQuery>,
+) -> Result<Json<CountEstimate>, ApiError> {
+    query.range = access.confine(query.range);
+    query.deadline = deadline;
+    let db = db.read().await;
+    access.check_query(&db, &query.query)?;
+    let sample_size = params
+        .approximate
+        .then(|| params.sample_size.unwrap_or(DEFAULT_COUNT_SAMPLE_SIZE));
+    let count = db.count_matching(&query, sample_size)?;
+    deadline.check()?;
+    Ok(Json(count))
+}
+
+fn invalid_query(message: String) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_query", message)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct BulkQueryParams {
+    #[serde(default)]
+    parallel: bool,
+}
+
+async fn make_vertical_query_bulk(
+    State(db): State<DBState>,
+    access: Access,
+    deadline: Deadline,
+    QueryParams(params): QueryParams<BulkQueryParams>,
+    Json(queries): Json<Vec<Query>>,
+) -> Result<Json<Vec<Result<Vec<ApiKey>, ApiError>>>, ApiError> {
+    let db = db.read_owned().await;
+    for query in &queries {
+        access.check_query(&db, query)?;
+    }
+    let results = if params.parallel {
+        // rayon blocks until every query is answered, which must not happen on async workers
+        tokio::task::spawn_blocking(move || db.vertical_query_batch(&queries, true, deadline))
+            .await
+            .map_err(|e| ApiError::internal(format!("parallel query failed: {e}")))?
+    } else {
+        db.vertical_query_batch(&queries, false, deadline)
+    };
+    deadline.check()?;
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(keys) => Ok(Ok(keys
+                .into_iter()
+                .filter(|&key| access.sees(key))
+                .map(ApiKey)
+                .collect())),
+            Err(QueryError::Invalid(message)) => Ok(Err(invalid_query(message))),
+            Err(QueryError::Corruption(e)) => Err(e),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Json(results))
+}
+
+async fn list_stored_queries(
+    State(state): State<AppState>,
+) -> Json<BTreeMap<String, FilteredQuery>> {
+    Json(state.stored_queries.list())
+}
+
+fn stored_query_not_found(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "query_not_found",
+        format!("no stored query {name}"),
+    )
+}
+
+async fn get_stored_query(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<FilteredQuery>, ApiError> {
+    state
+        .stored_queries
+        .get(&name)
+        .map(Json)
+        .ok_or_else(|| stored_query_not_found(&name))
+}
+
+async fn put_stored_query(
+    State(state): State<AppState>,
+    access: Access,
+    Path(name): Path<String>,
+    Json(query): Json<FilteredQuery>,
+) -> Result<StatusCode, ApiError> {
+    access.check_query(&*state.db.read().await, &query.query)?;
+    match state.stored_queries.put(name, query) {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Ok(StatusCode::OK),
+        Err(e) => Err(ApiError::internal(format!(
+            "failed to save stored queries: {e}"
+        ))),
+    }
+}
+
+async fn remove_stored_query(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    match state.stored_queries.remove(&name) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(stored_query_not_found(&name)),
+        Err(e) => Err(ApiError::internal(format!(
+            "failed to save stored queries: {e}"
+        ))),
+    }
+}
+
+async fn combine_stored_queries(
+    State(state): State<AppState>,
+    access: Access,
+    Json(combination): Json<Combination>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let db = state.db.read().await;
+    // stored queries may have been put by callers allowed more
+    for query in combination
+        .queries
+        .iter()
+        .filter_map(|name| state.stored_queries.get(name))
+    {
+        access.check_query(&db, &query.query)?;
+    }
+    let keys = state
+        .stored_queries
+        .combine(&db, &combination)
+        .map_err(|e| match e {
+            CombineError::UnknownQuery(name) => stored_query_not_found(&name),
+            CombineError::Corruption(e) => e.into(),
+            e => invalid_query(e.to_string()),
+        })?;
+    Ok(Json(keys.into_iter().map(ApiKey).collect()))
+}
+
+async fn list_schedules(State(state): State<AppState>) -> Json<BTreeMap<String, ScheduleInfo>> {
+    Json(state.schedules.list())
+}
+
+fn schedule_not_found(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "schedule_not_found",
+        format!("no schedule {name}"),
+    )
+}
+
+async fn get_schedule(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<ScheduleInfo>, ApiError> {
+    state
+        .schedules
+        .get(&name)
+        .map(Json)
+        .ok_or_else(|| schedule_not_found(&name))
+}
+
+async fn put_schedule(
+    State(state): State<AppState>,
+    access: Access,
+    Path(name): Path<String>,
+    Json(schedule): Json<Schedule>,
+) -> Result<StatusCode, ApiError> {
+    Schedules::validate(&schedule)?;
+    access.check_query(&*state.db.read().await, &schedule.query.query)?;
+    match state.schedules.put(name, schedule) {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Ok(StatusCode::OK),
+        Err(e) => Err(ApiError::internal(format!("failed to save schedules: {e}"))),
+    }
+}
+
+async fn remove_schedule(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    match state.schedules.remove(&name) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(schedule_not_found(&name)),
+        Err(e) => Err(ApiError::internal(format!("failed to save schedules: {e}"))),
+    }
+}
+
+async fn list_views(State(state): State<AppState>) -> Json<Vec<ViewInfo>> {
+    Json(state.views.list())
+}
+
+async fn create_view(
+    State(state): State<AppState>,
+    access: Access,
+    Json(definition): Json<ViewDefinition>,
+) -> Result<(StatusCode, Json<usize>), ApiError> {
+    // write lock keeps changes from being recorded between evaluation and registration
+    let db = state.db.write().await;
+    access.check_query(&db, &definition.query)?;
+    let count = state.views.create(&db, definition)?;
+    Ok((StatusCode::CREATED, Json(count)))
+}
+
+async fn remove_view(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    state.views.remove(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Views may have been created by callers allowed more
+fn check_view_access(
+    state: &AppState,
+    db: &Database<8>,
+    access: &Access,
+    name: &str,
+) -> Result<(), ApiError> {
+    match state.views.query(name) {
+        Some(query) => Ok(access.check_query(db, &query)?),
+        None => Ok(()),
+    }
+}
+
+async fn get_view_keys(
+    State(state): State<AppState>,
+    access: Access,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let db = state.db.read().await;
+    check_view_access(&state, &db, &access, &name)?;
+    let keys = state.views.read(&db, &name, |keys| {
+        keys.iter().copied().map(ApiKey).collect()
+    })?;
+    Ok(Json(keys))
+}
+
+async fn get_view_count(
+    State(state): State<AppState>,
+    access: Access,
+    Path(name): Path<String>,
+) -> Result<Json<usize>, ApiError> {
+    let db = state.db.read().await;
+    check_view_access(&state, &db, &access, &name)?;
+    Ok(Json(state.views.read(&db, &name, BTreeSet::len)?))
+}
+
+async fn make_facet_query(
+    State(db): State<DBState>,
+    access: Access,
+    deadline: Deadline,
+    Json(query): Json<Query>,
+) -> Result<Json<HashMap<String, usize>>, ApiError> {
+    let db = db.read().await;
+    access.check_query(&db, &query)?;
+    let counts = db.facet_counts(&query, &access.confine(KeyRange::default()), deadline)?;
+    deadline.check()?;
+    Ok(Json(
+        counts
+            .into_iter()
+            .filter(|(term, _)| access.allows(Action::Read, term))
+            .map(|(term, count)| (term.to_string(), count))
+            .collect(),
+    ))
+}
+
+/// Storage latency histograms in Prometheus text format
+async fn get_metrics(
+    State(db): State<DBState>,
+    State(tenant_metrics): State<Arc<TenantMetrics>>,
+) -> impl IntoResponse {
+    let mut metrics = db.read().await.latencies().render();
+    metrics.push_str(&tenant_metrics.render());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+}
+
+async fn get_stats(State(state): State<AppState>) -> Json<Stats> {
+    let mut stats = state.db.read().await.stats();
+    stats.last_snapshot = state.snapshotter.last_snapshot();
+    stats.handler_panics = state.handler_panics.load(Ordering::Relaxed);
+    stats.query_cache = state.query_cache.as_ref().map(|cache| cache.stats());
+    Json(stats)
+}
+
+async fn compact_storage(
+    State(state): State<AppState>,
+) -> Result<Json<CompactionReport>, ApiError> {
+    let compacted = state.jobs.run(JobKind::Compaction, async {
+        state.db.write().await.compact()
+    });
+    Ok(Json(compacted.await?))
+}
+
+#[derive(Serialize)]
+struct DeletedItem {
+    key: ApiKey,
+    #[serde(flatten)]
+    record: DeletedRecord,
+}
+
+async fn list_deleted_items(State(db): State<DBState>) -> Json<Vec<DeletedItem>> {
+    let db = db.read().await;
+    let mut deleted = db
+        .list_deleted()
+        .map(|(key, record)| DeletedItem {
+            key: ApiKey(key),
+            record: record.clone(),
+        })
+        .collect::<Vec<_>>();
+    deleted.sort_unstable_by_key(|item| (item.record.deleted_at, item.key.0));
+    Json(deleted)
+}
+
+#[derive(Serialize)]
+struct PurgeReport {
+    purged: usize,
+}
+
+async fn purge_deleted_items(State(state): State<AppState>) -> Json<PurgeReport> {
+    let purged =
+        soft_delete::purge_expired(&state.db, &state.changes, state.deleted_retention).await;
+    Json(PurgeReport { purged })
+}
+
+async fn list_jobs(State(state): State<AppState>) -> Json<Vec<JobInfo>> {
+    Json(state.jobs.list())
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<JobId>,
+) -> Result<Json<JobInfo>, ApiError> {
+    state
+        .jobs
+        .get(job_id)
+        .map(Json)
+        .ok_or_else(|| JobError::NotFound(job_id).into())
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<JobId>,
+) -> Result<Json<JobInfo>, ApiError> {
+    Ok(Json(state.jobs.cancel(job_id)?))
+}
+
+async fn check_consistency(State(db): State<DBState>) -> Json<ConsistencyReport> {
+    Json(db.read().await.check_consistency())
+}
+
+async fn export_json(State(db): State<DBState>) -> Result<Json<JsonExport>, ApiError> {
+    Ok(Json(db.read().await.export_json()?))
+}
+
+async fn save_state(State(state): State<AppState>) -> Result<StatusCode, ApiError> {
+    match state.snapshotter.save().await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => Err(ApiError::internal(e.to_string())),
+    }
+}
+
+async fn save_delta(State(state): State<AppState>) -> Result<Json<DeltaReport>, ApiError> {
+    state
+        .snapshotter
+        .save_delta()
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            DeltaError::NotEnabled => ApiError::new(
+                StatusCode::CONFLICT,
+                "incremental_snapshots_disabled",
+                e.to_string(),
+            ),
+            DeltaError::NoBase => {
+                ApiError::new(StatusCode::CONFLICT, "no_full_snapshot", e.to_string())
+            }
+            e => ApiError::internal(e.to_string()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::catch_panics;
+
+    async fn panicking() -> StatusCode {
+        panic!("handler failed")
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_answer_500_and_are_counted() {
+        let panics = Arc::new(AtomicU64::new(0));
+        let router = catch_panics(
+            Router::new().route("/panic", get(panicking)),
+            panics.clone(),
+        );
+        let request = || Request::get("/panic").body(Body::empty()).unwrap();
+
+        let response = router.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["code"], "internal");
+
+        router.oneshot(request()).await.unwrap();
+        assert_eq!(panics.load(Ordering::Relaxed), 2);
     }
 }
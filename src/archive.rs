@@ -0,0 +1,194 @@
+//! Copies of full snapshots kept for rollback, listed by `GET /admin/snapshots`
+//!
+//! Every full snapshot is copied next to data file as `<data file stem>-<unix secs>-<crc32>.db`,
+//! holding bare dump that `POST /admin/restore` accepts by path. A snapshot identical to the
+//! newest copy is not copied again. Names, sizes and key counts of copies are kept in
+//! `<data file stem>.archive.json`, since counting keys would take loading the whole copy.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::serde::rotation::{self, SlotError};
+
+/// Which copies survive a new one being added
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// Number of newest copies kept
+    pub keep: usize,
+    /// Copies older than this are removed even if fewer are kept, except the newest one
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedSnapshot {
+    /// Location to pass to `/admin/restore`
+    pub path: PathBuf,
+    /// Seconds since unix epoch
+    pub created_at: u64,
+    /// CRC32 of contents as 8 hex digits
+    pub hash: String,
+    pub size: u64,
+    pub keys: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("failed to write snapshot archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to read saved snapshot: {0}")]
+    Slot(#[from] SlotError),
+    #[error("saved snapshot slot is missing")]
+    MissingSlot,
+    #[error("snapshot archive manifest is corrupted: {0}")]
+    Manifest(#[from] serde_json::Error),
+}
+
+pub struct SnapshotArchive {
+    data_file: PathBuf,
+    retention: Retention,
+}
+
+impl SnapshotArchive {
+    pub fn new(data_file: impl Into<PathBuf>, retention: Retention) -> Self {
+        Self {
+            data_file: data_file.into(),
+            retention,
+        }
+    }
+
+    fn stem(&self) -> String {
+        self.data_file
+            .file_stem()
+            .map_or_else(|| "state".into(), |stem| stem.to_string_lossy())
+            .into_owned()
+    }
+
+    fn sibling(&self, name: String) -> PathBuf {
+        self.data_file.with_file_name(name)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.sibling(format!("{}.archive.json", self.stem()))
+    }
+
+    /// Copies that still exist, newest first
+    pub fn list(&self) -> Result<Vec<ArchivedSnapshot>, ArchiveError> {
+        let mut copies = match std::fs::read(self.manifest_path()) {
+            Ok(manifest) => serde_json::from_slice::<Vec<ArchivedSnapshot>>(&manifest)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        copies.retain(|copy| copy.path.exists());
+        Ok(copies)
+    }
+
+    /// Copy newest snapshot slot of data file holding `keys` keys and remove copies past
+    /// retention. Returns the new copy, None if snapshot is the same as the newest copy
+    pub fn add(&self, keys: usize) -> Result<Option<ArchivedSnapshot>, ArchiveError> {
+        let (_, slot) = rotation::slots_newest_first(&self.data_file)
+            .into_iter()
+            .next()
+            .ok_or(ArchiveError::MissingSlot)?;
+        let (_, payload) = rotation::read_slot(slot, true)?;
+        let hash = format!("{:08x}", crc32fast::hash(&payload));
+        let mut copies = self.list()?;
+        if copies.first().is_some_and(|newest| newest.hash == hash) {
+            return Ok(None);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = self.sibling(format!("{}-{}-{hash}.db", self.stem(), now.as_secs()));
+        write_replacing(&path, &payload)?;
+        let copy = ArchivedSnapshot {
+            path,
+            created_at: now.as_secs(),
+            hash,
+            size: payload.len() as u64,
+            keys,
+        };
+        copies.insert(0, copy.clone());
+
+        let mut kept = 0;
+        let (copies, expired): (Vec<_>, Vec<_>) = copies.into_iter().partition(|copy| {
+            let too_old = self.retention.max_age.is_some_and(|max_age| {
+                now.as_secs().saturating_sub(copy.created_at) > max_age.as_secs()
+            });
+            kept += 1;
+            kept == 1 || (kept <= self.retention.keep && !too_old)
+        });
+        write_replacing(&self.manifest_path(), &serde_json::to_vec_pretty(&copies)?)?;
+        for copy in expired {
+            if let Err(e) = std::fs::remove_file(&copy.path) {
+                tracing::warn!(path = %copy.path.display(), "failed to remove archived snapshot: {e}");
+            }
+        }
+        Ok(Some(copy))
+    }
+}
+
+fn write_replacing(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::BigRecordDecoding,
+        serde::two_phase_save,
+        storage::{Database, Key},
+    };
+
+    use super::{Retention, SnapshotArchive};
+
+    #[test]
+    fn copies_are_deduplicated_and_pruned() {
+        let dir = std::env::temp_dir().join(format!("elizadb-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_file = dir.join("state.elizadb");
+        let archive = SnapshotArchive::new(
+            &data_file,
+            Retention {
+                keep: 2,
+                max_age: None,
+            },
+        );
+
+        let mut db = Database::<8>::default();
+        let mut paths = vec![];
+        for key in 1..=3 {
+            db.set_flag(Key::try_from(key).unwrap(), "x").unwrap();
+            two_phase_save(&db, &data_file).unwrap();
+            let copy = archive.add(key as usize).unwrap().unwrap();
+            assert!(copy
+                .path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("state-"));
+            paths.push(copy.path);
+            two_phase_save(&db, &data_file).unwrap();
+            assert_eq!(archive.add(key as usize).unwrap(), None);
+        }
+
+        let copies = archive.list().unwrap();
+        assert_eq!(
+            copies.iter().map(|copy| copy.keys).collect::<Vec<_>>(),
+            [3, 2]
+        );
+        assert!(!paths[0].exists());
+        let restored =
+            crate::serde::load_possibly_missing::<8>(&copies[1].path, BigRecordDecoding::Eager)
+                .unwrap();
+        assert_eq!(restored.stats().keys, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
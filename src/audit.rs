@@ -0,0 +1,246 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract::Request, http::HeaderName, middleware::Next, response::Response};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+
+use crate::{access::bearer_api_key, changes::Change, storage::Key};
+
+pub static ACTOR_HEADER: HeaderName = HeaderName::from_static("x-elizadb-actor");
+
+/// Who made the request currently being handled
+#[derive(Clone, Debug, Default)]
+pub struct Actor {
+    /// Fingerprint of API key the request was authenticated with
+    key: Option<String>,
+    /// Named by `x-elizadb-actor` header, which anyone may set
+    claimed: Option<String>,
+}
+
+tokio::task_local! {
+    static ACTOR: Actor;
+}
+
+/// Actor of request currently being handled, anonymous outside of requests
+pub fn current_actor() -> Actor {
+    ACTOR.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Run `f` attributing changes it records to `actor`, for work done outside of request task
+pub fn with_actor<R>(actor: Actor, f: impl FnOnce() -> R) -> R {
+    ACTOR.sync_scope(actor, f)
+}
+
+/// Identifies API key in audit log without writing the key itself there
+fn key_fingerprint(api_key: &str) -> String {
+    format!("key:{:08x}", crc32fast::hash(api_key.as_bytes()))
+}
+
+/// Who applied which change and when
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since unix epoch
+    pub at: u64,
+    /// Fingerprint of API key of the request, None for requests without one
+    pub actor: Option<String>,
+    /// Actor named by `x-elizadb-actor` header, as claimed by the caller and not verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claimed_actor: Option<String>,
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+impl AuditEntry {
+    /// Entry of change applied just now, attributed to actor of the current request
+    pub fn now(seq: u64, change: Change) -> Self {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let actor = current_actor();
+        Self {
+            at,
+            actor: actor.key,
+            claimed_actor: actor.claimed,
+            seq,
+            change,
+        }
+    }
+}
+
+/// Append-only file of audit entries, one JSON object per line. Writes happen on a background task
+pub struct AuditLog {
+    path: PathBuf,
+    sender: mpsc::UnboundedSender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Open log for appending and start its writer task
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_entries(tokio::fs::File::from_std(file), receiver));
+        Ok(Self { path, sender })
+    }
+
+    /// Queue change for writing, attributing it to actor of the current request
+    pub fn log(&self, seq: u64, change: &Change) {
+        let _ = self.sender.send(AuditEntry::now(seq, change.clone()));
+    }
+
+    /// Entries recorded at or after `since` milliseconds since unix epoch
+    pub async fn read_since(&self, since: u64, limit: usize) -> std::io::Result<Vec<AuditEntry>> {
+        self.read_between(since, u64::MAX, limit).await
+    }
+
+    /// Entries recorded from `since` up to `until` milliseconds since unix epoch. Entries are
+    /// appended in the order they were recorded, so reading stops at the first one past `until`
+    pub async fn read_between(
+        &self,
+        since: u64,
+        until: u64,
+        limit: usize,
+    ) -> std::io::Result<Vec<AuditEntry>> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut result = vec![];
+        while let Some(line) = lines.next_line().await? {
+            if result.len() >= limit {
+                break;
+            }
+            // a line being appended concurrently may be incomplete
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                continue;
+            };
+            if entry.at > until {
+                break;
+            }
+            if entry.at >= since {
+                result.push(entry);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Up to `limit` most recent entries of changes to `key`, oldest first
+    pub async fn read_for_key(&self, key: Key, limit: usize) -> std::io::Result<Vec<AuditEntry>> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut result = VecDeque::new();
+        while let Some(line) = lines.next_line().await? {
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                continue;
+            };
+            if entry.change.key() != Some(key) || limit == 0 {
+                continue;
+            }
+            if result.len() >= limit {
+                result.pop_front();
+            }
+            result.push_back(entry);
+        }
+        Ok(result.into())
+    }
+}
+
+async fn write_entries(
+    mut file: tokio::fs::File,
+    mut receiver: mpsc::UnboundedReceiver<AuditEntry>,
+) {
+    let mut buffer = vec![];
+    while let Some(entry) = receiver.recv().await {
+        buffer.clear();
+        let mut next = Some(entry);
+        while let Some(entry) = next {
+            serde_json::to_writer(&mut buffer, &entry).expect("audit entries are always encodable");
+            buffer.push(b'\n');
+            next = receiver.try_recv().ok();
+        }
+        if let Err(e) = file.write_all(&buffer).await {
+            tracing::error!("failed to write audit log: {e}");
+        }
+    }
+}
+
+/// Make actor of request available to audit log while request is handled. Requests with unknown
+/// API keys are rejected by handlers, so that whatever key is presented can be taken as the actor
+pub async fn capture_actor(request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let actor = Actor {
+        key: bearer_api_key(headers).map(key_fingerprint),
+        claimed: headers
+            .get(&ACTOR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+    };
+    ACTOR.scope(actor, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::changes::Change;
+
+    use super::{key_fingerprint, Actor, AuditLog, ACTOR};
+
+    #[tokio::test]
+    async fn entries_are_appended_with_actor() {
+        let path = std::env::temp_dir().join(format!("elizadb-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::open(&path).unwrap();
+
+        log.log(
+            0,
+            &Change::AddTerm {
+                term: "a".to_string(),
+            },
+        );
+        let actor = Actor {
+            key: Some(key_fingerprint("secret")),
+            claimed: Some("alice".to_string()),
+        };
+        ACTOR
+            .scope(actor, async {
+                log.log(
+                    1,
+                    &Change::AddTerm {
+                        term: "b".to_string(),
+                    },
+                )
+            })
+            .await;
+
+        let mut entries = vec![];
+        for _ in 0..100 {
+            entries = log.read_since(0, 10).await.unwrap();
+            if entries.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].actor, None);
+        let actor = entries[1].actor.as_deref().unwrap();
+        assert!(actor.starts_with("key:") && !actor.contains("secret"));
+        assert_eq!(entries[1].claimed_actor.as_deref(), Some("alice"));
+        assert_eq!(entries[1].seq, 1);
+        let before = entries[0].at - 1;
+        assert!(log.read_between(0, before, 10).await.unwrap().is_empty());
+        let until_last = log.read_between(0, entries[1].at, 1).await.unwrap();
+        assert_eq!(until_last.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,654 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{Key, StorageCorruption, TermId};
+
+/// Set of term ids of a big record, stored as compressed bitmap
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TermBitmap(RoaringBitmap);
+
+impl TermBitmap {
+    /// Add term id, indicates if it is new
+    pub fn insert(&mut self, term_id: TermId) -> bool {
+        self.0.insert(term_id.into())
+    }
+
+    /// Remove term id, indicates if it was present
+    pub fn remove(&mut self, term_id: TermId) -> bool {
+        self.0.remove(term_id.into())
+    }
+
+    pub fn contains(&self, term_id: TermId) -> bool {
+        self.0.contains(term_id.into())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TermId> + '_ {
+        // only TermId values are ever inserted
+        self.0.iter().map(|term_id| term_id as TermId)
+    }
+
+    /// Approximate number of bytes taken by bitmap containers
+    pub fn memory_usage(&self) -> usize {
+        self.0.serialized_size()
+    }
+}
+
+/// Bitmap of a big record as stored in snapshots, so that decoding records can be left until
+/// the whole snapshot is read and then done in parallel
+#[derive(Clone, Debug, Default)]
+pub struct EncodedBitmap(Vec<u8>);
+
+/// Cookies opening serialized roaring bitmaps, see the portable roaring format
+const SERIAL_COOKIE_NO_RUNCONTAINER: u32 = 12346;
+const SERIAL_COOKIE: u16 = 12347;
+
+impl EncodedBitmap {
+    pub fn decode(&self) -> io::Result<TermBitmap> {
+        RoaringBitmap::deserialize_from(self.0.as_slice()).map(TermBitmap)
+    }
+
+    /// Number of term ids, read off container headers without decoding containers themselves
+    pub fn header_len(&self) -> io::Result<usize> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed bitmap header");
+        let bytes = self.0.as_slice();
+        let u32_at = |at: usize| {
+            bytes
+                .get(at..at + 4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .ok_or_else(malformed)
+        };
+        let cookie = u32_at(0)?;
+        let (containers, start) = if cookie == SERIAL_COOKIE_NO_RUNCONTAINER {
+            (u32_at(4)? as usize, 8)
+        } else if cookie as u16 == SERIAL_COOKIE {
+            let containers = (cookie >> 16) as usize + 1;
+            // run container flags follow the cookie
+            (containers, 4 + containers.div_ceil(8))
+        } else {
+            return Err(malformed());
+        };
+        let end = containers
+            .checked_mul(4)
+            .and_then(|size| size.checked_add(start))
+            .ok_or_else(malformed)?;
+        let headers = bytes.get(start..end).ok_or_else(malformed)?;
+        // every container header is key followed by cardinality minus one
+        Ok(headers
+            .chunks_exact(4)
+            .map(|header| u16::from_le_bytes([header[2], header[3]]) as usize + 1)
+            .sum())
+    }
+}
+
+/// Record left encoded when snapshot was loaded lazily, decoded when it is first read. Header is
+/// checked on load, corrupted containers only show once record is read
+#[derive(Clone)]
+pub(super) struct LazyRecord {
+    encoded: EncodedBitmap,
+    len: usize,
+    decoded: OnceLock<TermBitmap>,
+}
+
+impl TryFrom<EncodedBitmap> for LazyRecord {
+    type Error = io::Error;
+
+    fn try_from(encoded: EncodedBitmap) -> io::Result<Self> {
+        Ok(Self {
+            len: encoded.header_len()?,
+            encoded,
+            decoded: OnceLock::new(),
+        })
+    }
+}
+
+impl LazyRecord {
+    fn get(&self, key: Key) -> Result<&TermBitmap, StorageCorruption> {
+        if let Some(decoded) = self.decoded.get() {
+            return Ok(decoded);
+        }
+        let decoded = decode_lazy(key, &self.encoded)?;
+        Ok(self.decoded.get_or_init(|| decoded))
+    }
+
+    fn into_decoded(self, key: Key) -> Result<TermBitmap, StorageCorruption> {
+        match self.decoded.into_inner() {
+            Some(decoded) => Ok(decoded),
+            None => decode_lazy(key, &self.encoded),
+        }
+    }
+
+    pub(super) fn memory_usage(&self) -> usize {
+        self.encoded.0.capacity() + self.decoded.get().map_or(0, TermBitmap::memory_usage)
+    }
+}
+
+fn decode_lazy(key: Key, encoded: &EncodedBitmap) -> Result<TermBitmap, StorageCorruption> {
+    encoded
+        .decode()
+        .map_err(|e| StorageCorruption(format!("big record {key} in snapshot: {e}")))
+}
+
+impl<'de> Deserialize<'de> for EncodedBitmap {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = EncodedBitmap;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("roaring bitmap")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                Ok(EncodedBitmap(bytes.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(EncodedBitmap(bytes))
+            }
+
+            // formats without byte strings encode bitmaps as sequences, same as roaring accepts
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(EncodedBitmap(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+impl FromIterator<TermId> for TermBitmap {
+    fn from_iter<I: IntoIterator<Item = TermId>>(iter: I) -> Self {
+        Self(iter.into_iter().map(u32::from).collect())
+    }
+}
+
+/// Where a record that was written out lives inside spill file
+#[derive(Clone, Copy, Debug)]
+pub(super) struct SpilledRecord {
+    offset: u64,
+    len: u32,
+    /// Number of terms in record, known without reading it back
+    terms: u32,
+}
+
+/// Scratch file holding records evicted from memory. It is recreated on every start,
+/// durability is still provided by snapshots which contain every record
+pub(super) struct Spill {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    pub(super) records: HashMap<Key, SpilledRecord>,
+    /// Order in which records became hot, oldest are spilled first. May contain keys that are no longer hot
+    order: VecDeque<Key>,
+    cache_records: usize,
+    /// Bytes taken by records that were reloaded or rewritten since they were spilled
+    garbage: u64,
+}
+
+/// Spilled record of `key` could not be written or read back
+fn spill_failed(key: Key, e: impl std::fmt::Display) -> StorageCorruption {
+    StorageCorruption(format!("big record {key} in spill file: {e}"))
+}
+
+impl Spill {
+    fn write(&mut self, key: Key, set: &TermBitmap) -> Result<(), StorageCorruption> {
+        let encoded = rmp_serde::to_vec(set).map_err(|e| spill_failed(key, e))?;
+        self.file
+            .write_all_at(&encoded, self.end)
+            .map_err(|e| spill_failed(key, e))?;
+        let record = SpilledRecord {
+            offset: self.end,
+            len: encoded.len() as u32,
+            terms: set.len() as u32,
+        };
+        self.end += encoded.len() as u64;
+        if let Some(old) = self.records.insert(key, record) {
+            self.garbage += old.len as u64;
+        }
+        Ok(())
+    }
+
+    fn read(&self, key: Key, record: SpilledRecord) -> Result<TermBitmap, StorageCorruption> {
+        let mut buffer = vec![0; record.len as usize];
+        self.file
+            .read_exact_at(&mut buffer, record.offset)
+            .map_err(|e| spill_failed(key, e))?;
+        rmp_serde::from_slice(&buffer).map_err(|e| spill_failed(key, e))
+    }
+
+    /// Read-only view of records spilled so far. Spill file is only ever appended to or replaced
+    /// by rename, so bytes the view points at stay intact while the original keeps changing
+    fn frozen(&self) -> io::Result<Self> {
+        Ok(Self {
+            path: self.path.clone(),
+            file: self.file.try_clone()?,
+            end: self.end,
+            records: self.records.clone(),
+            order: Default::default(),
+            cache_records: self.cache_records,
+            garbage: self.garbage,
+        })
+    }
+
+    /// Read record back, it is only forgotten once that succeeded
+    fn take(&mut self, key: &Key) -> Result<Option<TermBitmap>, StorageCorruption> {
+        let Some(&record) = self.records.get(key) else {
+            return Ok(None);
+        };
+        let set = self.read(*key, record)?;
+        self.records.remove(key);
+        self.garbage += record.len as u64;
+        Ok(Some(set))
+    }
+
+    /// Rewrite file without records that are no longer stored in it
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        let mut end = 0;
+        let mut buffer = vec![];
+        for record in self.records.values_mut() {
+            buffer.resize(record.len as usize, 0);
+            self.file.read_exact_at(&mut buffer, record.offset)?;
+            temp.write_all_at(&buffer, end)?;
+            record.offset = end;
+            end += record.len as u64;
+        }
+
+        std::fs::rename(&temp_path, &self.path)?;
+        self.file = temp;
+        self.end = end;
+        self.garbage = 0;
+        Ok(())
+    }
+}
+
+/// Records that outgrew every smallset tier. When spilling is enabled only a bounded number of
+/// most recently written records stay in memory, the rest is read back from disk on access.
+/// Records of a lazily loaded snapshot stay encoded until they are accessed
+#[derive(Default)]
+pub struct BigStorage {
+    pub(super) hot: HashMap<Key, TermBitmap>,
+    /// Records not written to since snapshot was loaded lazily
+    pub(super) lazy: HashMap<Key, LazyRecord>,
+    pub(super) spill: Option<Spill>,
+}
+
+impl From<HashMap<Key, TermBitmap>> for BigStorage {
+    fn from(hot: HashMap<Key, TermBitmap>) -> Self {
+        Self {
+            hot,
+            lazy: HashMap::new(),
+            spill: None,
+        }
+    }
+}
+
+impl BigStorage {
+    /// Start keeping at most `cache_records` records in memory, writing the rest into file at `path`
+    pub fn spill_to(&mut self, path: impl AsRef<Path>, cache_records: usize) -> io::Result<()> {
+        if let Some(previous) = self.spill.take() {
+            for (&key, &record) in previous.records.iter() {
+                let set = previous.read(key, record).map_err(io::Error::other)?;
+                self.hot.insert(key, set);
+            }
+        }
+
+        let path = path.as_ref().to_owned();
+        // unlink rather than truncate, frozen copies may still be reading the old file
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        let spill = Spill {
+            path,
+            file,
+            end: 0,
+            records: HashMap::new(),
+            order: self.hot.keys().cloned().collect(),
+            cache_records: cache_records.max(1),
+            garbage: 0,
+        };
+        self.spill = Some(spill);
+        self.evict_excess(0).map_err(io::Error::other)
+    }
+
+    /// Copy that must not be written to, spilled records are shared with the original instead of
+    /// being read into memory
+    pub fn frozen(&self) -> io::Result<Self> {
+        Ok(Self {
+            hot: self.hot.clone(),
+            lazy: self.lazy.clone(),
+            spill: self.spill.as_ref().map(Spill::frozen).transpose()?,
+        })
+    }
+
+    /// Spill file path and cache size, if spilling is enabled
+    pub fn spill_settings(&self) -> Option<(PathBuf, usize)> {
+        let spill = self.spill.as_ref()?;
+        Some((spill.path.clone(), spill.cache_records))
+    }
+
+    /// Spill oldest hot records until there is room for `incoming` more. Records leave memory
+    /// only once they are written out, so a failed write loses nothing
+    fn evict_excess(&mut self, incoming: usize) -> Result<(), StorageCorruption> {
+        let Some(spill) = &mut self.spill else {
+            return Ok(());
+        };
+        while self.hot.len() + incoming > spill.cache_records {
+            let Some(&key) = spill.order.front() else {
+                break;
+            };
+            if let Some(set) = self.hot.get(&key) {
+                spill.write(key, set)?;
+                self.hot.remove(&key);
+            }
+            spill.order.pop_front();
+        }
+        Ok(())
+    }
+
+    fn make_hot(
+        &mut self,
+        key: Key,
+        set: TermBitmap,
+    ) -> Result<&mut TermBitmap, StorageCorruption> {
+        self.evict_excess(1)?;
+        if let Some(spill) = &mut self.spill {
+            spill.order.push_back(key);
+        }
+        Ok(self.hot.entry(key).or_insert(set))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hot.len()
+            + self.lazy.len()
+            + self.spill.as_ref().map_or(0, |spill| spill.records.len())
+    }
+
+    /// Number of lazily loaded records that were never read
+    pub fn undecoded(&self) -> usize {
+        self.lazy
+            .values()
+            .filter(|record| record.decoded.get().is_none())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.hot.keys().chain(self.lazy.keys()).cloned().chain(
+            self.spill
+                .iter()
+                .flat_map(|spill| spill.records.keys().cloned()),
+        )
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Cow<'_, TermBitmap>>, StorageCorruption> {
+        if let Some(set) = self.hot.get(key) {
+            return Ok(Some(Cow::Borrowed(set)));
+        }
+        if let Some(record) = self.lazy.get(key) {
+            return Ok(Some(Cow::Borrowed(record.get(*key)?)));
+        }
+        let Some(spill) = &self.spill else {
+            return Ok(None);
+        };
+        match spill.records.get(key) {
+            Some(&record) => Ok(Some(Cow::Owned(spill.read(*key, record)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Number of terms in record, without reading it back if it was spilled
+    pub fn len_of(&self, key: &Key) -> Option<usize> {
+        if let Some(set) = self.hot.get(key) {
+            return Some(set.len());
+        }
+        if let Some(record) = self.lazy.get(key) {
+            return Some(record.len);
+        }
+        Some(self.spill.as_ref()?.records.get(key)?.terms as usize)
+    }
+
+    /// Every record, spilled ones are read back one at a time
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = Result<(Key, Cow<'_, TermBitmap>), StorageCorruption>> {
+        self.hot
+            .iter()
+            .map(|(&key, set)| Ok((key, Cow::Borrowed(set))))
+            .chain(
+                self.lazy
+                    .iter()
+                    .map(|(&key, record)| Ok((key, Cow::Borrowed(record.get(key)?)))),
+            )
+            .chain(self.spill.iter().flat_map(|spill| {
+                spill
+                    .records
+                    .iter()
+                    .map(|(&key, &record)| Ok((key, Cow::Owned(spill.read(key, record)?))))
+            }))
+    }
+
+    /// Mutable access to record, loading it into memory if it was spilled
+    pub fn get_mut(&mut self, key: &Key) -> Result<Option<&mut TermBitmap>, StorageCorruption> {
+        if self.hot.contains_key(key) {
+            return Ok(self.hot.get_mut(key));
+        }
+        if let Some(record) = self.lazy.get(key) {
+            // decoded first, so that record is only moved once it could be read
+            record.get(*key)?;
+            let set = self.lazy.remove(key).unwrap().into_decoded(*key)?;
+            return self.make_hot(*key, set).map(Some);
+        }
+        match self
+            .spill
+            .as_mut()
+            .map(|spill| spill.take(key))
+            .transpose()?
+        {
+            Some(Some(set)) => self.make_hot(*key, set).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn get_or_insert_default(
+        &mut self,
+        key: Key,
+    ) -> Result<&mut TermBitmap, StorageCorruption> {
+        if self.get_mut(&key)?.is_none() {
+            return self.make_hot(key, TermBitmap::default());
+        }
+        Ok(self.hot.get_mut(&key).unwrap())
+    }
+
+    pub fn insert(&mut self, key: Key, set: TermBitmap) -> Result<(), StorageCorruption> {
+        self.remove(&key)?;
+        self.make_hot(key, set).map(|_| ())
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Result<Option<TermBitmap>, StorageCorruption> {
+        if let Some(set) = self.hot.remove(key) {
+            return Ok(Some(set));
+        }
+        if let Some(record) = self.lazy.get(key) {
+            record.get(*key)?;
+            return self.lazy.remove(key).unwrap().into_decoded(*key).map(Some);
+        }
+        match &mut self.spill {
+            Some(spill) => spill.take(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Release excess capacity, rewriting spill file if most of it is taken by stale records
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        self.hot.shrink_to_fit();
+        self.lazy.shrink_to_fit();
+        if let Some(spill) = &mut self.spill {
+            spill.order.retain(|key| self.hot.contains_key(key));
+            if spill.garbage > spill.end / 2 {
+                spill.rewrite()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::Key;
+
+    use super::{BigStorage, EncodedBitmap, LazyRecord, TermBitmap};
+
+    #[test]
+    fn spilled_records_are_read_back() {
+        let path = std::env::temp_dir().join(format!("elizadb-spill-{}", std::process::id()));
+        let mut storage = BigStorage::default();
+        for key in 1..=5 {
+            storage
+                .insert(
+                    Key::try_from(key).unwrap(),
+                    TermBitmap::from_iter([key as u16]),
+                )
+                .unwrap();
+        }
+        storage.spill_to(&path, 2).unwrap();
+
+        assert_eq!(storage.hot.len(), 2);
+        assert_eq!(storage.len(), 5);
+        assert_eq!(storage.iter().count(), 5);
+        for key in 1..=5 {
+            let set = storage.get(&Key::try_from(key).unwrap()).unwrap().unwrap();
+            assert_eq!(*set, TermBitmap::from_iter([key as u16]));
+        }
+
+        let cold = storage
+            .keys()
+            .find(|key| !storage.hot.contains_key(key))
+            .unwrap();
+        storage.get_or_insert_default(cold).unwrap().insert(100);
+        assert!(storage.hot.contains_key(&cold));
+        assert_eq!(storage.hot.len(), 2);
+        assert!(storage.get(&cold).unwrap().unwrap().contains(100));
+
+        for key in 1..=4 {
+            storage.remove(&Key::try_from(key).unwrap()).unwrap();
+        }
+        storage.shrink_to_fit().unwrap();
+        assert_eq!(storage.len(), 1);
+        assert!(storage.get(&Key::try_from(5).unwrap()).unwrap().is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lazy_records_are_checked_on_load_and_fail_reads_when_corrupted() {
+        let encode = |set: &TermBitmap| {
+            let mut bytes = vec![];
+            set.0.serialize_into(&mut bytes).unwrap();
+            EncodedBitmap(bytes)
+        };
+        for set in [
+            TermBitmap::default(),
+            TermBitmap::from_iter([1, 2, 3]),
+            TermBitmap::from_iter(0..5000),
+        ] {
+            assert_eq!(encode(&set).header_len().unwrap(), set.len());
+        }
+
+        let key = Key::try_from(1).unwrap();
+        let EncodedBitmap(mut bytes) = encode(&TermBitmap::from_iter(0..100));
+        assert!(LazyRecord::try_from(EncodedBitmap(bytes[..6].to_vec())).is_err());
+        bytes.truncate(bytes.len() - 10);
+        let mut storage = BigStorage::default();
+        let record = LazyRecord::try_from(EncodedBitmap(bytes)).unwrap();
+        storage.lazy.insert(key, record);
+        assert_eq!(storage.len_of(&key), Some(100));
+        assert!(storage.get(&key).is_err());
+        assert!(storage.get_mut(&key).is_err());
+        assert!(storage.remove(&key).is_err());
+        assert!(storage.lazy.contains_key(&key));
+    }
+
+    #[test]
+    fn failed_spill_io_loses_no_records() {
+        let path = std::env::temp_dir().join(format!("elizadb-spill-io-{}", std::process::id()));
+        let key = |key| Key::try_from(key).unwrap();
+        let mut storage = BigStorage::default();
+        for k in 1..=3 {
+            storage
+                .insert(key(k), TermBitmap::from_iter([k as u16]))
+                .unwrap();
+        }
+        storage.spill_to(&path, 2).unwrap();
+        let cold = storage
+            .keys()
+            .find(|k| !storage.hot.contains_key(k))
+            .unwrap();
+
+        // read-only handle makes writes fail, records stay hot rather than being dropped
+        let spill = storage.spill.as_mut().unwrap();
+        spill.file = std::fs::File::open(&path).unwrap();
+        assert!(storage.insert(key(4), TermBitmap::default()).is_err());
+        assert_eq!(storage.len(), 3);
+        assert!(storage.iter().all(|record| record.is_ok()));
+
+        // truncated file makes reads fail, spilled record is kept for a later attempt
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        assert!(storage.get(&cold).is_err());
+        assert!(storage.get_mut(&cold).is_err());
+        assert!(storage.spill.as_ref().unwrap().records.contains_key(&cold));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
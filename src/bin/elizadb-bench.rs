@@ -0,0 +1,227 @@
+//! Load generator driving elizadb HTTP API and reporting throughput and latency
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser, ValueEnum};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DistributionKind {
+    Uniform,
+    Zipf,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about)]
+struct Args {
+    /// Base URL of elizadb server
+    #[arg(long, default_value = "http://localhost:4200")]
+    url: String,
+
+    /// Total number of requests to send
+    #[arg(long, default_value_t = 100_000)]
+    requests: usize,
+
+    /// Number of requests in flight at once
+    #[arg(long, default_value_t = 32)]
+    concurrency: usize,
+
+    /// Number of distinct keys
+    #[arg(long, default_value_t = 100_000)]
+    keys: u64,
+
+    /// Number of distinct terms
+    #[arg(long, default_value_t = 256)]
+    terms: u64,
+
+    /// How keys are picked
+    #[arg(long, value_enum, default_value_t = DistributionKind::Uniform)]
+    key_distribution: DistributionKind,
+
+    /// How terms are picked
+    #[arg(long, value_enum, default_value_t = DistributionKind::Zipf)]
+    term_distribution: DistributionKind,
+
+    /// Exponent of zipf distributions
+    #[arg(long, default_value_t = 1.0)]
+    zipf_exponent: f64,
+
+    /// Fraction of requests that set flags, the rest are simple vertical queries
+    #[arg(long, default_value_t = 0.5)]
+    write_ratio: f64,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Sampler of values in `1..=size`
+enum Distribution {
+    Uniform(u64),
+    /// Cumulative probabilities of each rank
+    Zipf(Vec<f64>),
+}
+
+impl Distribution {
+    fn new(kind: DistributionKind, size: u64, exponent: f64) -> Self {
+        match kind {
+            DistributionKind::Uniform => Distribution::Uniform(size),
+            DistributionKind::Zipf => {
+                let weights = (1..=size)
+                    .map(|rank| 1.0 / (rank as f64).powf(exponent))
+                    .collect::<Vec<_>>();
+                let total = weights.iter().sum::<f64>();
+                let mut cumulative = 0.0;
+                Distribution::Zipf(
+                    weights
+                        .into_iter()
+                        .map(|weight| {
+                            cumulative += weight / total;
+                            cumulative
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> u64 {
+        match self {
+            Distribution::Uniform(size) => rng.gen_range(1..=*size),
+            Distribution::Zipf(cdf) => {
+                let point = rng.gen::<f64>();
+                cdf.partition_point(|&p| p < point).min(cdf.len() - 1) as u64 + 1
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl Samples {
+    fn merge(&mut self, other: Samples) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+
+    fn report(&mut self, name: &str, elapsed: Duration) {
+        if self.latencies.is_empty() {
+            return;
+        }
+        self.latencies.sort_unstable();
+        let percentile = |p: f64| {
+            let index = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+            self.latencies[index]
+        };
+        println!(
+            "{name}: {} requests, {} errors, {:.0} req/s, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.latencies.len(),
+            self.errors,
+            self.latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            self.latencies.last().unwrap(),
+        );
+    }
+}
+
+async fn worker(
+    args: Arc<Args>,
+    client: reqwest::Client,
+    keys: Arc<Distribution>,
+    terms: Arc<Distribution>,
+    requests: usize,
+    seed: u64,
+) -> (Samples, Samples) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (mut writes, mut queries) = (Samples::default(), Samples::default());
+
+    for _ in 0..requests {
+        let term = format!("t{}", terms.sample(&mut rng));
+        let is_write = rng.gen_bool(args.write_ratio.clamp(0.0, 1.0));
+        let request = if is_write {
+            let key = keys.sample(&mut rng);
+            client.post(format!("{}/items/{key}", args.url)).json(&term)
+        } else {
+            client
+                .post(format!("{}/query", args.url))
+                .json(&serde_json::json!({"type": "Simple", "term": term}))
+        };
+
+        let started = Instant::now();
+        let result = request.send().await;
+        let samples = if is_write { &mut writes } else { &mut queries };
+        samples.latencies.push(started.elapsed());
+        match result {
+            Ok(response) if response.status().is_success() => {
+                // drain body so the connection can be reused
+                let _ = response.bytes().await;
+            }
+            _ => samples.errors += 1,
+        }
+    }
+
+    (writes, queries)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arc::new(Args::parse());
+    let client = reqwest::Client::new();
+    let keys = Arc::new(Distribution::new(
+        args.key_distribution,
+        args.keys,
+        args.zipf_exponent,
+    ));
+    let terms = Arc::new(Distribution::new(
+        args.term_distribution,
+        args.terms,
+        args.zipf_exponent,
+    ));
+
+    // register every term up front so that queries never hit unknown terms
+    for term in 1..=args.terms {
+        client
+            .post(format!("{}/terms", args.url))
+            .json(&format!("t{term}"))
+            .send()
+            .await?;
+    }
+
+    let concurrency = args.concurrency.max(1);
+    let started = Instant::now();
+    let handles = (0..concurrency)
+        .map(|i| {
+            let requests =
+                args.requests / concurrency + usize::from(i < args.requests % concurrency);
+            tokio::spawn(worker(
+                args.clone(),
+                client.clone(),
+                keys.clone(),
+                terms.clone(),
+                requests,
+                args.seed.wrapping_add(i as u64),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let (mut writes, mut queries) = (Samples::default(), Samples::default());
+    for handle in handles {
+        let (worker_writes, worker_queries) = handle.await?;
+        writes.merge(worker_writes);
+        queries.merge(worker_queries);
+    }
+    let elapsed = started.elapsed();
+
+    println!("elapsed: {elapsed:?}");
+    writes.report("set_flag", elapsed);
+    queries.report("vertical_query", elapsed);
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Tools working on snapshot files, offline except for `verify`
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use ::serde::Serialize;
+use clap::{Parser, Subcommand};
+use elizadb::{
+    config::BigRecordDecoding,
+    query::FilteredQuery,
+    serde,
+    storage::{Database, Key},
+};
+use reqwest::{Client, StatusCode};
+
+#[derive(Clone, Debug, Parser)]
+#[command(about)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Write terms and key-term pairs of snapshot into SQLite database
+    ExportSqlite {
+        /// Data file whose newest slot and deltas are loaded, or a single slot file
+        snapshot: PathBuf,
+        /// SQLite database to write, existing tables `terms` and `key_terms` are replaced
+        out: PathBuf,
+    },
+    /// Write `key, term` pair of every flag into Parquet file
+    ExportParquet {
+        /// Data file whose newest slot and deltas are loaded, or a single slot file
+        snapshot: PathBuf,
+        out: PathBuf,
+        /// Export only keys matching query, given as JSON like the body of `POST /query`
+        #[arg(long)]
+        query: Option<String>,
+    },
+    /// Compare flags of randomly picked keys of snapshot with those served by running instance,
+    /// failing if any differ
+    Verify {
+        /// Data file whose newest slot and deltas are loaded, or a single slot file
+        #[arg(long)]
+        snapshot: PathBuf,
+        /// Base URL of instance, such as `http://localhost:4200`
+        #[arg(long)]
+        server: String,
+        /// Number of keys to compare
+        #[arg(long, default_value_t = 1000)]
+        sample: usize,
+        /// Sent as bearer token, for instances with access policy
+        #[arg(long)]
+        api_key: Option<String>,
+    },
+}
+
+fn load(snapshot: &Path) -> Result<Database<8>, Box<dyn std::error::Error>> {
+    if !snapshot.exists() && serde::rotation::slots_newest_first(snapshot).is_empty() {
+        return Err(format!("snapshot {} does not exist", snapshot.display()).into());
+    }
+    serde::load_possibly_missing(snapshot, BigRecordDecoding::Eager)
+}
+
+/// Key whose flags differ, None where it does not exist
+#[derive(Debug, Serialize)]
+struct Mismatch {
+    key: Key,
+    snapshot: Option<BTreeSet<String>>,
+    server: Option<BTreeSet<String>>,
+}
+
+/// Compare flags of `keys` in snapshot with those served by instance at `server` one by one with
+/// `GET /items/:key`. Keys written to after snapshot was taken show up as mismatches
+async fn verify(
+    db: &Database<8>,
+    server: &str,
+    api_key: Option<&str>,
+    keys: &[Key],
+) -> Result<Vec<Mismatch>, Box<dyn std::error::Error>> {
+    let client = Client::new();
+    let server = server.trim_end_matches('/');
+    let mut mismatches = Vec::new();
+    for &key in keys {
+        let mut request = client.get(format!("{server}/items/{key}"));
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let failed = |e| format!("request for key {key} failed: {e}");
+        let response = request.send().await.map_err(failed)?;
+        let served = match response.status() {
+            StatusCode::OK => Some(response.json::<BTreeSet<String>>().await.map_err(failed)?),
+            StatusCode::NOT_FOUND => None,
+            status => {
+                return Err(format!("server answered request for key {key} with {status}").into())
+            }
+        };
+        let stored = db
+            .horizontal_query(&key)?
+            .map(|terms| terms.into_iter().map(String::from).collect());
+        if stored != served {
+            mismatches.push(Mismatch {
+                key,
+                snapshot: stored,
+                server: served,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::ExportSqlite { snapshot, out } => {
+            let report = load(&snapshot)?.export_sqlite(&out)?;
+            println!(
+                "exported {} terms and {} key-term pairs to {}",
+                report.terms,
+                report.pairs,
+                out.display()
+            );
+        }
+        Command::ExportParquet {
+            snapshot,
+            out,
+            query,
+        } => {
+            let db = load(&snapshot)?;
+            let keys = match query {
+                Some(query) => {
+                    let query: FilteredQuery = serde_json::from_str(&query)?;
+                    Some(db.filtered_vertical_query(&query)?)
+                }
+                None => None,
+            };
+            let pairs = db.export_parquet(keys.as_deref(), &out)?;
+            println!("exported {pairs} key-term pairs to {}", out.display());
+        }
+        Command::Verify {
+            snapshot,
+            server,
+            sample,
+            api_key,
+        } => {
+            let db = load(&snapshot)?;
+            let keys = db.sample_keys(sample);
+            let mismatches = tokio::runtime::Runtime::new()?.block_on(verify(
+                &db,
+                &server,
+                api_key.as_deref(),
+                &keys,
+            ))?;
+            for mismatch in &mismatches {
+                println!("{}", serde_json::to_string(mismatch)?);
+            }
+            println!(
+                "checked {} keys, {} mismatched",
+                keys.len(),
+                mismatches.len()
+            );
+            if !mismatches.is_empty() {
+                return Err(format!("{} keys differ", mismatches.len()).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args.command) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
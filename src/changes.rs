@@ -0,0 +1,412 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    audit::AuditLog,
+    history::FlagHistory,
+    query_cache::QueryCache,
+    storage::{Database, Key, TermId},
+    views::Views,
+};
+
+/// Single mutation of database state as it is recorded in change feed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    AddTerm {
+        term: String,
+    },
+    /// Term created under an id asked for explicitly rather than the next free one
+    AddTermWithId {
+        term: String,
+        id: TermId,
+    },
+    RenameTerm {
+        term: String,
+        new_name: String,
+    },
+    MergeTerm {
+        term: String,
+        into: String,
+    },
+    AddAlias {
+        alias: String,
+        term: String,
+    },
+    RemoveAlias {
+        alias: String,
+    },
+    SetTermGroup {
+        name: String,
+        terms: Vec<String>,
+        #[serde(default)]
+        exclusive: bool,
+    },
+    RemoveTermGroup {
+        name: String,
+    },
+    SetTrigger {
+        name: String,
+        when: String,
+        #[serde(default)]
+        set: Vec<String>,
+        #[serde(default)]
+        webhook: Option<String>,
+    },
+    RemoveTrigger {
+        name: String,
+    },
+    CreateRecord {
+        key: Key,
+    },
+    SetKeyAlias {
+        key: Key,
+        alias: String,
+    },
+    RemoveKeyAlias {
+        key: Key,
+    },
+    SetFlag {
+        key: Key,
+        term: String,
+    },
+    UnsetFlag {
+        key: Key,
+        term: String,
+    },
+    DeleteRecord {
+        key: Key,
+        deleted_at: u64,
+    },
+    RestoreRecord {
+        key: Key,
+    },
+    PurgeRecord {
+        key: Key,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to apply {0:?}")]
+pub struct ApplyError(pub Change);
+
+impl Change {
+    /// Record this change concerns, None for changes of terms
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            Change::CreateRecord { key }
+            | Change::SetKeyAlias { key, .. }
+            | Change::RemoveKeyAlias { key }
+            | Change::SetFlag { key, .. }
+            | Change::UnsetFlag { key, .. }
+            | Change::DeleteRecord { key, .. }
+            | Change::RestoreRecord { key }
+            | Change::PurgeRecord { key } => Some(*key),
+            Change::AddTerm { .. }
+            | Change::AddTermWithId { .. }
+            | Change::RenameTerm { .. }
+            | Change::MergeTerm { .. }
+            | Change::AddAlias { .. }
+            | Change::RemoveAlias { .. }
+            | Change::SetTermGroup { .. }
+            | Change::RemoveTermGroup { .. }
+            | Change::SetTrigger { .. }
+            | Change::RemoveTrigger { .. } => None,
+        }
+    }
+
+    /// Replay this change on database the same way it was originally applied
+    pub fn apply<const SMALLSIZE: usize>(
+        &self,
+        db: &mut Database<SMALLSIZE>,
+    ) -> Result<(), ApplyError> {
+        let applied = match self {
+            Change::AddTerm { term } => db.add_term(term).is_ok(),
+            Change::AddTermWithId { term, id } => {
+                db.add_term_with_id(term, *id).is_ok() || db.get_term_id(term) == Some(*id)
+            }
+            Change::RenameTerm { term, new_name } => {
+                db.rename_term(term, new_name).is_ok() || db.get_term_id(new_name).is_some()
+            }
+            Change::MergeTerm { term, into } => {
+                db.merge_term(term, into).is_ok() || db.get_term_id(term) == db.get_term_id(into)
+            }
+            Change::AddAlias { alias, term } => {
+                db.add_alias(term, alias).is_ok() || db.get_term_id(alias) == db.get_term_id(term)
+            }
+            Change::RemoveAlias { alias } => {
+                db.remove_alias(alias);
+                true
+            }
+            Change::SetTermGroup {
+                name,
+                terms,
+                exclusive,
+            } => db.set_term_group(name, terms, *exclusive).is_ok(),
+            Change::RemoveTermGroup { name } => {
+                db.remove_term_group(name);
+                true
+            }
+            Change::SetTrigger {
+                name,
+                when,
+                set,
+                webhook,
+            } => db.set_trigger(name, when, set, webhook.as_deref()).is_ok(),
+            Change::RemoveTrigger { name } => {
+                db.remove_trigger(name);
+                true
+            }
+            Change::CreateRecord { key } => db.create_record(*key).is_ok(),
+            Change::SetKeyAlias { key, alias } => db.set_key_alias(*key, alias).is_ok(),
+            Change::RemoveKeyAlias { key } => {
+                db.remove_key_alias(*key);
+                true
+            }
+            // changes were validated where they were first applied
+            Change::SetFlag { key, term } => db
+                .with_validation_suspended(|db| db.set_flag(*key, term))
+                .is_ok(),
+            Change::UnsetFlag { key, term } => db.unset_flag(*key, term).is_ok(),
+            Change::DeleteRecord { key, deleted_at } => db.delete_record(*key, *deleted_at).is_ok(),
+            Change::RestoreRecord { key } => db.restore_record(*key).is_ok(),
+            Change::PurgeRecord { key } => {
+                db.purge_deleted_record(*key);
+                true
+            }
+        };
+
+        if applied {
+            Ok(())
+        } else {
+            Err(ApplyError(self.clone()))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SequencedChange {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// Page of change feed, `next` is the sequence to request afterwards
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub next: u64,
+    pub changes: Vec<SequencedChange>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("changes before {oldest} were already discarded")]
+pub struct ChangesDiscarded {
+    pub oldest: u64,
+}
+
+struct LogState {
+    first_seq: u64,
+    entries: VecDeque<Change>,
+}
+
+/// Bounded in-memory feed of applied changes, numbered by monotonically increasing sequence
+pub struct ChangeLog {
+    state: Mutex<LogState>,
+    capacity: usize,
+    appended: Notify,
+    audit: Option<Arc<AuditLog>>,
+    views: Option<Arc<Views>>,
+    query_cache: Option<Arc<QueryCache>>,
+    history: Option<Arc<FlagHistory>>,
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(LogState {
+                first_seq: 0,
+                entries: VecDeque::new(),
+            }),
+            capacity,
+            appended: Notify::new(),
+            audit: None,
+            views: None,
+            query_cache: None,
+            history: None,
+        }
+    }
+
+    /// Also write every recorded change into audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Also keep materialized views up to date with every recorded change
+    pub fn with_views(mut self, views: Arc<Views>) -> Self {
+        self.views = Some(views);
+        self
+    }
+
+    /// Also drop cached query results made stale by recorded changes
+    pub fn with_query_cache(mut self, query_cache: Arc<QueryCache>) -> Self {
+        self.query_cache = Some(query_cache);
+        self
+    }
+
+    /// Also remember recent changes of every record
+    pub fn with_history(mut self, history: Arc<FlagHistory>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Sequence number that will be assigned to next recorded change
+    pub fn next_seq(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.first_seq + state.entries.len() as u64
+    }
+
+    /// Append change, must be called while holding database write lock to keep feed ordered with state
+    pub fn record(&self, change: Change) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.first_seq + state.entries.len() as u64;
+        if let Some(audit) = &self.audit {
+            audit.log(seq, &change);
+        }
+        if let Some(history) = &self.history {
+            history.observe(seq, &change);
+        }
+        if let Some(views) = &self.views {
+            views.observe(&change);
+        }
+        if let Some(query_cache) = &self.query_cache {
+            query_cache.observe(&change);
+        }
+        state.entries.push_back(change);
+        while state.entries.len() > self.capacity {
+            state.entries.pop_front();
+            state.first_seq += 1;
+        }
+        drop(state);
+        self.appended.notify_waiters();
+    }
+
+    /// Forget every recorded change after state was replaced wholesale. One sequence number is
+    /// skipped so that even followers that were fully caught up find their position discarded
+    pub fn discard_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.first_seq += state.entries.len() as u64 + 1;
+        state.entries.clear();
+        drop(state);
+        if let Some(views) = &self.views {
+            views.invalidate_all();
+        }
+        if let Some(query_cache) = &self.query_cache {
+            query_cache.invalidate_all();
+        }
+        self.appended.notify_waiters();
+    }
+
+    pub fn since(&self, seq: u64, limit: usize) -> Result<Vec<SequencedChange>, ChangesDiscarded> {
+        let state = self.state.lock().unwrap();
+        if seq < state.first_seq {
+            return Err(ChangesDiscarded {
+                oldest: state.first_seq,
+            });
+        }
+        let offset = (seq - state.first_seq) as usize;
+        Ok(state
+            .entries
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .take(limit)
+            .map(|(i, change)| SequencedChange {
+                seq: state.first_seq + i as u64,
+                change: change.clone(),
+            })
+            .collect())
+    }
+
+    /// Same as `since`, but waits up to `timeout` for new changes if there are none yet
+    pub async fn wait_since(
+        &self,
+        seq: u64,
+        limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SequencedChange>, ChangesDiscarded> {
+        let appended = self.appended.notified();
+        let changes = self.since(seq, limit)?;
+        if !changes.is_empty() || timeout.is_zero() {
+            return Ok(changes);
+        }
+        let _ = tokio::time::timeout(timeout, appended).await;
+        self.since(seq, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::{Change, ChangeLog};
+
+    #[test]
+    fn old_changes_are_discarded_past_capacity() {
+        let log = ChangeLog::new(2);
+        for term in ["a", "b", "c"] {
+            log.record(Change::AddTerm {
+                term: term.to_string(),
+            });
+        }
+
+        assert_eq!(log.next_seq(), 3);
+        assert!(log.since(0, 10).is_err());
+        let changes = log.since(1, 10).unwrap();
+        assert_eq!(changes.iter().map(|c| c.seq).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn discarding_all_invalidates_caught_up_followers() {
+        let log = ChangeLog::new(10);
+        log.record(Change::AddTerm {
+            term: "a".to_string(),
+        });
+        log.discard_all();
+
+        assert!(log.since(1, 10).is_err());
+        assert_eq!(log.next_seq(), 2);
+        assert!(log.since(2, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replayed_changes_reproduce_state() {
+        let key = Key::try_from(1).unwrap();
+        let changes = [
+            Change::AddTerm {
+                term: "a".to_string(),
+            },
+            Change::SetFlag {
+                key,
+                term: "a".to_string(),
+            },
+            Change::RenameTerm {
+                term: "a".to_string(),
+                new_name: "b".to_string(),
+            },
+        ];
+
+        let mut db = Database::<8>::default();
+        for change in changes.iter() {
+            change.apply(&mut db).unwrap();
+        }
+        assert!(db.horizontal_query(&key).unwrap().unwrap().contains("b"));
+        assert_eq!(db.get_term_id("a"), None);
+    }
+}
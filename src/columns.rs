@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use roaring::RoaringBitmap;
+
+use crate::{
+    query::KeyRange,
+    smallset::SmallsetItem,
+    storage::{Database, Key, StorageCorruption, TermId},
+};
+
+/// Term-major copy of records: one bitmap per term over dense ordinals assigned to keys
+#[derive(Default)]
+pub struct TermColumns {
+    ordinals: HashMap<Key, u32>,
+    keys: Vec<Key>,
+    /// Ordinals of keys that currently have a record, deleted keys keep their ordinal
+    live: RoaringBitmap,
+    columns: HashMap<TermId, RoaringBitmap>,
+}
+
+impl TermColumns {
+    pub fn add_key(&mut self, key: Key) -> u32 {
+        let ordinal = *self.ordinals.entry(key).or_insert_with(|| {
+            self.keys.push(key);
+            (self.keys.len() - 1) as u32
+        });
+        self.live.insert(ordinal);
+        ordinal
+    }
+
+    /// Drop record of `key` along with all its terms
+    pub fn remove_key(&mut self, key: Key) {
+        let Some(&ordinal) = self.ordinals.get(&key) else {
+            return;
+        };
+        self.live.remove(ordinal);
+        for column in self.columns.values_mut() {
+            column.remove(ordinal);
+        }
+    }
+
+    pub fn set(&mut self, key: Key, term_id: TermId) {
+        let ordinal = self.add_key(key);
+        self.columns.entry(term_id).or_default().insert(ordinal);
+    }
+
+    pub fn unset(&mut self, key: Key, term_id: TermId) {
+        let Some(&ordinal) = self.ordinals.get(&key) else {
+            return;
+        };
+        if let Some(column) = self.columns.get_mut(&term_id) {
+            column.remove(ordinal);
+        }
+    }
+
+    /// Add keys of column `from` to column `to`, dropping `from`
+    pub fn move_column(&mut self, from: TermId, to: TermId) {
+        if let Some(column) = self.columns.remove(&from) {
+            *self.columns.entry(to).or_default() |= column;
+        }
+    }
+
+    pub fn remove_column(&mut self, term_id: TermId) {
+        self.columns.remove(&term_id);
+    }
+
+    /// Number of keys carrying `term_id`
+    pub fn count(&self, term_id: TermId) -> usize {
+        self.columns
+            .get(&term_id)
+            .map_or(0, |column| column.len() as usize)
+    }
+
+    fn resolve(&self, matches: RoaringBitmap, range: &KeyRange) -> Vec<Key> {
+        matches
+            .iter()
+            .map(|ordinal| self.keys[ordinal as usize])
+            .filter(|&key| range.contains(key))
+            .collect()
+    }
+
+    pub fn simple_query(&self, term_id: TermId, range: &KeyRange) -> Vec<Key> {
+        match self.columns.get(&term_id) {
+            Some(column) => self.resolve(column.clone(), range),
+            None => vec![],
+        }
+    }
+
+    /// Keys having at least `bound` of `terms`, `at_least[j]` holds keys matched by j of terms seen so far
+    pub fn k_of_n_query(&self, terms: &[TermId], bound: usize, range: &KeyRange) -> Vec<Key> {
+        if bound > terms.len() {
+            return vec![];
+        }
+
+        let mut at_least = vec![RoaringBitmap::new(); bound + 1];
+        at_least[0] = self.live.clone();
+        for term_id in terms {
+            let Some(column) = self.columns.get(term_id) else {
+                continue;
+            };
+            for j in (1..=bound).rev() {
+                let promoted = &at_least[j - 1] & column;
+                at_least[j] |= promoted;
+            }
+        }
+
+        self.resolve(at_least.pop().unwrap(), range)
+    }
+
+    /// Approximate number of bytes taken by ordinals and bitmaps
+    pub fn memory_usage(&self) -> usize {
+        self.ordinals.capacity() * (std::mem::size_of::<Key>() + std::mem::size_of::<u32>())
+            + self.keys.capacity() * std::mem::size_of::<Key>()
+            + self.live.serialized_size()
+            + self
+                .columns
+                .values()
+                .map(|column| column.serialized_size())
+                .sum::<usize>()
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Start maintaining term-major columns so that vertical queries become bitmap operations
+    pub fn enable_term_columns(&mut self) -> Result<(), StorageCorruption> {
+        let mut columns = TermColumns::default();
+        for record in self.records() {
+            let (key, record) = record?;
+            columns.add_key(key);
+            for term_id in record.term_ids() {
+                columns.set(key, term_id);
+            }
+        }
+        self.columns = Some(columns);
+        Ok(())
+    }
+
+    pub(super) fn columnar_simple_query(
+        &self,
+        term_id: SmallsetItem<TermId>,
+        range: &KeyRange,
+    ) -> Option<Vec<Key>> {
+        Some(self.columns.as_ref()?.simple_query(term_id.get(), range))
+    }
+
+    pub(super) fn columnar_k_of_n_query(
+        &self,
+        terms: &[SmallsetItem<TermId>],
+        bound: usize,
+        range: &KeyRange,
+    ) -> Option<Vec<Key>> {
+        let terms = terms.iter().map(|term| term.get()).collect::<Vec<_>>();
+        Some(self.columns.as_ref()?.k_of_n_query(&terms, bound, range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        query::Query,
+        storage::{Database, Key},
+    };
+
+    #[test]
+    fn columnar_queries_match_scans() {
+        let mut db = Database::<8>::default();
+        for key in 1..=50u64 {
+            for term in 0..10u64 {
+                if key % (term + 2) == 0 {
+                    db.set_flag(Key::try_from(key).unwrap(), &term.to_string())
+                        .unwrap();
+                }
+            }
+        }
+        let queries = [
+            Query::Simple {
+                term: "3".to_string(),
+            },
+            Query::KofN {
+                terms: vec!["0".to_string(), "1".to_string(), "2".to_string()],
+                bound: 2,
+            },
+            Query::KofN {
+                terms: vec!["0".to_string(), "4".to_string()],
+                bound: 0,
+            },
+            Query::KofN {
+                terms: vec![],
+                bound: 0,
+            },
+        ];
+        let deleted = Key::try_from(12).unwrap();
+        db.delete_record(deleted, 0).unwrap();
+        let scanned = queries
+            .iter()
+            .map(|query| {
+                let mut keys = db.vertical_query(query).unwrap();
+                keys.sort();
+                keys
+            })
+            .collect::<Vec<_>>();
+
+        db.enable_term_columns().unwrap();
+        db.unset_flag(Key::try_from(6).unwrap(), "0").unwrap();
+        db.set_flag(Key::try_from(6).unwrap(), "0").unwrap();
+        for (query, expected) in queries.iter().zip(&scanned) {
+            let mut keys = db.vertical_query(query).unwrap();
+            keys.sort();
+            assert!(!keys.contains(&deleted));
+            assert_eq!(&keys, expected);
+        }
+
+        db.restore_record(deleted).unwrap();
+        db.delete_record(deleted, 0).unwrap();
+        db.purge_deleted(1);
+        let mut keys = db.vertical_query(&queries[3]).unwrap();
+        keys.sort();
+        assert_eq!(keys, scanned[3]);
+    }
+}
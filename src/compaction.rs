@@ -0,0 +1,163 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    api::DBState,
+    jobs::{JobKind, Jobs},
+    smallset::Smallset,
+    storage::{corruption, Database, IndexLocation, SmallTier, StorageCorruption},
+};
+
+/// What a single compaction pass changed
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CompactionReport {
+    /// Records moved into a smaller tier
+    pub demoted: usize,
+    /// Smallsets rebuilt to get rid of tombstones
+    pub compacted: usize,
+    /// Trailing empty slots released from tier storage
+    pub trimmed: usize,
+}
+
+impl<const SIZE: usize> SmallTier<SIZE> {
+    fn compact_sets(&mut self) -> Result<usize, StorageCorruption> {
+        let mut compacted = 0;
+        for (key, set) in self.keys.iter().zip(self.sets.iter_mut()) {
+            let Some(key) = key else {
+                continue;
+            };
+            if set.tombstones() == 0 {
+                continue;
+            }
+            let mut target = Smallset::new_empty();
+            set.compact(&mut target).map_err(|item| {
+                corruption(format!(
+                    "term {item} of record {key} does not fit into its set"
+                ))
+            })?;
+            *set = target;
+            compacted += 1;
+        }
+        Ok(compacted)
+    }
+
+    fn trim_holes(&mut self) -> usize {
+        let before = self.keys.len();
+        while self.keys.last().is_some_and(Option::is_none) {
+            self.keys.pop();
+            self.sets.pop();
+        }
+        let len = self.keys.len();
+        self.holes.retain(|&hole| hole < len);
+        before - len
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Demote records that shrank, drop tombstones and release trailing holes
+    pub fn compact(&mut self) -> Result<CompactionReport, StorageCorruption> {
+        let mut report = CompactionReport::default();
+
+        // leave records half of target tier free so that they do not bounce right back on next insert
+        let shrinkable = self
+            .index
+            .iter()
+            .filter(|(_, location)| !matches!(location, IndexLocation::Small(_)))
+            .filter_map(|(&key, &location)| {
+                let target = (self.record_size(&key)? * 2).max(1);
+                (Self::tier_capacity_for(target) < Self::location_capacity(location))
+                    .then_some((key, target))
+            })
+            .collect::<Vec<_>>();
+        for (key, target) in shrinkable {
+            if let Some((items, _)) = self.detach(key)? {
+                self.attach(key, &items, target)?;
+                report.demoted += 1;
+            }
+        }
+
+        report.compacted = self.small.compact_sets()?
+            + self.tier16.compact_sets()?
+            + self.tier32.compact_sets()?
+            + self.tier64.compact_sets()?;
+        report.trimmed = self.small.trim_holes()
+            + self.tier16.trim_holes()
+            + self.tier32.trim_holes()
+            + self.tier64.trim_holes();
+        if let Err(e) = self.big_storage.shrink_to_fit() {
+            tracing::warn!("failed to rewrite big storage spill file: {e}");
+        }
+
+        Ok(report)
+    }
+
+    fn tier_capacity_for(min_capacity: usize) -> usize {
+        [SMALLSIZE, 16, 32, 64]
+            .into_iter()
+            .filter(|&capacity| capacity >= min_capacity)
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// Periodically compact database in background
+pub async fn run_periodically(db: DBState, jobs: Arc<Jobs>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let compacted = jobs.run(JobKind::Compaction, async { db.write().await.compact() });
+        let report = match compacted.await {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::error!("compaction failed: {e}");
+                continue;
+            }
+        };
+        if report.demoted + report.compacted + report.trimmed > 0 {
+            tracing::info!(
+                demoted = report.demoted,
+                compacted = report.compacted,
+                trimmed = report.trimmed,
+                "compacted storage"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, IndexLocation, Key};
+
+    #[test]
+    fn shrunk_records_are_demoted_and_holes_trimmed() {
+        let mut db = Database::<8>::default();
+        let (small, shrinking) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(small, "a").unwrap();
+        for term in 0..70 {
+            db.set_flag(shrinking, &term.to_string()).unwrap();
+        }
+        for term in 3..70 {
+            db.unset_flag(shrinking, &term.to_string()).unwrap();
+        }
+        // "7" has term id 9 and collides with "a", so removing "a" leaves a tombstone
+        db.set_flag(small, "7").unwrap();
+        db.unset_flag(small, "a").unwrap();
+
+        let report = db.compact().unwrap();
+
+        assert_eq!(report.demoted, 1);
+        assert_eq!(report.compacted, 1);
+        assert!(matches!(db.index[&shrinking], IndexLocation::Small(_)));
+        assert_eq!(
+            db.horizontal_query(&shrinking).unwrap().unwrap(),
+            ["0", "1", "2"].into()
+        );
+        assert_eq!(db.big_storage.len(), 0);
+        assert_eq!(db.tier16.keys.len(), 0);
+        assert!(db.small.sets.iter().all(|set| set.tombstones() == 0));
+        assert_eq!(db.horizontal_query(&small).unwrap().unwrap(), ["7"].into());
+        assert_eq!(db.compact().unwrap().demoted, 0);
+    }
+}
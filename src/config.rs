@@ -0,0 +1,265 @@
+use clap::{Parser, ValueEnum};
+
+/// How keys are written in API responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    /// JSON numbers, which lose precision above 2^53 in JavaScript
+    #[default]
+    Number,
+    /// Decimal strings
+    String,
+    /// `0x` prefixed 16 digit hex strings
+    Hex,
+}
+
+/// How log lines are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable text
+    #[default]
+    Plain,
+    /// One JSON object per line
+    Json,
+}
+
+/// When records that outgrew smallsets are decoded from snapshot loaded on start
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BigRecordDecoding {
+    /// All of them while snapshot is loaded, in parallel
+    #[default]
+    Eager,
+    /// Each one when it is first accessed, so that serving starts sooner. Corrupted records are
+    /// only noticed then
+    Lazy,
+}
+
+/// How records are laid out in memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StorageLayout {
+    /// One set of terms per key
+    #[default]
+    KeyMajor,
+    /// Additionally keep one bitmap of keys per term, trading memory for fast vertical queries
+    Columnar,
+}
+
+/// What happens when a new term is added to full term table
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TermEvictionPolicy {
+    /// Reject the new term
+    #[default]
+    Reject,
+    /// Forget a term no key carries to make room, rejecting only if every term is in use
+    EvictUnused,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, about)]
+pub struct Config {
+    /// Snapshot file state is loaded from and saved to
+    #[arg(long, value_name = "PATH", default_value = crate::serde::DEFAULT_SAVE_PATH)]
+    pub data_file: std::path::PathBuf,
+
+    /// Save full snapshot every this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub snapshot_interval_secs: Option<u64>,
+
+    /// Also upload every full snapshot to object storage location like `s3://bucket/prefix`,
+    /// downloading the newest one on start when there is no local snapshot
+    #[cfg(feature = "s3")]
+    #[arg(long, value_name = "URL")]
+    pub snapshot_target: Option<String>,
+
+    /// Number of most recent uploaded snapshots kept in object storage
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value_t = 5)]
+    pub snapshot_retention: usize,
+
+    /// Also keep copies of this many most recent full snapshots next to data file, for rollback
+    #[arg(long, value_name = "COPIES")]
+    pub snapshot_archive_keep: Option<usize>,
+
+    /// Remove archived snapshots older than this, except the newest one
+    #[arg(long, value_name = "SECONDS", requires = "snapshot_archive_keep")]
+    pub snapshot_archive_max_age_secs: Option<u64>,
+
+    /// Save snapshots from a copy of state so that writes are only paused while it is copied,
+    /// at the cost of holding that copy in memory until it is saved
+    #[arg(long)]
+    pub background_snapshots: bool,
+
+    /// Track changed keys so that `/service/save/delta` can write only them
+    #[arg(long)]
+    pub incremental_snapshots: bool,
+
+    /// Load snapshot, check it and exit without serving requests
+    #[arg(long)]
+    pub validate_only: bool,
+
+    /// Salvage decodable parts of snapshot that fails to load instead of exiting
+    #[arg(long)]
+    pub recover: bool,
+
+    /// Seed state from JSON export at this path instead of snapshot file
+    #[arg(long, value_name = "PATH", conflicts_with = "bootstrap_from")]
+    pub load_from_json: Option<std::path::PathBuf>,
+
+    /// JSON file with settings to apply on `SIGHUP`, see `POST /admin/config` for its format
+    #[arg(long, value_name = "PATH")]
+    pub reload_file: Option<std::path::PathBuf>,
+
+    /// Start with all mutating endpoints rejecting requests
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Load initial state from snapshot of peer at this base URL instead of local file
+    #[arg(long, value_name = "URL")]
+    pub bootstrap_from: Option<String>,
+
+    /// Run as read-only warm standby replicating state from leader at this base URL, until
+    /// promoted with `POST /admin/promote`
+    #[arg(long, value_name = "URL")]
+    pub follow: Option<String>,
+
+    /// Number of recent changes kept in memory for followers to catch up from
+    #[arg(long, default_value_t = 100_000)]
+    pub changelog_capacity: usize,
+
+    /// Number of recent `Idempotency-Key` responses remembered for replay
+    #[arg(long, default_value_t = 10_000)]
+    pub idempotency_capacity: usize,
+
+    /// Number of `/query` results kept in memory for repeated queries, caching is off unless given
+    #[arg(long)]
+    pub query_cache_capacity: Option<usize>,
+
+    /// Seconds a response stays available for replay by its `Idempotency-Key`
+    #[arg(long, default_value_t = 3600)]
+    pub idempotency_ttl_secs: u64,
+
+    /// Compact storage in background every this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub compact_interval_secs: Option<u64>,
+
+    /// Deleted records can be restored for this many seconds before they are purged
+    #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 3600)]
+    pub deleted_retention_secs: u64,
+
+    /// Purge deleted records past retention every this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+    pub purge_interval_secs: u64,
+
+    /// On shutdown, wait this long for in-flight requests before writing final snapshot
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub drain_timeout_secs: u64,
+
+    /// Reject buffered request bodies larger than this with 413. Newline-delimited bulk bodies
+    /// and snapshot restores are streamed and not limited
+    #[arg(long, value_name = "BYTES", default_value_t = 2 * 1024 * 1024)]
+    pub max_body_bytes: usize,
+
+    /// Reject writes with 507 once estimated memory usage exceeds this many bytes. Usage is
+    /// estimated at most once a second
+    #[arg(long, value_name = "BYTES")]
+    pub max_memory_bytes: Option<usize>,
+
+    /// Serve at most this many read requests at once, shedding further ones with 503 rather than
+    /// queueing them behind the database lock. Concurrency limits can be changed at runtime
+    #[arg(long, value_name = "N")]
+    pub max_concurrent_reads: Option<usize>,
+
+    /// Serve at most this many write requests at once, shedding further ones with 503
+    #[arg(long, value_name = "N")]
+    pub max_concurrent_writes: Option<usize>,
+
+    /// Serve at most this many admin requests at once, shedding further ones with 503
+    #[arg(long, value_name = "N")]
+    pub max_concurrent_admin: Option<usize>,
+
+    /// Keep only some of the records that outgrew smallsets in memory, spilling the rest into this scratch file
+    #[arg(long, value_name = "PATH")]
+    pub big_storage_spill_path: Option<std::path::PathBuf>,
+
+    /// Number of big records kept in memory when spilling is enabled
+    #[arg(long, default_value_t = 10_000)]
+    pub big_storage_cache_records: usize,
+
+    #[arg(long, value_enum, default_value_t = BigRecordDecoding::Eager)]
+    pub big_record_decoding: BigRecordDecoding,
+
+    /// JSON file with API keys and rules restricting access to records carrying certain terms,
+    /// see [`crate::access::AccessPolicy`]
+    #[arg(long, value_name = "PATH")]
+    pub access_policy: Option<std::path::PathBuf>,
+
+    /// JSON file with limits on number of keys and flags, in total and per key range,
+    /// see [`crate::quotas::Quotas`]
+    #[arg(long, value_name = "PATH")]
+    pub quotas: Option<std::path::PathBuf>,
+
+    /// Append who changed what and when to this file, one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<std::path::PathBuf>,
+
+    /// Write logs to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<std::path::PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Plain)]
+    pub log_format: LogFormat,
+
+    /// Start a new log file once the current one would grow past this size
+    #[arg(long, value_name = "BYTES", requires = "log_file")]
+    pub log_rotate_bytes: Option<u64>,
+
+    /// Start a new log file once the current one was written to for this long
+    #[arg(long, value_name = "SECONDS", requires = "log_file")]
+    pub log_rotate_secs: Option<u64>,
+
+    /// Number of rotated log files kept, named `<log file>.1` (newest) and up
+    #[arg(long, default_value_t = 5)]
+    pub log_keep_files: usize,
+
+    /// Keep this many most recent changes of every record in memory for `/items/:key/history`
+    #[arg(long, value_name = "ENTRIES")]
+    pub flag_history: Option<usize>,
+
+    /// Export tracing spans over OTLP (gRPC) to this collector endpoint, e.g. Jaeger
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Serve Arrow Flight bulk reads on this port
+    #[cfg(feature = "flight")]
+    #[arg(long, value_name = "PORT")]
+    pub flight_port: Option<u16>,
+
+    /// WASM module validating flags before they are set, may be given several times
+    #[cfg(feature = "plugins")]
+    #[arg(long = "plugin", value_name = "PATH")]
+    pub plugins: Vec<std::path::PathBuf>,
+
+    /// Apply single-record writes from a queue in batches under one lock instead of locking per request
+    #[arg(long)]
+    pub write_batching: bool,
+
+    /// Number of queued writes after which submitters wait, when write batching is enabled
+    #[arg(long, default_value_t = 1024)]
+    pub write_queue_capacity: usize,
+
+    /// Largest number of queued writes applied under one lock acquisition
+    #[arg(long, default_value_t = 256)]
+    pub write_batch_size: usize,
+
+    /// What to do when term table is full and a new term is added
+    #[arg(long, value_enum, default_value_t = TermEvictionPolicy::Reject)]
+    pub term_eviction: TermEvictionPolicy,
+
+    /// Storage layout to use
+    #[arg(long, value_enum, default_value_t = StorageLayout::KeyMajor)]
+    pub layout: StorageLayout,
+
+    /// Format of keys in responses, can be overridden per request with `x-elizadb-key-format` header
+    #[arg(long, value_enum, default_value_t = KeyFormat::Number)]
+    pub key_format: KeyFormat,
+}
@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::storage::{Database, IndexLocation, Key, SmallTier};
+
+/// Outcome of `check_consistency`, `problems` describes every violated invariant
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConsistencyReport {
+    pub consistent: bool,
+    pub checked_keys: usize,
+    pub checked_terms: usize,
+    pub problems: Vec<String>,
+}
+
+impl<const SIZE: usize> SmallTier<SIZE> {
+    /// Slots must match index, holes must point at free slots
+    fn check(
+        &self,
+        name: &str,
+        index: &std::collections::HashMap<Key, IndexLocation>,
+        location: impl Fn(usize) -> IndexLocation,
+        problems: &mut Vec<String>,
+    ) {
+        if self.keys.len() != self.sets.len() {
+            problems.push(format!(
+                "{name} tier has {} keys but {} sets",
+                self.keys.len(),
+                self.sets.len()
+            ));
+        }
+
+        for (slot, key) in self.keys.iter().enumerate() {
+            let Some(key) = key else { continue };
+            match index.get(key) {
+                Some(&found) if found == location(slot) => {}
+                found => problems.push(format!(
+                    "key {key} stored in {name} slot {slot} is indexed at {found:?}"
+                )),
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for &hole in &self.holes {
+            if !seen.insert(hole) {
+                problems.push(format!("{name} hole {hole} is listed more than once"));
+            }
+            match self.keys.get(hole) {
+                None => problems.push(format!("{name} hole {hole} is out of bounds")),
+                Some(Some(key)) => {
+                    problems.push(format!("{name} hole {hole} overlaps live key {key}"))
+                }
+                Some(None) => {}
+            }
+        }
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Verify invariants between index, storage tiers and term tables
+    pub fn check_consistency(&self) -> ConsistencyReport {
+        let mut problems = vec![];
+
+        for (&key, &location) in &self.index {
+            let stored = match location {
+                IndexLocation::Small(slot) => self.small.keys.get(slot).copied().flatten(),
+                IndexLocation::Tier16(slot) => self.tier16.keys.get(slot).copied().flatten(),
+                IndexLocation::Tier32(slot) => self.tier32.keys.get(slot).copied().flatten(),
+                IndexLocation::Tier64(slot) => self.tier64.keys.get(slot).copied().flatten(),
+                IndexLocation::Big => self.big_storage.len_of(&key).map(|_| key),
+            };
+            if stored != Some(key) {
+                problems.push(format!(
+                    "key {key} is indexed at {location:?} which holds {stored:?}"
+                ));
+            }
+        }
+
+        self.small
+            .check("small", &self.index, IndexLocation::Small, &mut problems);
+        self.tier16
+            .check("16", &self.index, IndexLocation::Tier16, &mut problems);
+        self.tier32
+            .check("32", &self.index, IndexLocation::Tier32, &mut problems);
+        self.tier64
+            .check("64", &self.index, IndexLocation::Tier64, &mut problems);
+        for key in self.big_storage.keys() {
+            if !matches!(self.index.get(&key), Some(IndexLocation::Big)) {
+                problems.push(format!("big record {key} is not indexed as big"));
+            }
+        }
+
+        for record in self.records() {
+            let (key, record) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    continue;
+                }
+            };
+            for term_id in record.term_ids() {
+                if !self.terms.contains_backward(&term_id) {
+                    problems.push(format!("key {key} carries unknown term id {term_id}"));
+                }
+            }
+        }
+
+        if !self.terms.is_mirrored() {
+            problems.push("term table directions disagree".to_string());
+        }
+        for (alias, term_id) in &self.aliases {
+            if !self.terms.contains_backward(term_id) {
+                problems.push(format!("alias {alias} points at unknown term id {term_id}"));
+            }
+        }
+
+        if !self.key_aliases.is_mirrored() {
+            problems.push("key alias table directions disagree".to_string());
+        }
+        for (alias, key) in self.key_aliases.iter() {
+            if !self.index.contains_key(key) {
+                problems.push(format!("key alias {alias} points at missing key {key}"));
+            }
+        }
+
+        ConsistencyReport {
+            consistent: problems.is_empty(),
+            checked_keys: self.index.len(),
+            checked_terms: self.terms.len(),
+            problems,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, IndexLocation, Key};
+
+    #[test]
+    fn consistency_check_finds_broken_index() {
+        let mut db = Database::<8>::default();
+        for key in 1..=3 {
+            let key = Key::try_from(key).unwrap();
+            for term in 0..(key.get() * 10) {
+                db.set_flag(key, &term.to_string()).unwrap();
+            }
+        }
+        db.compact().unwrap();
+        assert!(db.check_consistency().consistent);
+
+        db.index
+            .insert(Key::try_from(1).unwrap(), IndexLocation::Small(5));
+        let report = db.check_consistency();
+        assert!(!report.consistent);
+        assert_eq!(report.problems.len(), 2);
+    }
+}
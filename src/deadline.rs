@@ -0,0 +1,166 @@
+//! Deadlines callers give with `X-Request-Deadline` or `X-Request-Timeout`, past which nobody
+//! waits for the answer any more
+//!
+//! Read routes stop waiting on the database lock once deadline passes and vertical scans stop
+//! early, so that work nobody is going to read does not hold up other requests. Handlers answer
+//! such requests with 504 rather than with partial results. Writes are left to finish, they are
+//! not cut short halfway through.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::ApiError;
+
+/// Absolute deadline, in milliseconds since Unix epoch
+pub const DEADLINE_HEADER: &str = "x-request-deadline";
+/// Deadline relative to arrival of the request, in milliseconds
+pub const TIMEOUT_HEADER: &str = "x-request-timeout";
+
+/// Number of records scans go through between looks at the clock
+const CHECK_INTERVAL: usize = 1024;
+
+/// Point in time work may be given up at, none by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("request deadline passed before it was answered")]
+pub struct DeadlineExceeded;
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.0
+    }
+
+    pub fn expired(&self) -> bool {
+        self.0.is_some_and(|at| Instant::now() >= at)
+    }
+
+    pub fn check(&self) -> Result<(), DeadlineExceeded> {
+        match self.expired() {
+            true => Err(DeadlineExceeded),
+            false => Ok(()),
+        }
+    }
+
+    /// `items` cut short once deadline passes, callers [`Self::check`] whether that happened
+    pub fn bound<I: Iterator>(self, items: I) -> Bounded<I> {
+        Bounded {
+            items,
+            deadline: self,
+            until_check: CHECK_INTERVAL,
+        }
+    }
+
+    /// Earlier of the deadlines given by headers, invalid values are rejected with 400
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ApiError> {
+        let millis = |name: &str| -> Result<Option<u64>, ApiError> {
+            let Some(value) = headers.get(name) else {
+                return Ok(None);
+            };
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .map(Some)
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_deadline",
+                        format!("{name} has to be a number of milliseconds"),
+                    )
+                })
+        };
+        let timeout = millis(TIMEOUT_HEADER)?.map(Duration::from_millis);
+        let until_deadline = millis(DEADLINE_HEADER)?.map(|millis| {
+            (UNIX_EPOCH + Duration::from_millis(millis))
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        });
+        Ok(match timeout.into_iter().chain(until_deadline).min() {
+            Some(remaining) => Self::after(remaining),
+            None => Self::default(),
+        })
+    }
+}
+
+/// Iterator ending early once deadline passes, see [`Deadline::bound`]
+pub struct Bounded<I> {
+    items: I,
+    deadline: Deadline,
+    until_check: usize,
+}
+
+impl<I: Iterator> Iterator for Bounded<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        if self.deadline.0.is_some() {
+            self.until_check -= 1;
+            if self.until_check == 0 {
+                if self.deadline.expired() {
+                    return None;
+                }
+                self.until_check = CHECK_INTERVAL;
+            }
+        }
+        self.items.next()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Deadline {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_headers(&parts.headers)
+    }
+}
+
+/// Answer with 504 once deadline of request passes, dropping the handler if it is still waiting
+pub async fn enforce_deadline(request: Request, next: Next) -> Response {
+    let deadline = match Deadline::from_headers(request.headers()) {
+        Ok(deadline) => deadline,
+        Err(e) => return e.into_response(),
+    };
+    let Some(at) = deadline.instant() else {
+        return next.run(request).await;
+    };
+    match tokio::time::timeout_at(at.into(), next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::from(DeadlineExceeded).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::HeaderMap;
+
+    use super::{Deadline, TIMEOUT_HEADER};
+
+    #[test]
+    fn expired_deadline_cuts_iteration_short() {
+        assert_eq!(Deadline::default().bound(0..5000).count(), 5000);
+        assert!(Deadline::after(Duration::ZERO).bound(0..5000).count() < 5000);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, "60000".parse().unwrap());
+        let deadline = Deadline::from_headers(&headers).unwrap();
+        assert!(deadline.instant().is_some() && !deadline.expired());
+        headers.insert(TIMEOUT_HEADER, "soon".parse().unwrap());
+        assert!(Deadline::from_headers(&headers).is_err());
+    }
+}
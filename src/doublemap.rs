@@ -1,8 +1,8 @@
-use std::{
-    borrow::Borrow,
-    collections::{HashMap, HashSet},
-};
+use std::{borrow::Borrow, collections::HashMap, fmt::Debug};
 
+/// Map keeping one-to-one pairs searchable from both sides. Every left value is paired with at
+/// most one right value and the other way around
+#[derive(Clone)]
 pub struct DoubleMap<K, V> {
     forward: HashMap<K, V>,
     backward: HashMap<V, K>,
@@ -26,11 +26,56 @@ where
         Default::default()
     }
 
+    /// Insert pair, dropping any previous pairs that used either side so both directions stay in sync
     pub fn insert(&mut self, first: K, second: V) {
+        if let Some(old_second) = self.forward.remove(&first) {
+            self.backward.remove(&old_second);
+        }
+        if let Some(old_first) = self.backward.remove(&second) {
+            self.forward.remove(&old_first);
+        }
         self.forward.insert(first.clone(), second.clone());
         self.backward.insert(second, first);
     }
 
+    /// Remove pair by its left side, returning the right side if it was present
+    pub fn remove_forward<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: std::hash::Hash + std::cmp::Eq + ?Sized,
+    {
+        let second = self.forward.remove(key)?;
+        self.backward.remove(&second);
+        Some(second)
+    }
+
+    /// Remove pair by its right side, returning the left side if it was present
+    pub fn remove_backward<Q>(&mut self, key: &Q) -> Option<K>
+    where
+        V: Borrow<Q>,
+        Q: std::hash::Hash + std::cmp::Eq + ?Sized,
+    {
+        let first = self.backward.remove(key)?;
+        self.forward.remove(&first);
+        Some(first)
+    }
+
+    pub fn contains_forward<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::hash::Hash + std::cmp::Eq + ?Sized,
+    {
+        self.forward.contains_key(key)
+    }
+
+    pub fn contains_backward<Q>(&self, key: &Q) -> bool
+    where
+        V: Borrow<Q>,
+        Q: std::hash::Hash + std::cmp::Eq + ?Sized,
+    {
+        self.backward.contains_key(key)
+    }
+
     pub fn get_forward<Q>(&self, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
@@ -51,32 +96,164 @@ where
         self.forward.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty()
+    }
+
     pub fn left_keys(&self) -> impl Iterator<Item = &'_ K> {
         self.forward.keys()
     }
 
-    pub fn left_items(&self) -> impl Iterator<Item = (&'_ K, &'_ V)> {
+    /// Whether backward map holds exactly the reversed pairs of forward map
+    pub fn is_mirrored(&self) -> bool {
+        self.forward.len() == self.backward.len()
+            && self
+                .forward
+                .iter()
+                .all(|(first, second)| self.backward.get(second) == Some(first))
+    }
+
+    /// Iterator over all stored pairs, left side first, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&'_ K, &'_ V)> {
         self.forward.iter()
     }
 
-    pub fn rights(&self) -> impl Iterator<Item = &'_ V> {
-        self.backward.keys()
+    /// Keep only pairs for which `keep` returns true
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let backward = &mut self.backward;
+        self.forward.retain(|first, second| {
+            let kept = keep(first, second);
+            if !kept {
+                backward.remove(second);
+            }
+            kept
+        });
+    }
+
+    /// Pair of given left side for in-place manipulation. Pairing it with a right side that is
+    /// already taken drops the pair holding it, same as [`DoubleMap::insert`]
+    pub fn entry(&mut self, first: K) -> Entry<'_, K, V> {
+        if self.forward.contains_key(&first) {
+            Entry::Occupied(OccupiedEntry { map: self, first })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, first })
+        }
     }
 }
 
+/// Pair of a left side, either present or not, see [`DoubleMap::entry`]
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+pub struct OccupiedEntry<'a, K, V> {
+    map: &'a mut DoubleMap<K, V>,
+    first: K,
+}
+
+pub struct VacantEntry<'a, K, V> {
+    map: &'a mut DoubleMap<K, V>,
+    first: K,
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: std::hash::Hash + Eq + Clone,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => &entry.first,
+            Entry::Vacant(entry) => &entry.first,
+        }
+    }
+
+    /// Right side of the pair, pairing with `default` first if there is none
+    pub fn or_insert(self, default: V) -> &'a V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> &'a V {
+        match self {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: std::hash::Hash + Eq + Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.first
+    }
+
+    pub fn get(&self) -> &V {
+        &self.map.forward[&self.first]
+    }
+
+    pub fn into_ref(self) -> &'a V {
+        &self.map.forward[&self.first]
+    }
+
+    /// Pair left side with another right side, returning the previous one
+    pub fn insert(&mut self, second: V) -> V {
+        let previous = self.get().clone();
+        self.map.insert(self.first.clone(), second);
+        previous
+    }
+
+    /// Remove pair, returning its right side
+    pub fn remove(self) -> V {
+        let second = self.map.forward.remove(&self.first).unwrap();
+        self.map.backward.remove(&second);
+        second
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: std::hash::Hash + Eq + Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.first
+    }
+
+    pub fn insert(self, second: V) -> &'a V {
+        self.map.insert(self.first.clone(), second);
+        &self.map.forward[&self.first]
+    }
+}
+
+/// Map cannot become [`DoubleMap`] because two of its keys share a value
+#[derive(Debug, thiserror::Error)]
+#[error("value {value:?} is mapped from both {first:?} and {second:?}")]
+pub struct DuplicateValue<K: Debug, V: Debug> {
+    pub value: V,
+    pub first: K,
+    pub second: K,
+}
+
 impl<K, V> TryFrom<HashMap<K, V>> for DoubleMap<K, V>
 where
-    K: std::hash::Hash + std::cmp::Eq + Clone,
-    V: std::hash::Hash + std::cmp::Eq + Clone,
+    K: std::hash::Hash + std::cmp::Eq + Clone + Debug,
+    V: std::hash::Hash + std::cmp::Eq + Clone + Debug,
 {
-    type Error = ();
+    type Error = DuplicateValue<K, V>;
 
     fn try_from(value: HashMap<K, V>) -> Result<Self, Self::Error> {
-        let mut existing_backward = HashSet::new();
-        let mut result = DoubleMap::new();
+        let mut result = Self::new();
         for (k, v) in value {
-            if !existing_backward.insert(v.clone()) {
-                return Err(());
+            if let Some(first) = result.get_backward(&v) {
+                return Err(DuplicateValue {
+                    first: first.clone(),
+                    second: k,
+                    value: v,
+                });
             }
             result.insert(k, v);
         }
@@ -84,3 +261,73 @@ where
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{DoubleMap, Entry};
+
+    #[test]
+    fn reinsert_replaces_stale_entries() {
+        let mut map = DoubleMap::new();
+        map.insert("a".to_string(), 1);
+        map.insert("a".to_string(), 2);
+
+        assert_eq!(map.get_forward("a"), Some(&2));
+        assert_eq!(map.get_backward(&1), None);
+        assert_eq!(map.len(), 1);
+
+        map.insert("b".to_string(), 2);
+        assert_eq!(map.get_forward("a"), None);
+        assert_eq!(map.get_backward(&2).map(String::as_str), Some("b"));
+        assert_eq!(map.iter().count(), 1);
+    }
+
+    #[test]
+    fn removal_cleans_both_directions() {
+        let mut map = DoubleMap::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        assert_eq!(map.remove_forward("a"), Some(1));
+        assert!(!map.contains_backward(&1));
+        assert_eq!(map.remove_backward(&2).as_deref(), Some("b"));
+        assert!(!map.contains_forward("b"));
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn entry_keeps_both_directions_in_sync() {
+        let mut map = DoubleMap::new();
+        assert_eq!(map.entry("a".to_string()).or_insert(1), &1);
+        assert_eq!(map.entry("a".to_string()).or_insert(2), &1);
+
+        let Entry::Occupied(mut entry) = map.entry("a".to_string()) else {
+            panic!("pair was inserted");
+        };
+        assert_eq!(entry.insert(3), 1);
+        assert_eq!(map.get_backward(&3).map(String::as_str), Some("a"));
+        assert!(!map.contains_backward(&1));
+
+        map.insert("b".to_string(), 4);
+        map.retain(|_, &second| second != 3);
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(&"b".to_string(), &4)]);
+        assert!(!map.contains_backward(&3));
+        let Entry::Occupied(entry) = map.entry("b".to_string()) else {
+            panic!("pair was inserted");
+        };
+        assert_eq!(entry.remove(), 4);
+        assert!(map.is_empty() && map.is_mirrored());
+    }
+
+    #[test]
+    fn conversion_reports_colliding_keys() {
+        let map = HashMap::from([("a", 1), ("b", 1)]);
+        let error = DoubleMap::try_from(map).err().unwrap();
+        assert_eq!(error.value, 1);
+        let mut keys = [error.first, error.second];
+        keys.sort_unstable();
+        assert_eq!(keys, ["a", "b"]);
+    }
+}
@@ -0,0 +1,466 @@
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::{
+    access::AccessError,
+    changes::ChangesDiscarded,
+    deadline::DeadlineExceeded,
+    jobs::JobError,
+    key_aliases::KeyAliasError,
+    ndjson::NdjsonError,
+    plugins::WriteDenied,
+    query::QueryError,
+    quotas::QuotaExceeded,
+    reload::ReloadError,
+    schedules::ScheduleError,
+    soft_delete::RestoreError,
+    storage::{SetFlagError, StorageCorruption, TermError, TermTableFull},
+    term_locks::TermLocked,
+    time_travel::TimeTravelError,
+    transaction::TransactionError,
+    triggers::TriggerError,
+    versions::{VersionError, VersionMismatch},
+    views::ViewError,
+};
+
+/// Error returned by every endpoint, rendered as `{error, code, detail}`
+///
+/// `error` is a human readable message, `code` a stable identifier clients can match on
+/// and `detail` optional structured data about the failure.
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    detail: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    error: &'a str,
+    code: &'a str,
+    detail: &'a Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Serialize) -> Self {
+        self.detail = serde_json::to_value(detail).ok();
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    fn envelope(&self) -> Envelope<'_> {
+        Envelope {
+            error: &self.message,
+            code: self.code,
+            detail: &self.detail,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, axum::Json(self.envelope())).into_response()
+    }
+}
+
+/// Same envelope as in responses, for errors reported among results of bulk requests
+impl Serialize for ApiError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.envelope().serialize(serializer)
+    }
+}
+
+impl From<TermError> for ApiError {
+    fn from(error: TermError) -> Self {
+        let (status, code) = match &error {
+            TermError::UnknownTerm(_) => (StatusCode::NOT_FOUND, "unknown_term"),
+            TermError::AlreadyExists(_) => (StatusCode::CONFLICT, "term_exists"),
+            TermError::MergeIntoItself(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "merge_into_itself")
+            }
+            TermError::IdTaken(_) => (StatusCode::CONFLICT, "term_id_taken"),
+            TermError::InvalidId(_) => (StatusCode::BAD_REQUEST, "invalid_term_id"),
+            TermError::Corruption(_) => (StatusCode::INTERNAL_SERVER_ERROR, "storage_corruption"),
+        };
+        Self::new(status, code, error.to_string())
+    }
+}
+
+impl From<AccessError> for ApiError {
+    fn from(error: AccessError) -> Self {
+        match &error {
+            AccessError::UnknownApiKey => Self::new(
+                StatusCode::UNAUTHORIZED,
+                "unknown_api_key",
+                error.to_string(),
+            ),
+            AccessError::Denied { term, .. } => {
+                let term = term.clone();
+                Self::new(StatusCode::FORBIDDEN, "access_denied", error.to_string())
+                    .with_detail(serde_json::json!({ "term": term }))
+            }
+            AccessError::OutsideNamespace { namespace, .. } => {
+                let namespace = namespace.clone();
+                Self::new(
+                    StatusCode::FORBIDDEN,
+                    "outside_namespace",
+                    error.to_string(),
+                )
+                .with_detail(serde_json::json!({ "namespace": namespace }))
+            }
+            AccessError::NotForTenants(_) => {
+                Self::new(StatusCode::FORBIDDEN, "tenant_forbidden", error.to_string())
+            }
+            AccessError::AdminRequired => {
+                Self::new(StatusCode::FORBIDDEN, "admin_required", error.to_string())
+            }
+            AccessError::Corruption(_) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "storage_corruption",
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+impl From<TimeTravelError> for ApiError {
+    fn from(error: TimeTravelError) -> Self {
+        match &error {
+            TimeTravelError::TooManyEntries => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "replay_too_long",
+                error.to_string(),
+            ),
+            TimeTravelError::Audit(_) | TimeTravelError::Snapshot { .. } => {
+                Self::internal(error.to_string())
+            }
+        }
+    }
+}
+
+impl From<TermLocked> for ApiError {
+    fn from(error: TermLocked) -> Self {
+        Self::new(StatusCode::LOCKED, "term_locked", error.to_string())
+    }
+}
+
+impl From<KeyAliasError> for ApiError {
+    fn from(error: KeyAliasError) -> Self {
+        match &error {
+            KeyAliasError::UnknownKey(_) => {
+                Self::new(StatusCode::NOT_FOUND, "key_not_found", error.to_string())
+            }
+            KeyAliasError::AlreadyExists(_, _) => {
+                Self::new(StatusCode::CONFLICT, "key_alias_exists", error.to_string())
+            }
+        }
+    }
+}
+
+impl From<RestoreError> for ApiError {
+    fn from(error: RestoreError) -> Self {
+        match error {
+            RestoreError::NotDeleted(_) => {
+                Self::new(StatusCode::NOT_FOUND, "not_deleted", error.to_string())
+            }
+            RestoreError::KeyExists(_) => {
+                Self::new(StatusCode::CONFLICT, "key_exists", error.to_string())
+            }
+            RestoreError::TermTableFull(error) => error.into(),
+            RestoreError::QuotaExceeded(error) => error.into(),
+            RestoreError::Corruption(error) => error.into(),
+        }
+    }
+}
+
+impl From<VersionMismatch> for ApiError {
+    fn from(error: VersionMismatch) -> Self {
+        Self::new(StatusCode::CONFLICT, "version_mismatch", error.to_string())
+            .with_detail(serde_json::json!({ "version": error.current, "terms": error.terms }))
+    }
+}
+
+impl From<VersionError> for ApiError {
+    fn from(error: VersionError) -> Self {
+        match error {
+            VersionError::Mismatch(error) => error.into(),
+            VersionError::Corruption(error) => error.into(),
+        }
+    }
+}
+
+impl From<JobError> for ApiError {
+    fn from(error: JobError) -> Self {
+        let (status, code) = match &error {
+            JobError::NotFound(_) => (StatusCode::NOT_FOUND, "job_not_found"),
+            JobError::NotCancellable(_) => (StatusCode::CONFLICT, "job_not_cancellable"),
+            JobError::NotRunning(_) => (StatusCode::CONFLICT, "job_not_running"),
+        };
+        Self::new(status, code, error.to_string())
+    }
+}
+
+impl From<ScheduleError> for ApiError {
+    fn from(error: ScheduleError) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_schedule",
+            error.to_string(),
+        )
+    }
+}
+
+impl From<DeadlineExceeded> for ApiError {
+    fn from(error: DeadlineExceeded) -> Self {
+        Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "deadline_exceeded",
+            error.to_string(),
+        )
+    }
+}
+
+impl From<TriggerError> for ApiError {
+    fn from(error: TriggerError) -> Self {
+        match error {
+            TriggerError::Term(error) => error.into(),
+            _ => Self::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_trigger",
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+impl From<ViewError> for ApiError {
+    fn from(error: ViewError) -> Self {
+        let (status, code) = match &error {
+            ViewError::AlreadyExists(_) => (StatusCode::CONFLICT, "view_exists"),
+            ViewError::UnknownView(_) => (StatusCode::NOT_FOUND, "view_not_found"),
+            ViewError::InvalidQuery(_) => (StatusCode::BAD_REQUEST, "invalid_query"),
+            ViewError::Io(_) => return Self::internal(error.to_string()),
+        };
+        Self::new(status, code, error.to_string())
+    }
+}
+
+impl From<NdjsonError> for ApiError {
+    fn from(error: NdjsonError) -> Self {
+        match &error {
+            NdjsonError::Body(_) => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_body", error.to_string())
+            }
+            NdjsonError::Line { line, .. } | NdjsonError::LineTooLong { line } => Self::new(
+                StatusCode::BAD_REQUEST,
+                "malformed_ndjson",
+                error.to_string(),
+            )
+            .with_detail(serde_json::json!({ "line": line })),
+        }
+    }
+}
+
+impl From<ReloadError> for ApiError {
+    fn from(error: ReloadError) -> Self {
+        match &error {
+            ReloadError::InvalidLogLevel(_, _) => Self::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_log_level",
+                error.to_string(),
+            ),
+            _ => Self::internal(error.to_string()),
+        }
+    }
+}
+
+impl From<TermTableFull> for ApiError {
+    fn from(error: TermTableFull) -> Self {
+        Self::new(StatusCode::CONFLICT, "term_table_full", error.to_string())
+    }
+}
+
+impl From<QuotaExceeded> for ApiError {
+    fn from(error: QuotaExceeded) -> Self {
+        Self::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "quota_exceeded",
+            error.to_string(),
+        )
+        .with_detail(serde_json::json!({
+            "quota": error.quota,
+            "namespace": error.namespace,
+            "limit": error.limit,
+        }))
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(error: QueryError) -> Self {
+        match error {
+            QueryError::Invalid(message) => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_query", message)
+            }
+            QueryError::Corruption(error) => error.into(),
+        }
+    }
+}
+
+impl From<StorageCorruption> for ApiError {
+    fn from(error: StorageCorruption) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "storage_corruption",
+            error.to_string(),
+        )
+    }
+}
+
+impl From<SetFlagError> for ApiError {
+    fn from(error: SetFlagError) -> Self {
+        match error {
+            SetFlagError::TermTableFull(error) => error.into(),
+            SetFlagError::QuotaExceeded(error) => error.into(),
+            SetFlagError::UnknownTermId(_) => {
+                Self::new(StatusCode::NOT_FOUND, "unknown_term_id", error.to_string())
+            }
+            SetFlagError::Denied(error) => error.into(),
+            SetFlagError::Corruption(error) => error.into(),
+        }
+    }
+}
+
+impl From<WriteDenied> for ApiError {
+    fn from(error: WriteDenied) -> Self {
+        Self::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "write_denied",
+            error.to_string(),
+        )
+    }
+}
+
+impl From<TransactionError> for ApiError {
+    fn from(error: TransactionError) -> Self {
+        match error {
+            TransactionError::Corruption(error) => error.into(),
+            TransactionError::QuotaExceeded(error) => error.into(),
+            TransactionError::Denied(error) => error.into(),
+            TransactionError::TermTableFull(error) => error.into(),
+            TransactionError::UnknownTermId(_) => {
+                Self::new(StatusCode::NOT_FOUND, "unknown_term_id", error.to_string())
+            }
+            TransactionError::TermCapacityExceeded {
+                required,
+                available,
+            } => Self::new(
+                StatusCode::CONFLICT,
+                "term_capacity_exceeded",
+                error.to_string(),
+            )
+            .with_detail(serde_json::json!({ "required": required, "available": available })),
+        }
+    }
+}
+
+impl From<ChangesDiscarded> for ApiError {
+    fn from(error: ChangesDiscarded) -> Self {
+        Self::new(StatusCode::GONE, "changes_discarded", error.to_string())
+            .with_detail(serde_json::json!({ "oldest": error.oldest }))
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match &rejection {
+            JsonRejection::JsonSyntaxError(_) => "malformed_json",
+            JsonRejection::MissingJsonContentType(_) => "unsupported_content_type",
+            _ if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Self::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "body_too_large",
+                    "request body exceeds configured size limit, send bulk data as \
+                     newline-delimited JSON to stream it",
+                );
+            }
+            _ => "invalid_body",
+        };
+        Self::new(rejection.status(), code, rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), "invalid_path", rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(
+            rejection.status(),
+            "invalid_query_string",
+            rejection.body_text(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode, response::IntoResponse};
+
+    use super::ApiError;
+    use crate::storage::TermError;
+
+    #[tokio::test]
+    async fn errors_render_as_envelope() {
+        let response = ApiError::from(TermError::UnknownTerm("x".to_string())).into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "unknown term x", "code": "unknown_term", "detail": null})
+        );
+    }
+}
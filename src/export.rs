@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::BufReader,
+    path::Path,
+    sync::Arc,
+};
+
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    errors::ParquetError,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    key_aliases::KeyAliasError,
+    storage::{Database, Key, SetFlagError, StorageCorruption, TermError, TermId, TermTableFull},
+    triggers::{TriggerError, TriggerInfo},
+};
+
+/// Key is unsigned, stored in signed physical type as the format prescribes
+const PARQUET_SCHEMA: &str = "
+    message key_terms {
+        REQUIRED INT64 key (INTEGER(64, false));
+        REQUIRED BYTE_ARRAY term (UTF8);
+    }
+";
+
+/// Pairs buffered before they are written out as a row group
+const PARQUET_ROW_GROUP_PAIRS: usize = 1 << 20;
+
+/// Portable JSON representation of database contents, independent of snapshot format
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonExport {
+    /// Every term, including ones no key carries
+    #[serde(default)]
+    pub terms: Vec<String>,
+    /// Ids of terms, terms left out are given new ids on import
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub term_ids: BTreeMap<String, TermId>,
+    /// Highest term id ever assigned, so that import does not hand out ids of removed terms
+    #[serde(default)]
+    pub last_term_id: TermId,
+    /// Term aliases, mapping alias to term
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Key aliases, mapping alias to key
+    #[serde(default)]
+    pub key_aliases: BTreeMap<String, Key>,
+    /// Term groups, mapping group name to its terms
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub term_groups: BTreeMap<String, Vec<String>>,
+    /// Names of `term_groups` that are exclusive
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub exclusive_term_groups: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub triggers: BTreeMap<String, TriggerInfo>,
+    /// Terms of every key
+    #[serde(default)]
+    pub items: BTreeMap<Key, Vec<String>>,
+}
+
+/// What was written by [`Database::export_sqlite`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SqliteExportReport {
+    pub terms: usize,
+    pub pairs: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("failed to create export: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Parquet(#[from] ParquetError),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("failed to read export: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to decode export: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error(transparent)]
+    TermTableFull(#[from] TermTableFull),
+    #[error(transparent)]
+    SetFlag(#[from] SetFlagError),
+    #[error(transparent)]
+    Alias(#[from] TermError),
+    #[error("cannot import term: {0}")]
+    Term(TermError),
+    #[error(transparent)]
+    KeyAlias(#[from] KeyAliasError),
+    #[error("cannot import trigger: {0}")]
+    Trigger(#[from] TriggerError),
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    pub fn export_json(&self) -> Result<JsonExport, StorageCorruption> {
+        let mut terms = self
+            .terms
+            .iter()
+            .map(|(term, &id)| (id, term.clone()))
+            .collect::<Vec<_>>();
+        terms.sort_unstable();
+
+        Ok(JsonExport {
+            term_ids: terms.iter().map(|(id, term)| (term.clone(), *id)).collect(),
+            terms: terms.into_iter().map(|(_, term)| term).collect(),
+            last_term_id: self.last_term_id,
+            aliases: self
+                .list_aliases()
+                .map(|(alias, term)| (alias.to_string(), term.to_string()))
+                .collect(),
+            key_aliases: self
+                .list_key_aliases()
+                .map(|(alias, key)| (alias.to_string(), key))
+                .collect(),
+            term_groups: self
+                .list_term_groups()
+                .map(|(name, terms)| {
+                    let prefixes = self.term_group_prefixes(name).unwrap_or_default();
+                    (
+                        name.to_string(),
+                        terms
+                            .into_iter()
+                            .map(String::from)
+                            .chain(prefixes.into_iter().map(|prefix| format!("{prefix}*")))
+                            .collect(),
+                    )
+                })
+                .collect(),
+            exclusive_term_groups: self.exclusive_term_groups.clone(),
+            triggers: self
+                .list_triggers()
+                .map(|(name, trigger)| (name.to_string(), trigger))
+                .collect(),
+            items: self
+                .list_keys()
+                .map(|key| {
+                    let mut terms = self
+                        .horizontal_query(&key)?
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+                    terms.sort_unstable();
+                    Ok((key, terms))
+                })
+                .collect::<Result<_, StorageCorruption>>()?,
+        })
+    }
+
+    /// Write contents into SQLite database at `path` as tables `terms(id, name)` and
+    /// `key_terms(key, term_id)`, replacing tables of the same names. Aliases are not exported
+    pub fn export_sqlite(&self, path: impl AsRef<Path>) -> Result<SqliteExportReport, ExportError> {
+        let mut connection = rusqlite::Connection::open(path)?;
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(
+            "DROP TABLE IF EXISTS key_terms;
+             DROP TABLE IF EXISTS terms;
+             CREATE TABLE terms (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);
+             CREATE TABLE key_terms (
+                 key INTEGER NOT NULL,
+                 term_id INTEGER NOT NULL REFERENCES terms (id),
+                 PRIMARY KEY (key, term_id)
+             ) WITHOUT ROWID;",
+        )?;
+
+        let mut report = SqliteExportReport::default();
+        {
+            let mut insert_term =
+                transaction.prepare("INSERT INTO terms (id, name) VALUES (?1, ?2)")?;
+            for (term, id) in self.terms.iter() {
+                insert_term.execute((id, term))?;
+                report.terms += 1;
+            }
+            let mut insert_pair =
+                transaction.prepare("INSERT INTO key_terms (key, term_id) VALUES (?1, ?2)")?;
+            for record in self.records() {
+                let (key, record) = record?;
+                for term_id in record.term_ids() {
+                    insert_pair.execute((key.get(), term_id))?;
+                    report.pairs += 1;
+                }
+            }
+        }
+        transaction.execute_batch("CREATE INDEX key_terms_by_term ON key_terms (term_id, key);")?;
+        transaction.commit()?;
+        Ok(report)
+    }
+
+    /// Write `key, term` pair for every flag of `keys`, or of all keys, into Parquet file at
+    /// `path`. Returns number of pairs written
+    pub fn export_parquet(
+        &self,
+        keys: Option<&[Key]>,
+        path: impl AsRef<Path>,
+    ) -> Result<usize, ExportError> {
+        let mut names = vec![ByteArray::new(); self.last_term_id as usize + 1];
+        for (term, &id) in self.terms.iter() {
+            names[id as usize] = ByteArray::from(term.as_str());
+        }
+        let records: Box<dyn Iterator<Item = _>> = match keys {
+            Some(keys) => Box::new(keys.iter().filter_map(|&key| {
+                self.record(&key)
+                    .transpose()
+                    .map(|record| Ok((key, record?)))
+            })),
+            None => Box::new(self.records()),
+        };
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer = SerializedFileWriter::new(
+            std::fs::File::create(path)?,
+            Arc::new(parse_message_type(PARQUET_SCHEMA)?),
+            Arc::new(properties),
+        )?;
+        let (mut key_column, mut term_column) = (vec![], vec![]);
+        let mut written = 0;
+        let mut records = records.peekable();
+        while records.peek().is_some() {
+            for record in records.by_ref() {
+                let (key, record) = record?;
+                for term_id in record.term_ids() {
+                    key_column.push(key.get() as i64);
+                    term_column.push(names[term_id as usize].clone());
+                }
+                if key_column.len() >= PARQUET_ROW_GROUP_PAIRS {
+                    break;
+                }
+            }
+
+            let mut row_group = writer.next_row_group()?;
+            if let Some(mut column) = row_group.next_column()? {
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&key_column, None, None)?;
+                column.close()?;
+            }
+            if let Some(mut column) = row_group.next_column()? {
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&term_column, None, None)?;
+                column.close()?;
+            }
+            row_group.close()?;
+            written += key_column.len();
+            key_column.clear();
+            term_column.clear();
+        }
+        writer.close()?;
+        Ok(written)
+    }
+
+    /// Build database holding contents of `export`
+    pub fn from_json_export(export: &JsonExport) -> Result<Self, ImportError> {
+        let mut db = Self {
+            last_term_id: export.last_term_id,
+            ..Self::default()
+        };
+        // terms with ids go first, so that terms numbered on import cannot take their ids
+        for (term, &id) in &export.term_ids {
+            db.add_term_with_id(term, id).map_err(ImportError::Term)?;
+        }
+        for term in &export.terms {
+            db.add_term(term)?;
+        }
+        for (alias, term) in &export.aliases {
+            db.add_alias(term, alias)?;
+        }
+        for (name, terms) in &export.term_groups {
+            db.set_term_group(name, terms, export.exclusive_term_groups.contains(name))?;
+        }
+        for (&key, terms) in &export.items {
+            db.create_record(key).map_err(SetFlagError::from)?;
+            for term in terms {
+                db.set_flag(key, term)?;
+            }
+        }
+        for (alias, &key) in &export.key_aliases {
+            db.set_key_alias(key, alias)?;
+        }
+        // flags are imported as they were, without triggers setting any more of them
+        for (name, trigger) in &export.triggers {
+            db.set_trigger(
+                name,
+                &trigger.when,
+                &trigger.set,
+                trigger.webhook.as_deref(),
+            )?;
+        }
+        Ok(db)
+    }
+}
+
+/// Read database from JSON export file
+pub fn load_json<const SMALLSIZE: usize>(
+    path: impl AsRef<Path>,
+) -> Result<Database<SMALLSIZE>, ImportError> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+    let export: JsonExport = serde_json::from_reader(file)?;
+    Database::from_json_export(&export)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use crate::storage::{Database, Key};
+
+    use super::JsonExport;
+
+    #[test]
+    fn json_export_roundtrip() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in ["x", "y"] {
+            db.set_flag(a, term).unwrap();
+        }
+        db.create_record(b).unwrap();
+        db.add_term("unused").unwrap();
+        db.add_alias("x", "ex").unwrap();
+        db.set_key_alias(b, "bee").unwrap();
+
+        let json = serde_json::to_string(&db.export_json().unwrap()).unwrap();
+        let export: JsonExport = serde_json::from_str(&json).unwrap();
+        let restored = Database::<8>::from_json_export(&export).unwrap();
+
+        assert_eq!(restored.export_json(), db.export_json());
+        assert_eq!(restored.get_term_id("unused"), db.get_term_id("unused"));
+        assert_eq!(restored.resolve_key_alias("bee"), Some(b));
+    }
+    #[test]
+    fn sqlite_export_can_be_queried() {
+        let mut db = Database::<8>::default();
+        for (key, term) in [(1, "x"), (1, "y"), (2, "y")] {
+            db.set_flag(Key::try_from(key).unwrap(), term).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("elizadb-sqlite-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let report = db.export_sqlite(&path).unwrap();
+        // exporting again replaces tables instead of failing on existing ones
+        assert_eq!(db.export_sqlite(&path).unwrap(), report);
+        assert_eq!((report.terms, report.pairs), (2, 3));
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        let keys: Vec<u64> = connection
+            .prepare(
+                "SELECT key FROM key_terms JOIN terms ON terms.id = term_id \
+                 WHERE name = 'y' ORDER BY key",
+            )
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(keys, [1, 2]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parquet_export_holds_pairs_of_selected_keys() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for (key, term) in [(a, "x"), (a, "y"), (b, "y")] {
+            db.set_flag(key, term).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("elizadb-parquet-{}", std::process::id()));
+
+        assert_eq!(db.export_parquet(None, &path).unwrap(), 3);
+        assert_eq!(db.export_parquet(Some(&[b]), &path).unwrap(), 1);
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [r#"{key: 2, term: "y"}"#]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
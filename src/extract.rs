@@ -0,0 +1,133 @@
+//! Drop-in replacements for axum extractors that report rejections through [`ApiError`]
+
+use std::ops::{Deref, DerefMut};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequest, FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{api::DBState, error::ApiError, storage::Key, tenants::Tenant};
+
+/// JSON body extractor and response, see [`axum::Json`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(request, state).await?;
+        Ok(Self(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Json<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Path parameters extractor, see [`axum::extract::Path`]
+#[derive(Clone, Copy, Debug)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::<T>::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Query string extractor, see [`axum::extract::Query`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::<T>::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[derive(Deserialize)]
+struct ItemPath {
+    #[serde(default, with = "crate::keys::flexible_option")]
+    key: Option<Key>,
+    name: Option<String>,
+}
+
+/// Key addressed by request path, given either as numeric `:key` or as key alias `:name`.
+/// Tenants are rejected for keys outside of their namespace
+#[derive(Clone, Copy, Debug)]
+pub struct ItemKey(pub Key);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ItemKey
+where
+    DBState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(path) = Path::<ItemPath>::from_request_parts(parts, state).await?;
+        let key = match (path.key, path.name) {
+            (Some(key), _) => key,
+            (None, Some(name)) => DBState::from_ref(state)
+                .read()
+                .await
+                .resolve_key_alias(&name)
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        "unknown_key_alias",
+                        format!("key alias {name} does not exist"),
+                    )
+                })?,
+            (None, None) => return Err(ApiError::internal("route has no key parameter")),
+        };
+        if let Some(tenant) = parts.extensions.get::<Tenant>() {
+            tenant.check_key(key)?;
+        }
+        Ok(Self(key))
+    }
+}
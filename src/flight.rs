@@ -0,0 +1,317 @@
+//! Arrow Flight server streaming flags as record batches of `key, term` pairs
+//!
+//! `DoGet` with an empty ticket streams every flag, a ticket holding JSON query like the body of
+//! `POST /query` streams flags of matching keys only. Batches are built under a read lock held
+//! until the stream ends, so they form a consistent view of the database. Callers present API
+//! keys in `authorization` metadata like HTTP callers do, records they may not read are left out.
+
+// error types are dictated by tonic and arrow-flight
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
+
+use arrow_array::{
+    builder::{ArrayBuilder, StringBuilder, UInt64Builder},
+    ArrayRef, RecordBatch,
+};
+use arrow_flight::{
+    encode::FlightDataEncoderBuilder,
+    error::FlightError,
+    flight_service_server::{FlightService, FlightServiceServer},
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaResult, Ticket,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{
+    access::{self, Access, AccessPolicy},
+    api::DBState,
+    query::{FilteredQuery, QueryError},
+    term_locks::TermLocks,
+};
+
+/// Pairs collected into a single record batch
+const BATCH_PAIRS: usize = 64 * 1024;
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("key", DataType::UInt64, false),
+        Field::new("term", DataType::Utf8, false),
+    ]))
+}
+
+/// Serve Flight requests on `address` until the server fails
+pub async fn serve(reads: FlightReads, address: SocketAddr) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(FlightServiceServer::new(reads))
+        .serve(address)
+        .await
+}
+
+pub struct FlightReads {
+    db: DBState,
+    policy: Arc<AccessPolicy>,
+    locks: Arc<TermLocks>,
+}
+
+impl FlightReads {
+    pub fn new(db: DBState, policy: Arc<AccessPolicy>, locks: Arc<TermLocks>) -> Self {
+        Self { db, policy, locks }
+    }
+
+    fn access<T>(&self, request: &Request<T>) -> Result<Access, Status> {
+        let api_key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        Access::for_api_key(self.policy.clone(), self.locks.clone(), api_key)
+            .map_err(|e| Status::unauthenticated(e.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for FlightReads {
+    type HandshakeStream = BoxStream<HandshakeResponse>;
+    type ListFlightsStream = BoxStream<FlightInfo>;
+    type DoGetStream = BoxStream<FlightData>;
+    type DoPutStream = BoxStream<PutResult>;
+    type DoExchangeStream = BoxStream<FlightData>;
+    type DoActionStream = BoxStream<arrow_flight::Result>;
+    type ListActionsStream = BoxStream<ActionType>;
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let access = self.access(&request)?;
+        let ticket = request.into_inner().ticket;
+        let query = if ticket.is_empty() {
+            None
+        } else {
+            Some(
+                serde_json::from_slice::<FilteredQuery>(&ticket)
+                    .map_err(|e| Status::invalid_argument(format!("malformed query: {e}")))?,
+            )
+        };
+        let db = self.db.clone().read_owned().await;
+        let keys = query
+            .map(|mut query| {
+                access
+                    .check_query(&db, &query.query)
+                    .map_err(|e| Status::permission_denied(e.to_string()))?;
+                query.range = access.confine(query.range);
+                db.filtered_vertical_query(&query).map_err(|e| match e {
+                    QueryError::Invalid(message) => Status::invalid_argument(message),
+                    QueryError::Corruption(e) => Status::internal(e.to_string()),
+                })
+            })
+            .transpose()?;
+
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut names = vec![""; db.last_term_id as usize + 1];
+            for (term, &id) in db.terms.iter() {
+                names[id as usize] = term.as_str();
+            }
+            let records: Box<dyn Iterator<Item = _>> = match &keys {
+                Some(keys) => Box::new(keys.iter().filter_map(|&key| {
+                    db.record(&key).transpose().map(|record| Ok((key, record?)))
+                })),
+                None => Box::new(db.records()),
+            };
+            let records = records.filter(|record| match record {
+                Ok((key, _)) => access.check_record(&db, key, access::Action::Read).is_ok(),
+                Err(_) => true,
+            });
+
+            let (mut key_column, mut term_column) = (UInt64Builder::new(), StringBuilder::new());
+            for record in records {
+                let (key, record) = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(FlightError::ExternalError(Box::new(e))));
+                        return;
+                    }
+                };
+                for term_id in record.term_ids() {
+                    key_column.append_value(key.get());
+                    term_column.append_value(names[term_id as usize]);
+                }
+                if key_column.len() >= BATCH_PAIRS {
+                    let batch = finish_batch(&mut key_column, &mut term_column);
+                    if sender.blocking_send(batch).is_err() {
+                        return;
+                    }
+                }
+            }
+            if !key_column.is_empty() {
+                let _ = sender.blocking_send(finish_batch(&mut key_column, &mut term_column));
+            }
+        });
+
+        let encoded = FlightDataEncoderBuilder::new()
+            .with_schema(schema())
+            .build(ReceiverStream::new(receiver))
+            .map(|data| data.map_err(Status::from));
+        Ok(Response::new(Box::pin(encoded)))
+    }
+
+    async fn get_schema(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        let message = arrow_flight::IpcMessage::try_from(arrow_flight::SchemaAsIpc::new(
+            &schema(),
+            &Default::default(),
+        ))
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(SchemaResult { schema: message.0 }))
+    }
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("handshake is not needed"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("use DoGet with a ticket"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        Err(Status::unimplemented("use DoGet with a ticket"))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("use DoGet with a ticket"))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("flight endpoint is read-only"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("flight endpoint is read-only"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("there are no actions"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+}
+
+fn finish_batch(
+    keys: &mut UInt64Builder,
+    terms: &mut StringBuilder,
+) -> Result<RecordBatch, FlightError> {
+    let columns: Vec<ArrayRef> = vec![Arc::new(keys.finish()), Arc::new(terms.finish())];
+    Ok(RecordBatch::try_new(schema(), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow_array::{cast::AsArray, types::UInt64Type};
+    use arrow_flight::{flight_service_server::FlightServiceServer, FlightClient, Ticket};
+    use tokio::sync::RwLock;
+    use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
+
+    use crate::{
+        access::AccessPolicy,
+        storage::{Database, Key},
+        term_locks::TermLocks,
+    };
+
+    use super::FlightReads;
+
+    /// Number of pairs streamed for ticket
+    async fn pairs(client: &mut FlightClient, ticket: &'static str) -> usize {
+        let batches = client
+            .do_get(Ticket::new(ticket))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        batches
+            .into_iter()
+            .map(|batch| batch.unwrap().column(0).as_primitive::<UInt64Type>().len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn do_get_streams_pairs_of_matching_keys() {
+        let mut db = Database::<8>::default();
+        for (key, term) in [(1, "x"), (1, "y"), (2, "y"), (3, "pii:email")] {
+            db.set_flag(Key::try_from(key).unwrap(), term).unwrap();
+        }
+        let policy: AccessPolicy =
+            serde_json::from_str(r#"{"rules": [{"prefix": "pii:", "read": ["elevated"]}]}"#)
+                .unwrap();
+        let locks = std::env::temp_dir().join(format!("elizadb-flight-{}", std::process::id()));
+        let reads = FlightReads::new(
+            Arc::new(RwLock::new(db)),
+            Arc::new(policy),
+            Arc::new(TermLocks::open(locks).unwrap()),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tonic::transport::Server::builder()
+            .add_service(FlightServiceServer::new(reads))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+
+        let channel = tonic::transport::Channel::from_shared(format!("http://{address}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = FlightClient::new(channel);
+
+        assert_eq!(pairs(&mut client, "").await, 3);
+        assert_eq!(
+            pairs(&mut client, r#"{"type": "Simple", "term": "x"}"#).await,
+            2
+        );
+        let unknown = client
+            .do_get(Ticket::new(r#"{"type": "Simple", "term": "z"}"#))
+            .await;
+        assert!(unknown.is_err());
+        let protected = client
+            .do_get(Ticket::new(r#"{"type": "Simple", "term": "pii:email"}"#))
+            .await;
+        assert!(protected.is_err());
+    }
+}
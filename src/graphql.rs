@@ -0,0 +1,221 @@
+//! Read-only GraphQL schema over items, terms and vertical queries, served at `POST /graphql`
+//!
+//! Lets clients fetch terms of a key along with how many keys carry each of them in a single
+//! round trip. Keys are `ID`s, accepted and written like keys of the REST API. Resolvers check
+//! access policy like the REST handlers they stand in for.
+
+use std::sync::LazyLock;
+
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, ID};
+use axum::extract::State;
+
+use crate::{
+    access::{Access, Action},
+    api::DBState,
+    deadline::Deadline,
+    extract::Json,
+    keys::ApiKey,
+    query::{FilteredQuery, KeyRange, Query},
+};
+
+type ReadSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<ReadSchema> =
+    LazyLock::new(|| Schema::new(QueryRoot, EmptyMutation, EmptySubscription));
+
+pub async fn execute(
+    State(db): State<DBState>,
+    access: Access,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(SCHEMA.execute(request.data(db).data(access)).await)
+}
+
+fn parse_key(key: &ID) -> async_graphql::Result<ApiKey> {
+    key.parse()
+        .map_err(|e| async_graphql::Error::new(format!("invalid key {}: {e}", key.as_str())))
+}
+
+/// Every term must match unless `bound` says how many are enough
+fn vertical_query(terms: Vec<String>, bound: Option<usize>) -> Query {
+    match (terms.as_slice(), bound) {
+        ([term], None | Some(1)) => Query::Simple { term: term.clone() },
+        _ => Query::KofN {
+            bound: bound.unwrap_or(terms.len()),
+            terms,
+        },
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Key with its terms, null if there is no such key
+    async fn item(&self, ctx: &Context<'_>, key: ID) -> async_graphql::Result<Option<Item>> {
+        let ApiKey(key) = parse_key(&key)?;
+        let db = ctx.data::<DBState>()?.read().await;
+        ctx.data::<Access>()?
+            .check_record(&db, &key, Action::Read)?;
+        Ok(db.horizontal_query(&key)?.map(|terms| {
+            let mut terms = terms.into_iter().map(String::from).collect::<Vec<_>>();
+            terms.sort_unstable();
+            Item {
+                key: ApiKey(key),
+                terms: terms.into_iter().map(|name| Term { name }).collect(),
+            }
+        }))
+    }
+
+    /// Every term, ordered by name
+    async fn terms(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Term>> {
+        let db = ctx.data::<DBState>()?.read().await;
+        let mut names = db.terms.left_keys().cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+        Ok(names.into_iter().map(|name| Term { name }).collect())
+    }
+
+    /// Keys carrying at least `bound` of `terms`, all of them by default. `key_min` and `key_max`
+    /// filter matched keys without narrowing the scan
+    async fn keys(
+        &self,
+        ctx: &Context<'_>,
+        terms: Vec<String>,
+        bound: Option<usize>,
+        key_min: Option<ID>,
+        key_max: Option<ID>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<ID>> {
+        let access = ctx.data::<Access>()?;
+        let query = FilteredQuery {
+            query: vertical_query(terms, bound),
+            range: access.confine(KeyRange {
+                key_min: key_min.as_ref().map(parse_key).transpose()?.map(Into::into),
+                key_max: key_max.as_ref().map(parse_key).transpose()?.map(Into::into),
+            }),
+            candidate_keys: None,
+            sample: None,
+            deadline: Deadline::default(),
+        };
+        let db = ctx.data::<DBState>()?.read().await;
+        access.check_query(&db, &query.query)?;
+        let keys = db.filtered_vertical_query(&query)?;
+        Ok(keys
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|key| ID(ApiKey(key).to_string()))
+            .collect())
+    }
+
+    /// Number of keys carrying at least `bound` of `terms`, all of them by default
+    async fn count(
+        &self,
+        ctx: &Context<'_>,
+        terms: Vec<String>,
+        bound: Option<usize>,
+    ) -> async_graphql::Result<usize> {
+        let db = ctx.data::<DBState>()?.read().await;
+        let query = vertical_query(terms, bound);
+        ctx.data::<Access>()?.check_query(&db, &query)?;
+        Ok(db.vertical_query(&query)?.len())
+    }
+}
+
+pub struct Item {
+    key: ApiKey,
+    terms: Vec<Term>,
+}
+
+#[Object]
+impl Item {
+    async fn key(&self) -> ID {
+        ID(self.key.to_string())
+    }
+
+    /// Terms of the key, ordered by name
+    async fn terms(&self) -> &[Term] {
+        &self.terms
+    }
+}
+
+pub struct Term {
+    name: String,
+}
+
+#[Object]
+impl Term {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of keys carrying the term
+    async fn key_count(&self, ctx: &Context<'_>) -> async_graphql::Result<usize> {
+        let db = ctx.data::<DBState>()?.read().await;
+        ctx.data::<Access>()?
+            .check_terms(&db, Action::Read, [self.name.as_str()])?;
+        let query = Query::Simple {
+            term: self.name.clone(),
+        };
+        Ok(db.vertical_query(&query)?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::RwLock;
+
+    use crate::{
+        access::{Access, AccessPolicy},
+        storage::{Database, Key},
+        term_locks::TermLocks,
+    };
+
+    use super::SCHEMA;
+
+    #[tokio::test]
+    async fn item_terms_come_with_key_counts() {
+        let mut db = Database::<8>::default();
+        for (key, term) in [(1, "x"), (1, "y"), (2, "y"), (3, "pii:email")] {
+            db.set_flag(Key::try_from(key).unwrap(), term).unwrap();
+        }
+        let policy: AccessPolicy =
+            serde_json::from_str(r#"{"rules": [{"prefix": "pii:", "read": ["elevated"]}]}"#)
+                .unwrap();
+        let locks = std::env::temp_dir().join(format!("elizadb-graphql-{}", std::process::id()));
+        let access = Access::for_api_key(
+            Arc::new(policy),
+            Arc::new(TermLocks::open(locks).unwrap()),
+            None,
+        )
+        .unwrap();
+        let db = Arc::new(RwLock::new(db));
+
+        let request = async_graphql::Request::new(r#"{ item(key: "3") { key } }"#)
+            .data(db.clone())
+            .data(access.clone());
+        assert!(!SCHEMA.execute(request).await.errors.is_empty());
+
+        let request = async_graphql::Request::new(
+            r#"{
+                item(key: "1") { terms { name keyCount } }
+                keys(terms: ["y"], keyMin: "2")
+                count(terms: ["x", "y"], bound: 1)
+            }"#,
+        )
+        .data(db)
+        .data(access);
+
+        let response = SCHEMA.execute(request).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            serde_json::json!({
+                "item": {"terms": [{"name": "x", "keyCount": 1}, {"name": "y", "keyCount": 2}]},
+                "keys": ["2"],
+                "count": 2,
+            })
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Most recent changes of each record, answering `GET /items/:key/history`
+//!
+//! Entries are the same as those of audit log, kept in memory per key. Without in-memory history
+//! the endpoint falls back to scanning audit log if one is configured.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::{audit::AuditEntry, changes::Change, storage::Key};
+
+/// Bounded per-key history of flag and record changes, fed from change log
+pub struct FlagHistory {
+    entries: Mutex<HashMap<Key, VecDeque<AuditEntry>>>,
+    per_key: usize,
+}
+
+impl FlagHistory {
+    pub fn new(per_key: usize) -> Self {
+        Self {
+            entries: Mutex::default(),
+            per_key,
+        }
+    }
+
+    /// Remember `change` if it concerns a single record. Purged records lose their history,
+    /// renamed and merged terms keep their old names in entries recorded before
+    pub fn observe(&self, seq: u64, change: &Change) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Change::PurgeRecord { key } = change {
+            entries.remove(key);
+            return;
+        }
+        let Some(key) = change.key() else {
+            return;
+        };
+        if self.per_key == 0 {
+            return;
+        }
+        let history = entries.entry(key).or_default();
+        if history.len() >= self.per_key {
+            history.pop_front();
+        }
+        history.push_back(AuditEntry::now(seq, change.clone()));
+    }
+
+    /// Up to `limit` most recent entries of `key`, oldest first
+    pub fn get(&self, key: &Key, limit: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        let Some(history) = entries.get(key) else {
+            return vec![];
+        };
+        history
+            .iter()
+            .skip(history.len().saturating_sub(limit))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{changes::Change, storage::Key};
+
+    use super::FlagHistory;
+
+    #[test]
+    fn history_keeps_most_recent_changes_of_each_key() {
+        let history = FlagHistory::new(2);
+        let (key, other) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        let set = |key, term: &str| Change::SetFlag {
+            key,
+            term: term.to_string(),
+        };
+        history.observe(0, &Change::CreateRecord { key });
+        history.observe(1, &set(key, "x"));
+        history.observe(
+            2,
+            &Change::AddTerm {
+                term: "y".to_string(),
+            },
+        );
+        history.observe(3, &set(other, "y"));
+        history.observe(
+            4,
+            &Change::UnsetFlag {
+                key,
+                term: "x".to_string(),
+            },
+        );
+
+        let seqs = |key, limit| {
+            history
+                .get(&key, limit)
+                .iter()
+                .map(|entry| entry.seq)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(seqs(key, 10), [1, 4]);
+        assert_eq!(seqs(key, 1), [4]);
+        assert_eq!(seqs(other, 10), [3]);
+
+        history.observe(5, &Change::PurgeRecord { key });
+        assert!(history.get(&key, 10).is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{access::bearer_api_key, error::ApiError};
+
+pub static IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Largest response body that is remembered for replay
+const MAX_CACHED_BODY: usize = 16 * 1024 * 1024;
+
+/// Largest request body that may come with an idempotency key, as it is buffered to be hashed
+const MAX_HASHED_BODY: usize = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+enum Entry {
+    InFlight,
+    Done(StoredResponse),
+    /// Finished with a response too large or streamed to be kept
+    Unreplayable,
+}
+
+struct CachedEntry {
+    request: String,
+    created: Instant,
+    entry: Entry,
+}
+
+struct CacheState {
+    entries: HashMap<String, CachedEntry>,
+    order: VecDeque<String>,
+}
+
+/// Bounded store of responses to recently seen idempotency keys
+pub struct IdempotencyCache {
+    state: Mutex<CacheState>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+enum Lookup {
+    Miss,
+    Replay(StoredResponse),
+    InFlight,
+    Unreplayable,
+    Mismatch,
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Look key up, reserving it for this request on a miss
+    fn begin(&self, key: &str, request: &str) -> Lookup {
+        let mut state = self.state.lock().unwrap();
+        self.evict_expired(&mut state);
+
+        if let Some(cached) = state.entries.get(key) {
+            return if cached.request != request {
+                Lookup::Mismatch
+            } else {
+                match &cached.entry {
+                    Entry::InFlight => Lookup::InFlight,
+                    Entry::Done(response) => Lookup::Replay(response.clone()),
+                    Entry::Unreplayable => Lookup::Unreplayable,
+                }
+            };
+        }
+
+        while state.order.len() >= self.capacity {
+            let Some(oldest) = state.order.pop_front() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+        state.entries.insert(
+            key.to_string(),
+            CachedEntry {
+                request: request.to_string(),
+                created: Instant::now(),
+                entry: Entry::InFlight,
+            },
+        );
+        state.order.push_back(key.to_string());
+        Lookup::Miss
+    }
+
+    /// Record outcome of request holding `key`, forgetting key if it should be retried
+    fn finish(&self, key: &str, entry: Option<Entry>) {
+        let mut state = self.state.lock().unwrap();
+        match entry {
+            Some(entry) => {
+                if let Some(cached) = state.entries.get_mut(key) {
+                    cached.entry = entry;
+                }
+            }
+            None => {
+                state.entries.remove(key);
+                state.order.retain(|item| item != key);
+            }
+        }
+    }
+
+    /// Reserved key, released again unless completed, should the request panic or be dropped
+    fn reserve(self: &Arc<Self>, key: String) -> Reservation {
+        Reservation {
+            cache: self.clone(),
+            key: Some(key),
+        }
+    }
+
+    fn evict_expired(&self, state: &mut CacheState) {
+        while let Some(oldest) = state.order.front() {
+            let expired = state
+                .entries
+                .get(oldest)
+                .is_none_or(|cached| cached.created.elapsed() > self.ttl);
+            if !expired {
+                break;
+            }
+            let oldest = state.order.pop_front().unwrap();
+            state.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Key reserved by a request that is still in flight
+struct Reservation {
+    cache: Arc<IdempotencyCache>,
+    key: Option<String>,
+}
+
+impl Reservation {
+    fn complete(mut self, entry: Option<Entry>) {
+        if let Some(key) = self.key.take() {
+            self.cache.finish(&key, entry);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.cache.finish(&key, None);
+        }
+    }
+}
+
+/// Replays stored response for requests repeating an already seen `Idempotency-Key`
+pub async fn replay_idempotent(
+    State(cache): State<Arc<IdempotencyCache>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(&IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return next.run(request).await;
+    };
+    let key = scoped_key(bearer_api_key(request.headers()), key);
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_HASHED_BODY).await else {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "idempotent_body_too_large",
+            format!("requests with idempotency key may carry at most {MAX_HASHED_BODY} bytes"),
+        )
+        .into_response();
+    };
+    let fingerprint = fingerprint(&parts.method, &parts.uri, &body);
+    let request = Request::from_parts(parts, Body::from(body));
+
+    match cache.begin(&key, &fingerprint) {
+        Lookup::Replay(stored) => return rebuild(stored),
+        Lookup::InFlight => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "idempotency_in_progress",
+                "request with this idempotency key is still in progress",
+            )
+            .into_response()
+        }
+        Lookup::Unreplayable => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "idempotency_not_replayable",
+                "request with this idempotency key already completed, \
+                 its response was too large to be kept for replay",
+            )
+            .into_response()
+        }
+        Lookup::Mismatch => {
+            return ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+                "idempotency key was already used for a different request",
+            )
+            .into_response()
+        }
+        Lookup::Miss => {}
+    }
+
+    let reservation = cache.reserve(key);
+    let response = next.run(request).await;
+    // server errors are not remembered so that retrying them actually retries
+    if response.status().is_server_error() {
+        reservation.complete(None);
+        return response;
+    }
+
+    // streamed responses, newline-delimited ones in particular, do not know their size upfront
+    let fits = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_CACHED_BODY as u64);
+    if !fits {
+        reservation.complete(Some(Entry::Unreplayable));
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    match to_bytes(body, MAX_CACHED_BODY).await {
+        Ok(body) => {
+            let stored = StoredResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            };
+            reservation.complete(Some(Entry::Done(stored.clone())));
+            rebuild(stored)
+        }
+        Err(e) => {
+            reservation.complete(None);
+            ApiError::internal(format!("failed to read response: {e}")).into_response()
+        }
+    }
+}
+
+/// Idempotency keys of different API keys, tenants among them, never collide
+fn scoped_key(api_key: Option<&str>, key: &str) -> String {
+    // header values cannot hold newlines, so neither part can fake the separator
+    format!("{}\n{key}", api_key.unwrap_or_default())
+}
+
+/// Requests reusing a key have to match in method, path, query and body
+fn fingerprint(method: &Method, uri: &Uri, body: &[u8]) -> String {
+    format!(
+        "{method} {uri} {} {:08x}",
+        body.len(),
+        crc32fast::hash(body)
+    )
+}
+
+fn rebuild(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = stored.status;
+    *response.headers_mut() = stored.headers;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::http::{Method, StatusCode};
+
+    use super::{fingerprint, scoped_key, Entry, IdempotencyCache, Lookup, StoredResponse};
+
+    fn stored() -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::CREATED,
+            headers: Default::default(),
+            body: Default::default(),
+        }
+    }
+
+    #[test]
+    fn finished_requests_are_replayed() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        assert!(matches!(cache.begin("k", "POST /items"), Lookup::Miss));
+        assert!(matches!(cache.begin("k", "POST /items"), Lookup::InFlight));
+        cache.finish("k", Some(Entry::Done(stored())));
+        assert!(matches!(
+            cache.begin("k", "POST /items"),
+            Lookup::Replay(StoredResponse {
+                status: StatusCode::CREATED,
+                ..
+            })
+        ));
+        assert!(matches!(cache.begin("k", "POST /terms"), Lookup::Mismatch));
+    }
+
+    #[test]
+    fn abandoned_requests_release_key_and_streamed_ones_are_not_replayed() {
+        let cache = Arc::new(IdempotencyCache::new(10, Duration::from_secs(60)));
+        assert!(matches!(cache.begin("k", "POST /items"), Lookup::Miss));
+        let reservation = cache.reserve("k".to_string());
+        assert!(matches!(cache.begin("k", "POST /items"), Lookup::InFlight));
+        drop(reservation);
+        assert!(matches!(cache.begin("k", "POST /items"), Lookup::Miss));
+
+        cache.finish("k", Some(Entry::Unreplayable));
+        assert!(matches!(
+            cache.begin("k", "POST /items"),
+            Lookup::Unreplayable
+        ));
+    }
+
+    #[test]
+    fn keys_are_scoped_to_caller_and_body() {
+        assert_ne!(scoped_key(Some("a"), "k"), scoped_key(Some("b"), "k"));
+        assert_ne!(scoped_key(None, "k"), scoped_key(Some("a"), "k"));
+
+        let uri = "/items/5".parse().unwrap();
+        assert_eq!(
+            fingerprint(&Method::POST, &uri, b"[\"red\"]"),
+            fingerprint(&Method::POST, &uri, b"[\"red\"]")
+        );
+        assert_ne!(
+            fingerprint(&Method::POST, &uri, b"[\"red\"]"),
+            fingerprint(&Method::POST, &uri, b"[\"blue\"]")
+        );
+        let dry_run = "/items/5?dry_run=true".parse().unwrap();
+        assert_ne!(
+            fingerprint(&Method::POST, &uri, b""),
+            fingerprint(&Method::POST, &dry_run, b"")
+        );
+    }
+
+    #[test]
+    fn oldest_keys_are_evicted_past_capacity() {
+        let cache = IdempotencyCache::new(1, Duration::from_secs(60));
+        cache.begin("a", "POST /items");
+        cache.finish("a", Some(Entry::Done(stored())));
+        cache.begin("b", "POST /items");
+        assert!(matches!(cache.begin("a", "POST /items"), Lookup::Miss));
+    }
+}
@@ -0,0 +1,420 @@
+//! Bulk imports processed in background, so that clients only wait for the upload itself
+//!
+//! Uploaded dataset is spooled next to the data file and applied a chunk of rows per write lock
+//! acquisition, letting other requests through in between. Every row is a JSON object like
+//! `{"key": 5, "terms": ["red", "round"]}` on its own line, creating the key if needed and
+//! setting listed flags. Malformed rows are counted and skipped. Imports are registered as
+//! cancellable jobs, which stop before their next chunk. Sets pulled from Redis are spooled as
+//! the same rows before being applied. Rows are held to access policy and term locks as they apply
+//! to whoever started the import.
+
+use std::{
+    collections::BTreeMap,
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::body::Body;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::{Access, Action},
+    api::DBState,
+    changes::{Change, ChangeLog},
+    error::ApiError,
+    jobs::{JobHandle, JobKind, Jobs},
+    keys::ApiKey,
+    ndjson::NdjsonReader,
+    redis::RedisSource,
+    storage::Database,
+    transaction::{DryRun, DryRunReport, Operation},
+};
+
+/// Rows applied under a single write lock acquisition
+const IMPORT_CHUNK_ROWS: usize = 1024;
+
+/// Row errors kept per job, later ones are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Finished imports kept for progress lookups, oldest are forgotten first
+const MAX_FINISHED_IMPORTS: usize = 100;
+
+#[derive(Clone, Debug, Deserialize)]
+struct ImportRow {
+    key: ApiKey,
+    #[serde(default)]
+    terms: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportState {
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImportRowError {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImportProgress {
+    /// Id of the job applying the import
+    pub id: u64,
+    pub state: ImportState,
+    pub rows_processed: usize,
+    pub rows_failed: usize,
+    /// Sets read from Redis so far, rows are applied once all are read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sets_fetched: Option<usize>,
+    /// First [`MAX_REPORTED_ERRORS`] failed rows
+    pub errors: Vec<ImportRowError>,
+    /// Reason the whole import stopped, when state is failed
+    pub failure: Option<String>,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Progress of running and recently finished imports
+pub struct Imports {
+    spool_base: PathBuf,
+    next_spool: AtomicU64,
+    progress: Mutex<BTreeMap<u64, ImportProgress>>,
+    jobs: Arc<Jobs>,
+}
+
+impl Imports {
+    /// Uploads are spooled into files named after `spool_base`
+    pub fn new(spool_base: PathBuf, jobs: Arc<Jobs>) -> Self {
+        Self {
+            spool_base,
+            next_spool: AtomicU64::new(1),
+            progress: Default::default(),
+            jobs,
+        }
+    }
+
+    /// Reserve spool file for a new upload
+    pub fn prepare(&self) -> PathBuf {
+        let spool = self.next_spool.fetch_add(1, Ordering::Relaxed);
+        let mut path = self.spool_base.as_os_str().to_owned();
+        path.push(format!(".import.{spool}"));
+        path.into()
+    }
+
+    pub fn progress(&self, id: u64) -> Option<ImportProgress> {
+        self.progress.lock().unwrap().get(&id).cloned()
+    }
+
+    fn start(&self, id: u64, sets_fetched: Option<usize>) -> ImportProgress {
+        let progress = ImportProgress {
+            id,
+            state: ImportState::Running,
+            rows_processed: 0,
+            rows_failed: 0,
+            sets_fetched,
+            errors: vec![],
+            failure: None,
+            started_at: now(),
+            finished_at: None,
+        };
+        let mut imports = self.progress.lock().unwrap();
+        imports.insert(id, progress.clone());
+        let finished: Vec<u64> = imports
+            .values()
+            .filter(|import| import.state != ImportState::Running)
+            .map(|import| import.id)
+            .collect();
+        for expired in &finished[..finished.len().saturating_sub(MAX_FINISHED_IMPORTS)] {
+            imports.remove(expired);
+        }
+        progress
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut ImportProgress)) {
+        if let Some(progress) = self.progress.lock().unwrap().get_mut(&id) {
+            update(progress);
+        }
+    }
+
+    /// Apply spooled upload in background, removing spool file afterwards
+    pub fn spawn(
+        self: &Arc<Self>,
+        spool: PathBuf,
+        db: DBState,
+        changes: Arc<ChangeLog>,
+        access: Access,
+    ) -> ImportProgress {
+        let job = self.jobs.start_cancellable(JobKind::Import);
+        let progress = self.start(job.id(), None);
+        let imports = self.clone();
+        tokio::task::spawn_blocking(move || {
+            imports.apply(job, &spool, Ok(()), &db, &changes, &access)
+        });
+        progress
+    }
+
+    /// Read sets matching `source` from Redis into spool file and apply it in background
+    pub fn spawn_from_redis(
+        self: &Arc<Self>,
+        source: RedisSource,
+        db: DBState,
+        changes: Arc<ChangeLog>,
+        access: Access,
+    ) -> ImportProgress {
+        let job = self.jobs.start_cancellable(JobKind::Import);
+        let id = job.id();
+        let progress = self.start(id, Some(0));
+        let spool = self.prepare();
+        let imports = self.clone();
+        tokio::spawn(async move {
+            let fetched = crate::redis::spool_sets(&source, &spool, &job, |fetched| {
+                imports.update(id, |progress| progress.sets_fetched = Some(fetched))
+            })
+            .await
+            .map_err(|e| e.to_string());
+            tokio::task::spawn_blocking(move || {
+                imports.apply(job, &spool, fetched, &db, &changes, &access)
+            });
+        });
+        progress
+    }
+
+    /// Apply spool file unless reading it from its source failed, then remove it and record
+    /// outcome
+    fn apply(
+        &self,
+        job: JobHandle,
+        spool: &Path,
+        spooled: Result<(), String>,
+        db: &DBState,
+        changes: &ChangeLog,
+        access: &Access,
+    ) {
+        let id = job.id();
+        let result = spooled.and_then(|()| {
+            self.run(&job, spool, db, changes, access)
+                .map_err(|e| e.to_string())
+        });
+        let _ = std::fs::remove_file(spool);
+        let cancelled = job.is_cancelled();
+        self.update(id, |progress| {
+            progress.finished_at = Some(now());
+            match &result {
+                _ if cancelled => progress.state = ImportState::Cancelled,
+                Ok(()) => progress.state = ImportState::Finished,
+                Err(e) => {
+                    progress.state = ImportState::Failed;
+                    progress.failure = Some(e.clone());
+                }
+            }
+        });
+        job.finish(result);
+    }
+
+    fn run(
+        &self,
+        job: &JobHandle,
+        spool: &Path,
+        db: &DBState,
+        changes: &ChangeLog,
+        access: &Access,
+    ) -> std::io::Result<()> {
+        let id = job.id();
+        let mut lines = std::io::BufReader::new(std::fs::File::open(spool)?)
+            .lines()
+            .enumerate();
+        while !job.is_cancelled() {
+            let mut rows = vec![];
+            for (index, line) in lines.by_ref() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    rows.push((index + 1, serde_json::from_str::<ImportRow>(&line)));
+                }
+                if rows.len() == IMPORT_CHUNK_ROWS {
+                    break;
+                }
+            }
+            if rows.is_empty() {
+                return Ok(());
+            }
+
+            let mut errors = vec![];
+            let mut db = db.blocking_write();
+            for (line, row) in &rows {
+                let applied = match row {
+                    Ok(row) => apply_row(&mut db, changes, access, row),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(reason) = applied {
+                    errors.push(ImportRowError {
+                        line: *line,
+                        reason,
+                    });
+                }
+            }
+            drop(db);
+
+            self.update(id, |progress| {
+                progress.rows_processed += rows.len();
+                progress.rows_failed += errors.len();
+                let room = MAX_REPORTED_ERRORS.saturating_sub(progress.errors.len());
+                progress.errors.extend(errors.into_iter().take(room));
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Work out what importing `body` would change without spooling or applying it. Unlike actual
+/// import, a line that is not JSON at all fails the whole dry run
+pub async fn dry_run(db: &DBState, body: Body) -> Result<DryRunReport, ApiError> {
+    let mut reader = NdjsonReader::new(body);
+    let mut dry_run = DryRun::default();
+    loop {
+        let rows: Vec<serde_json::Value> = reader.next_chunk(IMPORT_CHUNK_ROWS).await?;
+        if rows.is_empty() {
+            return Ok(dry_run.report(&*db.read().await));
+        }
+        let db = db.read().await;
+        for row in rows {
+            let Ok(ImportRow {
+                key: ApiKey(key),
+                terms,
+            }) = serde_json::from_value(row)
+            else {
+                continue;
+            };
+            dry_run.apply(&db, Operation::CreateRecord { key })?;
+            for term in terms {
+                dry_run.apply(&db, Operation::SetFlag { key, term })?;
+            }
+        }
+    }
+}
+
+fn apply_row(
+    db: &mut Database<8>,
+    changes: &ChangeLog,
+    access: &Access,
+    row: &ImportRow,
+) -> Result<(), String> {
+    let terms = || row.terms.iter().map(String::as_str);
+    let ApiKey(key) = row.key;
+    access
+        .check_terms(db, Action::Write, terms())
+        .and_then(|()| access.check_record(db, &key, Action::Write))
+        .map_err(|e| e.to_string())?;
+    access
+        .check_unlocked(db, terms())
+        .map_err(|e| e.to_string())?;
+    for term in terms() {
+        let companions = db
+            .carried_exclusive_companions(key, term)
+            .map_err(|e| e.to_string())?;
+        access
+            .check_unlocked(db, companions)
+            .map_err(|e| e.to_string())?;
+    }
+    if db.create_record(key).map_err(|e| e.to_string())? {
+        changes.record(Change::CreateRecord { key });
+    }
+    for term in &row.terms {
+        let term_is_new = db.get_term_id(term).is_none();
+        match db.set_flag(key, term) {
+            Ok(changed) => {
+                if term_is_new {
+                    changes.record(Change::AddTerm { term: term.clone() });
+                }
+                if changed {
+                    changes.record(Change::SetFlag {
+                        key,
+                        term: term.clone(),
+                    });
+                }
+            }
+            Err(e) => return Err(format!("term {term}: {e}")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::RwLock;
+
+    use crate::{
+        access::{Access, AccessPolicy, TermRule},
+        changes::ChangeLog,
+        jobs::Jobs,
+        storage::Database,
+        term_locks::TermLocks,
+    };
+
+    use super::{ImportState, Imports};
+
+    #[tokio::test]
+    async fn import_applies_rows_and_reports_malformed_ones() {
+        let base = std::env::temp_dir().join(format!("elizadb-import-{}", std::process::id()));
+        let locks = Arc::new(TermLocks::open(TermLocks::path_for(&base)).unwrap());
+        locks.lock("managed").unwrap();
+        let policy = AccessPolicy {
+            rules: vec![TermRule {
+                prefix: "pii:".to_string(),
+                read: None,
+                write: Some(["privacy".to_string()].into()),
+            }],
+            ..Default::default()
+        };
+        let access = Access::for_api_key(Arc::new(policy), locks, None).unwrap();
+        let imports = Arc::new(Imports::new(base.clone(), Arc::new(Jobs::default())));
+        let spool = imports.prepare();
+        std::fs::write(
+            &spool,
+            "{\"key\": 1, \"terms\": [\"red\"]}\n\nnot json\n{\"key\": 2}\n\
+             {\"key\": 3, \"terms\": [\"managed\"]}\n{\"key\": 4, \"terms\": [\"pii:email\"]}\n",
+        )
+        .unwrap();
+        let db = Arc::new(RwLock::new(Database::<8>::default()));
+
+        let id = imports
+            .spawn(
+                spool.clone(),
+                db.clone(),
+                Arc::new(ChangeLog::new(16)),
+                access,
+            )
+            .id;
+        let progress = loop {
+            let progress = imports.progress(id).unwrap();
+            if progress.state != ImportState::Running {
+                break progress;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        assert_eq!(progress.state, ImportState::Finished);
+        assert_eq!((progress.rows_processed, progress.rows_failed), (5, 3));
+        assert_eq!(progress.errors[0].line, 3);
+        assert_eq!(db.read().await.list_keys().count(), 2);
+        assert!(!spool.exists());
+        std::fs::remove_file(TermLocks::path_for(&base)).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Registry of maintenance work running in background, listed by `GET /admin/jobs`
+//!
+//! Jobs that can stop halfway without leaving state inconsistent are started cancellable and
+//! check [`JobHandle::is_cancelled`] between steps, the rest run to completion once started.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+pub type JobId = u64;
+
+/// Finished jobs kept for listing, oldest are forgotten first
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Snapshot,
+    DeltaSnapshot,
+    Compaction,
+    Import,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub cancellable: bool,
+    /// Cancellation was requested but job has not reached a point where it can stop yet
+    pub cancel_requested: bool,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("no job {0}")]
+    NotFound(JobId),
+    #[error("job {0} cannot be cancelled")]
+    NotCancellable(JobId),
+    #[error("job {0} is no longer running")]
+    NotRunning(JobId),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+struct JobEntry {
+    info: JobInfo,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<JobId, JobEntry>>,
+}
+
+impl Jobs {
+    fn start(self: &Arc<Self>, kind: JobKind, cancellable: bool) -> JobHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let info = JobInfo {
+            id,
+            kind,
+            state: JobState::Running,
+            cancellable,
+            cancel_requested: false,
+            started_at: now(),
+            finished_at: None,
+            error: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(
+            id,
+            JobEntry {
+                info,
+                cancelled: cancelled.clone(),
+            },
+        );
+        let finished: Vec<JobId> = jobs
+            .values()
+            .filter(|job| job.info.state != JobState::Running)
+            .map(|job| job.info.id)
+            .collect();
+        for expired in &finished[..finished.len().saturating_sub(MAX_FINISHED_JOBS)] {
+            jobs.remove(expired);
+        }
+
+        JobHandle {
+            id,
+            jobs: self.clone(),
+            cancelled,
+            finished: false,
+        }
+    }
+
+    /// Register job that checks its handle for cancellation and reports its own outcome
+    pub fn start_cancellable(self: &Arc<Self>, kind: JobKind) -> JobHandle {
+        self.start(kind, true)
+    }
+
+    /// Track `work` as a job which cannot be cancelled, returning its result
+    pub async fn run<T, E: Display>(
+        self: &Arc<Self>,
+        kind: JobKind,
+        work: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let handle = self.start(kind, false);
+        let result = work.await;
+        handle.finish(result.as_ref().map(|_| ()).map_err(ToString::to_string));
+        result
+    }
+
+    /// Running jobs first, then finished ones, newest first within each group
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<JobInfo> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.info.clone())
+            .collect();
+        jobs.sort_by_key(|job| (job.state != JobState::Running, std::cmp::Reverse(job.id)));
+        jobs
+    }
+
+    pub fn get(&self, id: JobId) -> Option<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|job| job.info.clone())
+    }
+
+    /// Ask job to stop, it does so at its next cancellation check
+    pub fn cancel(&self, id: JobId) -> Result<JobInfo, JobError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(&id).ok_or(JobError::NotFound(id))?;
+        if job.info.state != JobState::Running {
+            return Err(JobError::NotRunning(id));
+        }
+        if !job.info.cancellable {
+            return Err(JobError::NotCancellable(id));
+        }
+        job.info.cancel_requested = true;
+        job.cancelled.store(true, Ordering::Relaxed);
+        Ok(job.info.clone())
+    }
+}
+
+/// Held by running job, marks it failed if dropped before [`JobHandle::finish`]
+pub struct JobHandle {
+    id: JobId,
+    jobs: Arc<Jobs>,
+    cancelled: Arc<AtomicBool>,
+    finished: bool,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Record outcome, jobs that were cancelled are reported as such regardless of it
+    pub fn finish(mut self, result: Result<(), String>) {
+        self.record(result);
+    }
+
+    fn record(&mut self, result: Result<(), String>) {
+        self.finished = true;
+        let cancelled = self.is_cancelled();
+        let mut jobs = self.jobs.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&self.id) else {
+            return;
+        };
+        job.info.finished_at = Some(now());
+        job.info.state = match result {
+            _ if cancelled => JobState::Cancelled,
+            Ok(()) => JobState::Finished,
+            Err(e) => {
+                job.info.error = Some(e);
+                JobState::Failed
+            }
+        };
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.record(Err("job stopped without reporting its outcome".to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{JobError, JobKind, JobState, Jobs};
+
+    #[tokio::test]
+    async fn jobs_are_listed_and_only_cancellable_ones_cancelled() {
+        let jobs = Arc::new(Jobs::default());
+        let failed = jobs
+            .run(JobKind::Compaction, async { Err::<(), _>("disk full") })
+            .await;
+        assert!(failed.is_err());
+
+        let import = jobs.start_cancellable(JobKind::Import);
+        jobs.cancel(import.id()).unwrap();
+        assert!(import.is_cancelled());
+        let id = import.id();
+        import.finish(Ok(()));
+
+        let listed = jobs.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].state, JobState::Cancelled);
+        assert_eq!(listed[1].state, JobState::Failed);
+        assert_eq!(listed[1].error.as_deref(), Some("disk full"));
+        assert!(matches!(jobs.cancel(id), Err(JobError::NotRunning(_))));
+
+        let snapshot = jobs.start(JobKind::Snapshot, false);
+        assert!(matches!(
+            jobs.cancel(snapshot.id()),
+            Err(JobError::NotCancellable(_))
+        ));
+        drop(snapshot);
+        assert_eq!(jobs.list()[0].state, JobState::Failed);
+    }
+}
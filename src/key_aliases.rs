@@ -0,0 +1,80 @@
+use crate::storage::{Database, Key};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyAliasError {
+    #[error("key {0} does not exist")]
+    UnknownKey(Key),
+    #[error("alias {0} is already used by key {1}")]
+    AlreadyExists(String, Key),
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Attach string identifier to existing key, replacing alias it had before
+    pub fn set_key_alias(&mut self, key: Key, alias: &str) -> Result<(), KeyAliasError> {
+        if !self.contains_key(&key) {
+            return Err(KeyAliasError::UnknownKey(key));
+        }
+        match self.key_aliases.get_forward(alias) {
+            Some(&owner) if owner != key => {
+                Err(KeyAliasError::AlreadyExists(alias.to_string(), owner))
+            }
+            _ => {
+                self.key_aliases.insert(alias.to_string(), key);
+                Ok(())
+            }
+        }
+    }
+
+    /// Detach alias from key, returning it if key had one
+    pub fn remove_key_alias(&mut self, key: Key) -> Option<String> {
+        self.key_aliases.remove_backward(&key)
+    }
+
+    pub fn resolve_key_alias(&self, alias: &str) -> Option<Key> {
+        self.key_aliases.get_forward(alias).copied()
+    }
+
+    pub fn key_alias(&self, key: Key) -> Option<&'_ str> {
+        self.key_aliases.get_backward(&key).map(String::as_str)
+    }
+
+    /// Pairs of alias and the key it resolves to
+    pub fn list_key_aliases(&self) -> impl Iterator<Item = (&'_ str, Key)> {
+        self.key_aliases
+            .iter()
+            .map(|(alias, &key)| (alias.as_str(), key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::KeyAliasError;
+
+    #[test]
+    fn key_aliases_resolve_and_move() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        assert!(matches!(
+            db.set_key_alias(a, "uuid-a"),
+            Err(KeyAliasError::UnknownKey(_))
+        ));
+
+        db.create_record(a).unwrap();
+        db.create_record(b).unwrap();
+        db.set_key_alias(a, "uuid-a").unwrap();
+        assert_eq!(db.resolve_key_alias("uuid-a"), Some(a));
+        assert!(matches!(
+            db.set_key_alias(b, "uuid-a"),
+            Err(KeyAliasError::AlreadyExists(_, owner)) if owner == a
+        ));
+
+        db.set_key_alias(a, "uuid-a2").unwrap();
+        assert_eq!(db.resolve_key_alias("uuid-a"), None);
+        assert_eq!(db.key_alias(a), Some("uuid-a2"));
+
+        assert_eq!(db.remove_key_alias(a).as_deref(), Some("uuid-a2"));
+        assert_eq!(db.key_alias(a), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Keys as they appear in API payloads
+//!
+//! Clients may send keys as JSON numbers, decimal strings or `0x` prefixed hex strings.
+//! Responses write keys in [`KeyFormat`] chosen for the current request.
+
+use std::fmt;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{config::KeyFormat, error::ApiError, storage::Key};
+
+pub static KEY_FORMAT_HEADER: HeaderName = HeaderName::from_static("x-elizadb-key-format");
+
+tokio::task_local! {
+    /// Format of keys in response to the request currently being handled
+    static KEY_FORMAT: KeyFormat;
+}
+
+fn current_format() -> KeyFormat {
+    KEY_FORMAT.try_with(|format| *format).unwrap_or_default()
+}
+
+/// Key that is written in requested format and can be parsed from number or string
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiKey(pub Key);
+
+impl From<Key> for ApiKey {
+    fn from(key: Key) -> Self {
+        Self(key)
+    }
+}
+
+impl From<ApiKey> for Key {
+    fn from(key: ApiKey) -> Self {
+        key.0
+    }
+}
+
+impl fmt::Display for ApiKey {
+    /// Key in format of current response, numbers are written as decimal
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match current_format() {
+            KeyFormat::Number | KeyFormat::String => write!(f, "{}", self.0),
+            KeyFormat::Hex => write!(f, "0x{:016x}", self.0.get()),
+        }
+    }
+}
+
+impl std::str::FromStr for ApiKey {
+    type Err = de::value::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        de::Visitor::visit_str(KeyVisitor, value)
+    }
+}
+
+impl Serialize for ApiKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match current_format() {
+            KeyFormat::Number => serializer.serialize_u64(self.0.get()),
+            KeyFormat::String => serializer.collect_str(&self.0),
+            KeyFormat::Hex => serializer.collect_str(&format_args!("0x{:016x}", self.0.get())),
+        }
+    }
+}
+
+struct KeyVisitor;
+
+impl de::Visitor<'_> for KeyVisitor {
+    type Value = ApiKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a non-zero key as number, decimal string or 0x prefixed hex string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<ApiKey, E> {
+        Key::new(value)
+            .map(ApiKey)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(0), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<ApiKey, E> {
+        u64::try_from(value)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+            .and_then(|value| self.visit_u64(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<ApiKey, E> {
+        let parsed = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        parsed
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+            .and_then(|value| self.visit_u64(value))
+    }
+}
+
+impl<'de> Deserialize<'de> for ApiKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+/// For `#[serde(with)]` on plain [`Key`] fields of API payloads
+pub mod flexible {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::ApiKey;
+    use crate::storage::Key;
+
+    pub fn serialize<S: Serializer>(key: &Key, serializer: S) -> Result<S::Ok, S::Error> {
+        ApiKey(*key).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        ApiKey::deserialize(deserializer).map(Key::from)
+    }
+}
+
+/// For `#[serde(with)]` on optional [`Key`] fields of API payloads
+pub mod flexible_option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::ApiKey;
+    use crate::storage::Key;
+
+    pub fn serialize<S: Serializer>(key: &Option<Key>, serializer: S) -> Result<S::Ok, S::Error> {
+        key.map(ApiKey).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Key>, D::Error> {
+        Option::<ApiKey>::deserialize(deserializer).map(|key| key.map(Key::from))
+    }
+}
+
+/// For `#[serde(with)]` on optional lists of [`Key`] in API payloads
+pub mod flexible_option_vec {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::ApiKey;
+    use crate::storage::Key;
+
+    pub fn serialize<S: Serializer>(
+        keys: &Option<Vec<Key>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        keys.as_ref()
+            .map(|keys| keys.iter().copied().map(ApiKey).collect::<Vec<_>>())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<Key>>, D::Error> {
+        Option::<Vec<ApiKey>>::deserialize(deserializer)
+            .map(|keys| keys.map(|keys| keys.into_iter().map(Key::from).collect()))
+    }
+}
+
+/// Serve request writing keys in format from `x-elizadb-key-format` header, or `default` without one
+pub async fn apply_key_format(
+    State(default): State<KeyFormat>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let format = match request.headers().get(&KEY_FORMAT_HEADER) {
+        None => default,
+        Some(value) => match value
+            .to_str()
+            .ok()
+            .and_then(|value| KeyFormat::from_str(value, true).ok())
+        {
+            Some(format) => format,
+            None => {
+                return ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_key_format",
+                    "key format must be one of number, string or hex",
+                )
+                .into_response()
+            }
+        },
+    };
+    KEY_FORMAT.scope(format, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::KeyFormat, storage::Key};
+
+    use super::{ApiKey, KEY_FORMAT};
+
+    #[test]
+    fn keys_are_parsed_from_numbers_and_strings() {
+        let expected = ApiKey(Key::try_from(255).unwrap());
+        for input in ["255", "\"255\"", "\"0xff\"", "\"0x00000000000000FF\""] {
+            assert_eq!(serde_json::from_str::<ApiKey>(input).unwrap(), expected);
+        }
+        for input in ["0", "-1", "\"0x\"", "\"abc\"", "\"0\"", "1.5"] {
+            assert!(serde_json::from_str::<ApiKey>(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn keys_are_written_in_requested_format() {
+        let key = ApiKey(Key::try_from(u64::MAX).unwrap());
+        let write = |format| KEY_FORMAT.sync_scope(format, || serde_json::to_string(&key).unwrap());
+
+        assert_eq!(serde_json::to_string(&key).unwrap(), "18446744073709551615");
+        assert_eq!(write(KeyFormat::String), "\"18446744073709551615\"");
+        assert_eq!(write(KeyFormat::Hex), "\"0xffffffffffffffff\"");
+    }
+}
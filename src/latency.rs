@@ -0,0 +1,202 @@
+//! Timing histograms of storage operations, split by storage class of records involved
+//!
+//! Exported in Prometheus text format by `GET /metrics`.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::stats::StorageClass;
+
+/// Upper bounds of histogram buckets in microseconds, last bucket is unbounded
+const BUCKET_BOUNDS_MICROS: [u64; 16] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 50_000, 100_000, 1_000_000,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    SetFlag,
+    HorizontalQuery,
+    /// Full scan answering vertical query, columnar answers and candidate lookups are not timed
+    VerticalScan,
+}
+
+impl Operation {
+    const ALL: [Operation; 3] = [
+        Operation::SetFlag,
+        Operation::HorizontalQuery,
+        Operation::VerticalScan,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::SetFlag => "set_flag",
+            Operation::HorizontalQuery => "horizontal_query",
+            Operation::VerticalScan => "vertical_scan",
+        }
+    }
+}
+
+const CLASSES: [StorageClass; 2] = [StorageClass::Small, StorageClass::Big];
+
+fn class_name(class: StorageClass) -> &'static str {
+    match class {
+        StorageClass::Small => "small",
+        StorageClass::Big => "big",
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MICROS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros();
+        let bucket = BUCKET_BOUNDS_MICROS.partition_point(|&bound| u128::from(bound) < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Iterator adapter adding time spent producing items to `histogram` once dropped, if polled
+pub struct Timed<'h, I> {
+    inner: I,
+    histogram: &'h Histogram,
+    elapsed: Option<Duration>,
+}
+
+impl<'h, I> Timed<'h, I> {
+    pub fn new(inner: I, histogram: &'h Histogram) -> Self {
+        Self {
+            inner,
+            histogram,
+            elapsed: None,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for Timed<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let started = Instant::now();
+        let item = self.inner.next();
+        *self.elapsed.get_or_insert_default() += started.elapsed();
+        item
+    }
+}
+
+impl<I> Drop for Timed<'_, I> {
+    fn drop(&mut self) {
+        if let Some(elapsed) = self.elapsed {
+            self.histogram.observe(elapsed);
+        }
+    }
+}
+
+/// Histograms of every operation and storage class, since database was loaded
+#[derive(Default)]
+pub struct Latencies {
+    histograms: [[Histogram; 2]; 3],
+}
+
+impl Latencies {
+    pub fn get(&self, operation: Operation, class: StorageClass) -> &Histogram {
+        let operation = Operation::ALL
+            .iter()
+            .position(|&known| known == operation)
+            .unwrap();
+        let class = CLASSES.iter().position(|&known| known == class).unwrap();
+        &self.histograms[operation][class]
+    }
+
+    pub fn observe(&self, operation: Operation, class: StorageClass, elapsed: Duration) {
+        self.get(operation, class).observe(elapsed);
+    }
+
+    /// Histograms in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        const NAME: &str = "elizadb_storage_operation_seconds";
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP {NAME} Duration of storage operations by storage class of records involved"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {NAME} histogram").unwrap();
+        for operation in Operation::ALL {
+            for class in CLASSES {
+                let histogram = self.get(operation, class);
+                let labels = format!(
+                    "operation=\"{}\",storage=\"{}\"",
+                    operation.name(),
+                    class_name(class)
+                );
+                let mut cumulative = 0;
+                for (bucket, bound) in histogram.buckets.iter().zip(BUCKET_BOUNDS_MICROS) {
+                    cumulative += bucket.load(Ordering::Relaxed);
+                    let le = bound as f64 / 1e6;
+                    writeln!(out, "{NAME}_bucket{{{labels},le=\"{le}\"}} {cumulative}").unwrap();
+                }
+                let count = histogram.count();
+                writeln!(out, "{NAME}_bucket{{{labels},le=\"+Inf\"}} {count}").unwrap();
+                let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+                writeln!(out, "{NAME}_sum{{{labels}}} {sum}").unwrap();
+                writeln!(out, "{NAME}_count{{{labels}}} {count}").unwrap();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        query::Query,
+        stats::StorageClass,
+        storage::{Database, Key},
+    };
+
+    use super::Operation;
+
+    #[test]
+    fn operations_are_timed_per_storage_class() {
+        let mut db = Database::<8>::default();
+        let (small, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(small, "x").unwrap();
+        for term in 0..100 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+        db.horizontal_query(&big).unwrap().unwrap();
+        db.vertical_query(&Query::Simple {
+            term: "x".to_string(),
+        })
+        .unwrap();
+
+        let count = |operation, class| db.latencies().get(operation, class).count();
+        assert_eq!(count(Operation::SetFlag, StorageClass::Small), 1 + 64);
+        assert_eq!(count(Operation::SetFlag, StorageClass::Big), 100 - 64);
+        assert_eq!(count(Operation::HorizontalQuery, StorageClass::Small), 0);
+        assert_eq!(count(Operation::HorizontalQuery, StorageClass::Big), 1);
+        assert_eq!(count(Operation::VerticalScan, StorageClass::Small), 1);
+        assert_eq!(count(Operation::VerticalScan, StorageClass::Big), 1);
+
+        let rendered = db.latencies().render();
+        assert!(rendered.contains(
+            "elizadb_storage_operation_seconds_count{operation=\"horizontal_query\",storage=\"big\"} 1"
+        ));
+    }
+}
@@ -0,0 +1,60 @@
+pub use storage::{Database, Key};
+
+pub mod access;
+pub mod api;
+pub mod archive;
+pub mod audit;
+pub mod bigstore;
+pub mod changes;
+pub mod columns;
+pub mod compaction;
+pub mod config;
+pub mod consistency;
+pub mod deadline;
+pub mod doublemap;
+pub mod error;
+pub mod export;
+pub mod extract;
+#[cfg(feature = "flight")]
+pub mod flight;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod history;
+pub mod idempotency;
+pub mod import;
+pub mod jobs;
+pub mod key_aliases;
+pub mod keys;
+pub mod latency;
+pub mod log_file;
+pub mod ndjson;
+pub mod plugins;
+pub mod query;
+pub mod query_cache;
+pub mod quotas;
+pub mod redis;
+pub mod reload;
+pub mod replication;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod schedules;
+pub mod serde;
+pub mod smallset;
+pub mod snapshots;
+pub mod soft_delete;
+pub mod stats;
+pub mod storage;
+pub mod stored_queries;
+pub mod telemetry;
+pub mod tenants;
+pub mod term_capacity;
+pub mod term_groups;
+pub mod term_locks;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod time_travel;
+pub mod transaction;
+pub mod triggers;
+pub mod versions;
+pub mod views;
+pub mod write_queue;
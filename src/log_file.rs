@@ -0,0 +1,138 @@
+//! Log file that is rotated once it grows past a size or gets too old
+//!
+//! Rotation renames the active file to `<path>.1`, shifting older files up by one and removing
+//! those past the number kept, then starts a new active file.
+
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When to start a new log file, never if neither limit is set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub interval: Option<Duration>,
+    /// Number of rotated files kept besides the active one
+    pub keep: usize,
+}
+
+struct ActiveFile {
+    file: File,
+    written: u64,
+    opened_at: Instant,
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    active: Mutex<ActiveFile>,
+}
+
+impl RotatingFile {
+    /// Appends to existing file at `path`, counting its size towards the limit
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        let active = open_active(&path)?;
+        Ok(Self {
+            path,
+            rotation,
+            active: Mutex::new(active),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn due(&self, active: &ActiveFile, incoming: usize) -> bool {
+        let too_big = self.rotation.max_bytes.is_some_and(|max_bytes| {
+            active.written > 0 && active.written + incoming as u64 > max_bytes
+        });
+        let too_old = self
+            .rotation
+            .interval
+            .is_some_and(|interval| active.opened_at.elapsed() >= interval);
+        too_big || too_old
+    }
+
+    fn rotate(&self, active: &mut ActiveFile) -> io::Result<()> {
+        active.file.flush()?;
+        let _ = std::fs::remove_file(self.rotated_path(self.rotation.keep.max(1)));
+        for index in (1..self.rotation.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.rotation.keep > 0 {
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        *active = open_active(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_active(path: &Path) -> io::Result<ActiveFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(ActiveFile {
+        written: file.metadata()?.len(),
+        file,
+        opened_at: Instant::now(),
+    })
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if self.due(&active, buf.len()) {
+            self.rotate(&mut active)?;
+        }
+        let written = active.file.write(buf)?;
+        active.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        active.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{RotatingFile, Rotation};
+
+    #[test]
+    fn files_are_rotated_by_size_keeping_newest() {
+        let dir = std::env::temp_dir().join(format!("elizadb-log-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("elizadb.log");
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            interval: None,
+            keep: 2,
+        };
+        let log = RotatingFile::open(&path, rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            (&log).write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("elizadb.log"), "fourth\n");
+        assert_eq!(read("elizadb.log.1"), "third\n");
+        assert_eq!(read("elizadb.log.2"), "second\n");
+        assert!(!dir.join("elizadb.log.3").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
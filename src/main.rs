@@ -1,18 +1,57 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use storage::{Database, Key};
+use clap::Parser;
+use elizadb::{
+    api, compaction, config, export, jobs, quotas, replication, serde, snapshots, telemetry,
+    triggers,
+};
 use tokio::sync::RwLock;
 
-mod api;
-mod doublemap;
-mod query;
-mod serde;
-mod smallset;
-mod storage;
-
 #[tokio::main]
 async fn main() {
-    let state = match serde::load_possibly_missing(serde::DEFAULT_SAVE_PATH) {
+    let config = config::Config::parse();
+    let log_filter = match telemetry::init(&config) {
+        Ok(log_filter) => log_filter,
+        Err(e) => {
+            eprintln!("error initializing tracing: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let seeded = config.bootstrap_from.is_some() || config.load_from_json.is_some();
+    let snapshot_exists = config.data_file.exists()
+        || !serde::rotation::slots_newest_first(&config.data_file).is_empty();
+    #[cfg(feature = "s3")]
+    let snapshot_exists = snapshot_exists || (!seeded && download_snapshot(&config).await);
+    if config.validate_only && !seeded && !snapshot_exists {
+        eprintln!("snapshot {} does not exist", config.data_file.display());
+        std::process::exit(1);
+    }
+
+    let loaded = match &config.bootstrap_from {
+        Some(peer) => replication::fetch_snapshot(&reqwest::Client::new(), peer)
+            .await
+            .map(|(state, _)| state)
+            .map_err(|e| e as Box<dyn std::error::Error>),
+        None => match &config.load_from_json {
+            Some(path) => export::load_json(path).map_err(|e| e.into()),
+            None => serde::load_possibly_missing(&config.data_file, config.big_record_decoding)
+                .or_else(|e| {
+                    if !config.recover {
+                        return Err(e);
+                    }
+                    eprintln!("error loading database state: {e}, attempting recovery");
+                    let (state, report) = serde::recovery::recover_file(&config.data_file)?;
+                    eprintln!(
+                        "recovered snapshot: {}",
+                        serde_json::to_string(&report).unwrap_or_default()
+                    );
+                    Ok(state)
+                }),
+        },
+    };
+
+    let mut state = match loaded {
         Ok(state) => state,
         Err(e) => {
             eprintln!("error loading database state: {e}");
@@ -20,10 +59,180 @@ async fn main() {
         }
     };
 
+    if config.validate_only {
+        let report = state.check_consistency();
+        for problem in &report.problems {
+            eprintln!("{problem}");
+        }
+        if !report.consistent {
+            eprintln!("snapshot has {} problems", report.problems.len());
+            std::process::exit(1);
+        }
+        println!(
+            "checked {} keys and {} terms, snapshot is consistent",
+            report.checked_keys, report.checked_terms
+        );
+        return;
+    }
+
+    if config.layout == config::StorageLayout::Columnar {
+        if let Err(e) = state.enable_term_columns() {
+            eprintln!("error building term columns: {e}");
+            std::process::exit(1);
+        }
+    }
+    state.set_term_eviction(config.term_eviction);
+    if let Some(path) = &config.quotas {
+        match quotas::Quotas::load(path) {
+            Ok(quotas) => state.set_quotas(quotas),
+            Err(e) => {
+                eprintln!("error loading quotas: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if config.incremental_snapshots {
+        state.enable_dirty_tracking();
+    }
+    #[cfg(feature = "plugins")]
+    if !config.plugins.is_empty() {
+        match elizadb::plugins::WasmPlugins::load(&config.plugins) {
+            Ok(plugins) => state.set_flag_validator(Arc::new(plugins)),
+            Err(e) => {
+                eprintln!("error loading plugins: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    // followers leave webhooks to the leader, whose writes they replay
+    if config.follow.is_none() {
+        state.set_trigger_webhooks(triggers::deliver_webhooks());
+    }
+    if let Some(path) = &config.big_storage_spill_path {
+        if let Err(e) = state.spill_big_records(path, config.big_storage_cache_records) {
+            eprintln!("error opening big storage spill file: {e}");
+            std::process::exit(1);
+        }
+    }
+
     let database = Arc::new(RwLock::new(state));
-    let router = api::build_router(database);
+    let jobs = Arc::new(jobs::Jobs::default());
+    if let Some(interval) = config.compact_interval_secs {
+        tokio::spawn(compaction::run_periodically(
+            database.clone(),
+            jobs.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+    let snapshotter = match snapshots::Snapshotter::new(database.clone(), jobs.clone(), &config) {
+        Ok(snapshotter) => snapshotter,
+        Err(e) => {
+            eprintln!("error configuring snapshots: {e}");
+            std::process::exit(1);
+        }
+    };
+    tokio::spawn(snapshots::run_periodically(snapshotter.clone()));
+    let app_state = match api::AppState::new(
+        database.clone(),
+        snapshotter.clone(),
+        jobs,
+        log_filter,
+        &config,
+    ) {
+        Ok(app_state) => app_state,
+        Err(e) => {
+            eprintln!("error opening audit log: {e}");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    tokio::spawn(elizadb::reload::reload_on_sighup(app_state.reloader()));
+    #[cfg(feature = "flight")]
+    if let Some(port) = config.flight_port {
+        use axum::extract::FromRef;
+        let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+        let reads = elizadb::flight::FlightReads::new(
+            database.clone(),
+            FromRef::from_ref(&app_state),
+            FromRef::from_ref(&app_state),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = elizadb::flight::serve(reads, address).await {
+                eprintln!("flight server failed: {e}");
+            }
+        });
+    }
+    let router = api::build_router(app_state);
     let bind_string = "0.0.0.0:4200";
     println!("{}", bind_string);
     let listener = tokio::net::TcpListener::bind(bind_string).await.unwrap();
-    axum::serve(listener, router).await.unwrap();
+
+    let (signalled, shutdown_started) = tokio::sync::oneshot::channel();
+    let server = axum::serve(listener, router).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        eprintln!("shutting down, draining in-flight requests");
+        let _ = signalled.send(());
+    });
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+    let drain_deadline = async move {
+        if shutdown_started.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        served = server => served.unwrap(),
+        _ = drain_deadline => eprintln!("drain timeout elapsed with requests still in flight"),
+    }
+
+    if let Err(e) = snapshotter.save().await {
+        eprintln!("error saving final snapshot: {e}");
+        std::process::exit(1);
+    }
+}
+
+/// Resolves on Ctrl-C or, on unix, `SIGTERM`
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                eprintln!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Fetch newest uploaded snapshot into first slot, false if target is unset or holds none
+#[cfg(feature = "s3")]
+async fn download_snapshot(config: &config::Config) -> bool {
+    let Some(url) = &config.snapshot_target else {
+        return false;
+    };
+    let [slot, _] = serde::rotation::slot_paths(&config.data_file);
+    let downloaded = match elizadb::s3::S3Target::new(url, config.snapshot_retention) {
+        Ok(target) => target.download_newest(&slot).await,
+        Err(e) => Err(e),
+    };
+    downloaded.unwrap_or_else(|e| {
+        eprintln!("error downloading snapshot from {url}: {e}");
+        std::process::exit(1);
+    })
 }
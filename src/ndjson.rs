@@ -0,0 +1,131 @@
+//! Newline-delimited JSON request bodies, parsed a chunk of lines at a time so that bulk imports
+//! are never buffered whole
+
+use std::pin::Pin;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap},
+};
+use serde::de::DeserializeOwned;
+use tokio_stream::{Stream, StreamExt};
+
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Lines longer than this are rejected rather than buffered until a newline shows up
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NdjsonError {
+    #[error("failed to read request body: {0}")]
+    Body(#[from] axum::Error),
+    #[error("line {line} is not valid JSON: {error}")]
+    Line {
+        line: usize,
+        error: serde_json::Error,
+    },
+    #[error("line {line} is longer than {MAX_LINE_BYTES} bytes")]
+    LineTooLong { line: usize },
+}
+
+pub fn is_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(CONTENT_TYPE))
+}
+
+pub struct NdjsonReader {
+    stream: Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>,
+    buffer: Vec<u8>,
+    /// Number of lines consumed so far, blank ones included
+    line: usize,
+    exhausted: bool,
+}
+
+impl NdjsonReader {
+    pub fn new(body: Body) -> Self {
+        Self {
+            stream: Box::pin(body.into_data_stream()),
+            buffer: vec![],
+            line: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Parse at most `max` following values, blank lines are skipped. Returns empty chunk once
+    /// body is exhausted
+    pub async fn next_chunk<T: DeserializeOwned>(
+        &mut self,
+        max: usize,
+    ) -> Result<Vec<T>, NdjsonError> {
+        let mut chunk = vec![];
+        let mut scanned = 0;
+        while chunk.len() < max {
+            let line = match self.buffer[scanned..].iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    let line: Vec<u8> = self.buffer.drain(..=scanned + end).collect();
+                    scanned = 0;
+                    line
+                }
+                None if self.exhausted => {
+                    if self.buffer.is_empty() {
+                        break;
+                    }
+                    scanned = 0;
+                    std::mem::take(&mut self.buffer)
+                }
+                None => {
+                    scanned = self.buffer.len();
+                    if scanned > MAX_LINE_BYTES {
+                        return Err(NdjsonError::LineTooLong {
+                            line: self.line + 1,
+                        });
+                    }
+                    match self.stream.next().await {
+                        Some(data) => self.buffer.extend_from_slice(&data?),
+                        None => self.exhausted = true,
+                    }
+                    continue;
+                }
+            };
+
+            self.line += 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let value = serde_json::from_slice(&line).map_err(|error| NdjsonError::Line {
+                line: self.line,
+                error,
+            })?;
+            chunk.push(value);
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::{NdjsonError, NdjsonReader};
+
+    #[tokio::test]
+    async fn reads_values_in_chunks_across_frames() {
+        let frames: Vec<Result<_, std::io::Error>> =
+            vec![Ok("1\n2\n\n3"), Ok("4\n5"), Ok("\n6\n"), Ok("x\n")];
+        let mut reader = NdjsonReader::new(Body::from_stream(tokio_stream::iter(frames)));
+
+        assert_eq!(reader.next_chunk::<u64>(2).await.unwrap(), [1, 2]);
+        assert_eq!(reader.next_chunk::<u64>(2).await.unwrap(), [34, 5]);
+        assert_eq!(reader.next_chunk::<u64>(1).await.unwrap(), [6]);
+        assert!(matches!(
+            reader.next_chunk::<u64>(2).await,
+            Err(NdjsonError::Line { line: 7, .. })
+        ));
+
+        let mut reader = NdjsonReader::new(Body::from("7\n8"));
+        assert_eq!(reader.next_chunk::<u64>(10).await.unwrap(), [7, 8]);
+        assert!(reader.next_chunk::<u64>(10).await.unwrap().is_empty());
+    }
+}
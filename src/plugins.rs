@@ -0,0 +1,283 @@
+//! Validation hooks deciding whether a flag may be set, such as WASM modules given with `--plugin`
+//!
+//! WASM modules export `memory`, `alloc(len: i32) -> i32` and
+//! `validate(key: i64, term: i32, term_len: i32, flags: i32, flags_len: i32) -> i32`, given the
+//! term and JSON array of record's terms in memory from `alloc`. Nonzero denies the write, as do
+//! traps and running out of fuel.
+
+use std::sync::Arc;
+
+use crate::storage::{Database, Key, SetFlagError};
+
+/// Decides whether flags may be set, consulted under database write lock
+pub trait FlagValidator: Send + Sync {
+    /// Fails if flag of `term` may not be set on `key`, which carries `flags` so far
+    fn validate(&self, key: Key, term: &str, flags: &[&str]) -> Result<(), WriteDenied>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("plugin {plugin} denied setting {term} on key {key}: {reason}")]
+pub struct WriteDenied {
+    pub plugin: String,
+    pub key: Key,
+    pub term: String,
+    pub reason: String,
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Consult `validator` before setting flags from now on
+    pub fn set_flag_validator(&mut self, validator: Arc<dyn FlagValidator>) {
+        self.validator = Some(validator);
+    }
+
+    /// Fails if validator denies setting flag of `term`, which may not exist yet, on `key`
+    pub(crate) fn check_flag_allowed(&self, key: Key, term: &str) -> Result<(), SetFlagError> {
+        if self.validation_suspended {
+            return Ok(());
+        }
+        self.check_derived_flag_allowed(key, term)
+    }
+
+    /// Like [`Self::check_flag_allowed`], but also while validation is suspended, since flags
+    /// set by triggers were not validated beforehand
+    pub(crate) fn check_derived_flag_allowed(
+        &self,
+        key: Key,
+        term: &str,
+    ) -> Result<(), SetFlagError> {
+        let Some(validator) = &self.validator else {
+            return Ok(());
+        };
+        let term = self
+            .get_term_id(term)
+            .and_then(|term_id| self.explain_term_id(term_id))
+            .unwrap_or(term);
+        let mut flags = self
+            .record(&key)?
+            .map(|record| record.term_ids())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|term_id| self.explain_term_id(term_id))
+            .collect::<Vec<_>>();
+        flags.sort_unstable();
+        Ok(validator.validate(key, term, &flags)?)
+    }
+
+    /// Run `write` without consulting validator on flags it sets, for writes that need no
+    /// validation or were validated as a whole beforehand
+    pub(crate) fn with_validation_suspended<R>(&mut self, write: impl FnOnce(&mut Self) -> R) -> R {
+        let suspended = std::mem::replace(&mut self.validation_suspended, true);
+        let result = write(self);
+        self.validation_suspended = suspended;
+        result
+    }
+}
+
+#[cfg(feature = "plugins")]
+pub use wasm::{PluginError, WasmPlugins};
+
+#[cfg(feature = "plugins")]
+mod wasm {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+    use super::{FlagValidator, WriteDenied};
+    use crate::storage::Key;
+
+    /// Fuel a single call may use, bounding how long a plugin can hold up writes
+    const PLUGIN_FUEL: u64 = 10_000_000;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("cannot load plugin {}: {message}", path.display())]
+    pub struct PluginError {
+        path: PathBuf,
+        message: String,
+    }
+
+    struct WasmPlugin {
+        name: String,
+        store: Mutex<Store<()>>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        validate: TypedFunc<(i64, i32, i32, i32, i32), i32>,
+    }
+
+    /// Plugins consulted in order until one of them denies
+    pub struct WasmPlugins(Vec<WasmPlugin>);
+
+    impl WasmPlugins {
+        pub fn load(paths: &[PathBuf]) -> Result<Self, PluginError> {
+            paths
+                .iter()
+                .map(|path| {
+                    WasmPlugin::load(path).map_err(|message| PluginError {
+                        path: path.clone(),
+                        message,
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(Self)
+        }
+    }
+
+    impl WasmPlugin {
+        /// Plugin is named after its file
+        fn load(path: &Path) -> Result<Self, String> {
+            let wasm = std::fs::read(path).map_err(|e| e.to_string())?;
+            let mut config = wasmi::Config::default();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config);
+            let module = Module::new(&engine, &wasm).map_err(|e| e.to_string())?;
+            let mut store = Store::new(&engine, ());
+            store.set_fuel(PLUGIN_FUEL).map_err(|e| e.to_string())?;
+            let instance = Linker::new(&engine)
+                .instantiate(&mut store, &module)
+                .and_then(|instance| instance.start(&mut store))
+                .map_err(|e| e.to_string())?;
+            let memory = instance
+                .get_memory(&store, "memory")
+                .ok_or("module does not export memory")?;
+            let alloc = instance
+                .get_typed_func(&store, "alloc")
+                .map_err(|e| format!("alloc: {e}"))?;
+            let validate = instance
+                .get_typed_func(&store, "validate")
+                .map_err(|e| format!("validate: {e}"))?;
+            let name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Ok(Self {
+                name,
+                store: Mutex::new(store),
+                memory,
+                alloc,
+                validate,
+            })
+        }
+
+        /// Whether plugin allows the write, Err if it failed to decide
+        fn allows(&self, key: Key, term: &str, flags: &[&str]) -> Result<bool, String> {
+            let flags = serde_json::to_vec(flags).map_err(|e| e.to_string())?;
+            let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+            store.set_fuel(PLUGIN_FUEL).map_err(|e| e.to_string())?;
+            let mut pass = |bytes: &[u8]| -> Result<(i32, i32), String> {
+                let len = i32::try_from(bytes.len()).map_err(|e| e.to_string())?;
+                let at = self
+                    .alloc
+                    .call(&mut *store, len)
+                    .map_err(|e| e.to_string())?;
+                self.memory
+                    .write(&mut *store, at as u32 as usize, bytes)
+                    .map_err(|e| e.to_string())?;
+                Ok((at, len))
+            };
+            let (term, term_len) = pass(term.as_bytes())?;
+            let (flags, flags_len) = pass(&flags)?;
+            let verdict = self
+                .validate
+                .call(
+                    &mut *store,
+                    (key.get() as i64, term, term_len, flags, flags_len),
+                )
+                .map_err(|e| e.to_string())?;
+            Ok(verdict == 0)
+        }
+    }
+
+    impl FlagValidator for WasmPlugins {
+        fn validate(&self, key: Key, term: &str, flags: &[&str]) -> Result<(), WriteDenied> {
+            for plugin in &self.0 {
+                let reason = match plugin.allows(key, term, flags) {
+                    Ok(true) => continue,
+                    Ok(false) => "denied".to_string(),
+                    Err(e) => format!("failed: {e}"),
+                };
+                return Err(WriteDenied {
+                    plugin: plugin.name.clone(),
+                    key,
+                    term: term.to_string(),
+                    reason,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::sync::Arc;
+
+        use crate::storage::{Database, Key, SetFlagError};
+
+        use super::WasmPlugins;
+
+        /// Allows a flag only on records carrying none yet
+        const SINGLE_FLAG: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (global $next (mut i32) (i32.const 16))
+              (func (export "alloc") (param $len i32) (result i32)
+                (local $at i32)
+                (local.set $at (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $at))
+              (func (export "validate")
+                (param i64) (param i32) (param i32) (param i32) (param $flags_len i32)
+                (result i32)
+                (global.set $next (i32.const 16))
+                (i32.ne (local.get $flags_len) (i32.const 2))))
+        "#;
+
+        const SPINNING: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 16))
+              (func (export "validate")
+                (param i64) (param i32) (param i32) (param i32) (param i32)
+                (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0)))
+        "#;
+
+        fn plugin(name: &str, source: &str) -> std::path::PathBuf {
+            let path = std::env::temp_dir().join(format!("{name}-{}.wasm", std::process::id()));
+            std::fs::write(&path, wat::parse_str(source).unwrap()).unwrap();
+            path
+        }
+
+        #[test]
+        fn plugins_deny_writes() {
+            let single = plugin("single", SINGLE_FLAG);
+            let spinning = plugin("spinning", SPINNING);
+            let key = Key::try_from(1).unwrap();
+
+            let mut db = Database::<8>::default();
+            db.set_flag_validator(Arc::new(
+                WasmPlugins::load(std::slice::from_ref(&single)).unwrap(),
+            ));
+            assert_eq!(db.set_flag(key, "a"), Ok(true));
+            assert!(matches!(
+                db.set_flag(key, "b"),
+                Err(SetFlagError::Denied(denied)) if denied.reason == "denied"
+            ));
+            assert!(db.get_term_id("b").is_none());
+
+            let mut db = Database::<8>::default();
+            db.set_flag_validator(Arc::new(
+                WasmPlugins::load(std::slice::from_ref(&spinning)).unwrap(),
+            ));
+            assert!(matches!(
+                db.set_flag(key, "a"),
+                Err(SetFlagError::Denied(denied)) if denied.reason.starts_with("failed")
+            ));
+
+            std::fs::remove_file(single).unwrap();
+            std::fs::remove_file(spinning).unwrap();
+        }
+    }
+}
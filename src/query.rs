@@ -1,122 +1,901 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
-use serde::Deserialize;
+use rand::{seq::IteratorRandom, Rng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::smallset::SmallsetItem;
+use crate::{
+    deadline::Deadline,
+    latency::{Operation, Timed},
+    smallset::SmallsetItem,
+    stats::StorageClass,
+    storage::{RecordRef, StorageCorruption, TermId},
+    term_groups::GroupMatch,
+    Database, Key,
+};
 
-use super::{Database, Key};
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    /// Query refers to unknown terms, term ids or groups
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
 
-#[derive(Clone, Debug, Deserialize)]
+impl From<String> for QueryError {
+    fn from(message: String) -> Self {
+        Self::Invalid(message)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Query {
-    Simple { term: String },
-    KofN { terms: Vec<String>, bound: usize },
+    Simple {
+        term: String,
+    },
+    KofN {
+        terms: Vec<String>,
+        bound: usize,
+    },
+    /// Terms of a term group, any or all of them
+    Group {
+        group: String,
+        #[serde(default)]
+        mode: GroupMatch,
+    },
+}
+
+/// Inclusive bounds on keys matched by a vertical query. Records are not kept in key order, so
+/// the range only filters keys of a full scan: a narrow range costs as much as no range at all.
+/// Use `candidate_keys` to look up a few keys instead
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct KeyRange {
+    #[serde(
+        default,
+        with = "crate::keys::flexible_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub key_min: Option<Key>,
+    #[serde(
+        default,
+        with = "crate::keys::flexible_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub key_max: Option<Key>,
+}
+
+impl KeyRange {
+    pub fn contains(&self, key: Key) -> bool {
+        self.key_min.is_none_or(|min| key >= min) && self.key_max.is_none_or(|max| key <= max)
+    }
+}
+
+/// Query naming terms by id, for clients that keep the mapping of names to ids themselves
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum IdQuery {
+    Simple { term_id: TermId },
+    KofN { term_ids: Vec<TermId>, bound: usize },
+}
+
+impl IdQuery {
+    pub fn term_ids(&self) -> &[TermId] {
+        match self {
+            IdQuery::Simple { term_id } => std::slice::from_ref(term_id),
+            IdQuery::KofN { term_ids, .. } => term_ids,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FilteredQuery<Q = Query> {
+    #[serde(flatten)]
+    pub query: Q,
+    /// Filters matched keys, see [`KeyRange`] for why it does not narrow the scan
+    #[serde(flatten)]
+    pub range: KeyRange,
+    /// Evaluate only these keys, looking each up instead of scanning all records
+    #[serde(
+        default,
+        with = "crate::keys::flexible_option_vec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub candidate_keys: Option<Vec<Key>>,
+    /// Return this many matching keys picked uniformly at random instead of all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<usize>,
+    /// Scans stop early once it passes, leaving results incomplete
+    #[serde(skip)]
+    pub deadline: Deadline,
+}
+
+/// Normal quantile bounds of approximate counts are given for, 95% confidence
+const ESTIMATE_CONFIDENCE_Z: f64 = 1.96;
+
+/// Number of matching keys, for approximate counts with bounds it lies within at 95% confidence
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct CountEstimate {
+    pub count: usize,
+    pub lower: usize,
+    pub upper: usize,
+    pub approximate: bool,
+    /// Records query was evaluated on to make the estimate, zero for exact counts
+    pub sampled: usize,
+}
+
+impl CountEstimate {
+    fn exact(count: usize) -> Self {
+        Self {
+            count,
+            lower: count,
+            upper: count,
+            approximate: false,
+            sampled: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SimilarityMetric {
+    #[default]
+    Jaccard,
+    Overlap,
+}
+
+/// How flags of two keys differ, as reported by `/items/:key/diff/:other`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FlagDiff {
+    pub only_a: Vec<String>,
+    pub only_b: Vec<String>,
+    pub shared: Vec<String>,
+}
+
+impl FlagDiff {
+    /// Terms come out sorted by name
+    pub fn new(a: &HashSet<&str>, b: &HashSet<&str>) -> Self {
+        let sorted = |terms: &mut dyn Iterator<Item = &&str>| {
+            let mut terms: Vec<String> = terms.map(|term| term.to_string()).collect();
+            terms.sort_unstable();
+            terms
+        };
+        Self {
+            only_a: sorted(&mut a.difference(b)),
+            only_b: sorted(&mut b.difference(a)),
+            shared: sorted(&mut a.intersection(b)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SimilarKey {
+    #[serde(with = "crate::keys::flexible")]
+    pub key: Key,
+    pub score: f64,
 }
 
 impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
-    pub fn explain_term_id(&self, term_id: u8) -> Option<&'_ str> {
+    pub fn explain_term_id(&self, term_id: TermId) -> Option<&'_ str> {
         self.terms
             .get_backward(&term_id)
             .map(|entry| entry.as_str())
     }
 
-    pub fn horizontal_query(&self, key: &Key) -> Option<HashSet<&'_ str>> {
-        let location = self.index.get(key)?;
-        match location {
-            &super::storage::IndexLocation::Small(location) => {
-                let set = *self.get_smallset(location)?;
-                Some(
-                    set.iter()
-                        .filter_map(|item| self.explain_term_id(item))
-                        .collect(),
-                )
+    #[tracing::instrument(level = "debug", skip(self), fields(terms))]
+    pub fn horizontal_query(
+        &self,
+        key: &Key,
+    ) -> Result<Option<HashSet<&'_ str>>, StorageCorruption> {
+        let started = Instant::now();
+        let Some(record) = self.record(key)? else {
+            return Ok(None);
+        };
+        let terms = record
+            .term_ids()
+            .into_iter()
+            .filter_map(|item| self.explain_term_id(item))
+            .collect::<HashSet<_>>();
+        tracing::Span::current().record("terms", terms.len());
+        self.observe_latency(Operation::HorizontalQuery, key, started);
+        Ok(Some(terms))
+    }
+
+    /// For each of `keys`, which of `terms` it carries, None for keys that do not exist
+    pub fn filtered_multi_get<'t>(
+        &self,
+        keys: &[Key],
+        terms: &'t [String],
+    ) -> Result<Vec<Option<Vec<&'t str>>>, StorageCorruption> {
+        // unknown terms cannot be set on any key
+        let resolved = terms
+            .iter()
+            .filter_map(|term| {
+                let item = SmallsetItem::try_from(self.get_term_id(term)?).ok()?;
+                Some((term.as_str(), item))
+            })
+            .collect::<Vec<_>>();
+
+        keys.iter()
+            .map(|key| {
+                Ok(self.record(key)?.map(|record| {
+                    resolved
+                        .iter()
+                        .filter(|&&(_, item)| record.contains(item))
+                        .map(|&(term, _)| term)
+                        .collect()
+                }))
+            })
+            .collect()
+    }
+
+    /// Ids of terms `key` carries, None if key does not exist
+    pub fn record_term_ids(&self, key: &Key) -> Result<Option<Vec<TermId>>, StorageCorruption> {
+        Ok(self.record(key)?.map(|record| record.term_ids()))
+    }
+
+    /// Top `limit` keys sharing most terms with `key` according to `metric`, None if key does not exist
+    pub fn similar_keys(
+        &self,
+        key: &Key,
+        limit: usize,
+        metric: SimilarityMetric,
+    ) -> Result<Option<Vec<SimilarKey>>, StorageCorruption> {
+        let Some(probe) = self.record_term_ids(key)? else {
+            return Ok(None);
+        };
+        let probe_items = probe
+            .iter()
+            .filter_map(|&item| SmallsetItem::try_from(item).ok())
+            .collect::<Vec<_>>();
+
+        let score = |overlap: usize, other_size: usize| match metric {
+            SimilarityMetric::Jaccard => {
+                overlap as f64 / (probe.len() + other_size - overlap) as f64
             }
-            super::storage::IndexLocation::Big => Some(
-                self.big_storage
-                    .get(key)?
-                    .iter()
-                    .cloned()
-                    .filter_map(|item| self.explain_term_id(item))
-                    .collect(),
-            ),
+            SimilarityMetric::Overlap => overlap as f64,
+        };
+
+        let mut candidates = vec![];
+        for record in self.records() {
+            let (other, record) = record?;
+            let overlap = probe_items
+                .iter()
+                .filter(|&&item| record.contains(item))
+                .count();
+            if other != *key && overlap > 0 {
+                candidates.push(SimilarKey {
+                    key: other,
+                    score: score(overlap, record.size()),
+                });
+            }
+        }
+
+        candidates.sort_unstable_by(|a, b| b.score.total_cmp(&a.score).then(a.key.cmp(&b.key)));
+        candidates.truncate(limit);
+        Ok(Some(candidates))
+    }
+
+    pub fn vertical_query(&self, query: &Query) -> Result<Vec<Key>, QueryError> {
+        self.vertical_query_until(query, Deadline::default())
+    }
+
+    /// Scan stops early once `deadline` passes, leaving result incomplete
+    pub fn vertical_query_until(
+        &self,
+        query: &Query,
+        deadline: Deadline,
+    ) -> Result<Vec<Key>, QueryError> {
+        let resolved = self.resolve(query)?;
+        Ok(self.vertical_query_in_range(resolved, &KeyRange::default(), None, None, deadline)?)
+    }
+
+    /// Candidate keys are reported in ascending order, the ones that do not exist are skipped
+    pub fn filtered_vertical_query(&self, query: &FilteredQuery) -> Result<Vec<Key>, QueryError> {
+        let resolved = self.resolve(&query.query)?;
+        self.filtered_resolved_query(resolved, query)
+    }
+
+    /// Same as [`Self::filtered_vertical_query`] with terms given by id
+    pub fn filtered_vertical_query_by_id(
+        &self,
+        query: &FilteredQuery<IdQuery>,
+    ) -> Result<Vec<Key>, QueryError> {
+        let resolved = self.resolve_ids(&query.query)?;
+        self.filtered_resolved_query(resolved, query)
+    }
+
+    fn filtered_resolved_query<Q>(
+        &self,
+        resolved: ResolvedQuery,
+        query: &FilteredQuery<Q>,
+    ) -> Result<Vec<Key>, QueryError> {
+        let candidates = query.candidate_keys.as_ref().map(|keys| {
+            let mut keys = keys.clone();
+            keys.sort_unstable();
+            keys.dedup();
+            keys
+        });
+        Ok(self.vertical_query_in_range(
+            resolved,
+            &query.range,
+            candidates.as_deref(),
+            query.sample,
+            query.deadline,
+        )?)
+    }
+
+    /// Records a query has to evaluate, only those of `candidates` when given
+    fn scanned_records<'a>(
+        &'a self,
+        candidates: Option<&'a [Key]>,
+    ) -> Box<dyn Iterator<Item = Result<(Key, RecordRef<'a, SMALLSIZE>), StorageCorruption>> + 'a>
+    {
+        match candidates {
+            Some(keys) => Box::new(keys.iter().filter_map(|&key| {
+                let record = self.record(&key).transpose()?;
+                Some(record.map(|record| (key, record)))
+            })),
+            None => Box::new(self.records()),
         }
     }
 
-    pub fn vertical_query(&self, query: &Query) -> Result<Vec<Key>, String> {
-        match query {
-            Query::Simple { term } => {
-                let Some(term_id) = self.get_term_id(term) else {
-                    return Err(format!("unknown term {}", term));
-                };
-                Ok(self.simple_vertical_query(term_id.try_into().unwrap()))
+    fn resolve(&self, query: &Query) -> Result<ResolvedQuery, String> {
+        let resolve_term = |term: &String| {
+            self.get_term_id(term)
+                .map(|term_id| term_id.try_into().unwrap())
+        };
+        Ok(match query {
+            Query::Simple { term } => ResolvedQuery::Simple(
+                resolve_term(term).ok_or_else(|| format!("unknown term {}", term))?,
+            ),
+            Query::KofN { terms, bound } => ResolvedQuery::KofN {
+                terms: terms
+                    .iter()
+                    .map(|term| resolve_term(term).ok_or_else(|| term.clone()))
+                    .collect::<Result<_, _>>()?,
+                bound: *bound,
+            },
+            Query::Group { group, mode } => {
+                let terms: Vec<_> = self
+                    .term_group_ids(group)
+                    .ok_or_else(|| format!("unknown term group {group}"))?
+                    .into_iter()
+                    .map(|term_id| term_id.try_into().unwrap())
+                    .collect();
+                ResolvedQuery::KofN {
+                    bound: match mode {
+                        GroupMatch::Any => 1,
+                        GroupMatch::All => terms.len(),
+                    },
+                    terms,
+                }
             }
-            Query::KofN { terms, bound } => {
-                let resolved_terms = terms
+        })
+    }
+
+    fn resolve_ids(&self, query: &IdQuery) -> Result<ResolvedQuery, String> {
+        let resolve_id = |term_id: TermId| {
+            SmallsetItem::try_from(term_id)
+                .ok()
+                .filter(|_| self.terms.contains_backward(&term_id))
+                .ok_or_else(|| format!("unknown term id {term_id}"))
+        };
+        Ok(match query {
+            IdQuery::Simple { term_id } => ResolvedQuery::Simple(resolve_id(*term_id)?),
+            IdQuery::KofN { term_ids, bound } => ResolvedQuery::KofN {
+                terms: term_ids
                     .iter()
-                    .map(|term| self.get_term_id(term).ok_or(term))
-                    .map(|term_idx| term_idx.map(|term| term.try_into().unwrap()))
-                    .collect::<Result<Vec<_>, &String>>()?;
+                    .map(|&term_id| resolve_id(term_id))
+                    .collect::<Result<_, _>>()?,
+                bound: *bound,
+            },
+        })
+    }
+
+    #[tracing::instrument(
+        name = "vertical_query",
+        level = "debug",
+        skip_all,
+        fields(terms, columnar = self.columns.is_some(), candidates, sample)
+    )]
+    fn vertical_query_in_range(
+        &self,
+        resolved: ResolvedQuery,
+        range: &KeyRange,
+        candidates: Option<&[Key]>,
+        sample: Option<usize>,
+        deadline: Deadline,
+    ) -> Result<Vec<Key>, StorageCorruption> {
+        let span = tracing::Span::current();
+        span.record("terms", resolved.term_count());
+        let matching = self.matching_keys(resolved, range, candidates, deadline);
+        let result = match sample {
+            Some(size) => {
+                span.record("sample", size);
+                reservoir_sample(matching, size)?
+            }
+            None => matching.collect::<Result<_, _>>()?,
+        };
+        span.record("candidates", result.len());
+        Ok(result)
+    }
 
-                Ok(self.k_of_n_query(&resolved_terms, *bound))
+    /// Number of keys matching `query` within range that carry each term, terms absent from the
+    /// result are omitted
+    pub fn facet_counts(
+        &self,
+        query: &Query,
+        within: &KeyRange,
+        deadline: Deadline,
+    ) -> Result<HashMap<&'_ str, usize>, QueryError> {
+        let resolved = self.resolve(query)?;
+        let mut counts = HashMap::<TermId, usize>::new();
+        let matching = self.vertical_query_in_range(resolved, within, None, None, deadline)?;
+        for key in deadline.bound(matching.into_iter()) {
+            for term_id in self.record_term_ids(&key)?.unwrap_or_default() {
+                *counts.entry(term_id).or_default() += 1;
             }
         }
+
+        Ok(counts
+            .into_iter()
+            .filter_map(|(term_id, count)| Some((self.explain_term_id(term_id)?, count)))
+            .collect())
     }
 
-    fn simple_vertical_query(&self, term_id: SmallsetItem) -> Vec<Key> {
-        self.small_keys
-            .iter()
-            .zip(self.small_storage.iter())
-            .filter_map(|(key, set)| {
-                let &Some(key) = key else {
-                    return None;
-                };
-                if set.contains(term_id) {
-                    Some(key)
-                } else {
-                    None
+    /// Evaluate several queries against the same state, optionally spreading them over a thread pool
+    pub fn vertical_query_batch(
+        &self,
+        queries: &[Query],
+        parallel: bool,
+        deadline: Deadline,
+    ) -> Vec<Result<Vec<Key>, QueryError>> {
+        if parallel {
+            queries
+                .par_iter()
+                .map(|query| self.vertical_query_until(query, deadline))
+                .collect()
+        } else {
+            queries
+                .iter()
+                .map(|query| self.vertical_query_until(query, deadline))
+                .collect()
+        }
+    }
+
+    /// Matching keys, produced lazily unless answered from columns
+    fn matching_keys<'a>(
+        &'a self,
+        query: ResolvedQuery,
+        range: &'a KeyRange,
+        candidates: Option<&'a [Key]>,
+        deadline: Deadline,
+    ) -> Box<dyn Iterator<Item = Result<Key, StorageCorruption>> + 'a> {
+        if candidates.is_none() {
+            let columnar = match &query {
+                ResolvedQuery::Simple(term_id) => self.columnar_simple_query(*term_id, range),
+                ResolvedQuery::KofN { terms, bound } => {
+                    self.columnar_k_of_n_query(terms, *bound, range)
                 }
+            };
+            if let Some(keys) = columnar {
+                return Box::new(keys.into_iter().map(Ok));
+            }
+        }
+        let Some(candidates) = candidates else {
+            let small_query = query.clone();
+            let small = deadline
+                .bound(self.small_records())
+                .filter_map(move |(key, record)| {
+                    (range.contains(key) && small_query.matches(&record)).then_some(Ok(key))
+                });
+            let big = deadline
+                .bound(self.big_records())
+                .filter_map(move |record| query.matching_key(record, range));
+            let histogram = |class| self.latencies.get(Operation::VerticalScan, class);
+            return Box::new(
+                Timed::new(small, histogram(StorageClass::Small))
+                    .chain(Timed::new(big, histogram(StorageClass::Big))),
+            );
+        };
+        Box::new(
+            deadline
+                .bound(self.scanned_records(Some(candidates)))
+                .filter_map(move |record| query.matching_key(record, range)),
+        )
+    }
+
+    /// Number of keys matching `query`, its `sample` is ignored. With `sample_size` given and
+    /// more records than that stored, query is evaluated on that many records picked at random
+    /// and the count extrapolated, unless answering exactly is cheap anyway because columns are
+    /// maintained or candidate keys are listed
+    pub fn count_matching(
+        &self,
+        query: &FilteredQuery,
+        sample_size: Option<usize>,
+    ) -> Result<CountEstimate, QueryError> {
+        let resolved = self.resolve(&query.query)?;
+        let slots = self.small.keys.len()
+            + self.tier16.keys.len()
+            + self.tier32.keys.len()
+            + self.tier64.keys.len();
+        let sample_size = sample_size.filter(|&size| {
+            size > 0 && size < slots && query.candidate_keys.is_none() && self.columns.is_none()
+        });
+        let Some(sample_size) = sample_size else {
+            let mut candidates = query.candidate_keys.clone();
+            if let Some(keys) = &mut candidates {
+                keys.sort_unstable();
+                keys.dedup();
+            }
+            let count = self
+                .matching_keys(
+                    resolved,
+                    &query.range,
+                    candidates.as_deref(),
+                    query.deadline,
+                )
+                .try_fold(0, |count, key| key.map(|_| count + 1))?;
+            return Ok(CountEstimate::exact(count));
+        };
+
+        // big records are few, count them exactly and sample slots of the tiers
+        let big = self
+            .big_records()
+            .filter_map(|record| resolved.matching_key(record, &query.range))
+            .try_fold(0, |count, key| key.map(|_| count + 1))?;
+        let mut rng = rand::thread_rng();
+        let sampled = rand::seq::index::sample(&mut rng, slots, sample_size).into_iter();
+        let hits = query
+            .deadline
+            .bound(sampled)
+            .filter(|&slot| {
+                self.slot_record(slot).is_some_and(|(key, record)| {
+                    query.range.contains(key) && resolved.matches(&record)
+                })
             })
-            .chain(self.big_storage.iter().filter_map(|(&key, set)| {
-                if set.contains(&term_id.into()) {
-                    Some(key)
-                } else {
-                    None
-                }
-            }))
-            .collect()
+            .count();
+
+        // Wilson score interval, which stays sensible when hardly any sampled slot matches
+        let (n, z) = (sample_size as f64, ESTIMATE_CONFIDENCE_Z);
+        let p = hits as f64 / n;
+        let denominator = 1.0 + z * z / n;
+        let center = (p + z * z / (2.0 * n)) / denominator;
+        let margin = z / denominator * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+        let scale =
+            |fraction: f64| big + (fraction.clamp(0.0, 1.0) * slots as f64).round() as usize;
+        Ok(CountEstimate {
+            count: scale(p),
+            lower: scale(center - margin),
+            upper: scale(center + margin),
+            approximate: true,
+            sampled: sample_size,
+        })
+    }
+
+    /// Record stored in given slot of the tiers taken one after another, None for holes
+    fn slot_record(&self, slot: usize) -> Option<(Key, RecordRef<'_, SMALLSIZE>)> {
+        let mut slot = slot;
+        if slot < self.small.keys.len() {
+            return Some((
+                self.small.keys[slot]?,
+                RecordRef::Small(&self.small.sets[slot]),
+            ));
+        }
+        slot -= self.small.keys.len();
+        if slot < self.tier16.keys.len() {
+            return Some((
+                self.tier16.keys[slot]?,
+                RecordRef::Tier16(&self.tier16.sets[slot]),
+            ));
+        }
+        slot -= self.tier16.keys.len();
+        if slot < self.tier32.keys.len() {
+            return Some((
+                self.tier32.keys[slot]?,
+                RecordRef::Tier32(&self.tier32.sets[slot]),
+            ));
+        }
+        slot -= self.tier32.keys.len();
+        Some((
+            self.tier64.keys.get(slot).copied()??,
+            RecordRef::Tier64(&self.tier64.sets[slot]),
+        ))
+    }
+
+    /// Up to `size` keys picked uniformly at random, in ascending order
+    pub fn sample_keys(&self, size: usize) -> Vec<Key> {
+        let mut keys = self
+            .index
+            .keys()
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), size);
+        keys.sort_unstable();
+        keys
+    }
+}
+
+/// Query with term names replaced by ids
+#[derive(Clone)]
+enum ResolvedQuery {
+    Simple(SmallsetItem<TermId>),
+    KofN {
+        terms: Vec<SmallsetItem<TermId>>,
+        bound: usize,
+    },
+}
+
+impl ResolvedQuery {
+    fn term_count(&self) -> usize {
+        match self {
+            ResolvedQuery::Simple(_) => 1,
+            ResolvedQuery::KofN { terms, .. } => terms.len(),
+        }
+    }
+
+    fn matches<const SMALLSIZE: usize>(&self, record: &RecordRef<'_, SMALLSIZE>) -> bool {
+        match self {
+            ResolvedQuery::Simple(term_id) => record.contains(*term_id),
+            ResolvedQuery::KofN { terms, bound } => record.count_contained(terms) >= *bound,
+        }
+    }
+
+    /// Key of scanned record if it matches within `range`, None for records that do not
+    fn matching_key<const SMALLSIZE: usize>(
+        &self,
+        record: Result<(Key, RecordRef<'_, SMALLSIZE>), StorageCorruption>,
+        range: &KeyRange,
+    ) -> Option<Result<Key, StorageCorruption>> {
+        match record {
+            Ok((key, record)) => (range.contains(key) && self.matches(&record)).then_some(Ok(key)),
+            Err(e) => Some(Err(e)),
+        }
     }
+}
+
+/// Pick `size` of `keys` uniformly at random in a single pass, reported in ascending order
+fn reservoir_sample(
+    keys: impl Iterator<Item = Result<Key, StorageCorruption>>,
+    size: usize,
+) -> Result<Vec<Key>, StorageCorruption> {
+    let mut rng = rand::thread_rng();
+    let mut reservoir = Vec::with_capacity(size.min(1024));
+    for (seen, key) in keys.enumerate() {
+        let key = key?;
+        if reservoir.len() < size {
+            reservoir.push(key);
+        } else {
+            let slot = rng.gen_range(0..=seen);
+            if slot < size {
+                reservoir[slot] = key;
+            }
+        }
+    }
+    reservoir.sort_unstable();
+    Ok(reservoir)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deadline::Deadline,
+        storage::{Database, Key, SetFlagError},
+    };
+
+    use super::{FilteredQuery, FlagDiff, IdQuery, KeyRange, Query, SimilarityMetric};
+
+    #[test]
+    fn facets_count_terms_of_matching_keys() {
+        let mut db = Database::<8>::default();
+        let (a, b, c) = (
+            Key::try_from(1).unwrap(),
+            Key::try_from(2).unwrap(),
+            Key::try_from(3).unwrap(),
+        );
+
+        db.set_flag(a, "x").unwrap();
+        db.set_flag(a, "y").unwrap();
+        db.set_flag(b, "x").unwrap();
+        db.set_flag(b, "z").unwrap();
+        db.set_flag(c, "z").unwrap();
 
-    fn k_of_n_query(&self, terms: &[SmallsetItem], bound: usize) -> Vec<Key> {
-        self.small_keys
+        let facets = db
+            .facet_counts(
+                &Query::Simple {
+                    term: "x".to_string(),
+                },
+                &KeyRange::default(),
+                Deadline::default(),
+            )
+            .unwrap();
+        assert_eq!(facets.get("x"), Some(&2));
+        assert_eq!(facets.get("y"), Some(&1));
+        assert_eq!(facets.get("z"), Some(&1));
+    }
+
+    #[test]
+    fn filtered_multi_get_reports_requested_terms_only() {
+        let mut db = Database::<8>::default();
+        let (a, b, missing) = (
+            Key::try_from(1).unwrap(),
+            Key::try_from(2).unwrap(),
+            Key::try_from(3).unwrap(),
+        );
+        for term in ["x", "y", "z"] {
+            db.set_flag(a, term).unwrap();
+        }
+        db.set_flag(b, "y").unwrap();
+
+        let terms = ["x", "y", "unknown"].map(String::from);
+        assert_eq!(
+            db.filtered_multi_get(&[a, b, missing], &terms).unwrap(),
+            vec![Some(vec!["x", "y"]), Some(vec!["y"]), None]
+        );
+    }
+
+    #[test]
+    fn key_range_restricts_vertical_query() {
+        let mut db = Database::<8>::default();
+        for key in 1..=5 {
+            db.set_flag(Key::try_from(key).unwrap(), "x").unwrap();
+        }
+
+        let query: FilteredQuery =
+            serde_json::from_str(r#"{"type": "Simple", "term": "x", "key_min": 2, "key_max": 4}"#)
+                .unwrap();
+        let mut result = db.filtered_vertical_query(&query).unwrap();
+        result.sort();
+        assert_eq!(
+            result,
+            [2, 3, 4].map(|key| Key::try_from(key).unwrap()).to_vec()
+        );
+    }
+
+    #[test]
+    fn flags_are_set_and_queried_by_id() {
+        let mut db = Database::<8>::default();
+        let key = |key| Key::try_from(key).unwrap();
+        db.set_flag(key(1), "x").unwrap();
+        let y = db.add_term("y").unwrap().get();
+        assert!(db.set_flag_by_id(key(2), y).unwrap());
+        assert!(!db.set_flag_by_id(key(2), y).unwrap());
+        assert_eq!(
+            db.set_flag_by_id(key(2), 99),
+            Err(SetFlagError::UnknownTermId(99))
+        );
+        assert_eq!(db.record_term_ids(&key(2)).unwrap(), Some(vec![y]));
+
+        let query: FilteredQuery<IdQuery> = serde_json::from_str(&format!(
+            r#"{{"type": "KofN", "term_ids": [1, {y}], "bound": 1, "key_min": 2}}"#
+        ))
+        .unwrap();
+        assert_eq!(db.filtered_vertical_query_by_id(&query).unwrap(), [key(2)]);
+        let query: FilteredQuery<IdQuery> =
+            serde_json::from_str(r#"{"type": "Simple", "term_id": 99}"#).unwrap();
+        assert!(db.filtered_vertical_query_by_id(&query).is_err());
+    }
+
+    #[test]
+    fn candidate_keys_restrict_vertical_query() {
+        let mut db = Database::<8>::default();
+        for key in 1..=5 {
+            db.set_flag(Key::try_from(key).unwrap(), "x").unwrap();
+        }
+        db.set_flag(Key::try_from(6).unwrap(), "y").unwrap();
+        db.enable_term_columns().unwrap();
+
+        let query: FilteredQuery = serde_json::from_str(
+            r#"{"type": "KofN", "terms": ["x", "y"], "bound": 1, "candidate_keys": [6, 4, 9, 2, 4], "key_max": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            db.filtered_vertical_query(&query).unwrap(),
+            [2, 4].map(|key| Key::try_from(key).unwrap()).to_vec()
+        );
+    }
+
+    #[test]
+    fn sample_picks_requested_number_of_matching_keys() {
+        let mut db = Database::<8>::default();
+        for key in 1..=100 {
+            let term = if key % 2 == 0 { "even" } else { "odd" };
+            db.set_flag(Key::try_from(key).unwrap(), term).unwrap();
+        }
+
+        let sample = |size: usize| {
+            let query: FilteredQuery = serde_json::from_str(&format!(
+                r#"{{"type": "Simple", "term": "even", "key_min": 11, "sample": {size}}}"#
+            ))
+            .unwrap();
+            db.filtered_vertical_query(&query).unwrap()
+        };
+        let picked = sample(10);
+        assert_eq!(picked.len(), 10);
+        assert!(picked.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(picked
             .iter()
-            .zip(self.small_storage.iter())
-            .filter_map(|(key, set)| {
-                let &Some(key) = key else {
-                    return None;
-                };
-                let mut total = 0;
-                for &item in terms {
-                    if set.contains(item) {
-                        total += 1;
-                    }
-                    if total >= bound {
-                        return Some(key);
-                    }
-                }
-                None
-            })
-            .chain(self.big_storage.iter().filter_map(|(&key, set)| {
-                let mut total = 0;
-                for item in terms {
-                    if set.contains(&u8::from(*item)) {
-                        total += 1;
-                    }
-                    if total >= bound {
-                        return Some(key);
-                    }
-                }
-                None
-            }))
-            .collect()
+            .all(|&key| u64::from(key) % 2 == 0 && u64::from(key) > 10));
+        assert_eq!(sample(1000).len(), 45);
+    }
+
+    #[test]
+    fn approximate_count_bounds_exact_count() {
+        let mut db = Database::<8>::default();
+        for key in 1..=20_000 {
+            let term = if key % 4 == 0 { "quarter" } else { "rest" };
+            db.set_flag(Key::try_from(key).unwrap(), term).unwrap();
+        }
+        let query: FilteredQuery =
+            serde_json::from_str(r#"{"type": "KofN", "terms": ["quarter"], "bound": 1}"#).unwrap();
+
+        let exact = db.count_matching(&query, None).unwrap();
+        assert_eq!((exact.count, exact.approximate), (5000, false));
+        let estimate = db.count_matching(&query, Some(2000)).unwrap();
+        assert!(estimate.approximate);
+        assert_eq!(estimate.sampled, 2000);
+        assert!(estimate.lower <= estimate.count && estimate.count <= estimate.upper);
+        assert!(estimate.lower > 4000 && estimate.upper < 6000);
+        assert!(!db.count_matching(&query, Some(50_000)).unwrap().approximate);
+    }
+
+    #[test]
+    fn flag_diff_splits_terms_of_two_keys() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in ["x", "y", "z"] {
+            db.set_flag(a, term).unwrap();
+        }
+        for term in ["z", "w", "x"] {
+            db.set_flag(b, term).unwrap();
+        }
+
+        let diff = FlagDiff::new(
+            &db.horizontal_query(&a).unwrap().unwrap(),
+            &db.horizontal_query(&b).unwrap().unwrap(),
+        );
+        assert_eq!(diff.only_a, ["y"]);
+        assert_eq!(diff.only_b, ["w"]);
+        assert_eq!(diff.shared, ["x", "z"]);
+    }
+
+    #[test]
+    fn similar_keys_are_ranked_by_jaccard() {
+        let mut db = Database::<8>::default();
+        let (a, b, c) = (
+            Key::try_from(1).unwrap(),
+            Key::try_from(2).unwrap(),
+            Key::try_from(3).unwrap(),
+        );
+
+        for term in ["x", "y", "z"] {
+            db.set_flag(a, term).unwrap();
+        }
+        for term in ["x", "y"] {
+            db.set_flag(b, term).unwrap();
+        }
+        for term in ["x", "q", "w", "e"] {
+            db.set_flag(c, term).unwrap();
+        }
+
+        let result = db
+            .similar_keys(&a, 10, SimilarityMetric::Jaccard)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            result.iter().map(|item| item.key).collect::<Vec<_>>(),
+            vec![b, c]
+        );
+        assert_eq!(
+            db.similar_keys(&a, 1, SimilarityMetric::Overlap)
+                .unwrap()
+                .unwrap()[0]
+                .key,
+            b
+        );
     }
 }
@@ -0,0 +1,376 @@
+//! Cache of vertical query results for `POST /query`
+//!
+//! Queries are keyed with their terms resolved into canonical names, so that a query spelled with
+//! aliases or listing terms in another order shares an entry. Entries are dropped as soon as a
+//! flag of one of their terms changes, changes to term names or whole records clear the cache.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{
+    changes::Change,
+    query::{FilteredQuery, Query, QueryError},
+    storage::{Database, Key},
+    term_groups::GroupMatch,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    terms: Vec<String>,
+    bound: usize,
+    key_min: Option<Key>,
+    key_max: Option<Key>,
+}
+
+impl CacheKey {
+    /// Whether records without any of the query terms match, so that new records change result
+    fn matches_empty(&self) -> bool {
+        self.bound == 0
+    }
+}
+
+struct CachedResult {
+    keys: Arc<[Key]>,
+    /// Canonical names and aliases of terms the query involves
+    names: HashSet<String>,
+    /// Prefixes of exclusive groups of these terms, terms added with them are involved as well
+    prefixes: Vec<String>,
+    used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CachedResult>,
+    /// Entries by last use, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn remove_where(&mut self, stale: impl Fn(&CacheKey, &CachedResult) -> bool) -> usize {
+        let before = self.entries.len();
+        let recency = &mut self.recency;
+        self.entries.retain(|key, result| {
+            let keep = !stale(key, result);
+            if !keep {
+                recency.remove(&result.used);
+            }
+            keep
+        });
+        before - self.entries.len()
+    }
+}
+
+/// Hit and miss counts since start, as reported by `/stats`
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct QueryCacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for new ones
+    pub evictions: u64,
+    /// Entries dropped because data they were computed from changed
+    pub invalidations: u64,
+}
+
+/// Least recently used vertical query results, kept consistent by observing change feed
+pub struct QueryCache {
+    state: Mutex<CacheState>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::default(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Result of `query`, evaluated only if not cached. Must be called while holding database read
+    /// lock, so that no change is recorded between evaluating query and caching its result.
+    /// Sampled queries and queries restricted to candidate keys are always evaluated
+    pub fn query<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        query: &FilteredQuery,
+    ) -> Result<Arc<[Key]>, QueryError> {
+        let cache_key = match normalize(db, query) {
+            Some(cache_key) if self.capacity > 0 => cache_key,
+            _ => return db.filtered_vertical_query(query).map(Arc::from),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(cached) = state.entries.get_mut(&cache_key) {
+            let previous = std::mem::replace(&mut cached.used, tick);
+            let keys = cached.keys.clone();
+            state.recency.remove(&previous);
+            state.recency.insert(tick, cache_key);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(keys);
+        }
+        drop(state);
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let keys: Arc<[Key]> = db.filtered_vertical_query(query)?.into();
+        // scan may have been cut short
+        if query.deadline.expired() {
+            return Ok(keys);
+        }
+        let names = names_involved(db, &cache_key.terms);
+        let term_ids = cache_key
+            .terms
+            .iter()
+            .filter_map(|term| db.get_term_id(term))
+            .collect::<Vec<_>>();
+        let prefixes = db
+            .exclusive_prefixes(&term_ids)
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let mut state = self.state.lock().unwrap();
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        state.recency.insert(tick, cache_key.clone());
+        let replaced = state.entries.insert(
+            cache_key,
+            CachedResult {
+                keys: keys.clone(),
+                names,
+                prefixes,
+                used: tick,
+            },
+        );
+        if let Some(replaced) = replaced {
+            state.recency.remove(&replaced.used);
+        }
+        Ok(keys)
+    }
+
+    /// Drop entries `change` may have made stale, called while database write lock is held
+    pub fn observe(&self, change: &Change) {
+        let mut state = self.state.lock().unwrap();
+        let invalidated = match change {
+            Change::SetFlag { term, .. } | Change::UnsetFlag { term, .. } => {
+                state.remove_where(|key, result| {
+                    result.names.contains(term)
+                        || result.prefixes.iter().any(|prefix| term.starts_with(prefix))
+                        || key.matches_empty()
+                })
+            }
+            Change::CreateRecord { .. } => state.remove_where(|key, _| key.matches_empty()),
+            Change::RenameTerm { .. }
+            | Change::MergeTerm { .. }
+            | Change::AddAlias { .. }
+            | Change::RemoveAlias { .. }
+            // exclusive groups and triggers decide which names involved in a query can change
+            // its result
+            | Change::SetTermGroup { .. }
+            | Change::RemoveTermGroup { .. }
+            | Change::SetTrigger { .. }
+            | Change::RemoveTrigger { .. }
+            | Change::DeleteRecord { .. }
+            | Change::RestoreRecord { .. } => state.remove_where(|_, _| true),
+            Change::AddTerm { .. }
+            | Change::AddTermWithId { .. }
+            | Change::SetKeyAlias { .. }
+            | Change::RemoveKeyAlias { .. }
+            | Change::PurgeRecord { .. } => 0,
+        };
+        self.invalidations
+            .fetch_add(invalidated as u64, Ordering::Relaxed);
+    }
+
+    /// State was replaced wholesale, nothing cached can be trusted
+    pub fn invalidate_all(&self) {
+        let invalidated = self.state.lock().unwrap().remove_where(|_, _| true);
+        self.invalidations
+            .fetch_add(invalidated as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            capacity: self.capacity,
+            entries: self.state.lock().unwrap().entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn canonical_names<const SMALLSIZE: usize>(
+    db: &Database<SMALLSIZE>,
+    terms: &[String],
+) -> Option<Vec<String>> {
+    terms
+        .iter()
+        .map(|term| {
+            let term_id = db.get_term_id(term)?;
+            db.explain_term_id(term_id).map(String::from)
+        })
+        .collect()
+}
+
+/// Key query is cached under, `None` if it cannot be cached or names a term that does not exist
+fn normalize<const SMALLSIZE: usize>(
+    db: &Database<SMALLSIZE>,
+    query: &FilteredQuery,
+) -> Option<CacheKey> {
+    if query.candidate_keys.is_some() || query.sample.is_some() {
+        return None;
+    }
+    let (mut terms, bound) = match &query.query {
+        Query::Simple { term } => (canonical_names(db, std::slice::from_ref(term))?, 1),
+        Query::KofN { terms, bound } => (canonical_names(db, terms)?, *bound),
+        // members of groups with prefixes change as terms are added
+        Query::Group { group, .. } if !db.term_group_prefixes(group)?.is_empty() => return None,
+        // keyed by current members, so that changing the group does not have to invalidate
+        Query::Group { group, mode } => {
+            let terms = db
+                .term_group(group)?
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            let bound = match mode {
+                GroupMatch::Any => 1,
+                GroupMatch::All => terms.len(),
+            };
+            (terms, bound)
+        }
+    };
+    terms.sort_unstable();
+    Some(CacheKey {
+        terms,
+        bound,
+        key_min: query.range.key_min,
+        key_max: query.range.key_max,
+    })
+}
+
+fn names_involved<const SMALLSIZE: usize>(
+    db: &Database<SMALLSIZE>,
+    terms: &[String],
+) -> HashSet<String> {
+    let mut term_ids = terms
+        .iter()
+        .filter_map(|term| db.get_term_id(term))
+        .collect::<HashSet<_>>();
+    // setting flag of a term in exclusive group clears flags of the other terms in it
+    let companions = term_ids
+        .iter()
+        .flat_map(|&term_id| db.exclusive_companions(term_id))
+        .collect::<Vec<_>>();
+    term_ids.extend(companions);
+    // and setting flag of a term sets flags of terms its triggers set
+    let sources = term_ids
+        .iter()
+        .flat_map(|&term_id| db.trigger_sources(term_id))
+        .collect::<Vec<_>>();
+    term_ids.extend(sources);
+    db.aliases
+        .iter()
+        .filter(|(_, term_id)| term_ids.contains(term_id))
+        .map(|(alias, _)| alias.clone())
+        .chain(
+            term_ids
+                .iter()
+                .filter_map(|&term_id| db.explain_term_id(term_id))
+                .map(String::from),
+        )
+        .chain(terms.iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        changes::Change,
+        query::FilteredQuery,
+        storage::{Database, Key},
+    };
+
+    use super::QueryCache;
+
+    #[test]
+    fn cached_results_follow_changes() {
+        let mut db = Database::<8>::default();
+        let key = |key| Key::try_from(key).unwrap();
+        db.set_flag(key(1), "x").unwrap();
+        db.set_flag(key(2), "y").unwrap();
+        db.add_alias("x", "ex").unwrap();
+        let cache = QueryCache::new(2);
+        let query = |json: &str| serde_json::from_str::<FilteredQuery>(json).unwrap();
+
+        let x = query(r#"{"type": "Simple", "term": "x"}"#);
+        let xy = query(r#"{"type": "KofN", "terms": ["y", "ex"], "bound": 1}"#);
+        let yx = query(r#"{"type": "KofN", "terms": ["x", "y"], "bound": 1}"#);
+        assert_eq!(&*cache.query(&db, &x).unwrap(), [key(1)]);
+        assert_eq!(&*cache.query(&db, &xy).unwrap(), [key(1), key(2)]);
+        assert_eq!(&*cache.query(&db, &yx).unwrap(), [key(1), key(2)]);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 2));
+
+        db.set_flag(key(3), "ex").unwrap();
+        cache.observe(&Change::SetFlag {
+            key: key(3),
+            term: "ex".to_string(),
+        });
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(&*cache.query(&db, &x).unwrap(), [key(1), key(3)]);
+
+        cache.query(&db, &xy).unwrap();
+        cache
+            .query(&db, &query(r#"{"type": "Simple", "term": "y"}"#))
+            .unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        assert!(cache
+            .query(&db, &query(r#"{"type": "Simple", "term": "z"}"#))
+            .is_err());
+    }
+
+    #[test]
+    fn setting_exclusive_companion_invalidates_result() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "open").unwrap();
+        db.add_term("closed").unwrap();
+        db.set_term_group("status", &["open".to_string(), "closed".to_string()], true)
+            .unwrap();
+        let cache = QueryCache::new(2);
+        let open = serde_json::from_str(r#"{"type": "Simple", "term": "open"}"#).unwrap();
+        assert_eq!(&*cache.query(&db, &open).unwrap(), [key]);
+
+        db.set_flag(key, "closed").unwrap();
+        cache.observe(&Change::SetFlag {
+            key,
+            term: "closed".to_string(),
+        });
+        assert!(cache.query(&db, &open).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+//! Limits on number of keys and flags, in total and within namespaces
+//!
+//! Namespaces are named key ranges, such as the ones tenants of a shared deployment are given.
+//! Usage is counted as records change so that checking a write stays cheap, and counted again
+//! from scratch after changes that rewrite many records at once.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query::KeyRange,
+    storage::{Database, Key},
+};
+
+/// Contents of `--quotas` file, limits left out are not enforced
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quotas {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_flags_per_key: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_flags: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<NamespaceQuota>,
+}
+
+/// Limits on keys within `range`, namespaces may overlap
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamespaceQuota {
+    pub name: String,
+    #[serde(flatten)]
+    pub range: KeyRange,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_flags: Option<usize>,
+}
+
+impl Quotas {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub struct QuotaExceeded {
+    /// Field of [`Quotas`] or [`NamespaceQuota`] that would be exceeded
+    pub quota: &'static str,
+    pub namespace: Option<String>,
+    pub limit: usize,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.namespace {
+            Some(namespace) => write!(
+                f,
+                "quota {} of namespace {namespace} would be exceeded, limit is {}",
+                self.quota, self.limit
+            ),
+            None => write!(
+                f,
+                "quota {} would be exceeded, limit is {}",
+                self.quota, self.limit
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Usage {
+    pub keys: usize,
+    pub flags: usize,
+}
+
+/// Quotas along with what they currently count, as reported by `/stats`
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaUtilization {
+    pub quotas: Quotas,
+    pub total: Usage,
+    pub namespaces: HashMap<String, Usage>,
+}
+
+/// Quotas enforced by storage and usage they are checked against
+#[derive(Clone, Debug, Default)]
+pub(crate) struct QuotaState {
+    quotas: Quotas,
+    flags: usize,
+    namespaces: Vec<Usage>,
+    /// Set while applying writes that were checked as a whole beforehand
+    suspended: bool,
+}
+
+impl QuotaState {
+    fn namespaces_of(&self, key: Key) -> impl Iterator<Item = usize> + '_ {
+        self.quotas
+            .namespaces
+            .iter()
+            .enumerate()
+            .filter(move |(_, namespace)| namespace.range.contains(key))
+            .map(|(i, _)| i)
+    }
+}
+
+fn check(
+    quota: &'static str,
+    namespace: Option<&str>,
+    limit: Option<usize>,
+    used: usize,
+    added: isize,
+) -> Result<(), QuotaExceeded> {
+    match limit {
+        Some(limit) if added > 0 && used.saturating_add_signed(added) > limit => {
+            Err(QuotaExceeded {
+                quota,
+                namespace: namespace.map(String::from),
+                limit,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Start enforcing `quotas`, counting current usage. Contents already over quota are kept
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = Some(QuotaState {
+            namespaces: vec![Usage::default(); quotas.namespaces.len()],
+            quotas,
+            ..Default::default()
+        });
+        self.recount_quota_usage();
+    }
+
+    pub fn quota_utilization(&self) -> Option<QuotaUtilization> {
+        let state = self.quotas.as_ref()?;
+        Some(QuotaUtilization {
+            quotas: state.quotas.clone(),
+            total: Usage {
+                keys: self.index.len(),
+                flags: state.flags,
+            },
+            namespaces: state
+                .quotas
+                .namespaces
+                .iter()
+                .zip(&state.namespaces)
+                .map(|(namespace, &usage)| (namespace.name.clone(), usage))
+                .collect(),
+        })
+    }
+
+    pub(crate) fn recount_quota_usage(&mut self) {
+        let Some(mut state) = self.quotas.take() else {
+            return;
+        };
+        state.flags = 0;
+        state.namespaces.fill(Usage::default());
+        for key in self.index.keys().copied() {
+            let flags = self.record_size(&key).unwrap_or_default();
+            state.flags += flags;
+            for i in state.namespaces_of(key).collect::<Vec<_>>() {
+                state.namespaces[i].keys += 1;
+                state.namespaces[i].flags += flags;
+            }
+        }
+        self.quotas = Some(state);
+    }
+
+    /// Fails if records of keys growing to given numbers of flags would exceed any quota.
+    /// Keys that do not exist yet are counted as created
+    pub(crate) fn check_quota_growth(
+        &self,
+        growth: impl IntoIterator<Item = (Key, usize)>,
+    ) -> Result<(), QuotaExceeded> {
+        let Some(state) = self.quotas.as_ref().filter(|state| !state.suspended) else {
+            return Ok(());
+        };
+        let quotas = &state.quotas;
+        let (mut added_keys, mut added_flags) = (0, 0);
+        let mut namespaces = vec![(0, 0); state.namespaces.len()];
+        for (key, flags) in growth {
+            let current = self.record_size(&key);
+            if current.is_none_or(|current| flags > current) {
+                check(
+                    "max_flags_per_key",
+                    None,
+                    quotas.max_flags_per_key,
+                    0,
+                    flags as isize,
+                )?;
+            }
+            let (keys, flags) = (
+                current.is_none() as isize,
+                flags as isize - current.unwrap_or(0) as isize,
+            );
+            added_keys += keys;
+            added_flags += flags;
+            for i in state.namespaces_of(key) {
+                namespaces[i].0 += keys;
+                namespaces[i].1 += flags;
+            }
+        }
+        check(
+            "max_keys",
+            None,
+            quotas.max_keys,
+            self.index.len(),
+            added_keys,
+        )?;
+        check(
+            "max_total_flags",
+            None,
+            quotas.max_total_flags,
+            state.flags,
+            added_flags,
+        )?;
+        for ((namespace, usage), (keys, flags)) in quotas
+            .namespaces
+            .iter()
+            .zip(&state.namespaces)
+            .zip(namespaces)
+        {
+            let name = Some(namespace.name.as_str());
+            check("max_keys", name, namespace.max_keys, usage.keys, keys)?;
+            check("max_flags", name, namespace.max_flags, usage.flags, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Keep usage up to date with `keys` and `flags` added to record of `key`
+    pub(crate) fn count_quota_usage(&mut self, key: Key, keys: isize, flags: isize) {
+        let Some(state) = &mut self.quotas else {
+            return;
+        };
+        state.flags = state.flags.saturating_add_signed(flags);
+        for i in state.namespaces_of(key).collect::<Vec<_>>() {
+            let usage = &mut state.namespaces[i];
+            usage.keys = usage.keys.saturating_add_signed(keys);
+            usage.flags = usage.flags.saturating_add_signed(flags);
+        }
+    }
+
+    /// Run `write` without enforcing quotas, for writes whose outcome was checked as a whole
+    pub(crate) fn with_quotas_suspended<R>(&mut self, write: impl FnOnce(&mut Self) -> R) -> R {
+        if let Some(state) = &mut self.quotas {
+            state.suspended = true;
+        }
+        let result = write(self);
+        if let Some(state) = &mut self.quotas {
+            state.suspended = false;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        query::KeyRange,
+        storage::{Database, Key, SetFlagError},
+    };
+
+    use super::{NamespaceQuota, Quotas};
+
+    #[test]
+    fn writes_past_quotas_are_rejected() {
+        let mut db = Database::<8>::default();
+        let key = |key| Key::try_from(key).unwrap();
+        db.set_flag(key(1), "x").unwrap();
+        db.set_quotas(Quotas {
+            max_keys: Some(3),
+            max_flags_per_key: Some(2),
+            namespaces: vec![NamespaceQuota {
+                name: "tenant".to_string(),
+                range: KeyRange {
+                    key_min: Some(key(10)),
+                    key_max: None,
+                },
+                max_keys: None,
+                max_flags: Some(3),
+            }],
+            ..Default::default()
+        });
+
+        db.set_flag(key(1), "y").unwrap();
+        let exceeded = |result| match result {
+            Err(SetFlagError::QuotaExceeded(e)) => e.quota,
+            other => panic!("expected quota to be exceeded, got {other:?}"),
+        };
+        assert_eq!(exceeded(db.set_flag(key(1), "z")), "max_flags_per_key");
+        assert!(db.set_flag(key(1), "y").is_ok());
+
+        db.set_flag(key(10), "x").unwrap();
+        db.set_flag(key(10), "y").unwrap();
+        db.set_flag(key(11), "x").unwrap();
+        assert_eq!(exceeded(db.set_flag(key(11), "y")), "max_flags");
+        db.unset_flag(key(10), "y").unwrap();
+        db.set_flag(key(11), "y").unwrap();
+        assert!(db.create_record(key(2)).is_err());
+
+        let usage = db.quota_utilization().unwrap();
+        assert_eq!((usage.total.keys, usage.total.flags), (3, 5));
+        assert_eq!(usage.namespaces["tenant"].flags, 3);
+    }
+}
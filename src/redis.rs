@@ -0,0 +1,356 @@
+//! Minimal Redis client pulling sets out of a Redis instance for import
+//!
+//! Speaks just enough RESP2 to walk keys with `SCAN` and read their members with `SMEMBERS`.
+//! Every set becomes an import row, member names becoming terms.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+};
+
+use crate::jobs::JobHandle;
+
+/// Keys requested per `SCAN` round trip
+const SCAN_COUNT: usize = 1000;
+
+/// Where to import sets from, as accepted by `POST /bulk/import/redis`
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedisSource {
+    /// `redis://[:password@]host[:port][/db]`
+    pub url: String,
+    /// Glob selecting keys to import, as understood by `SCAN MATCH`
+    #[serde(default = "default_pattern")]
+    pub pattern: String,
+    /// Stripped from Redis key names, the rest has to be an elizadb key
+    #[serde(default)]
+    pub key_prefix: String,
+}
+
+fn default_pattern() -> String {
+    "*".to_string()
+}
+
+impl RedisSource {
+    /// Check url up front, so that typos are reported before import starts
+    pub fn validate(&self) -> Result<(), RedisError> {
+        Target::parse(&self.url).map(|_| ())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedisError {
+    #[error("invalid redis url {0}")]
+    InvalidUrl(String),
+    #[error("failed to talk to redis: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("redis replied with error: {0}")]
+    Server(String),
+    #[error("unexpected reply from redis: {0}")]
+    Protocol(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Reply {
+    /// Status or integer, whose value is never needed
+    Status,
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn into_bulk(self) -> Result<Vec<u8>, RedisError> {
+        match self {
+            Reply::Bulk(Some(bytes)) => Ok(bytes),
+            other => Err(RedisError::Protocol(format!(
+                "expected string, got {other:?}"
+            ))),
+        }
+    }
+
+    fn into_array(self) -> Result<Vec<Reply>, RedisError> {
+        match self {
+            Reply::Array(items) => Ok(items),
+            other => Err(RedisError::Protocol(format!(
+                "expected array, got {other:?}"
+            ))),
+        }
+    }
+}
+
+/// Connection address and credentials from `redis://` url
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Target {
+    address: String,
+    password: Option<String>,
+    db: Option<u32>,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Self, RedisError> {
+        let invalid = || RedisError::InvalidUrl(url.to_string());
+        let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, None),
+            Some((authority, db)) => (authority, Some(db.parse().map_err(|_| invalid())?)),
+            None => (rest, None),
+        };
+        let (password, host) = match authority.rsplit_once('@') {
+            Some((credentials, host)) => {
+                let password = credentials.rsplit(':').next().unwrap_or(credentials);
+                (Some(password.to_string()), host)
+            }
+            None => (None, authority),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:6379")
+        };
+        Ok(Self {
+            address,
+            password,
+            db,
+        })
+    }
+}
+
+struct Connection {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: BufWriter<tokio::net::tcp::OwnedWriteHalf>,
+}
+
+impl Connection {
+    async fn open(target: &Target) -> Result<Self, RedisError> {
+        let (reader, writer) = TcpStream::connect(&target.address).await?.into_split();
+        let mut connection = Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        };
+        if let Some(password) = &target.password {
+            connection.command(&["AUTH", password]).await?;
+        }
+        if let Some(db) = target.db {
+            connection.command(&["SELECT", &db.to_string()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&mut self, args: &[&str]) -> Result<Reply, RedisError> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend(format!("${}\r\n", arg.len()).into_bytes());
+            request.extend(arg.as_bytes());
+            request.extend(b"\r\n");
+        }
+        self.writer.write_all(&request).await?;
+        self.writer.flush().await?;
+        self.read_reply().await
+    }
+
+    async fn read_line(&mut self) -> Result<String, RedisError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(RedisError::Protocol("connection closed".to_string()));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, RedisError> {
+        // replies nest only as deep as SCAN's [cursor, [keys]], an explicit stack is not worth it
+        let line = self.read_line().await?;
+        let length = |value: &str| {
+            value
+                .parse::<i64>()
+                .map_err(|_| RedisError::Protocol(line.clone()))
+        };
+        match line.split_at_checked(1) {
+            Some(("+" | ":", _)) => Ok(Reply::Status),
+            Some(("-", message)) => Err(RedisError::Server(message.to_string())),
+            Some(("$", value)) => {
+                let Ok(size) = usize::try_from(length(value)?) else {
+                    return Ok(Reply::Bulk(None));
+                };
+                let mut bytes = vec![0; size + 2];
+                self.reader.read_exact(&mut bytes).await?;
+                bytes.truncate(size);
+                Ok(Reply::Bulk(Some(bytes)))
+            }
+            Some(("*", value)) => {
+                let count = usize::try_from(length(value)?).unwrap_or(0);
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    items.push(Box::pin(self.read_reply()).await?);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => Err(RedisError::Protocol(line.clone())),
+        }
+    }
+}
+
+/// Import row written to spool
+#[derive(Serialize)]
+struct SpooledRow<'a> {
+    key: &'a str,
+    terms: Vec<String>,
+}
+
+/// Write every set matching `source` into `spool` as import rows, calling `fetched` with number of
+/// sets written so far after every `SCAN` batch. Keys that are not sets are skipped
+pub async fn spool_sets(
+    source: &RedisSource,
+    spool: &Path,
+    job: &JobHandle,
+    mut fetched: impl FnMut(usize),
+) -> Result<(), RedisError> {
+    let mut connection = Connection::open(&Target::parse(&source.url)?).await?;
+    let mut output = BufWriter::new(tokio::fs::File::create(spool).await?);
+    let count = SCAN_COUNT.to_string();
+    let mut cursor = "0".to_string();
+    let mut written = 0;
+    loop {
+        let args = [
+            "SCAN",
+            &cursor,
+            "MATCH",
+            &source.pattern,
+            "COUNT",
+            &count,
+            "TYPE",
+            "set",
+        ];
+        let mut reply = connection.command(&args).await?.into_array()?.into_iter();
+        let (Some(next), Some(keys), None) = (reply.next(), reply.next(), reply.next()) else {
+            return Err(RedisError::Protocol("SCAN reply is not a pair".to_string()));
+        };
+        cursor = String::from_utf8_lossy(&next.into_bulk()?).into_owned();
+
+        for name in keys.into_array()? {
+            let name = String::from_utf8_lossy(&name.into_bulk()?).into_owned();
+            let members = connection.command(&["SMEMBERS", &name]).await?;
+            let terms = members
+                .into_array()?
+                .into_iter()
+                .map(|member| Ok(String::from_utf8_lossy(&member.into_bulk()?).into_owned()))
+                .collect::<Result<_, RedisError>>()?;
+            let row = SpooledRow {
+                key: name.strip_prefix(&source.key_prefix).unwrap_or(&name),
+                terms,
+            };
+            let mut line = serde_json::to_vec(&row).map_err(std::io::Error::other)?;
+            line.push(b'\n');
+            output.write_all(&line).await?;
+            written += 1;
+        }
+        fetched(written);
+
+        if cursor == "0" || job.is_cancelled() {
+            break;
+        }
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::jobs::{JobKind, Jobs};
+
+    use super::{spool_sets, RedisSource, Target};
+
+    fn bulk(value: &str) -> String {
+        format!("${}\r\n{value}\r\n", value.len())
+    }
+
+    fn array(items: &[String]) -> String {
+        format!("*{}\r\n{}", items.len(), items.concat())
+    }
+
+    /// Serve a single connection, answering SCAN in two batches
+    async fn fake_redis(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(header) = lines.next_line().await.unwrap() {
+            let count: usize = header[1..].parse().unwrap();
+            let mut args = vec![];
+            for _ in 0..count {
+                lines.next_line().await.unwrap();
+                args.push(lines.next_line().await.unwrap().unwrap());
+            }
+            let reply = match (args[0].as_str(), args[1].as_str()) {
+                ("SCAN", "0") => array(&[bulk("7"), array(&[bulk("user:1"), bulk("user:2")])]),
+                ("SCAN", _) => array(&[bulk("0"), array(&[bulk("user:3")])]),
+                ("SMEMBERS", "user:2") => array(&[]),
+                ("SMEMBERS", _) => array(&[bulk("red"), bulk("round")]),
+                _ => "-ERR unknown command\r\n".to_string(),
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn sets_are_spooled_as_import_rows() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(fake_redis(listener));
+        let source = RedisSource {
+            url: format!("redis://{address}"),
+            pattern: "user:*".to_string(),
+            key_prefix: "user:".to_string(),
+        };
+        let spool = std::env::temp_dir().join(format!("elizadb-redis-{}", std::process::id()));
+        let job = Arc::new(Jobs::default()).start_cancellable(JobKind::Import);
+
+        let mut batches = vec![];
+        spool_sets(&source, &spool, &job, |fetched| batches.push(fetched))
+            .await
+            .unwrap();
+
+        assert_eq!(batches, [2, 3]);
+        let rows = std::fs::read_to_string(&spool).unwrap();
+        assert_eq!(
+            rows.lines().collect::<Vec<_>>(),
+            [
+                r#"{"key":"1","terms":["red","round"]}"#,
+                r#"{"key":"2","terms":[]}"#,
+                r#"{"key":"3","terms":["red","round"]}"#,
+            ]
+        );
+        std::fs::remove_file(spool).unwrap();
+    }
+
+    #[test]
+    fn urls_are_parsed_into_targets() {
+        assert_eq!(
+            Target::parse("redis://localhost").unwrap(),
+            Target {
+                address: "localhost:6379".to_string(),
+                password: None,
+                db: None,
+            }
+        );
+        assert_eq!(
+            Target::parse("redis://:secret@10.0.0.1:7000/2").unwrap(),
+            Target {
+                address: "10.0.0.1:7000".to_string(),
+                password: Some("secret".to_string()),
+                db: Some(2),
+            }
+        );
+        assert!(Target::parse("http://localhost").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Settings that can be changed while running, through `POST /admin/config` or by sending
+//! `SIGHUP` to re-read the file given with `--reload-file`
+//!
+//! The file holds the same JSON object the endpoint accepts, fields left out keep their value.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{api::ConcurrencyLimits, snapshots::Snapshotter, telemetry::LogFilter};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    /// Log filter in `RUST_LOG` syntax
+    pub log_level: Option<String>,
+    /// Zero disables periodic snapshots
+    pub snapshot_interval_secs: Option<u64>,
+    pub read_only: Option<bool>,
+    /// Zero lifts the limit, same for the other concurrency limits
+    pub max_concurrent_reads: Option<usize>,
+    pub max_concurrent_writes: Option<usize>,
+    pub max_concurrent_admin: Option<usize>,
+}
+
+/// Current values of reloadable settings
+#[derive(Clone, Debug, Serialize)]
+pub struct RuntimeConfig {
+    pub log_level: String,
+    pub snapshot_interval_secs: Option<u64>,
+    pub read_only: bool,
+    pub max_concurrent_reads: Option<usize>,
+    pub max_concurrent_writes: Option<usize>,
+    pub max_concurrent_admin: Option<usize>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("invalid log level {0:?}: {1}")]
+    InvalidLogLevel(String, String),
+    #[error("no reload file is configured")]
+    NoReloadFile,
+    #[error("failed to read reload file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse reload file: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+#[derive(Clone)]
+pub struct Reloader {
+    log_filter: LogFilter,
+    snapshotter: Snapshotter,
+    read_only: Arc<AtomicBool>,
+    concurrency: ConcurrencyLimits,
+    file: Option<PathBuf>,
+}
+
+impl Reloader {
+    pub fn new(
+        log_filter: LogFilter,
+        snapshotter: Snapshotter,
+        read_only: Arc<AtomicBool>,
+        concurrency: ConcurrencyLimits,
+        file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            log_filter,
+            snapshotter,
+            read_only,
+            concurrency,
+            file,
+        }
+    }
+
+    pub fn current(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            log_level: self.log_filter.directives(),
+            snapshot_interval_secs: self.snapshotter.interval().map(|period| period.as_secs()),
+            read_only: self.read_only.load(Ordering::Relaxed),
+            max_concurrent_reads: self.concurrency.reads.get(),
+            max_concurrent_writes: self.concurrency.writes.get(),
+            max_concurrent_admin: self.concurrency.admin.get(),
+        }
+    }
+
+    /// Apply every given setting, or none of them if log level is invalid
+    pub fn apply(&self, update: ConfigUpdate) -> Result<RuntimeConfig, ReloadError> {
+        if let Some(directives) = &update.log_level {
+            self.log_filter
+                .set(directives)
+                .map_err(|e| ReloadError::InvalidLogLevel(directives.clone(), e.to_string()))?;
+        }
+        if let Some(secs) = update.snapshot_interval_secs {
+            self.snapshotter
+                .set_interval((secs > 0).then(|| Duration::from_secs(secs)));
+        }
+        if let Some(read_only) = update.read_only {
+            self.read_only.store(read_only, Ordering::Relaxed);
+        }
+        let limits = [
+            (update.max_concurrent_reads, &self.concurrency.reads),
+            (update.max_concurrent_writes, &self.concurrency.writes),
+            (update.max_concurrent_admin, &self.concurrency.admin),
+        ];
+        for (update, limit) in limits {
+            if let Some(max) = update {
+                limit.set((max > 0).then_some(max));
+            }
+        }
+        Ok(self.current())
+    }
+
+    pub async fn reload_file(&self) -> Result<RuntimeConfig, ReloadError> {
+        let path = self.file.as_ref().ok_or(ReloadError::NoReloadFile)?;
+        let contents = tokio::fs::read(path).await?;
+        self.apply(serde_json::from_slice(&contents)?)
+    }
+}
+
+/// Re-read reload file every time process receives `SIGHUP`
+#[cfg(unix)]
+pub async fn reload_on_sighup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match reloader.reload_file().await {
+            Ok(config) => tracing::info!(?config, "reloaded configuration"),
+            Err(e) => tracing::error!("failed to reload configuration: {e}"),
+        }
+    }
+}
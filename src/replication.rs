@@ -0,0 +1,138 @@
+use std::{sync::Arc, time::Duration};
+
+use reqwest::StatusCode;
+use tokio::task::JoinHandle;
+
+use crate::{
+    api::DBState, changes::ChangeBatch, query_cache::QueryCache, storage::Database, views::Views,
+};
+
+/// Header carrying sequence number of the first change not included in snapshot
+pub static SNAPSHOT_SEQ_HEADER: &str = "x-elizadb-seq";
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const POLL_WAIT_MS: u64 = 30_000;
+
+type ReplicationError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Warm standby following leader until it is promoted to take writes itself
+pub struct Standby {
+    leader: String,
+    task: JoinHandle<()>,
+}
+
+impl Standby {
+    /// Start following leader, must be called within tokio runtime
+    pub fn start(
+        leader: String,
+        db: DBState,
+        views: Arc<Views>,
+        query_cache: Option<Arc<QueryCache>>,
+    ) -> Self {
+        let task = tokio::spawn(follow(leader.clone(), db, views, query_cache));
+        Self { leader, task }
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    /// Stop applying leader changes. Batch being applied is either applied whole or not at all,
+    /// since it is applied without yielding
+    pub fn promote(self) -> String {
+        self.task.abort();
+        self.leader
+    }
+}
+
+/// Keep `db`, `views` and `query_cache` in sync with leader forever, re-bootstrapping from
+/// snapshot whenever the feed is lost
+pub async fn follow(
+    leader: String,
+    db: DBState,
+    views: Arc<Views>,
+    query_cache: Option<Arc<QueryCache>>,
+) {
+    let client = reqwest::Client::new();
+    let leader = leader.trim_end_matches('/');
+    loop {
+        if let Err(e) = replicate(&client, leader, &db, &views, query_cache.as_deref()).await {
+            tracing::warn!(leader, "replication interrupted: {e}");
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn replicate(
+    client: &reqwest::Client,
+    leader: &str,
+    db: &DBState,
+    views: &Views,
+    query_cache: Option<&QueryCache>,
+) -> Result<(), ReplicationError> {
+    let mut next_seq = bootstrap(client, leader, db, views, query_cache).await?;
+
+    loop {
+        let response = client
+            .get(format!("{leader}/replication/changes"))
+            .query(&[("since", next_seq), ("wait_ms", POLL_WAIT_MS)])
+            .send()
+            .await?;
+        if response.status() == StatusCode::GONE {
+            return Err("fell behind leader change feed".into());
+        }
+        let batch: ChangeBatch = response.error_for_status()?.json().await?;
+
+        if !batch.changes.is_empty() {
+            let mut db = db.write().await;
+            for change in batch.changes {
+                change.change.apply(&mut db)?;
+                views.observe(&change.change);
+                if let Some(query_cache) = query_cache {
+                    query_cache.observe(&change.change);
+                }
+            }
+        }
+        next_seq = batch.next;
+    }
+}
+
+async fn bootstrap(
+    client: &reqwest::Client,
+    leader: &str,
+    db: &DBState,
+    views: &Views,
+    query_cache: Option<&QueryCache>,
+) -> Result<u64, ReplicationError> {
+    let (state, seq) = fetch_snapshot(client, leader).await?;
+    let mut db = db.write().await;
+    db.replace_with(state)?;
+    views.invalidate_all();
+    if let Some(query_cache) = query_cache {
+        query_cache.invalidate_all();
+    }
+    Ok(seq)
+}
+
+/// Download and decode snapshot of peer state, returning it with sequence of the first change it lacks
+pub async fn fetch_snapshot<const SMALLSIZE: usize>(
+    client: &reqwest::Client,
+    peer: &str,
+) -> Result<(Database<SMALLSIZE>, u64), ReplicationError> {
+    let peer = peer.trim_end_matches('/');
+    let response = client
+        .get(format!("{peer}/admin/snapshot/stream"))
+        .send()
+        .await?
+        .error_for_status()?;
+    let seq = response
+        .headers()
+        .get(SNAPSHOT_SEQ_HEADER)
+        .ok_or("snapshot response has no sequence header")?
+        .to_str()?
+        .parse()?;
+    let body = response.bytes().await?;
+
+    let state = Database::load(&mut body.as_ref())?;
+    Ok((state, seq))
+}
@@ -0,0 +1,135 @@
+//! Snapshot uploads to S3-compatible object storage, for nodes without persistent disks
+//!
+//! Uploaded objects are slot files as written next to the data file, named after upload time so
+//! that listing them in lexical order also orders them by age.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    buffered::BufWriter,
+    path::Path as ObjectPath,
+    ObjectStore,
+};
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+
+const OBJECT_PREFIX: &str = "snapshot-";
+
+const OBJECT_SUFFIX: &str = ".elizadb";
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3Error {
+    #[error("snapshot target must look like s3://bucket/prefix, got {0}")]
+    InvalidUrl(String),
+    #[error("object storage request failed: {0}")]
+    Store(#[from] object_store::Error),
+    #[error("failed to transfer snapshot: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub struct S3Target {
+    store: Arc<AmazonS3>,
+    prefix: ObjectPath,
+    retention: usize,
+}
+
+impl S3Target {
+    /// Credentials, region and endpoint are taken from the usual `AWS_*` environment variables
+    pub fn new(url: &str, retention: usize) -> Result<Self, S3Error> {
+        let location = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| S3Error::InvalidUrl(url.to_string()))?;
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(S3Error::InvalidUrl(url.to_string()));
+        }
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+        Ok(Self {
+            store: Arc::new(store),
+            prefix: ObjectPath::from(prefix),
+            retention: retention.max(1),
+        })
+    }
+
+    /// Uploaded snapshots ordered from oldest to newest
+    async fn list(&self) -> Result<Vec<ObjectPath>, S3Error> {
+        let mut listing = self.store.list(Some(&self.prefix));
+        let mut objects = vec![];
+        while let Some(meta) = listing.next().await {
+            let location = meta?.location;
+            let is_snapshot = location.filename().is_some_and(|name| {
+                name.starts_with(OBJECT_PREFIX) && name.ends_with(OBJECT_SUFFIX)
+            });
+            if is_snapshot {
+                objects.push(location);
+            }
+        }
+        objects.sort_unstable();
+        Ok(objects)
+    }
+
+    /// Stream file at `path` into a new object, then delete uploads beyond retention limit
+    pub async fn upload(&self, path: &Path) -> Result<ObjectPath, S3Error> {
+        let uploaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let object = self
+            .prefix
+            .child(format!("{OBJECT_PREFIX}{uploaded_at:020}{OBJECT_SUFFIX}"));
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut writer = BufWriter::new(self.store.clone(), object.clone());
+        tokio::io::copy(&mut file, &mut writer).await?;
+        writer.shutdown().await?;
+
+        let objects = self.list().await?;
+        for expired in &objects[..objects.len().saturating_sub(self.retention)] {
+            self.store.delete(expired).await?;
+        }
+        Ok(object)
+    }
+
+    /// Download newest uploaded snapshot into `path`, false if nothing was uploaded yet
+    pub async fn download_newest(&self, path: &Path) -> Result<bool, S3Error> {
+        let Some(newest) = self.list().await?.pop() else {
+            return Ok(false);
+        };
+        let mut contents = self.store.get(&newest).await?.into_stream();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".download");
+        let mut file = tokio::fs::File::create(&partial).await?;
+        while let Some(chunk) = contents.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(partial, path).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{S3Error, S3Target};
+
+    #[test]
+    fn target_url_must_name_bucket() {
+        assert!(matches!(
+            S3Target::new("s3:///prefix", 5),
+            Err(S3Error::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            S3Target::new("gs://bucket", 5),
+            Err(S3Error::InvalidUrl(_))
+        ));
+        let target = S3Target::new("s3://bucket/nested/prefix", 0).unwrap();
+        assert_eq!(target.prefix.as_ref(), "nested/prefix");
+        assert_eq!(target.retention, 1);
+    }
+}
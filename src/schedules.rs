@@ -0,0 +1,365 @@
+//! Queries run on a fixed interval with their results posted to a webhook
+//!
+//! Every schedule runs in its own task, started when it is registered or loaded on startup and
+//! aborted when it is replaced or removed. Definitions are kept in a JSON file next to the data
+//! file, run status only in memory.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{api::DBState, keys::ApiKey, query::FilteredQuery, triggers::is_webhook_url};
+
+/// How long webhook may take to accept results before run is reported failed
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    /// Every matching key
+    #[default]
+    Keys,
+    /// Number of matching keys and how it changed since previous run
+    Count,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub query: FilteredQuery,
+    pub interval_secs: u64,
+    /// Results are POSTed here as JSON
+    pub webhook: String,
+    #[serde(default)]
+    pub payload: Payload,
+}
+
+/// Body posted to webhook
+#[derive(Clone, Debug, Serialize)]
+struct Delivery<'a> {
+    schedule: &'a str,
+    run_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<Vec<ApiKey>>,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RunStatus {
+    pub runs: u64,
+    pub last_run_at: Option<u64>,
+    pub last_count: Option<usize>,
+    /// Why last run failed, evaluating query or delivering its results
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScheduleInfo {
+    #[serde(flatten)]
+    pub schedule: Schedule,
+    pub status: RunStatus,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScheduleError {
+    #[error("interval must be at least one second")]
+    ZeroInterval,
+    #[error("webhook {0} is not an http or https url")]
+    InvalidWebhook(String),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+struct Entry {
+    schedule: Schedule,
+    status: Arc<Mutex<RunStatus>>,
+    task: JoinHandle<()>,
+}
+
+pub struct Schedules {
+    path: PathBuf,
+    db: DBState,
+    client: reqwest::Client,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Schedules {
+    /// Definitions are stored at `<data_file>.schedules`
+    pub fn path_for(data_file: &Path) -> PathBuf {
+        let mut path = data_file.as_os_str().to_owned();
+        path.push(".schedules");
+        path.into()
+    }
+
+    /// Load schedules and start running them, must be called within tokio runtime
+    pub fn open(path: PathBuf, db: DBState) -> std::io::Result<Self> {
+        let schedules: BTreeMap<String, Schedule> = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let this = Self {
+            path,
+            db,
+            client: reqwest::Client::new(),
+            entries: Mutex::default(),
+        };
+        let entries = schedules
+            .into_iter()
+            .map(|(name, schedule)| {
+                let entry = this.start(name.clone(), schedule);
+                (name, entry)
+            })
+            .collect();
+        *this.entries.lock().unwrap() = entries;
+        Ok(this)
+    }
+
+    fn persist(&self, entries: &BTreeMap<String, Entry>) -> std::io::Result<()> {
+        let schedules: BTreeMap<&String, &Schedule> = entries
+            .iter()
+            .map(|(name, entry)| (name, &entry.schedule))
+            .collect();
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&schedules)?)?;
+        std::fs::rename(partial, &self.path)
+    }
+
+    fn start(&self, name: String, schedule: Schedule) -> Entry {
+        let status = Arc::new(Mutex::new(RunStatus::default()));
+        let task = tokio::spawn(run_periodically(
+            name,
+            schedule.clone(),
+            self.db.clone(),
+            self.client.clone(),
+            status.clone(),
+        ));
+        Entry {
+            schedule,
+            status,
+            task,
+        }
+    }
+
+    pub fn list(&self) -> BTreeMap<String, ScheduleInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.info()))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<ScheduleInfo> {
+        self.entries.lock().unwrap().get(name).map(Entry::info)
+    }
+
+    pub fn validate(schedule: &Schedule) -> Result<(), ScheduleError> {
+        if schedule.interval_secs == 0 {
+            return Err(ScheduleError::ZeroInterval);
+        }
+        if !is_webhook_url(&schedule.webhook) {
+            return Err(ScheduleError::InvalidWebhook(schedule.webhook.clone()));
+        }
+        Ok(())
+    }
+
+    /// Register or replace schedule, returns whether it was newly created. Replaced schedule
+    /// starts over, first run happening one interval later
+    pub fn put(&self, name: String, schedule: Schedule) -> std::io::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = self.start(name.clone(), schedule);
+        let previous = entries.insert(name.clone(), entry);
+        if let Err(e) = self.persist(&entries) {
+            let failed = match previous {
+                Some(previous) => entries.insert(name, previous),
+                None => entries.remove(&name),
+            };
+            failed.into_iter().for_each(|entry| entry.task.abort());
+            return Err(e);
+        }
+        let created = previous.is_none();
+        previous.into_iter().for_each(|entry| entry.task.abort());
+        Ok(created)
+    }
+
+    /// Returns whether there was such schedule
+    pub fn remove(&self, name: &str) -> std::io::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(previous) = entries.remove(name) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.persist(&entries) {
+            entries.insert(name.to_string(), previous);
+            return Err(e);
+        }
+        previous.task.abort();
+        Ok(true)
+    }
+}
+
+impl Entry {
+    fn info(&self) -> ScheduleInfo {
+        ScheduleInfo {
+            schedule: self.schedule.clone(),
+            status: self.status.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Drop for Schedules {
+    fn drop(&mut self) {
+        for entry in self.entries.get_mut().unwrap().values() {
+            entry.task.abort();
+        }
+    }
+}
+
+async fn run_periodically(
+    name: String,
+    schedule: Schedule,
+    db: DBState,
+    client: reqwest::Client,
+    status: Arc<Mutex<RunStatus>>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(schedule.interval_secs));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let previous_count = status.lock().unwrap().last_count;
+        let run_at = now();
+        let result = run_once(&name, &schedule, &db, &client, run_at, previous_count).await;
+
+        let mut status = status.lock().unwrap();
+        status.runs += 1;
+        status.last_run_at = Some(run_at);
+        match result {
+            Ok(count) => {
+                status.last_count = Some(count);
+                status.last_error = None;
+            }
+            Err(e) => {
+                tracing::warn!(schedule = %name, "scheduled query failed: {e}");
+                status.last_error = Some(e);
+            }
+        }
+    }
+}
+
+/// Evaluate query and deliver results, returning number of matching keys
+async fn run_once(
+    name: &str,
+    schedule: &Schedule,
+    db: &DBState,
+    client: &reqwest::Client,
+    run_at: u64,
+    previous_count: Option<usize>,
+) -> Result<usize, String> {
+    let keys = db
+        .read()
+        .await
+        .filtered_vertical_query(&schedule.query)
+        .map_err(|e| e.to_string())?;
+    let count = keys.len();
+    let delivery = match schedule.payload {
+        Payload::Keys => Delivery {
+            schedule: name,
+            run_at,
+            keys: Some(keys.into_iter().map(ApiKey).collect()),
+            count,
+            previous_count: None,
+            delta: None,
+        },
+        Payload::Count => Delivery {
+            schedule: name,
+            run_at,
+            keys: None,
+            count,
+            previous_count,
+            delta: previous_count.map(|previous| count as i64 - previous as i64),
+        },
+    };
+
+    client
+        .post(&schedule.webhook)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(&delivery)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("delivering to {}: {e}", schedule.webhook))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{routing::post, Json, Router};
+    use tokio::sync::{mpsc, RwLock};
+
+    use crate::storage::{Database, Key};
+
+    use super::{Schedule, Schedules};
+
+    #[tokio::test]
+    async fn schedule_posts_count_deltas_to_webhook() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let webhook = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                sender.send(body).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, webhook).await });
+
+        let db = Arc::new(RwLock::new(Database::<8>::default()));
+        db.write()
+            .await
+            .set_flag(Key::try_from(1).unwrap(), "x")
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("elizadb-schedules-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let schedules = Schedules::open(path.clone(), db.clone()).unwrap();
+        let schedule: Schedule = serde_json::from_value(serde_json::json!({
+            "query": {"type": "Simple", "term": "x"},
+            "interval_secs": 1,
+            "webhook": format!("http://{address}/hook"),
+            "payload": "count",
+        }))
+        .unwrap();
+        Schedules::validate(&schedule).unwrap();
+        assert!(schedules.put("xs".to_string(), schedule).unwrap());
+
+        let wait = Duration::from_secs(5);
+        let first = tokio::time::timeout(wait, received.recv()).await.unwrap();
+        assert_eq!(first.unwrap()["count"], 1);
+        db.write()
+            .await
+            .set_flag(Key::try_from(2).unwrap(), "x")
+            .unwrap();
+        let second = tokio::time::timeout(wait, received.recv()).await.unwrap();
+        assert_eq!(second.unwrap()["delta"], 1);
+        assert!(schedules.get("xs").unwrap().status.runs >= 1);
+
+        assert!(schedules.remove("xs").unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
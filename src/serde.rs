@@ -1,123 +1,389 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::{BufReader, BufWriter, Read, Write},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{BufReader, Read, Write},
+    time::Instant,
 };
 
-use serde::{Deserialize, Serialize};
+use rayon::prelude::*;
+
+use serde::{
+    ser::{SerializeMap, SerializeSeq, SerializeStruct},
+    Deserialize, Serialize, Serializer,
+};
+
+pub mod delta;
+pub mod recovery;
+pub mod rotation;
 
 use crate::{
+    bigstore::{EncodedBitmap, TermBitmap},
+    config::BigRecordDecoding,
     doublemap::DoubleMap,
-    smallset::Smallset,
-    storage::{Database, IndexLocation, Key},
+    smallset::{SlotValue, Smallset},
+    soft_delete::DeletedRecord,
+    storage::{Database, IndexLocation, Key, SmallTier, StorageCorruption, TermId},
+    triggers::Trigger,
 };
 
-impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
-    fn from_existing_data(
-        terms: HashMap<String, u8>,
-        small_keys: Vec<Key>,
-        small_storage: Vec<Smallset<SMALLSIZE>>,
-        big_storage: HashMap<Key, HashSet<u8>>,
-    ) -> Self {
-        let index = Self::build_index(small_keys.as_ref(), &big_storage);
+/// Prefix of versioned snapshots. Legacy snapshots start with msgpack array marker instead
+const SNAPSHOT_MAGIC: &[u8; 3] = b"ELZ";
+
+/// Version 1 is the headerless format with u8 term ids, version 2 widened term ids to u16,
+/// version 3 added intermediate smallset tiers, version 4 stores big records as bitmaps,
+/// version 5 added key aliases, version 6 added deleted records, version 7 added explicit term ids,
+/// version 8 added term groups, version 9 added exclusive term groups, version 10 added triggers,
+/// version 11 added prefixes of term groups
+const FORMAT_VERSION: u8 = 11;
+
+/// Oldest headered version that can still be read, missing tiers are loaded as empty
+const MIN_FORMAT_VERSION: u8 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DumpError {
+    #[error("failed to write snapshot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode snapshot: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("failed to read snapshot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unsupported snapshot format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("failed to decode snapshot: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("failed to decode big record: {0}")]
+    BigRecord(std::io::Error),
+}
 
+impl<const SIZE: usize> SmallTier<SIZE> {
+    fn from_compact(tier: TierScheme<TermId, SIZE>) -> Self {
         Self {
-            terms: DoubleMap::try_from(terms).unwrap(),
-            index,
+            keys: tier.keys.into_iter().map(Option::Some).collect(),
+            sets: tier.storage,
             holes: Default::default(),
-            small_keys: small_keys.into_iter().map(Option::Some).collect(),
-            small_storage,
-            big_storage,
         }
     }
 
-    fn build_index(
-        small_keys: &[Key],
-        big_storage: &HashMap<Key, HashSet<u8>>,
-    ) -> HashMap<Key, IndexLocation> {
-        let mut result = HashMap::new();
-        for (i, key) in small_keys.iter().enumerate() {
-            result.insert(*key, IndexLocation::Small(i));
+    /// Tier encoded as `TierScheme` straight from its slots, skipping holes
+    fn scheme(&self) -> impl Serialize + '_ {
+        let len = self.keys().count();
+        TierSchemeRef {
+            keys: LazySeq {
+                len,
+                items: move || self.keys(),
+            },
+            storage: LazySeq {
+                len,
+                items: move || self.iter().map(|(_, set)| set),
+            },
         }
-        for key in big_storage.keys() {
-            result.insert(*key, IndexLocation::Big);
+    }
+
+    fn index_into(
+        &self,
+        index: &mut HashMap<Key, IndexLocation>,
+        location: impl Fn(usize) -> IndexLocation,
+    ) {
+        for (i, key) in self.keys.iter().enumerate() {
+            if let Some(key) = key {
+                index.insert(*key, location(i));
+            }
         }
+    }
+}
 
-        result
+/// Term table of `names` listed in id order. Formats before version 7 kept ids dense and left
+/// them out, so names are numbered by position unless every id is given
+pub(super) fn term_table(names: Vec<String>, ids: &[TermId]) -> DoubleMap<String, TermId> {
+    let mut terms = DoubleMap::new();
+    if ids.len() == names.len() {
+        for (name, &id) in names.into_iter().zip(ids) {
+            terms.insert(name, id);
+        }
+    } else {
+        for (i, name) in names.into_iter().enumerate() {
+            // i + 1 because 0 is used as nieche for NO_VALUE
+            terms.insert(name, (i + 1) as TermId);
+        }
     }
+    terms
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    fn from_existing_data(
+        terms: DoubleMap<String, TermId>,
+        serde: SerializationScheme<TermId, SMALLSIZE>,
+    ) -> Self {
+        let mut key_aliases = DoubleMap::new();
+        for (alias, key) in serde.key_aliases {
+            key_aliases.insert(alias, key);
+        }
 
-    fn compact_small_items(&self) -> (Vec<Key>, Vec<Smallset<SMALLSIZE>>) {
-        let (mut keys, mut values) = (
-            Vec::with_capacity(self.small_keys.len()),
-            Vec::with_capacity(self.small_keys.len()),
+        let last_term_id = terms
+            .iter()
+            .map(|(_, &id)| id)
+            .fold(serde.last_term_id, TermId::max);
+        let mut db = Self {
+            terms,
+            last_term_id,
+            evicted_terms: Default::default(),
+            aliases: serde.aliases,
+            key_aliases,
+            term_groups: serde
+                .term_groups
+                .into_iter()
+                .map(|(name, members)| (name, members.into_iter().collect()))
+                .collect(),
+            exclusive_term_groups: serde.exclusive_term_groups.into_iter().collect(),
+            term_group_prefixes: serde.term_group_prefixes,
+            triggers: serde.triggers,
+            index: Default::default(),
+            small: SmallTier::from_compact(TierScheme {
+                keys: serde.small_keys,
+                storage: serde.small_storage,
+            }),
+            tier16: SmallTier::from_compact(serde.tier16),
+            tier32: SmallTier::from_compact(serde.tier32),
+            tier64: SmallTier::from_compact(serde.tier64),
+            deleted: serde.deleted_records,
+            columns: None,
+            dirty: None,
+            term_eviction: Default::default(),
+            quotas: None,
+            versions: Default::default(),
+            latencies: Default::default(),
+            trigger_webhooks: None,
+            validator: None,
+            eviction_guard: None,
+            validation_suspended: false,
+            big_storage: serde
+                .big_storage
+                .into_iter()
+                .map(|(key, set)| (key, set.into_iter().collect()))
+                .chain(serde.big_records)
+                .collect::<HashMap<_, _>>()
+                .into(),
+        };
+        db.index = db.build_index();
+        db
+    }
+
+    fn from_scheme(mut serde: SerializationScheme<TermId, SMALLSIZE>) -> Self {
+        let terms = term_table(std::mem::take(&mut serde.terms), &serde.term_ids);
+        Self::from_existing_data(terms, serde)
+    }
+
+    /// Decode big records in parallel while the rest of state is assembled and indexed, or keep
+    /// them encoded if decoding is lazy. Returns time spent on each of the two
+    fn from_encoded(
+        mut serde: SerializationScheme<TermId, SMALLSIZE, EncodedBitmap>,
+        decoding: BigRecordDecoding,
+    ) -> Result<(Self, std::time::Duration, std::time::Duration), LoadError> {
+        let encoded = std::mem::take(&mut serde.big_records);
+        let big_keys = encoded.keys().copied().collect::<Vec<_>>();
+        if decoding == BigRecordDecoding::Lazy {
+            let started = Instant::now();
+            let mut db = Self::from_scheme(serde.with_big_records(HashMap::new()));
+            db.index
+                .extend(big_keys.into_iter().map(|key| (key, IndexLocation::Big)));
+            db.big_storage.lazy = encoded
+                .into_iter()
+                .map(|(key, bitmap)| Ok((key, bitmap.try_into()?)))
+                .collect::<Result<_, std::io::Error>>()
+                .map_err(LoadError::BigRecord)?;
+            return Ok((db, std::time::Duration::ZERO, started.elapsed()));
+        }
+        let ((big_records, decoding), (mut db, indexing)) = rayon::join(
+            || {
+                let started = Instant::now();
+                let big_records = encoded
+                    .into_par_iter()
+                    .map(|(key, bitmap)| Ok((key, bitmap.decode()?)))
+                    .collect::<Result<HashMap<_, _>, std::io::Error>>();
+                (big_records, started.elapsed())
+            },
+            || {
+                let started = Instant::now();
+                let mut db = Self::from_scheme(serde.with_big_records(HashMap::new()));
+                db.index
+                    .extend(big_keys.into_iter().map(|key| (key, IndexLocation::Big)));
+                (db, started.elapsed())
+            },
         );
+        db.big_storage
+            .hot
+            .extend(big_records.map_err(LoadError::BigRecord)?);
+        Ok((db, decoding, indexing))
+    }
 
-        for (&key, &value) in self.small_keys.iter().zip(self.small_storage.iter()) {
-            let Some(key) = key else {
-                continue;
-            };
-            keys.push(key);
-            values.push(value);
+    fn build_index(&self) -> HashMap<Key, IndexLocation> {
+        let mut result = HashMap::new();
+        self.small.index_into(&mut result, IndexLocation::Small);
+        self.tier16.index_into(&mut result, IndexLocation::Tier16);
+        self.tier32.index_into(&mut result, IndexLocation::Tier32);
+        self.tier64.index_into(&mut result, IndexLocation::Tier64);
+        for key in self.big_storage.keys() {
+            result.insert(key, IndexLocation::Big);
         }
 
-        (keys, values)
+        result
+    }
+
+    /// Copy of contents for saving or streaming without holding lock, not meant to be modified
+    pub(crate) fn frozen(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            terms: self.terms.clone(),
+            last_term_id: self.last_term_id,
+            evicted_terms: Default::default(),
+            aliases: self.aliases.clone(),
+            key_aliases: self.key_aliases.clone(),
+            term_groups: self.term_groups.clone(),
+            exclusive_term_groups: self.exclusive_term_groups.clone(),
+            term_group_prefixes: self.term_group_prefixes.clone(),
+            triggers: self.triggers.clone(),
+            index: Default::default(),
+            small: self.small.clone(),
+            tier16: self.tier16.clone(),
+            tier32: self.tier32.clone(),
+            tier64: self.tier64.clone(),
+            big_storage: self.big_storage.frozen()?,
+            deleted: self.deleted.clone(),
+            columns: None,
+            dirty: None,
+            term_eviction: self.term_eviction,
+            quotas: None,
+            versions: Default::default(),
+            latencies: Default::default(),
+            trigger_webhooks: None,
+            validator: None,
+            eviction_guard: None,
+            validation_suspended: false,
+        })
     }
 
-    fn compact_terms(&self) -> Vec<String> {
-        let mut items = self.terms.left_items().collect::<Vec<_>>();
+    /// Term names and their ids, both in id order
+    fn compact_terms(&self) -> (Vec<&String>, Vec<TermId>) {
+        let mut items = self.terms.iter().collect::<Vec<_>>();
         items.sort_unstable_by_key(|(_, &idx)| idx);
-        items
-            .into_iter()
-            .map(|(term, _idx)| term)
-            .cloned()
-            .collect()
+        items.into_iter().unzip()
     }
 
-    pub fn dump(&self, buffer: &mut impl Write) -> Result<(), rmp_serde::encode::Error> {
-        let (small_keys, small_storage) = self.compact_small_items();
-
-        let terms = self.compact_terms();
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(records = self.index.len(), terms = self.terms.len())
+    )]
+    pub fn dump(&self, buffer: &mut impl Write) -> Result<(), DumpError> {
+        buffer.write_all(SNAPSHOT_MAGIC)?;
+        buffer.write_all(&[FORMAT_VERSION])?;
+        rmp_serde::encode::write(buffer, &SchemeRef(self))?;
+        Ok(())
+    }
 
-        let serde = SerializationScheme {
-            terms,
-            small_keys,
-            small_storage,
-            big_storage: self.big_storage.clone(),
-        };
+    /// Snapshot of contents in the same versioned format as [`Self::dump`] writes
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DumpError> {
+        let mut buffer = vec![];
+        self.dump(&mut buffer)?;
+        Ok(buffer)
+    }
 
-        rmp_serde::encode::write(buffer, &serde)
+    /// Read snapshot made by [`Self::to_bytes`] or [`Self::dump`]
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, LoadError> {
+        Self::load(&mut bytes)
     }
 
-    pub fn load(buffer: &mut impl Read) -> Result<Self, rmp_serde::decode::Error> {
-        let serde: SerializationScheme<SMALLSIZE> = rmp_serde::decode::from_read(buffer)?;
+    pub fn load(buffer: &mut impl Read) -> Result<Self, LoadError> {
+        Self::load_with(buffer, BigRecordDecoding::Eager)
+    }
 
-        let terms = serde
-            .terms
-            .into_iter()
-            .enumerate()
-            // v + 1 because 0 is used as nieche for NO_VALUE
-            .map(|(v, k)| (k, (v + 1) as u8))
-            .collect();
+    /// Snapshot is read in one pass, with records of big storage left encoded. Those are decoded
+    /// in parallel afterwards while index is being built, or on access if decoding is lazy
+    #[tracing::instrument(level = "debug", skip_all, fields(records, terms))]
+    pub fn load_with(
+        buffer: &mut impl Read,
+        decoding: BigRecordDecoding,
+    ) -> Result<Self, LoadError> {
+        let started = Instant::now();
+        let mut first_byte = [0u8; 1];
+        buffer.read_exact(&mut first_byte)?;
+
+        let serde: SerializationScheme<TermId, SMALLSIZE, EncodedBitmap> =
+            if first_byte[0] == SNAPSHOT_MAGIC[0] {
+                let mut header = [0u8; SNAPSHOT_MAGIC.len()];
+                buffer.read_exact(&mut header)?;
+                let (magic, version) = header.split_at(SNAPSHOT_MAGIC.len() - 1);
+                if magic != &SNAPSHOT_MAGIC[1..]
+                    || !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version[0])
+                {
+                    return Err(LoadError::UnsupportedVersion(version[0]));
+                }
+                rmp_serde::decode::from_read(buffer)?
+            } else {
+                let legacy: SerializationScheme<u8, SMALLSIZE, EncodedBitmap> =
+                    rmp_serde::decode::from_read(first_byte.as_slice().chain(buffer))?;
+                legacy.widen()
+            };
+        let decoded = started.elapsed();
+
+        let (db, bitmaps, indexing) = Self::from_encoded(serde, decoding)?;
+        tracing::Span::current()
+            .record("records", db.index.len())
+            .record("terms", db.terms.len());
+        tracing::info!(
+            records = db.index.len(),
+            terms = db.terms.len(),
+            big_records = db.big_storage.len(),
+            decoding = ?decoded,
+            big_records_decoding = ?bitmaps,
+            lazy = decoding == BigRecordDecoding::Lazy,
+            ?indexing,
+            "loaded snapshot"
+        );
+        Ok(db)
+    }
+}
 
-        Ok(Self::from_existing_data(
-            terms,
-            serde.small_keys,
-            serde.small_storage,
-            serde.big_storage,
-        ))
+/// Save copy of state taken under a brief read lock, encoding and syncing it after the lock is
+/// released so that writers are only paused while state is copied. Returns time spent holding lock
+/// and number of keys saved
+pub async fn save_in_background<const SMALLSIZE: usize>(
+    db: &tokio::sync::RwLock<Database<SMALLSIZE>>,
+    save_path: std::path::PathBuf,
+) -> Result<(std::time::Duration, usize), Box<dyn std::error::Error + Send + Sync>> {
+    let started = std::time::Instant::now();
+    let (copy, keys) = {
+        let db = db.read().await;
+        let mut copy = db.frozen()?;
+        // changed keys move over to the copy so that saving it resets them
+        copy.dirty = db.take_dirty().map(std::sync::Mutex::new);
+        (copy, db.key_count())
+    };
+    let paused = started.elapsed();
+
+    let (copy, saved) = tokio::task::spawn_blocking(move || {
+        let saved = two_phase_save(&copy, save_path).map_err(|e| e.to_string());
+        (copy, saved)
+    })
+    .await?;
+    if saved.is_err() {
+        // changes copied along with state are still missing from any snapshot
+        if let Some(keys) = copy.take_dirty() {
+            db.read().await.restore_dirty(keys);
+        }
     }
+    saved?;
+    Ok((paused, keys))
 }
 
+/// Save into one of the rotating slots of `save_path`, through a temporary file renamed into place
 pub fn two_phase_save<const SMALLSIZE: usize>(
     state: &Database<SMALLSIZE>,
     save_path: impl AsRef<std::path::Path>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let final_path = save_path.as_ref();
-    let temp_path = get_temp_filename(final_path);
-
-    write_to_file(state, &temp_path)?;
-
-    std::fs::rename(temp_path, final_path)?;
-
+    rotation::save_rotating(state, save_path)?;
     Ok(())
 }
 
@@ -127,15 +393,6 @@ fn get_temp_filename(save_path: &std::path::Path) -> std::path::PathBuf {
     temp_filename
 }
 
-fn write_to_file<const SMALLSIZE: usize>(
-    state: &Database<SMALLSIZE>,
-    path: impl AsRef<std::path::Path>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut temp_file = BufWriter::new(std::fs::File::create(path.as_ref())?);
-    state.dump(&mut temp_file)?;
-    Ok(())
-}
-
 fn add_extension(path: &mut std::path::PathBuf, extension: impl AsRef<std::path::Path>) {
     match path.extension() {
         Some(ext) => {
@@ -148,34 +405,309 @@ fn add_extension(path: &mut std::path::PathBuf, extension: impl AsRef<std::path:
     };
 }
 
+/// Decode snapshot given either as contents of a slot file or as bare dump
+pub fn load_bytes<const SMALLSIZE: usize>(
+    bytes: &[u8],
+) -> Result<Database<SMALLSIZE>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut payload = match rotation::slot_payload(bytes) {
+        Some(payload) => payload?,
+        None => bytes,
+    };
+    Ok(Database::load(&mut payload)?)
+}
+
 pub static DEFAULT_SAVE_PATH: &str = "state.elizadb";
 
+/// Load newest usable rotating slot of `path` with its deltas applied, or `path` itself as saved before slots were introduced
 pub fn load_possibly_missing<const SMALLSIZE: usize>(
     path: impl AsRef<std::path::Path>,
+    decoding: BigRecordDecoding,
 ) -> Result<Database<SMALLSIZE>, Box<dyn std::error::Error>> {
     let path = path.as_ref();
+    let started = Instant::now();
+    if let Some((generation, mut state)) = rotation::load_newest(path, decoding)? {
+        let loading = started.elapsed();
+        delta::apply_chain(&mut state, path, generation)?;
+        tracing::info!(
+            generation,
+            ?loading,
+            deltas = ?started.elapsed() - loading,
+            "loaded state"
+        );
+        return Ok(state);
+    }
     if !path.exists() {
         return Ok(Default::default());
     }
     let mut input_file = BufReader::new(std::fs::File::open(path)?);
 
-    let state = Database::<SMALLSIZE>::load(&mut input_file)?;
+    let state = Database::<SMALLSIZE>::load_with(&mut input_file, decoding)?;
     Ok(state)
 }
 
+/// `B` is the type big records are decoded as
 #[derive(Serialize, Deserialize)]
-struct SerializationScheme<const SMALLSIZE: usize> {
+#[serde(bound(
+    serialize = "T: SlotValue, B: Serialize",
+    deserialize = "T: SlotValue, B: Deserialize<'de>"
+))]
+struct SerializationScheme<T, const SMALLSIZE: usize, B = TermBitmap> {
     terms: Vec<String>,
     small_keys: Vec<Key>,
-    small_storage: Vec<Smallset<SMALLSIZE>>,
-    big_storage: HashMap<Key, HashSet<u8>>,
+    small_storage: Vec<Smallset<T, SMALLSIZE>>,
+    big_storage: HashMap<Key, HashSet<T>>,
+    #[serde(default)]
+    aliases: HashMap<String, T>,
+    #[serde(default)]
+    tier16: TierScheme<T, 16>,
+    #[serde(default)]
+    tier32: TierScheme<T, 32>,
+    #[serde(default)]
+    tier64: TierScheme<T, 64>,
+    #[serde(default)]
+    big_records: HashMap<Key, B>,
+    #[serde(default)]
+    key_aliases: HashMap<String, Key>,
+    #[serde(default)]
+    deleted_records: HashMap<Key, DeletedRecord>,
+    /// Ids of `terms`, which are numbered by position when left out
+    #[serde(default)]
+    term_ids: Vec<TermId>,
+    #[serde(default)]
+    last_term_id: TermId,
+    /// Members of term groups by id
+    #[serde(default)]
+    term_groups: HashMap<String, Vec<TermId>>,
+    #[serde(default)]
+    exclusive_term_groups: Vec<String>,
+    #[serde(default)]
+    triggers: BTreeMap<String, Trigger>,
+    #[serde(default)]
+    term_group_prefixes: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: SlotValue")]
+struct TierScheme<T, const SIZE: usize> {
+    keys: Vec<Key>,
+    storage: Vec<Smallset<T, SIZE>>,
+}
+
+/// Sequence encoded from iterator as it is consumed, so that nothing is collected beforehand.
+/// Length has to be known upfront since msgpack stores it before elements
+struct LazySeq<F> {
+    len: usize,
+    items: F,
+}
+
+impl<F, I> Serialize for LazySeq<F>
+where
+    F: Fn() -> I,
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for item in (self.items)() {
+            seq.serialize_element(&item)?;
+        }
+        seq.end()
+    }
+}
+
+/// Map counterpart of `LazySeq`, iterator yields key-value pairs or errors failing serialization
+struct LazyMap<F> {
+    len: usize,
+    entries: F,
+}
+
+impl<F, I, K, V, E> Serialize for LazyMap<F>
+where
+    F: Fn() -> I,
+    I: Iterator<Item = Result<(K, V), E>>,
+    K: Serialize,
+    V: Serialize,
+    E: std::fmt::Display,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len))?;
+        for entry in (self.entries)() {
+            let (key, value) = entry.map_err(serde::ser::Error::custom)?;
+            map.serialize_entry(&key, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// Borrowing counterpart of `TierScheme`
+#[derive(Serialize)]
+#[serde(rename = "TierScheme")]
+struct TierSchemeRef<K, S> {
+    keys: K,
+    storage: S,
+}
+
+/// Encodes database with the same layout as `SerializationScheme` without copying its contents
+struct SchemeRef<'a, const SMALLSIZE: usize>(&'a Database<SMALLSIZE>);
+
+impl<const SMALLSIZE: usize> Serialize for SchemeRef<'_, SMALLSIZE> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let db = self.0;
+        let small_len = db.small.keys().count();
+        let (terms, term_ids) = db.compact_terms();
+        let mut scheme = serializer.serialize_struct("SerializationScheme", 17)?;
+        scheme.serialize_field("terms", &terms)?;
+        scheme.serialize_field(
+            "small_keys",
+            &LazySeq {
+                len: small_len,
+                items: || db.small.keys(),
+            },
+        )?;
+        scheme.serialize_field(
+            "small_storage",
+            &LazySeq {
+                len: small_len,
+                items: || db.small.iter().map(|(_, set)| set),
+            },
+        )?;
+        // superseded by `big_records`, kept so that the layout of older fields does not change
+        scheme.serialize_field("big_storage", &HashMap::<Key, HashSet<TermId>>::new())?;
+        scheme.serialize_field("aliases", &db.aliases)?;
+        scheme.serialize_field("tier16", &db.tier16.scheme())?;
+        scheme.serialize_field("tier32", &db.tier32.scheme())?;
+        scheme.serialize_field("tier64", &db.tier64.scheme())?;
+        // spilled records are read back one at a time
+        scheme.serialize_field(
+            "big_records",
+            &LazyMap {
+                len: db.big_storage.len(),
+                entries: || db.big_storage.iter(),
+            },
+        )?;
+        scheme.serialize_field(
+            "key_aliases",
+            &LazyMap {
+                len: db.key_aliases.len(),
+                entries: || db.key_aliases.iter().map(Ok::<_, StorageCorruption>),
+            },
+        )?;
+        scheme.serialize_field("deleted_records", &db.deleted)?;
+        scheme.serialize_field("term_ids", &term_ids)?;
+        scheme.serialize_field("last_term_id", &db.last_term_id)?;
+        scheme.serialize_field("term_groups", &db.term_groups)?;
+        scheme.serialize_field("exclusive_term_groups", &db.exclusive_term_groups)?;
+        scheme.serialize_field("triggers", &db.triggers)?;
+        scheme.serialize_field("term_group_prefixes", &db.term_group_prefixes)?;
+        scheme.end()
+    }
+}
+
+/// Contents in the layout of snapshots, without their header. Unlike [`Database::dump`] works with
+/// any serde format, though only snapshots carry format version
+impl<const SMALLSIZE: usize> Serialize for Database<SMALLSIZE> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SchemeRef(self).serialize(serializer)
+    }
+}
+
+impl<'de, const SMALLSIZE: usize> Deserialize<'de> for Database<SMALLSIZE> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SerializationScheme::<TermId, SMALLSIZE>::deserialize(deserializer).map(Self::from_scheme)
+    }
+}
+
+impl<T, const SIZE: usize> Default for TierScheme<T, SIZE> {
+    fn default() -> Self {
+        Self {
+            keys: vec![],
+            storage: vec![],
+        }
+    }
+}
+
+impl<T, const SMALLSIZE: usize, B> SerializationScheme<T, SMALLSIZE, B> {
+    fn with_big_records<C>(
+        self,
+        big_records: HashMap<Key, C>,
+    ) -> SerializationScheme<T, SMALLSIZE, C> {
+        SerializationScheme {
+            terms: self.terms,
+            small_keys: self.small_keys,
+            small_storage: self.small_storage,
+            big_storage: self.big_storage,
+            aliases: self.aliases,
+            tier16: self.tier16,
+            tier32: self.tier32,
+            tier64: self.tier64,
+            big_records,
+            key_aliases: self.key_aliases,
+            deleted_records: self.deleted_records,
+            term_ids: self.term_ids,
+            last_term_id: self.last_term_id,
+            term_groups: self.term_groups,
+            exclusive_term_groups: self.exclusive_term_groups,
+            triggers: self.triggers,
+            term_group_prefixes: self.term_group_prefixes,
+        }
+    }
+}
+
+impl<const SMALLSIZE: usize, B> SerializationScheme<u8, SMALLSIZE, B> {
+    /// Convert legacy u8 term ids, re-inserting set contents so slot markers get translated
+    fn widen(self) -> SerializationScheme<TermId, SMALLSIZE, B> {
+        SerializationScheme {
+            terms: self.terms,
+            small_keys: self.small_keys,
+            small_storage: self
+                .small_storage
+                .par_iter()
+                .map(|set| {
+                    let mut wide = Smallset::new_empty();
+                    for item in set.iter() {
+                        wide.insert(TermId::from(item).try_into().unwrap()).unwrap();
+                    }
+                    wide
+                })
+                .collect(),
+            big_storage: self
+                .big_storage
+                .into_iter()
+                .map(|(key, set)| (key, set.into_iter().map(TermId::from).collect()))
+                .collect(),
+            aliases: self
+                .aliases
+                .into_iter()
+                .map(|(alias, term_id)| (alias, term_id.into()))
+                .collect(),
+            // headerless snapshots predate tiers
+            tier16: Default::default(),
+            tier32: Default::default(),
+            tier64: Default::default(),
+            big_records: Default::default(),
+            key_aliases: Default::default(),
+            deleted_records: Default::default(),
+            term_ids: Default::default(),
+            last_term_id: Default::default(),
+            term_groups: Default::default(),
+            exclusive_term_groups: Default::default(),
+            triggers: Default::default(),
+            term_group_prefixes: Default::default(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
 
-    use crate::storage::{Database, Key};
+    use crate::{
+        config::BigRecordDecoding,
+        smallset::Smallset,
+        storage::{Database, Key, TermId},
+    };
+
+    use super::{LoadError, SerializationScheme, SNAPSHOT_MAGIC};
 
     #[test]
     fn state_is_stored_and_loaded() {
@@ -185,7 +717,7 @@ mod tests {
 
         db.add_term("term").unwrap();
         db.add_term("term2").unwrap();
-        db.create_record(key);
+        db.create_record(key).unwrap();
         db.set_flag(key, "term").unwrap();
 
         let mut storage = vec![];
@@ -196,7 +728,7 @@ mod tests {
         let db = Database::<8>::load(&mut reader).unwrap();
 
         assert_eq!(
-            db.horizontal_query(&key),
+            db.horizontal_query(&key).unwrap(),
             Some({
                 let mut set = HashSet::new();
                 set.insert("term");
@@ -204,4 +736,213 @@ mod tests {
             })
         )
     }
+
+    #[test]
+    fn database_round_trips_through_serde_formats() {
+        let mut db = Database::<8>::default();
+        let (small, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(small, "x").unwrap();
+        for term in 0..100 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+        db.add_alias("x", "ex").unwrap();
+        db.set_key_alias(small, "first").unwrap();
+        db.delete_record(small, 100).unwrap();
+
+        let json = serde_json::to_string(&db).unwrap();
+        let bytes = db.to_bytes().unwrap();
+        for copy in [
+            serde_json::from_str::<Database<8>>(&json).unwrap(),
+            Database::<8>::from_bytes(&bytes).unwrap(),
+        ] {
+            assert_eq!(copy.horizontal_query(&big).unwrap().unwrap().len(), 100);
+            assert_eq!(copy.get_term_id("ex"), db.get_term_id("x"));
+            assert!(copy.deleted_record(small).is_some());
+            assert_eq!(copy.export_json(), db.export_json());
+        }
+    }
+
+    #[test]
+    fn legacy_headerless_snapshot_is_widened() {
+        let (small_key, big_key) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        let mut small_set = Smallset::<u8, 8>::new_empty();
+        small_set.insert(1.try_into().unwrap()).unwrap();
+        small_set.insert(2.try_into().unwrap()).unwrap();
+        let legacy = SerializationScheme::<u8, 8> {
+            terms: vec!["a".to_string(), "b".to_string()],
+            small_keys: vec![small_key],
+            small_storage: vec![small_set],
+            big_storage: HashMap::from([(big_key, HashSet::from([2]))]),
+            aliases: HashMap::from([("alias".to_string(), 1)]),
+            tier16: Default::default(),
+            tier32: Default::default(),
+            tier64: Default::default(),
+            big_records: Default::default(),
+            key_aliases: Default::default(),
+            deleted_records: Default::default(),
+            term_ids: Default::default(),
+            last_term_id: Default::default(),
+            term_groups: Default::default(),
+            exclusive_term_groups: Default::default(),
+            triggers: Default::default(),
+            term_group_prefixes: Default::default(),
+        };
+        let storage = rmp_serde::encode::to_vec(&legacy).unwrap();
+
+        let db = Database::<8>::load(&mut storage.as_slice()).unwrap();
+
+        assert_eq!(
+            db.horizontal_query(&small_key).unwrap(),
+            Some(HashSet::from(["a", "b"]))
+        );
+        assert_eq!(
+            db.horizontal_query(&big_key).unwrap(),
+            Some(HashSet::from(["b"]))
+        );
+        assert_eq!(db.get_term_id("alias"), db.get_term_id("a"));
+    }
+
+    #[test]
+    fn term_ids_beyond_u8_survive_roundtrip() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        for i in 0..300 {
+            db.add_term(&i.to_string()).unwrap();
+        }
+        db.set_flag(key, "299").unwrap();
+
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+        let db = Database::<8>::load(&mut storage.as_slice()).unwrap();
+
+        assert_eq!(db.get_term_id("299"), Some(300));
+        assert_eq!(
+            db.horizontal_query(&key).unwrap(),
+            Some(HashSet::from(["299"]))
+        );
+    }
+
+    #[test]
+    fn corrupted_big_record_fails_to_load() {
+        let mut db = Database::<8>::default();
+        for term in 0..70 {
+            db.set_flag(Key::try_from(1).unwrap(), &term.to_string())
+                .unwrap();
+        }
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+
+        // cookie that starts serialized roaring bitmaps
+        let cookie = [0x3a, 0x30, 0, 0];
+        let at = storage
+            .windows(cookie.len())
+            .position(|window| window == cookie)
+            .unwrap();
+        storage[at] = 0;
+        assert!(matches!(
+            Database::<8>::load(&mut storage.as_slice()),
+            Err(LoadError::BigRecord(_))
+        ));
+    }
+
+    #[test]
+    fn lazily_loaded_big_records_are_decoded_on_access() {
+        let mut db = Database::<8>::default();
+        let (first, second) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in 0..70 {
+            db.set_flag(first, &term.to_string()).unwrap();
+            db.set_flag(second, &term.to_string()).unwrap();
+        }
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+
+        let mut db =
+            Database::<8>::load_with(&mut storage.as_slice(), BigRecordDecoding::Lazy).unwrap();
+        assert_eq!(db.big_storage.undecoded(), 2);
+        assert_eq!(db.horizontal_query(&first).unwrap().unwrap().len(), 70);
+        assert_eq!(db.big_storage.undecoded(), 1);
+        db.set_flag(second, "new").unwrap();
+        assert_eq!(db.big_storage.undecoded(), 0);
+        assert_eq!(db.horizontal_query(&second).unwrap().unwrap().len(), 71);
+
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+        let db = Database::<8>::load(&mut storage.as_slice()).unwrap();
+        assert_eq!(db.horizontal_query(&first).unwrap().unwrap().len(), 70);
+        assert_eq!(db.horizontal_query(&second).unwrap().unwrap().len(), 71);
+    }
+
+    #[test]
+    fn promoted_records_survive_roundtrip() {
+        let mut db = Database::<8>::default();
+        let (tiered, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in 0..20 {
+            db.set_flag(tiered, &term.to_string()).unwrap();
+        }
+        for term in 0..70 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+
+        db.set_key_alias(big, "big").unwrap();
+
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+        let db = Database::<8>::load(&mut storage.as_slice()).unwrap();
+
+        assert_eq!(db.resolve_key_alias("big"), Some(big));
+        assert_eq!(db.horizontal_query(&tiered).unwrap().unwrap().len(), 20);
+        assert_eq!(db.horizontal_query(&big).unwrap().unwrap().len(), 70);
+        assert_eq!(db.tier32.keys().collect::<Vec<_>>(), [tiered]);
+    }
+
+    #[test]
+    fn dump_streams_scheme_layout_including_spilled_records() {
+        let path = std::env::temp_dir().join(format!("elizadb-dump-{}", std::process::id()));
+        let mut db = Database::<8>::default();
+        let (small, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(small, "a").unwrap();
+        for term in 0..70 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+        db.set_key_alias(small, "small").unwrap();
+        db.spill_big_records(&path, 0).unwrap();
+
+        let mut storage = vec![];
+        db.dump(&mut storage).unwrap();
+        let scheme: SerializationScheme<TermId, 8> =
+            rmp_serde::from_slice(&storage[SNAPSHOT_MAGIC.len() + 1..]).unwrap();
+
+        assert_eq!(scheme.terms.len(), 71);
+        assert_eq!(scheme.small_keys, [small]);
+        assert_eq!(scheme.small_storage.len(), 1);
+        assert_eq!(scheme.big_records[&big].len(), 70);
+        assert_eq!(scheme.key_aliases["small"], small);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn frozen_copy_is_unaffected_by_later_writes() {
+        let path = std::env::temp_dir().join(format!("elizadb-frozen-{}", std::process::id()));
+        let mut db = Database::<8>::default();
+        for key in 1..=3 {
+            for term in 0..70 {
+                db.set_flag(Key::try_from(key).unwrap(), &term.to_string())
+                    .unwrap();
+            }
+        }
+        db.spill_big_records(&path, 1).unwrap();
+        let expected = db.export_json();
+        let frozen = db.frozen().unwrap();
+
+        for key in 1..=4 {
+            db.set_flag(Key::try_from(key).unwrap(), "later").unwrap();
+        }
+        db.spill_big_records(&path, 1).unwrap();
+
+        let mut storage = vec![];
+        frozen.dump(&mut storage).unwrap();
+        let loaded = Database::<8>::load(&mut storage.as_slice()).unwrap();
+        assert_eq!(loaded.export_json(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
 }
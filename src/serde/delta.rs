@@ -0,0 +1,346 @@
+//! Incremental snapshots holding only records changed since the last full snapshot
+//!
+//! Deltas form a chain per full snapshot generation, `<data file>.delta.<generation>.<seq>` with
+//! `seq` counting from 1. Loading applies the chain on top of the slot it was based on, stopping at
+//! the first delta that is missing or damaged.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+
+use super::{add_extension, get_temp_filename, rotation, term_table};
+use crate::{
+    doublemap::DoubleMap,
+    soft_delete::DeletedRecord,
+    storage::{Database, Key, StorageCorruption, TermId},
+    triggers::Trigger,
+};
+
+const DELTA_MAGIC: &[u8; 4] = b"ELZD";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeltaError {
+    #[error("incremental snapshots are not enabled")]
+    NotEnabled,
+    #[error("there is no full snapshot to base delta on")]
+    NoBase,
+    #[error("failed to access delta: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode delta: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("failed to decode delta: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("delta header does not match its file name")]
+    BadHeader,
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+    #[error("delta checksum mismatch")]
+    Checksum,
+}
+
+/// Written delta, as reported by `/service/save/delta`
+#[derive(Clone, Debug, Serialize)]
+pub struct DeltaReport {
+    pub generation: u64,
+    pub seq: u64,
+    pub records: usize,
+}
+
+/// Term tables are small compared to records and are stored whole in every delta
+#[derive(Serialize, Deserialize)]
+struct DeltaScheme {
+    terms: Vec<String>,
+    aliases: HashMap<String, TermId>,
+    key_aliases: HashMap<String, Key>,
+    records: Vec<(Key, Vec<TermId>)>,
+    /// Changed keys that no longer have a record
+    #[serde(default)]
+    removed: Vec<Key>,
+    #[serde(default)]
+    deleted_records: HashMap<Key, DeletedRecord>,
+    #[serde(default)]
+    term_ids: Vec<TermId>,
+    #[serde(default)]
+    last_term_id: TermId,
+    /// Left out by deltas written before term groups existed, which keep groups of their base
+    #[serde(default)]
+    term_groups: Option<HashMap<String, Vec<TermId>>>,
+    #[serde(default)]
+    exclusive_term_groups: Vec<String>,
+    #[serde(default)]
+    term_group_prefixes: BTreeMap<String, BTreeSet<String>>,
+    /// Left out by deltas written before triggers existed, which keep triggers of their base
+    #[serde(default)]
+    triggers: Option<BTreeMap<String, Trigger>>,
+}
+
+pub fn delta_path(base: impl AsRef<Path>, generation: u64, seq: u64) -> PathBuf {
+    let mut path = base.as_ref().to_owned();
+    add_extension(&mut path, format!("delta.{generation}.{seq}"));
+    path
+}
+
+/// Existing chain of deltas based on full snapshot `generation`, in order
+fn chain(base: &Path, generation: u64) -> impl Iterator<Item = (u64, PathBuf)> + '_ {
+    (1..)
+        .map(move |seq| (seq, delta_path(base, generation, seq)))
+        .take_while(|(_, path)| path.exists())
+}
+
+/// Remove every delta based on full snapshot `generation`
+pub fn remove_chain(base: impl AsRef<Path>, generation: u64) -> std::io::Result<()> {
+    for (_, path) in chain(base.as_ref(), generation).collect::<Vec<_>>() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Start tracking changed keys so that deltas can be written
+    pub fn enable_dirty_tracking(&mut self) {
+        if self.dirty.is_none() {
+            self.dirty = Some(Mutex::new(HashSet::new()));
+        }
+    }
+
+    /// Number of keys changed since last snapshot, None unless tracking is enabled
+    pub fn dirty_keys(&self) -> Option<usize> {
+        let dirty = self.dirty.as_ref()?;
+        Some(dirty.lock().unwrap_or_else(|e| e.into_inner()).len())
+    }
+
+    /// Reset changed keys, returning them so they can be put back if snapshot fails
+    pub(super) fn take_dirty(&self) -> Option<HashSet<Key>> {
+        let dirty = self.dirty.as_ref()?;
+        Some(std::mem::take(
+            &mut *dirty.lock().unwrap_or_else(|e| e.into_inner()),
+        ))
+    }
+
+    pub(super) fn restore_dirty(&self, keys: HashSet<Key>) {
+        if let Some(dirty) = &self.dirty {
+            dirty.lock().unwrap_or_else(|e| e.into_inner()).extend(keys);
+        }
+    }
+
+    fn apply_delta(&mut self, delta: DeltaScheme) -> Result<(), StorageCorruption> {
+        self.terms = term_table(delta.terms, &delta.term_ids);
+        self.last_term_id = self.last_term_id.max(delta.last_term_id);
+        self.aliases = delta.aliases;
+        let mut key_aliases = DoubleMap::new();
+        for (alias, key) in delta.key_aliases {
+            key_aliases.insert(alias, key);
+        }
+        self.key_aliases = key_aliases;
+        self.deleted = delta.deleted_records;
+        if let Some(term_groups) = delta.term_groups {
+            self.term_groups = term_groups
+                .into_iter()
+                .map(|(name, members)| (name, members.into_iter().collect()))
+                .collect();
+            self.exclusive_term_groups = delta.exclusive_term_groups.into_iter().collect();
+            self.term_group_prefixes = delta.term_group_prefixes;
+        }
+        if let Some(triggers) = delta.triggers {
+            self.triggers = triggers;
+        }
+
+        for (key, items) in delta.records {
+            self.detach(key)?;
+            self.attach(key, &items, 0)?;
+        }
+        for key in delta.removed {
+            self.detach(key)?;
+            self.index.remove(&key);
+        }
+        self.recount_quota_usage();
+        Ok(())
+    }
+}
+
+fn write_delta<const SMALLSIZE: usize>(
+    state: &Database<SMALLSIZE>,
+    keys: &HashSet<Key>,
+    path: &Path,
+    generation: u64,
+    seq: u64,
+) -> Result<usize, DeltaError> {
+    let (terms, term_ids) = state.compact_terms();
+    let delta = DeltaScheme {
+        terms: terms.into_iter().cloned().collect(),
+        term_ids,
+        last_term_id: state.last_term_id,
+        aliases: state.aliases.clone(),
+        key_aliases: state
+            .list_key_aliases()
+            .map(|(alias, key)| (alias.to_string(), key))
+            .collect(),
+        records: keys
+            .iter()
+            .filter_map(|&key| {
+                state
+                    .record(&key)
+                    .transpose()
+                    .map(|record| Ok((key, record?.term_ids())))
+            })
+            .collect::<Result<_, StorageCorruption>>()?,
+        removed: keys
+            .iter()
+            .filter(|key| !state.contains_key(key))
+            .copied()
+            .collect(),
+        deleted_records: state.deleted.clone(),
+        term_groups: Some(
+            state
+                .term_groups
+                .iter()
+                .map(|(name, members)| (name.clone(), members.iter().copied().collect()))
+                .collect(),
+        ),
+        exclusive_term_groups: state.exclusive_term_groups.iter().cloned().collect(),
+        term_group_prefixes: state.term_group_prefixes.clone(),
+        triggers: Some(state.triggers.clone()),
+    };
+    let payload = rmp_serde::encode::to_vec(&delta)?;
+
+    let temp_path = get_temp_filename(path);
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    file.write_all(DELTA_MAGIC)?;
+    file.write_u64::<BigEndian>(generation)?;
+    file.write_u64::<BigEndian>(seq)?;
+    file.write_u32::<BigEndian>(crc32fast::hash(&payload))?;
+    file.write_all(&payload)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(temp_path, path)?;
+    Ok(delta.records.len())
+}
+
+/// Write records changed since last snapshot as next delta of the newest full snapshot
+pub fn save_delta<const SMALLSIZE: usize>(
+    state: &Database<SMALLSIZE>,
+    base: impl AsRef<Path>,
+) -> Result<DeltaReport, DeltaError> {
+    let base = base.as_ref();
+    let Some(&(generation, _)) = rotation::slots_newest_first(base).first() else {
+        return Err(DeltaError::NoBase);
+    };
+    let seq = chain(base, generation).count() as u64 + 1;
+
+    let keys = state.take_dirty().ok_or(DeltaError::NotEnabled)?;
+    match write_delta(
+        state,
+        &keys,
+        &delta_path(base, generation, seq),
+        generation,
+        seq,
+    ) {
+        Ok(records) => Ok(DeltaReport {
+            generation,
+            seq,
+            records,
+        }),
+        Err(e) => {
+            state.restore_dirty(keys);
+            Err(e)
+        }
+    }
+}
+
+fn read_delta(path: &Path, generation: u64, seq: u64) -> Result<DeltaScheme, DeltaError> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; DELTA_MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != DELTA_MAGIC
+        || file.read_u64::<BigEndian>()? != generation
+        || file.read_u64::<BigEndian>()? != seq
+    {
+        return Err(DeltaError::BadHeader);
+    }
+    let checksum = file.read_u32::<BigEndian>()?;
+    let mut payload = vec![];
+    file.read_to_end(&mut payload)?;
+    if crc32fast::hash(&payload) != checksum {
+        return Err(DeltaError::Checksum);
+    }
+    Ok(rmp_serde::from_slice(&payload)?)
+}
+
+/// Apply chain of deltas based on full snapshot `generation`, returning how many were applied.
+/// Damaged delta and the ones after it are renamed aside, since they can no longer be applied in order
+pub fn apply_chain<const SMALLSIZE: usize>(
+    state: &mut Database<SMALLSIZE>,
+    base: impl AsRef<Path>,
+    generation: u64,
+) -> std::io::Result<usize> {
+    let deltas = chain(base.as_ref(), generation).collect::<Vec<_>>();
+    for (applied, (seq, path)) in deltas.iter().enumerate() {
+        let applied_delta = read_delta(path, generation, *seq)
+            .and_then(|delta| state.apply_delta(delta).map_err(DeltaError::from));
+        match applied_delta {
+            Ok(()) => {}
+            Err(e) => {
+                tracing::warn!(path = %path.display(), "delta is unusable, discarding rest of chain: {e}");
+                for (_, path) in &deltas[applied..] {
+                    let mut aside = path.clone();
+                    add_extension(&mut aside, "corrupt");
+                    std::fs::rename(path, aside)?;
+                }
+                return Ok(applied);
+            }
+        }
+    }
+    Ok(deltas.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::BigRecordDecoding,
+        serde::{load_possibly_missing, two_phase_save},
+        storage::{Database, Key},
+    };
+
+    use super::{delta_path, save_delta};
+
+    #[test]
+    fn full_snapshot_and_deltas_restore_state() {
+        let dir = std::env::temp_dir().join(format!("elizadb-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("state.elizadb");
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+
+        let mut db = Database::<8>::default();
+        db.enable_dirty_tracking();
+        db.set_flag(a, "x").unwrap();
+        two_phase_save(&db, &base).unwrap();
+        assert_eq!(db.dirty_keys(), Some(0));
+
+        db.set_flag(b, "y").unwrap();
+        let report = save_delta(&db, &base).unwrap();
+        assert_eq!((report.generation, report.seq, report.records), (1, 1, 1));
+
+        db.unset_flag(a, "x").unwrap();
+        for term in 0..20 {
+            db.set_flag(b, &term.to_string()).unwrap();
+        }
+        db.rename_term("y", "why").unwrap();
+        assert_eq!(save_delta(&db, &base).unwrap().seq, 2);
+
+        let loaded = load_possibly_missing::<8>(&base, BigRecordDecoding::Eager).unwrap();
+        assert_eq!(loaded.export_json(), db.export_json());
+        assert!(loaded.check_consistency().consistent);
+
+        // full snapshot supersedes deltas of the generation it replaces
+        two_phase_save(&db, &base).unwrap();
+        two_phase_save(&db, &base).unwrap();
+        assert!(!delta_path(&base, 1, 1).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+//! Best-effort loading of damaged snapshots
+//!
+//! Snapshot body is decoded one element at a time. Elements that are well-formed msgpack but fail to
+//! decode into their type are skipped, while the first structurally broken element ends decoding and
+//! everything after it is reported as lost.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{de::DeserializeOwned, de::IgnoredAny, Deserialize, Serialize};
+
+use super::{
+    rotation, LoadError, SerializationScheme, TierScheme, FORMAT_VERSION, MIN_FORMAT_VERSION,
+    SNAPSHOT_MAGIC,
+};
+use crate::{
+    bigstore::TermBitmap,
+    smallset::Smallset,
+    storage::{Database, Key, TermId},
+};
+
+/// How much of one snapshot section could be decoded
+#[derive(Clone, Debug, Serialize)]
+pub struct SectionReport {
+    pub name: &'static str,
+    pub recovered: usize,
+    /// Number of elements section declared, None if even that could not be read
+    pub expected: Option<usize>,
+}
+
+impl SectionReport {
+    pub fn is_complete(&self) -> bool {
+        self.expected == Some(self.recovered)
+    }
+}
+
+/// What was salvaged from a damaged snapshot and what was lost
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RecoveryReport {
+    pub sections: Vec<SectionReport>,
+    /// Records whose key or term set was lost, or whose key appeared twice
+    pub dropped_records: usize,
+    /// Terms that could not be decoded and were replaced by placeholder names to keep ids stable
+    pub placeholder_terms: usize,
+    /// Flags referring to terms that were lost, removed from records
+    pub dropped_flags: usize,
+    /// Term and key aliases pointing at lost terms or keys
+    pub dropped_aliases: usize,
+}
+
+impl RecoveryReport {
+    pub fn is_lossless(&self) -> bool {
+        self.sections.iter().all(SectionReport::is_complete)
+            && self.dropped_records == 0
+            && self.placeholder_terms == 0
+            && self.dropped_flags == 0
+            && self.dropped_aliases == 0
+    }
+}
+
+struct Salvage<'a> {
+    input: &'a [u8],
+    broken: bool,
+    report: RecoveryReport,
+}
+
+impl<'a> Salvage<'a> {
+    /// Decode next value, skipping it if it is well-formed but not a valid `T`
+    fn next<T: DeserializeOwned>(&mut self) -> Option<T> {
+        if self.broken {
+            return None;
+        }
+        let start = self.input;
+        if let Ok(value) = T::deserialize(&mut rmp_serde::Deserializer::new(&mut self.input)) {
+            return Some(value);
+        }
+        self.input = start;
+        if IgnoredAny::deserialize(&mut rmp_serde::Deserializer::new(&mut self.input)).is_err() {
+            self.broken = true;
+        }
+        None
+    }
+
+    fn array_len(&mut self) -> Option<usize> {
+        if self.broken {
+            return None;
+        }
+        match rmp::decode::read_array_len(&mut self.input) {
+            Ok(len) => Some(len as usize),
+            Err(_) => {
+                self.broken = true;
+                None
+            }
+        }
+    }
+
+    fn map_len(&mut self) -> Option<usize> {
+        if self.broken {
+            return None;
+        }
+        match rmp::decode::read_map_len(&mut self.input) {
+            Ok(len) => Some(len as usize),
+            Err(_) => {
+                self.broken = true;
+                None
+            }
+        }
+    }
+
+    /// Elements of array section, keeping positions of undecodable ones as None
+    fn seq<T: DeserializeOwned>(&mut self, name: &'static str) -> Vec<Option<T>> {
+        let expected = self.array_len();
+        let items = (0..expected.unwrap_or(0))
+            .map(|_| self.next())
+            .collect::<Vec<_>>();
+        self.report.sections.push(SectionReport {
+            name,
+            recovered: items.iter().flatten().count(),
+            expected,
+        });
+        items
+    }
+
+    fn map<K: DeserializeOwned, V: DeserializeOwned>(&mut self, name: &'static str) -> Vec<(K, V)> {
+        let expected = self.map_len();
+        let mut entries = vec![];
+        for _ in 0..expected.unwrap_or(0) {
+            // value has to be consumed even if key was bad, to stay aligned
+            let (key, value) = (self.next(), self.next());
+            if let (Some(key), Some(value)) = (key, value) {
+                entries.push((key, value));
+            }
+        }
+        self.report.sections.push(SectionReport {
+            name,
+            recovered: entries.len(),
+            expected,
+        });
+        entries
+    }
+
+    /// Pair up keys with their sets, dropping slots where either was lost
+    fn pairs<T>(&mut self, keys: Vec<Option<Key>>, sets: Vec<Option<T>>) -> (Vec<Key>, Vec<T>) {
+        let total = keys.len().max(sets.len());
+        let (keys, sets): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .zip(sets)
+            .filter_map(|(key, set)| Some((key?, set?)))
+            .unzip();
+        self.report.dropped_records += total - keys.len();
+        (keys, sets)
+    }
+
+    fn tier<const SIZE: usize>(
+        &mut self,
+        keys_name: &'static str,
+        storage_name: &'static str,
+    ) -> TierScheme<TermId, SIZE> {
+        // tiers are stored as two element arrays of keys and sets
+        if self.array_len().is_some_and(|fields| fields != 2) {
+            self.broken = true;
+        }
+        let keys = self.seq(keys_name);
+        let sets = self.seq::<Smallset<TermId, SIZE>>(storage_name);
+        let (keys, storage) = self.pairs(keys, sets);
+        TierScheme { keys, storage }
+    }
+}
+
+/// Load as much of versioned snapshot in `input` as can be decoded
+pub fn recover<const SMALLSIZE: usize>(
+    input: &[u8],
+) -> Result<(Database<SMALLSIZE>, RecoveryReport), LoadError> {
+    let header_len = SNAPSHOT_MAGIC.len() + 1;
+    if input.len() < header_len || input[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC[..] {
+        // headerless snapshots are too old to be worth salvaging piecewise
+        let db = Database::load(&mut &input[..])?;
+        return Ok((db, RecoveryReport::default()));
+    }
+    let version = input[SNAPSHOT_MAGIC.len()];
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let mut salvage = Salvage {
+        input: &input[header_len..],
+        broken: false,
+        report: RecoveryReport::default(),
+    };
+    // older versions have fewer fields, the ones they lack stay empty
+    let fields = salvage.array_len().unwrap_or(0);
+
+    let mut terms = salvage.seq::<String>("terms");
+    // terms past the point where decoding broke off cannot be referenced by anything salvaged
+    while salvage.broken && terms.last().is_some_and(Option::is_none) {
+        terms.pop();
+    }
+    salvage.report.placeholder_terms = terms.iter().filter(|term| term.is_none()).count();
+    let terms = terms
+        .into_iter()
+        .enumerate()
+        .map(|(i, term)| term.unwrap_or_else(|| format!("lost-term-{}", i + 1)))
+        .collect();
+
+    let small_keys = salvage.seq("small_keys");
+    let small_storage = salvage.seq::<Smallset<TermId, SMALLSIZE>>("small_storage");
+    let (small_keys, small_storage) = salvage.pairs(small_keys, small_storage);
+    let big_storage = salvage.map("big_storage").into_iter().collect();
+    let mut scheme = SerializationScheme {
+        terms,
+        small_keys,
+        small_storage,
+        big_storage,
+        aliases: HashMap::new(),
+        tier16: TierScheme::default(),
+        tier32: TierScheme::default(),
+        tier64: TierScheme::default(),
+        big_records: HashMap::new(),
+        key_aliases: HashMap::new(),
+        deleted_records: HashMap::new(),
+        term_ids: vec![],
+        last_term_id: 0,
+        term_groups: HashMap::new(),
+        exclusive_term_groups: vec![],
+        triggers: BTreeMap::new(),
+        term_group_prefixes: BTreeMap::new(),
+    };
+    if fields > 4 {
+        scheme.aliases = salvage.map("aliases").into_iter().collect();
+    }
+    if fields > 7 {
+        scheme.tier16 = salvage.tier("tier16_keys", "tier16_storage");
+        scheme.tier32 = salvage.tier("tier32_keys", "tier32_storage");
+        scheme.tier64 = salvage.tier("tier64_keys", "tier64_storage");
+    }
+    if fields > 8 {
+        scheme.big_records = salvage
+            .map::<Key, TermBitmap>("big_records")
+            .into_iter()
+            .collect();
+    }
+    if fields > 9 {
+        scheme.key_aliases = salvage.map("key_aliases").into_iter().collect();
+    }
+    if fields > 10 {
+        scheme.deleted_records = salvage.map("deleted_records").into_iter().collect();
+    }
+    if fields > 11 {
+        // ids are only usable if every one of them survived, terms are numbered by position otherwise
+        scheme.term_ids = salvage
+            .seq::<TermId>("term_ids")
+            .into_iter()
+            .collect::<Option<_>>()
+            .unwrap_or_default();
+    }
+    if fields > 12 {
+        scheme.last_term_id = salvage.next().unwrap_or(0);
+    }
+    if fields > 13 {
+        scheme.term_groups = salvage.map("term_groups").into_iter().collect();
+    }
+    if fields > 14 {
+        scheme.exclusive_term_groups = salvage
+            .seq("exclusive_term_groups")
+            .into_iter()
+            .flatten()
+            .collect();
+    }
+    if fields > 15 {
+        scheme.triggers = salvage.map("triggers").into_iter().collect();
+    }
+    if fields > 16 {
+        scheme.term_group_prefixes = salvage.map("term_group_prefixes").into_iter().collect();
+    }
+
+    let mut report = salvage.report;
+    let mut db = Database::from_scheme(scheme);
+    report.dropped_records += db.drop_unindexed_records();
+    report.dropped_flags = db.drop_unknown_term_ids();
+    report.dropped_aliases = db.drop_dangling_aliases();
+    Ok((db, report))
+}
+
+/// Load as much of snapshot at `path` as can be decoded, from its newest rotating slot if it has any
+pub fn recover_file<const SMALLSIZE: usize>(
+    path: impl AsRef<std::path::Path>,
+) -> Result<(Database<SMALLSIZE>, RecoveryReport), LoadError> {
+    let newest_slot = rotation::slots_newest_first(&path)
+        .into_iter()
+        .find_map(|(_, slot)| rotation::read_slot(slot, false).ok());
+    match newest_slot {
+        Some((_, payload)) => recover(&payload),
+        None => recover(&std::fs::read(path)?),
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Remove slots of keys that were stored twice and lost to a later copy in index
+    fn drop_unindexed_records(&mut self) -> usize {
+        let stored = self.records().count();
+        let indexed = self.index.len();
+        if stored == indexed {
+            return 0;
+        }
+        // rebuild every record from scratch so that each key lives in exactly one slot, records
+        // that cannot be read back are lost like the ones that cannot be attached
+        let records = self
+            .index
+            .keys()
+            .map(|&key| (key, self.record(&key).ok().flatten().map(|r| r.term_ids())))
+            .collect::<Vec<_>>();
+        let rebuilt = Self {
+            terms: std::mem::take(&mut self.terms),
+            aliases: std::mem::take(&mut self.aliases),
+            key_aliases: std::mem::take(&mut self.key_aliases),
+            deleted: std::mem::take(&mut self.deleted),
+            ..Default::default()
+        };
+        *self = rebuilt;
+        let mut unattached = 0;
+        for (key, items) in records {
+            match items {
+                Some(items) if self.attach(key, &items, 0).is_ok() => {}
+                _ => unattached += 1,
+            }
+        }
+        stored - indexed + unattached
+    }
+
+    fn drop_dangling_aliases(&mut self) -> usize {
+        let before = self.aliases.len() + self.key_aliases.len();
+        let terms = &self.terms;
+        self.aliases
+            .retain(|_, term_id| terms.contains_backward(term_id));
+        for members in self.term_groups.values_mut() {
+            members.retain(|term_id| terms.contains_backward(term_id));
+        }
+        let unknown = self
+            .triggers
+            .values()
+            .flat_map(|trigger| trigger.set.iter().chain([&trigger.when]))
+            .filter(|term_id| !terms.contains_backward(term_id))
+            .copied()
+            .collect::<Vec<_>>();
+        for term_id in unknown {
+            self.untrigger_term(term_id);
+        }
+        let dangling = self
+            .key_aliases
+            .iter()
+            .filter(|(_, key)| !self.index.contains_key(key))
+            .map(|(_, &key)| key)
+            .collect::<Vec<_>>();
+        for key in dangling {
+            self.key_aliases.remove_backward(&key);
+        }
+        before - self.aliases.len() - self.key_aliases.len()
+    }
+
+    /// Unset flags whose term ids have no name, so that newly added terms cannot inherit them
+    fn drop_unknown_term_ids(&mut self) -> usize {
+        let damaged = self
+            .records()
+            .filter_map(Result::ok)
+            .filter(|(_, record)| {
+                record
+                    .term_ids()
+                    .iter()
+                    .any(|term_id| !self.terms.contains_backward(term_id))
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        let mut dropped = 0;
+        for key in damaged {
+            let Ok(Some((items, capacity))) = self.detach(key) else {
+                continue;
+            };
+            let known = items
+                .iter()
+                .copied()
+                .filter(|term_id| self.terms.contains_backward(term_id))
+                .collect::<Vec<_>>();
+            dropped += items.len() - known.len();
+            if self.attach(key, &known, capacity).is_err() {
+                dropped += known.len();
+            }
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::recover;
+
+    fn sample() -> Vec<u8> {
+        let mut db = Database::<8>::default();
+        for key in 1..=50 {
+            let key = Key::try_from(key).unwrap();
+            for term in 0..=(key.get() % 20) {
+                db.set_flag(key, &format!("term-{term}")).unwrap();
+            }
+        }
+        for term in 0..70 {
+            db.set_flag(Key::try_from(51).unwrap(), &format!("term-{term}"))
+                .unwrap();
+        }
+        db.add_alias("term-1", "first").unwrap();
+        db.set_key_alias(Key::try_from(1).unwrap(), "one").unwrap();
+
+        let mut snapshot = vec![];
+        db.dump(&mut snapshot).unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn intact_snapshot_is_recovered_losslessly() {
+        let snapshot = sample();
+        let (db, report) = recover::<8>(&snapshot).unwrap();
+        assert!(report.is_lossless(), "{report:?}");
+        assert_eq!(db.list_keys().count(), 51);
+        assert!(db.check_consistency().consistent);
+    }
+
+    #[test]
+    fn truncated_snapshot_keeps_decoded_prefix() {
+        let snapshot = sample();
+        let (db, report) = recover::<8>(&snapshot[..snapshot.len() / 2]).unwrap();
+        assert!(!report.is_lossless());
+        assert!(report.sections[0].is_complete(), "terms come first");
+        assert!(db.list_keys().count() < 51);
+        assert!(db.check_consistency().consistent);
+    }
+
+    #[test]
+    fn any_single_byte_corruption_leaves_consistent_database() {
+        let snapshot = sample();
+        for position in 4..snapshot.len() {
+            let mut damaged = snapshot.clone();
+            damaged[position] ^= 0xff;
+            if let Ok((db, _)) = recover::<8>(&damaged) {
+                assert!(db.check_consistency().consistent, "byte {position}");
+            }
+        }
+    }
+}
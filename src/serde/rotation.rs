@@ -0,0 +1,269 @@
+//! Snapshots written alternately into two slot files next to data file
+//!
+//! Every slot starts with a header holding generation counter and checksum of the snapshot that
+//! follows it. Saving always overwrites the slot that does not hold the newest valid snapshot, so a
+//! crash during save leaves the previous one intact, and loading falls back to the older slot when
+//! the newer one does not pass checksum or fails to decode.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{add_extension, delta, get_temp_filename};
+use crate::{config::BigRecordDecoding, storage::Database};
+
+const SLOT_MAGIC: &[u8; 4] = b"ELZR";
+
+const SLOT_HEADER_LEN: u64 = SLOT_MAGIC.len() as u64 + 8 + 4;
+
+const SLOT_SUFFIXES: [&str; 2] = ["a", "b"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum SlotError {
+    #[error("failed to read snapshot slot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("snapshot slot has no valid header")]
+    BadHeader,
+    #[error("snapshot slot checksum mismatch")]
+    Checksum,
+}
+
+/// Paths of both slots belonging to data file at `base`
+pub fn slot_paths(base: impl AsRef<Path>) -> [PathBuf; 2] {
+    SLOT_SUFFIXES.map(|suffix| {
+        let mut path = base.as_ref().to_owned();
+        add_extension(&mut path, suffix);
+        path
+    })
+}
+
+fn read_header(file: &mut impl Read) -> Result<(u64, u32), SlotError> {
+    let mut magic = [0u8; SLOT_MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != SLOT_MAGIC {
+        return Err(SlotError::BadHeader);
+    }
+    let generation = file.read_u64::<BigEndian>()?;
+    let checksum = file.read_u32::<BigEndian>()?;
+    Ok((generation, checksum))
+}
+
+/// Generation of snapshot in slot, None if slot is missing or its header is unreadable
+fn slot_generation(path: &Path) -> Option<u64> {
+    let mut file = File::open(path).ok()?;
+    read_header(&mut file)
+        .ok()
+        .map(|(generation, _)| generation)
+}
+
+/// Existing slots ordered from newest to oldest generation
+pub fn slots_newest_first(base: impl AsRef<Path>) -> Vec<(u64, PathBuf)> {
+    let mut slots = slot_paths(base)
+        .into_iter()
+        .filter_map(|path| Some((slot_generation(&path)?, path)))
+        .collect::<Vec<_>>();
+    slots.sort_unstable_by_key(|&(generation, _)| std::cmp::Reverse(generation));
+    slots
+}
+
+/// Generation and snapshot bytes stored in slot, checksum is verified when `verify` is set
+pub fn read_slot(path: impl AsRef<Path>, verify: bool) -> Result<(u64, Vec<u8>), SlotError> {
+    let mut file = File::open(path)?;
+    let (generation, checksum) = read_header(&mut file)?;
+    let mut payload = vec![];
+    file.read_to_end(&mut payload)?;
+    if verify && crc32fast::hash(&payload) != checksum {
+        return Err(SlotError::Checksum);
+    }
+    Ok((generation, payload))
+}
+
+/// Snapshot stored in slot file contents, None if `bytes` are not a slot file at all
+pub fn slot_payload(bytes: &[u8]) -> Option<Result<&[u8], SlotError>> {
+    let mut header = bytes.get(..SLOT_HEADER_LEN as usize)?;
+    let (_, checksum) = read_header(&mut header).ok()?;
+    let payload = &bytes[SLOT_HEADER_LEN as usize..];
+    if crc32fast::hash(payload) != checksum {
+        return Some(Err(SlotError::Checksum));
+    }
+    Some(Ok(payload))
+}
+
+/// Writer computing checksum of everything passing through it
+struct Checksummed<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether snapshot in slot matches its checksum, without holding it in memory
+fn verify_slot(path: &Path) -> Result<(), SlotError> {
+    let mut file = File::open(path)?;
+    let (_, checksum) = read_header(&mut file)?;
+    let mut hashing = Checksummed {
+        inner: std::io::sink(),
+        hasher: crc32fast::Hasher::new(),
+    };
+    std::io::copy(&mut file, &mut hashing)?;
+    if hashing.hasher.finalize() != checksum {
+        return Err(SlotError::Checksum);
+    }
+    Ok(())
+}
+
+/// Write snapshot keeping the newest valid one intact, returning path of the slot written
+pub fn save_rotating<const SMALLSIZE: usize>(
+    state: &Database<SMALLSIZE>,
+    base: impl AsRef<Path>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let slots = slot_paths(&base);
+    let existing = slots_newest_first(&base);
+    let newest = existing.first().map(|(generation, _)| *generation);
+    let target = match existing.first() {
+        // a broken newest slot is worth less than the older one, so it is the one to replace
+        Some((_, path)) if verify_slot(path).is_err() => path,
+        Some((_, path)) => slots.iter().find(|slot| *slot != path).unwrap(),
+        None => &slots[0],
+    };
+    let replaced = existing
+        .iter()
+        .find(|(_, path)| path == target)
+        .map(|&(generation, _)| generation);
+
+    let dirty = state.take_dirty();
+    let written = write_slot(state, target, newest.map_or(1, |generation| generation + 1));
+    if let Err(e) = written {
+        if let Some(dirty) = dirty {
+            state.restore_dirty(dirty);
+        }
+        return Err(e);
+    }
+    if let Some(generation) = replaced {
+        delta::remove_chain(&base, generation)?;
+    }
+    Ok(target.clone())
+}
+
+fn write_slot<const SMALLSIZE: usize>(
+    state: &Database<SMALLSIZE>,
+    target: &Path,
+    generation: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_path = get_temp_filename(target);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(SLOT_MAGIC)?;
+    file.write_u64::<BigEndian>(generation)?;
+    file.write_u32::<BigEndian>(0)?;
+
+    let mut writer = Checksummed {
+        inner: BufWriter::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+    state.dump(&mut writer)?;
+    writer.flush()?;
+    let checksum = writer.hasher.finalize();
+    let mut file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(SLOT_HEADER_LEN - 4))?;
+    file.write_u32::<BigEndian>(checksum)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(temp_path, target)?;
+    Ok(())
+}
+
+/// Load newest slot that passes checksum and decodes along with its generation, None if there are
+/// no slots at all
+pub fn load_newest<const SMALLSIZE: usize>(
+    base: impl AsRef<Path>,
+    decoding: BigRecordDecoding,
+) -> Result<Option<(u64, Database<SMALLSIZE>)>, Box<dyn std::error::Error>> {
+    let mut last_error = None;
+    for (generation, path) in slots_newest_first(base) {
+        let loaded = read_slot(&path, true)
+            .map_err(Box::<dyn std::error::Error>::from)
+            .and_then(|(_, payload)| Ok(Database::load_with(&mut payload.as_slice(), decoding)?));
+        match loaded {
+            Ok(state) => return Ok(Some((generation, state))),
+            Err(e) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    generation,
+                    "snapshot slot is unusable, falling back to older one: {e}"
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::{
+        config::BigRecordDecoding,
+        storage::{Database, Key},
+    };
+
+    use super::{load_newest, save_rotating, slots_newest_first};
+
+    #[test]
+    fn corrupt_newest_slot_falls_back_to_older() {
+        let dir = std::env::temp_dir().join(format!("elizadb-rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("state.elizadb");
+
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "old").unwrap();
+        let first = save_rotating(&db, &base).unwrap();
+        db.set_flag(key, "new").unwrap();
+        let second = save_rotating(&db, &base).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(slots_newest_first(&base)[0], (2, second.clone()));
+
+        let (_, loaded) = load_newest::<8>(&base, BigRecordDecoding::Eager)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.horizontal_query(&key).unwrap().unwrap().len(), 2);
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&second)
+            .unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let (generation, loaded) = load_newest::<8>(&base, BigRecordDecoding::Eager)
+            .unwrap()
+            .unwrap();
+        assert_eq!(generation, 1);
+        assert_eq!(loaded.horizontal_query(&key).unwrap().unwrap().len(), 1);
+
+        // broken slot is the one overwritten next
+        assert_eq!(save_rotating(&db, &base).unwrap(), second);
+        assert_eq!(slots_newest_first(&base)[0], (3, second));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
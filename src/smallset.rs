@@ -1,61 +1,145 @@
-use std::usize;
-
-use serde::{Deserialize, Serialize};
+//! Fixed-capacity open-addressing set of small integers, stored inline without allocation
+//!
+//! Records keep their term ids in these. Two values of the element type are reserved as slot
+//! markers, so elements go in as [`SmallsetItem`], which is known not to be one of them.
+//!
+//! ```
+//! use elizadb::smallset::{Smallset, SmallsetItem};
+//!
+//! let mut set: Smallset<u8, 8> = [3, 11, 5].into_iter().collect();
+//! assert_eq!(set.insert(SmallsetItem::new(7).unwrap()), Ok(true));
+//! assert!(set.contains(SmallsetItem::new(11).unwrap()));
+//! assert_eq!(set.len(), 4);
+//!
+//! let mut values: Vec<u8> = set.into_iter().collect();
+//! values.sort();
+//! assert_eq!(values, [3, 5, 7, 11]);
+//! ```
+
+use std::{fmt::Debug, hash::Hash};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_big_array::BigArray;
 
+/// Integer type that can be stored in Smallset slots, with two values reserved as slot markers
+pub trait SlotValue:
+    Copy + Eq + Hash + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// Marks slot that was never written
+    const EMPTY_SLOT: Self;
+    /// Marks slot whose value was removed
+    const TOMBSTONE: Self;
+
+    fn as_index(self) -> usize;
+
+    /// Whether any of `slots` holds `value`, comparing a machine word worth of slots at a time
+    fn any_slot_equals(slots: &[Self], value: Self) -> bool;
+}
+
+/// Value that is known not to collide with slot markers
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
-pub(super) struct SmallsetItem(u8);
+pub struct SmallsetItem<T>(T);
 
-impl From<SmallsetItem> for u8 {
-    fn from(val: SmallsetItem) -> Self {
-        val.0
+impl<T: SlotValue> SmallsetItem<T> {
+    /// Wrap value, None if it is one of slot markers
+    pub fn new(value: T) -> Option<Self> {
+        if value == T::EMPTY_SLOT || value == T::TOMBSTONE {
+            None
+        } else {
+            Some(SmallsetItem(value))
+        }
+    }
+
+    pub fn get(self) -> T {
+        self.0
     }
 }
 
-impl TryFrom<u8> for SmallsetItem {
-    type Error = u8;
+macro_rules! slot_value {
+    ($value: ty, $tombstone: expr) => {
+        impl SlotValue for $value {
+            const EMPTY_SLOT: Self = 0;
+            const TOMBSTONE: Self = $tombstone;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            EMPTY_SLOT | TOMBSTONE => Err(value),
-            _any_other => Ok(SmallsetItem(value)),
+            fn as_index(self) -> usize {
+                self as usize
+            }
+
+            fn any_slot_equals(slots: &[Self], value: Self) -> bool {
+                const LANES: usize = 8 / std::mem::size_of::<$value>();
+                // lowest and highest bit of every lane
+                const ONES: u64 = u64::MAX / <$value>::MAX as u64;
+                const HIGH: u64 = ONES << (<$value>::BITS - 1);
+
+                let pattern = ONES * value as u64;
+                let mut chunks = slots.chunks_exact(LANES);
+                let mut found = false;
+                for chunk in &mut chunks {
+                    let word = chunk
+                        .iter()
+                        .fold(0u64, |word, &slot| word << <$value>::BITS | slot as u64);
+                    // lanes equal to value become zero, and a word has a zero lane exactly when
+                    // borrowing from its lanes leaves a high bit that was clear before
+                    let diff = word ^ pattern;
+                    found |= diff.wrapping_sub(ONES) & !diff & HIGH != 0;
+                }
+                found | chunks.remainder().contains(&value)
+            }
         }
-    }
+
+        impl From<SmallsetItem<$value>> for $value {
+            fn from(val: SmallsetItem<$value>) -> Self {
+                val.0
+            }
+        }
+
+        impl TryFrom<$value> for SmallsetItem<$value> {
+            type Error = $value;
+
+            fn try_from(value: $value) -> Result<Self, Self::Error> {
+                SmallsetItem::new(value).ok_or(value)
+            }
+        }
+    };
 }
 
+slot_value!(u8, 0xff);
+slot_value!(u16, 0xffff);
+
+/// Set holding at most `SIZE` elements. Removed elements leave tombstones behind until the set is
+/// compacted or cleared, so equality compares elements rather than slot layout
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub struct Smallset<const SIZE: usize> {
+#[serde(bound = "T: SlotValue")]
+pub struct Smallset<T, const SIZE: usize> {
     #[serde(with = "BigArray")]
-    backing_storage: [u8; SIZE],
+    backing_storage: [T; SIZE],
 }
 
-pub const EMPTY_SLOT: u8 = 0;
-pub const TOMBSTONE: u8 = 0xff;
-
-impl<const SIZE: usize> Smallset<SIZE> {
+impl<T: SlotValue, const SIZE: usize> Smallset<T, SIZE> {
     /// Construct a new set without any elements
     pub fn new_empty() -> Self {
-        let backing_storage = [EMPTY_SLOT; SIZE];
+        let backing_storage = [T::EMPTY_SLOT; SIZE];
         Smallset { backing_storage }
     }
 
-    /// Construct a set from existing storage. Storage is not changed in any way and MUST come from Smallset
-    pub fn reiterpret(backing_storage: [u8; SIZE]) -> Self {
-        Smallset { backing_storage }
-    }
-
-    fn hash(data: u8) -> usize {
-        data as usize % SIZE
+    fn hash(data: T) -> usize {
+        data.as_index() % SIZE
     }
 
     fn probe(previous_index: usize) -> usize {
         (previous_index + 1) % SIZE
     }
 
+    /// Sets up to this size are searched whole without branching rather than along probe chains
+    const SCAN_WHOLE: bool = SIZE <= 16;
+
     /// Check if this value is stored in the set
-    pub fn contains(&self, data: SmallsetItem) -> bool {
-        let data = data.into();
+    pub fn contains(&self, data: SmallsetItem<T>) -> bool {
+        let data = data.get();
+        if Self::SCAN_WHOLE {
+            return T::any_slot_equals(&self.backing_storage, data);
+        }
         let hashcode = Self::hash(data);
         let mut look_position = hashcode;
         let mut attempt = 0;
@@ -64,7 +148,7 @@ impl<const SIZE: usize> Smallset<SIZE> {
             if value_in_slot == data {
                 return true;
             }
-            if value_in_slot == EMPTY_SLOT {
+            if value_in_slot == T::EMPTY_SLOT {
                 return false;
             }
 
@@ -74,118 +158,239 @@ impl<const SIZE: usize> Smallset<SIZE> {
         false
     }
 
-    /// Slot where this value could be written, None if map is full. Slot may contain value, contain tombstone or be empty
-    fn locate_slot_mut(&mut self, data: u8) -> Option<(&mut u8, usize)> {
-        let hashcode = Self::hash(data);
-        let mut look_position = hashcode;
-        let mut attempt = 0;
-        while attempt < SIZE {
-            let value_in_slot = self.backing_storage[look_position];
-            if value_in_slot == data || value_in_slot == EMPTY_SLOT || value_in_slot == TOMBSTONE {
-                return Some((&mut self.backing_storage[look_position], look_position));
-            }
-
-            look_position = Self::probe(look_position);
-            attempt += 1;
-        }
-
-        None
+    /// Number of `items` stored in the set, counting repeated items each time. Slots are scanned
+    /// once for the whole list instead of walking a probe chain per item, which is cheaper when
+    /// looking up several items and compiles into vector comparisons
+    pub fn contains_many(&self, items: &[SmallsetItem<T>]) -> u32 {
+        self.backing_storage
+            .iter()
+            .map(|&slot| items.iter().filter(|item| item.0 == slot).count() as u32)
+            .sum()
     }
 
-    /// Slot where this value could be written, None if map is full. Slot may contain value, contain tombstone or be empty
-    fn locate_insertion_slot(&self, data: u8) -> Option<(&u8, usize)> {
-        let hashcode = Self::hash(data);
-        let mut look_position = hashcode;
-        let mut attempt = 0;
-        while attempt < SIZE {
+    /// Walk probe chain of value up to the first empty slot, returning slot holding the value
+    /// and first slot where it could be written (tombstone or empty) if there is one
+    fn locate(&self, data: T) -> (Option<usize>, Option<usize>) {
+        let mut look_position = Self::hash(data);
+        let mut free_slot = None;
+        for _ in 0..SIZE {
             let value_in_slot = self.backing_storage[look_position];
-            if value_in_slot == data || value_in_slot == EMPTY_SLOT || value_in_slot == TOMBSTONE {
-                return Some((&self.backing_storage[look_position], look_position));
+            if value_in_slot == data {
+                return (Some(look_position), free_slot);
+            }
+            if value_in_slot == T::EMPTY_SLOT {
+                return (None, free_slot.or(Some(look_position)));
+            }
+            if value_in_slot == T::TOMBSTONE && free_slot.is_none() {
+                free_slot = Some(look_position);
             }
 
             look_position = Self::probe(look_position);
-            attempt += 1;
         }
 
-        None
+        (None, free_slot)
     }
 
     /// Insert this value into set and return bool indicating if it is new or error if set is full
-    pub fn insert(&mut self, data: SmallsetItem) -> Result<bool, u8> {
-        let data = data.into();
-        let (slot, _) = self.locate_slot_mut(data).ok_or(data)?;
-        if *slot == data {
-            return Ok(false);
+    pub fn insert(&mut self, data: SmallsetItem<T>) -> Result<bool, T> {
+        let data = data.get();
+        if Self::SCAN_WHOLE {
+            if T::any_slot_equals(&self.backing_storage, data) {
+                return Ok(false);
+            }
+            // absent value goes into the first free slot of its probe chain
+            let mut position = Self::hash(data);
+            for _ in 0..SIZE {
+                let value_in_slot = self.backing_storage[position];
+                if value_in_slot == T::EMPTY_SLOT || value_in_slot == T::TOMBSTONE {
+                    self.backing_storage[position] = data;
+                    return Ok(true);
+                }
+                position = Self::probe(position);
+            }
+            return Err(data);
+        }
+        match self.locate(data) {
+            (Some(_), _) => Ok(false),
+            (None, Some(free_slot)) => {
+                self.backing_storage[free_slot] = data;
+                Ok(true)
+            }
+            (None, None) => Err(data),
         }
-        *slot = data;
-        Ok(true)
     }
 
     /// Remove value from set, returning bool if it was here
-    pub fn remove(&mut self, data: SmallsetItem) -> bool {
-        let data = data.into();
-        let Some((slot, index)) = self.locate_insertion_slot(data) else {
+    pub fn remove(&mut self, data: SmallsetItem<T>) -> bool {
+        let (Some(index), _) = self.locate(data.get()) else {
             return false;
         };
-        if *slot == EMPTY_SLOT || *slot == TOMBSTONE {
-            return false;
+
+        // probe chains never contain empty slots, so if the chain ends right after this slot no other
+        // value is looked up through it and it can be emptied along with tombstones leading to it
+        if self.backing_storage[Self::probe(index)] != T::EMPTY_SLOT {
+            self.backing_storage[index] = T::TOMBSTONE;
+            return true;
+        }
+        let mut position = index;
+        for _ in 0..SIZE {
+            self.backing_storage[position] = T::EMPTY_SLOT;
+            position = (position + SIZE - 1) % SIZE;
+            if self.backing_storage[position] != T::TOMBSTONE {
+                break;
+            }
         }
-        let next_position = Self::probe(index);
-        self.backing_storage[index] = if self.backing_storage[next_position] == EMPTY_SLOT {
-            EMPTY_SLOT
-        } else {
-            TOMBSTONE
-        };
         true
     }
 
-    /// Load factor computed as occupied / capacity
-    pub fn load_factor(&self) -> f32 {
-        let occupied_slots = self.size();
-
-        occupied_slots as f32 / SIZE as f32
-    }
-
     /// Number of elements stored in this set
-    pub fn size(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.backing_storage
             .iter()
             .copied()
-            .filter(|&item| item != EMPTY_SLOT && item != TOMBSTONE)
+            .filter(|&item| item != T::EMPTY_SLOT && item != T::TOMBSTONE)
             .count()
     }
 
-    /// Number of elements this set can store
-    pub fn capacity(&self) -> usize {
-        SIZE
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
     }
 
-    /// Iterator over elements of the set
-    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+    /// Number of slots holding removed values, these slow down lookups until the set is compacted
+    pub fn tombstones(&self) -> usize {
         self.backing_storage
             .iter()
-            .cloned()
-            .filter(|&item| item != EMPTY_SLOT && item != TOMBSTONE)
+            .filter(|&&item| item == T::TOMBSTONE)
+            .count()
+    }
+
+    /// Iterator over elements of the set, in no particular order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            slots: self.backing_storage.iter(),
+        }
     }
 
-    /// Clone self into compatible set, getting rid of any tombstones in the process
-    pub fn compact<const OTHERSIZE: usize>(&self, target: &mut Smallset<OTHERSIZE>) {
-        target.backing_storage.fill(EMPTY_SLOT);
+    /// Clone self into compatible set, getting rid of any tombstones in the process. Fails with
+    /// the first item `target` has no room for
+    pub fn compact<const OTHERSIZE: usize>(
+        &self,
+        target: &mut Smallset<T, OTHERSIZE>,
+    ) -> Result<(), T> {
+        target.backing_storage.fill(T::EMPTY_SLOT);
         for item in self.iter() {
-            target.insert(item.try_into().unwrap()).unwrap();
+            target.insert(SmallsetItem(item))?;
         }
+        Ok(())
     }
 
     pub fn clear(&mut self) {
-        self.backing_storage.fill(EMPTY_SLOT);
+        self.backing_storage.fill(T::EMPTY_SLOT);
+    }
+}
+
+impl<T: SlotValue, const SIZE: usize> Default for Smallset<T, SIZE> {
+    fn default() -> Self {
+        Self::new_empty()
+    }
+}
+
+impl<T: SlotValue, const SIZE: usize, const OTHERSIZE: usize> PartialEq<Smallset<T, OTHERSIZE>>
+    for Smallset<T, SIZE>
+{
+    fn eq(&self, other: &Smallset<T, OTHERSIZE>) -> bool {
+        self.len() == other.len() && self.iter().all(|item| other.contains(SmallsetItem(item)))
+    }
+}
+
+impl<T: SlotValue, const SIZE: usize> Eq for Smallset<T, SIZE> {}
+
+/// Panics if a value is one of slot markers or there are more distinct values than `SIZE`, use
+/// [`Smallset::insert`] to handle those
+impl<T: SlotValue, const SIZE: usize> Extend<T> for Smallset<T, SIZE> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            let item = SmallsetItem::new(value)
+                .unwrap_or_else(|| panic!("{value:?} is reserved as slot marker"));
+            if self.insert(item).is_err() {
+                panic!("set is full, {SIZE} values fit");
+            }
+        }
+    }
+}
+
+/// Panics under the same conditions as [`Extend`]
+impl<T: SlotValue, const SIZE: usize> FromIterator<T> for Smallset<T, SIZE> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self::new_empty();
+        set.extend(iter);
+        set
+    }
+}
+
+/// Borrowing iterator over elements, see [`Smallset::iter`]
+#[derive(Clone, Debug)]
+pub struct Iter<'a, T> {
+    slots: std::slice::Iter<'a, T>,
+}
+
+impl<T: SlotValue> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.slots
+            .by_ref()
+            .copied()
+            .find(|&item| item != T::EMPTY_SLOT && item != T::TOMBSTONE)
+    }
+}
+
+/// Owning iterator over elements of a set
+#[derive(Clone, Debug)]
+pub struct IntoIter<T, const SIZE: usize> {
+    slots: std::array::IntoIter<T, SIZE>,
+}
+
+impl<T: SlotValue, const SIZE: usize> Iterator for IntoIter<T, SIZE> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.slots
+            .by_ref()
+            .find(|&item| item != T::EMPTY_SLOT && item != T::TOMBSTONE)
+    }
+}
+
+impl<T: SlotValue, const SIZE: usize> IntoIterator for Smallset<T, SIZE> {
+    type Item = T;
+    type IntoIter = IntoIter<T, SIZE>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            slots: self.backing_storage.into_iter(),
+        }
+    }
+}
+
+impl<'a, T: SlotValue, const SIZE: usize> IntoIterator for &'a Smallset<T, SIZE> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
     use super::Smallset;
 
-    type Small8 = Smallset<8>;
+    type Small8 = Smallset<u8, 8>;
+    type Wide8 = Smallset<u16, 8>;
 
     macro_rules! item {
         ($x: expr) => {
@@ -235,4 +440,146 @@ mod tests {
         assert!(!set.contains(item!(2)));
         assert!(set.contains(item!(10)));
     }
+
+    #[test]
+    fn wide_items_beyond_u8_range_are_stored() {
+        let mut set = Wide8::new_empty();
+        set.insert(item!(300u16)).unwrap();
+        set.insert(item!(2u16)).unwrap();
+
+        assert!(set.contains(item!(300u16)));
+        assert!(set.contains(item!(2u16)));
+        assert!(!set.contains(item!(255u16)));
+        assert!(super::SmallsetItem::try_from(0xffffu16).is_err());
+    }
+
+    #[test]
+    fn removal_inside_chain_keeps_later_items_reachable() {
+        let mut set = Small8::new_empty();
+        // all hash to slot 1 and form one chain
+        for value in [1, 9, 17, 25] {
+            set.insert(item!(value)).unwrap();
+        }
+        assert!(set.remove(item!(9)));
+        assert!(set.remove(item!(17)));
+
+        assert!(set.contains(item!(25)));
+        assert!(!set.contains(item!(17)));
+        assert_eq!(set.insert(item!(25)), Ok(false));
+        assert_eq!(set.len(), 2);
+        assert!(set.remove(item!(25)));
+        assert!(!set.contains(item!(25)));
+        assert_eq!(set.tombstones(), 0);
+    }
+
+    #[test]
+    fn reinsert_after_tombstone_does_not_duplicate() {
+        let mut set = Small8::new_empty();
+        for value in [1, 30, 38] {
+            set.insert(item!(value)).unwrap();
+        }
+        set.remove(item!(30));
+
+        assert_eq!(set.insert(item!(38)), Ok(false));
+        assert!(set.remove(item!(38)));
+        assert!(!set.contains(item!(38)));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn full_set_reuses_tombstones() {
+        let mut set = Small8::new_empty();
+        for value in 1..=8 {
+            set.insert(item!(value)).unwrap();
+        }
+        assert!(set.insert(item!(9)).is_err());
+        set.remove(item!(3));
+
+        assert_eq!(set.insert(item!(9)), Ok(true));
+        assert!((1..=9).filter(|&v| v != 3).all(|v| set.contains(item!(v))));
+    }
+
+    #[test]
+    fn whole_set_scan_tells_apart_values_sharing_bytes() {
+        let set: Wide8 = [0x0101, 0x0200, 0xfe].into_iter().collect();
+        for present in [0x0101u16, 0x0200, 0xfe] {
+            assert!(set.contains(item!(present)));
+        }
+        for absent in [0x0001u16, 0x0100, 0x0002, 0xfe00, 0x01fe] {
+            assert!(!set.contains(item!(absent)));
+        }
+        let wide: Smallset<u16, 32> = (1..=20).collect();
+        assert!(wide.contains(item!(20u16)) && !wide.contains(item!(21u16)));
+    }
+
+    #[test]
+    fn contains_many_counts_listed_items() {
+        let mut set: Wide8 = [9, 17, 300].into_iter().collect();
+        set.remove(item!(9));
+        let items = [17, 300, 9, 17, 4].map(|value: u16| item!(value));
+        assert_eq!(set.contains_many(&items), 3);
+        assert_eq!(set.contains_many(&[]), 0);
+    }
+
+    #[test]
+    fn collected_sets_compare_by_elements() {
+        let mut set: Small8 = [4, 12, 20].into_iter().collect();
+        set.remove(item!(4));
+        let other: Smallset<u8, 16> = [20, 12].into_iter().collect();
+        assert_eq!(set, other);
+        assert_ne!(set, Small8::default());
+
+        set.extend([1, 2]);
+        let mut values: Vec<u8> = (&set).into_iter().collect();
+        values.sort_unstable();
+        assert_eq!(values, [1, 2, 12, 20]);
+        assert!(!set.is_empty() && Small8::new_empty().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "set is full")]
+    fn collecting_too_many_values_panics() {
+        let _: Small8 = (1..=9).collect();
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Insert(u8),
+        Remove(u8),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // narrow value range makes collisions and long probe chains likely
+        prop_oneof![
+            (1u8..40).prop_map(Op::Insert),
+            (1u8..40).prop_map(Op::Remove),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn behaves_like_hashset(ops in prop::collection::vec(op(), 0..200)) {
+            let mut set = Small8::new_empty();
+            let mut reference = HashSet::new();
+
+            for op in ops {
+                match op {
+                    Op::Insert(value) => match set.insert(item!(value)) {
+                        Ok(is_new) => prop_assert_eq!(is_new, reference.insert(value)),
+                        Err(_) => prop_assert!(!reference.contains(&value) && reference.len() == 8),
+                    },
+                    Op::Remove(value) => {
+                        prop_assert_eq!(set.remove(item!(value)), reference.remove(&value))
+                    }
+                }
+
+                prop_assert_eq!(set.len(), reference.len());
+                let all = (1u8..40).map(|value| item!(value)).collect::<Vec<_>>();
+                prop_assert_eq!(set.contains_many(&all) as usize, reference.len());
+                for value in 1u8..40 {
+                    prop_assert_eq!(set.contains(item!(value)), reference.contains(&value));
+                }
+            }
+        }
+    }
 }
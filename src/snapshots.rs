@@ -0,0 +1,203 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    api::DBState,
+    archive::{ArchiveError, Retention, SnapshotArchive},
+    config::Config,
+    jobs::{JobKind, Jobs},
+    serde::delta::{DeltaError, DeltaReport},
+    stats::SnapshotInfo,
+};
+
+#[cfg(feature = "s3")]
+use crate::s3::S3Target;
+
+/// Takes full snapshots and deltas of shared state, on request or periodically
+#[derive(Clone)]
+pub struct Snapshotter {
+    db: DBState,
+    data_file: PathBuf,
+    background: bool,
+    /// Held for the duration of every save so that full snapshots and deltas do not interleave
+    saving: Arc<tokio::sync::Mutex<()>>,
+    last_snapshot: Arc<Mutex<Option<SnapshotInfo>>>,
+    jobs: Arc<Jobs>,
+    /// Period of automatic snapshots, none when disabled
+    interval: Arc<tokio::sync::watch::Sender<Option<Duration>>>,
+    /// Copies of full snapshots kept for rollback, when enabled
+    archive: Option<Arc<SnapshotArchive>>,
+    #[cfg(feature = "s3")]
+    remote: Option<Arc<S3Target>>,
+}
+
+impl Snapshotter {
+    pub fn data_file(&self) -> &std::path::Path {
+        &self.data_file
+    }
+
+    /// Fails if remote snapshot target is configured but invalid
+    pub fn new(
+        db: DBState,
+        jobs: Arc<Jobs>,
+        config: &Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            db,
+            data_file: config.data_file.clone(),
+            background: config.background_snapshots,
+            saving: Default::default(),
+            last_snapshot: Default::default(),
+            jobs,
+            interval: Arc::new(tokio::sync::watch::Sender::new(
+                config.snapshot_interval_secs.map(Duration::from_secs),
+            )),
+            archive: config.snapshot_archive_keep.map(|keep| {
+                let retention = Retention {
+                    keep,
+                    max_age: config
+                        .snapshot_archive_max_age_secs
+                        .map(Duration::from_secs),
+                };
+                Arc::new(SnapshotArchive::new(config.data_file.clone(), retention))
+            }),
+            #[cfg(feature = "s3")]
+            remote: match &config.snapshot_target {
+                Some(url) => Some(Arc::new(S3Target::new(url, config.snapshot_retention)?)),
+                None => None,
+            },
+        })
+    }
+
+    /// Save full snapshot into data file, copying it into archive and uploading it if configured
+    pub async fn save(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.jobs.run(JobKind::Snapshot, self.save_tracked()).await
+    }
+
+    async fn save_tracked(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let _saving = self.saving.lock().await;
+        let started = Instant::now();
+        let saved = match self.save_locally().await {
+            Ok((paused, keys)) => self
+                .add_to_archive(keys)
+                .await
+                .map(|_| paused)
+                .map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        #[cfg(feature = "s3")]
+        let saved = match (saved, &self.remote) {
+            (Ok(paused), Some(remote)) => self.upload(remote).await.map(|_| paused),
+            (saved, _) => saved,
+        };
+
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        *self.last_snapshot.lock().unwrap() = Some(SnapshotInfo {
+            finished_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            paused_ms: saved.as_ref().map_or(0, |paused| paused.as_millis() as u64),
+            succeeded: saved.is_ok(),
+        });
+        saved.map(|_| ())
+    }
+
+    /// Returns time during which writes were blocked and number of keys saved
+    async fn save_locally(
+        &self,
+    ) -> Result<(Duration, usize), Box<dyn std::error::Error + Send + Sync>> {
+        if self.background {
+            return crate::serde::save_in_background(&self.db, self.data_file.clone()).await;
+        }
+        let started = Instant::now();
+        let db = self.db.read().await;
+        crate::serde::two_phase_save(&db, &self.data_file).map_err(|e| e.to_string())?;
+        Ok((started.elapsed(), db.key_count()))
+    }
+
+    async fn add_to_archive(&self, keys: usize) -> Result<(), ArchiveError> {
+        let Some(archive) = self.archive.clone() else {
+            return Ok(());
+        };
+        let added = tokio::task::spawn_blocking(move || archive.add(keys))
+            .await
+            .expect("archiving snapshot does not panic")?;
+        if let Some(copy) = added {
+            tracing::info!(path = %copy.path.display(), "archived snapshot");
+        }
+        Ok(())
+    }
+
+    pub fn archive(&self) -> Option<&SnapshotArchive> {
+        self.archive.as_deref()
+    }
+
+    #[cfg(feature = "s3")]
+    async fn upload(
+        &self,
+        remote: &S3Target,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some((_, slot)) = crate::serde::rotation::slots_newest_first(&self.data_file)
+            .into_iter()
+            .next()
+        else {
+            return Err("saved snapshot slot is missing".into());
+        };
+        let uploaded = remote.upload(&slot).await?;
+        tracing::info!(object = %uploaded, "uploaded snapshot");
+        Ok(())
+    }
+
+    pub async fn save_delta(&self) -> Result<DeltaReport, DeltaError> {
+        self.jobs
+            .run(JobKind::DeltaSnapshot, async {
+                let _saving = self.saving.lock().await;
+                let db = self.db.read().await;
+                crate::serde::delta::save_delta(&db, &self.data_file)
+            })
+            .await
+    }
+
+    pub fn last_snapshot(&self) -> Option<SnapshotInfo> {
+        self.last_snapshot.lock().unwrap().clone()
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        *self.interval.borrow()
+    }
+
+    /// Change period of automatic snapshots, counting anew from now
+    pub fn set_interval(&self, interval: Option<Duration>) {
+        self.interval.send_replace(interval);
+    }
+}
+
+/// Periodically save full snapshot in background, following interval changes
+pub async fn run_periodically(snapshotter: Snapshotter) {
+    let mut interval = snapshotter.interval.subscribe();
+    loop {
+        let period = *interval.borrow_and_update();
+        let Some(period) = period else {
+            if interval.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(period) => {
+                if let Err(e) = snapshotter.save().await {
+                    tracing::error!("periodic snapshot failed: {e}");
+                }
+            }
+            changed = interval.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
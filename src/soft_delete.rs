@@ -0,0 +1,241 @@
+//! Soft deletion: deleted records are tombstoned rather than destroyed
+//!
+//! `DELETE /items/:key` moves the record aside with its terms, hiding it from queries and
+//! listings, and `POST /items/:key/restore` puts it back. Records deleted longer than retention
+//! ago are purged by a periodic sweep. Tombstones are saved in snapshots along with records.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::DBState,
+    changes::{Change, ChangeLog},
+    quotas::QuotaExceeded,
+    storage::{corruption, Database, Key, SetFlagError, StorageCorruption, TermTableFull},
+};
+
+/// Record as it was when deleted
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedRecord {
+    /// Seconds since Unix epoch
+    pub deleted_at: u64,
+    /// Terms by name, so that term ids freed and reused meanwhile cannot mix them up
+    pub terms: Vec<String>,
+    /// Key alias, given back on restore unless another key took it meanwhile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreError {
+    #[error("key {0} is not deleted")]
+    NotDeleted(Key),
+    #[error("key {0} was created again after being deleted")]
+    KeyExists(Key),
+    #[error(transparent)]
+    TermTableFull(#[from] TermTableFull),
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaExceeded),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Tombstone record of `key` as deleted at `deleted_at`, returns whether there was one
+    pub fn delete_record(&mut self, key: Key, deleted_at: u64) -> Result<bool, StorageCorruption> {
+        let Some(record) = self.record(&key)? else {
+            return Ok(false);
+        };
+        let term_ids = record.term_ids();
+        let terms = term_ids
+            .iter()
+            .map(|&term_id| {
+                self.explain_term_id(term_id)
+                    .map(String::from)
+                    .ok_or_else(|| corruption(format!("record {key} holds unknown term {term_id}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.detach(key)?;
+        self.index.remove(&key);
+        if let Some(columns) = &mut self.columns {
+            columns.remove_key(key);
+        }
+        self.count_quota_usage(key, -1, -(term_ids.len() as isize));
+        let alias = self.key_aliases.remove_backward(&key);
+        self.deleted.insert(
+            key,
+            DeletedRecord {
+                deleted_at,
+                terms,
+                alias,
+            },
+        );
+        self.mark_dirty(key);
+        Ok(true)
+    }
+
+    /// Bring tombstoned record back, creating terms that were removed meanwhile
+    pub fn restore_record(&mut self, key: Key) -> Result<DeletedRecord, RestoreError> {
+        let deleted = self
+            .deleted
+            .get(&key)
+            .ok_or(RestoreError::NotDeleted(key))?;
+        if self.contains_key(&key) {
+            return Err(RestoreError::KeyExists(key));
+        }
+        let missing_terms = deleted
+            .terms
+            .iter()
+            .filter(|term| self.get_term_id(term).is_none())
+            .count();
+        if missing_terms > self.remaining_term_capacity() {
+            return Err(TermTableFull.into());
+        }
+        self.check_quota_growth([(key, deleted.terms.len())])?;
+
+        let deleted = self.deleted.remove(&key).unwrap();
+        // flags were validated when they were first set
+        self.with_quotas_suspended(|db| {
+            db.with_validation_suspended(|db| {
+                db.create_record(key)?;
+                for term in &deleted.terms {
+                    db.set_flag(key, term).map_err(|e| match e {
+                        SetFlagError::TermTableFull(e) => RestoreError::TermTableFull(e),
+                        SetFlagError::QuotaExceeded(e) => RestoreError::QuotaExceeded(e),
+                        SetFlagError::UnknownTermId(_) => unreachable!("terms are set by name"),
+                        SetFlagError::Denied(_) => unreachable!("validation is suspended"),
+                        SetFlagError::Corruption(e) => RestoreError::Corruption(e),
+                    })?;
+                }
+                Ok::<_, RestoreError>(())
+            })
+        })?;
+        if let Some(alias) = &deleted.alias {
+            if self.resolve_key_alias(alias).is_none() {
+                let _ = self.set_key_alias(key, alias);
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Forget records deleted before `deleted_before`, returning their keys
+    pub fn purge_deleted(&mut self, deleted_before: u64) -> Vec<Key> {
+        let expired = self
+            .deleted
+            .iter()
+            .filter(|(_, deleted)| deleted.deleted_at < deleted_before)
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for &key in &expired {
+            self.deleted.remove(&key);
+            self.versions.forget(key);
+        }
+        expired
+    }
+
+    pub fn deleted_record(&self, key: Key) -> Option<&'_ DeletedRecord> {
+        self.deleted.get(&key)
+    }
+
+    /// Forget a single tombstone, returns whether there was one
+    pub fn purge_deleted_record(&mut self, key: Key) -> bool {
+        self.versions.forget(key);
+        self.deleted.remove(&key).is_some()
+    }
+
+    pub fn list_deleted(&self) -> impl Iterator<Item = (Key, &'_ DeletedRecord)> {
+        self.deleted.iter().map(|(&key, deleted)| (key, deleted))
+    }
+}
+
+/// Purge records deleted longer than `retention` ago, recording purges in change feed.
+/// Returns number of purged records
+pub async fn purge_expired(db: &DBState, changes: &ChangeLog, retention: Duration) -> usize {
+    let deleted_before = now().saturating_sub(retention.as_secs());
+    let mut db = db.write().await;
+    let purged = db.purge_deleted(deleted_before);
+    for &key in &purged {
+        changes.record(Change::PurgeRecord { key });
+    }
+    purged.len()
+}
+
+/// Periodically purge records whose retention has passed, skipping sweeps while `paused` tells
+/// that instance takes no writes
+pub async fn purge_periodically(
+    db: DBState,
+    changes: Arc<ChangeLog>,
+    retention: Duration,
+    interval: Duration,
+    paused: impl Fn() -> bool,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if paused() {
+            continue;
+        }
+        let purged = purge_expired(&db, &changes, retention).await;
+        if purged > 0 {
+            tracing::info!(purged, "purged deleted records");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::RestoreError;
+
+    #[test]
+    fn deleted_records_are_hidden_until_restored() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in ["x", "y"] {
+            db.set_flag(a, term).unwrap();
+        }
+        db.set_flag(b, "x").unwrap();
+        db.set_key_alias(a, "first").unwrap();
+
+        assert!(db.delete_record(a, 100).unwrap());
+        assert!(!db.delete_record(a, 100).unwrap());
+        assert_eq!(db.horizontal_query(&a).unwrap(), None);
+        assert_eq!(db.list_keys().collect::<Vec<_>>(), [b]);
+        assert_eq!(db.resolve_key_alias("first"), None);
+
+        let restored = db.restore_record(a).unwrap();
+        assert_eq!(restored.alias.as_deref(), Some("first"));
+        assert_eq!(db.horizontal_query(&a).unwrap().unwrap().len(), 2);
+        assert_eq!(db.resolve_key_alias("first"), Some(a));
+        assert!(matches!(
+            db.restore_record(a),
+            Err(RestoreError::NotDeleted(_))
+        ));
+
+        db.delete_record(a, 100).unwrap();
+        db.delete_record(b, 200).unwrap();
+        assert_eq!(db.purge_deleted(150), [a]);
+        assert!(matches!(
+            db.restore_record(a),
+            Err(RestoreError::NotDeleted(_))
+        ));
+        db.create_record(b).unwrap();
+        assert!(matches!(
+            db.restore_record(b),
+            Err(RestoreError::KeyExists(_))
+        ));
+    }
+}
@@ -0,0 +1,467 @@
+use std::{collections::HashMap, mem::size_of};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bigstore::{LazyRecord, SpilledRecord, TermBitmap},
+    columns::TermColumns,
+    query::KeyRange,
+    query_cache::QueryCacheStats,
+    quotas::QuotaUtilization,
+    smallset::{Smallset, SmallsetItem},
+    storage::{Database, IndexLocation, Key, SmallTier, StorageCorruption, TermError, TermId},
+};
+
+/// Rough per-entry overhead of std hash tables (control byte plus load factor slack)
+const HASH_ENTRY_OVERHEAD: usize = 2;
+
+/// Estimated heap usage in bytes, broken down by structure
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MemoryUsage {
+    pub small_storage: usize,
+    pub big_storage: usize,
+    pub terms: usize,
+    pub index: usize,
+    /// Term-major columns, zero unless columnar layout is enabled
+    pub columns: usize,
+    pub total: usize,
+}
+
+/// Record counts and memory usage reported by `/stats`
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    pub keys: usize,
+    pub terms: usize,
+    pub aliases: usize,
+    /// Number of terms that can still be added before term table is full
+    pub remaining_term_capacity: usize,
+    /// Number of records stored in each tier, keyed by tier capacity, `small` or `big`. Spilled records are also counted as big
+    pub records_per_tier: HashMap<String, usize>,
+    pub memory: MemoryUsage,
+    /// Configured quotas and how much of them is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quotas: Option<QuotaUtilization>,
+    /// Most recent full snapshot taken since start, filled in by the server
+    pub last_snapshot: Option<SnapshotInfo>,
+    /// Requests whose handler panicked since start, filled in by the server
+    pub handler_panics: u64,
+    /// Hits and misses of `/query` result cache if enabled, filled in by the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_cache: Option<QueryCacheStats>,
+}
+
+/// Timing of a full snapshot
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotInfo {
+    /// Seconds since Unix epoch when snapshot finished
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    /// Part of `duration_ms` during which writes were blocked
+    pub paused_ms: u64,
+    pub succeeded: bool,
+}
+
+/// Term as listed by `/terms/detailed`
+#[derive(Clone, Debug, Serialize)]
+pub struct TermUsage {
+    pub name: String,
+    pub id: TermId,
+    /// Number of keys carrying the term, zero means the term is unused
+    pub key_count: usize,
+    /// Unused term evicted since start to free the id for this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evicted: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageClass {
+    /// Any of the smallset tiers
+    Small,
+    Big,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyOrder {
+    Key,
+}
+
+/// Filters and pagination of `GET /items`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KeyListing {
+    /// Only keys carrying at least this many flags
+    #[serde(default)]
+    pub min_flags: Option<usize>,
+    #[serde(default)]
+    pub has_term: Option<String>,
+    #[serde(default)]
+    pub storage: Option<StorageClass>,
+    /// Keys come in storage order unless given
+    #[serde(default)]
+    pub order: Option<KeyOrder>,
+    /// Only keys above this one, last key of the previous page when ordered by key
+    #[serde(default, with = "crate::keys::flexible_option")]
+    pub after: Option<Key>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Only keys within, set for tenants rather than by callers
+    #[serde(skip)]
+    pub within: KeyRange,
+}
+
+/// Where a single record is stored, as reported by `/items/:key/info`
+#[derive(Clone, Debug, Serialize)]
+pub struct ItemInfo {
+    #[serde(with = "crate::keys::flexible")]
+    pub key: Key,
+    /// Tier holding the record, named the same way as in `records_per_tier` of `/stats`
+    pub tier: String,
+    pub flags: usize,
+    /// Number of flags the tier holds per record, None for big records
+    pub capacity: Option<usize>,
+    /// Flags that can still be set before record is promoted into a larger tier, None for big records
+    pub remaining_capacity: Option<usize>,
+    /// Whether big record currently lives in spill file rather than in memory
+    pub spilled: bool,
+    /// Changes whenever record does, see `expected_version` of `PUT /items/:key`
+    pub version: u64,
+}
+
+fn hash_table_bytes<K, V>(capacity: usize) -> usize {
+    capacity * (size_of::<K>() + size_of::<V>() + HASH_ENTRY_OVERHEAD)
+}
+
+impl<const SIZE: usize> SmallTier<SIZE> {
+    fn memory_usage(&self) -> usize {
+        self.keys.capacity() * size_of::<Option<Key>>()
+            + self.sets.capacity() * size_of::<Smallset<TermId, SIZE>>()
+            + self.holes.capacity() * size_of::<usize>()
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Estimate of memory held by this database. Allocator overhead is not accounted for
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let small_storage = self.small.memory_usage()
+            + self.tier16.memory_usage()
+            + self.tier32.memory_usage()
+            + self.tier64.memory_usage();
+
+        // spilled records only cost their entry in spill index
+        let big_storage = hash_table_bytes::<Key, TermBitmap>(self.big_storage.hot.capacity())
+            + self
+                .big_storage
+                .hot
+                .values()
+                .map(TermBitmap::memory_usage)
+                .sum::<usize>()
+            + hash_table_bytes::<Key, LazyRecord>(self.big_storage.lazy.capacity())
+            + self
+                .big_storage
+                .lazy
+                .values()
+                .map(LazyRecord::memory_usage)
+                .sum::<usize>()
+            + self.big_storage.spill.as_ref().map_or(0, |spill| {
+                hash_table_bytes::<Key, SpilledRecord>(spill.records.capacity())
+            });
+
+        // term table keeps every name twice, once per direction
+        let terms = self
+            .terms
+            .left_keys()
+            .map(|term| term.capacity())
+            .sum::<usize>()
+            + self.terms.len()
+                * 2
+                * (size_of::<String>() + size_of::<TermId>() + HASH_ENTRY_OVERHEAD)
+            + self
+                .aliases
+                .keys()
+                .map(|alias| alias.capacity())
+                .sum::<usize>()
+            + hash_table_bytes::<String, TermId>(self.aliases.capacity());
+
+        let index = hash_table_bytes::<Key, IndexLocation>(self.index.capacity());
+
+        let columns = self.columns.as_ref().map_or(0, TermColumns::memory_usage);
+
+        MemoryUsage {
+            small_storage,
+            big_storage,
+            terms,
+            index,
+            columns,
+            total: small_storage + big_storage + terms + index + columns,
+        }
+    }
+
+    pub fn item_info(&self, key: Key) -> Option<ItemInfo> {
+        let location = *self.index.get(&key)?;
+        let flags = self.record_size(&key)?;
+        let capacity = match location {
+            IndexLocation::Big => None,
+            location => Some(Self::location_capacity(location)),
+        };
+        let tier = match location {
+            IndexLocation::Small(_) => "small".to_string(),
+            IndexLocation::Big => "big".to_string(),
+            location => Self::location_capacity(location).to_string(),
+        };
+        Some(ItemInfo {
+            key,
+            tier,
+            flags,
+            capacity,
+            remaining_capacity: capacity.map(|capacity| capacity.saturating_sub(flags)),
+            spilled: self
+                .big_storage
+                .spill
+                .as_ref()
+                .is_some_and(|spill| spill.records.contains_key(&key)),
+            version: self.record_version(key),
+        })
+    }
+
+    /// Keys matching every filter of `listing`, fails if `has_term` is not known
+    pub fn list_keys_filtered(&self, listing: &KeyListing) -> Result<Vec<Key>, TermError> {
+        let term = match &listing.has_term {
+            Some(term) => Some(
+                self.get_term_id(term)
+                    .and_then(|term_id| SmallsetItem::try_from(term_id).ok())
+                    .ok_or_else(|| TermError::UnknownTerm(term.clone()))?,
+            ),
+            None => None,
+        };
+        let mut keys = self
+            .index
+            .iter()
+            .filter(|(&key, _)| {
+                listing.after.is_none_or(|after| key > after) && listing.within.contains(key)
+            })
+            .filter(|(_, location)| match listing.storage {
+                Some(StorageClass::Big) => matches!(location, IndexLocation::Big),
+                Some(StorageClass::Small) => !matches!(location, IndexLocation::Big),
+                None => true,
+            })
+            .filter_map(|(key, _)| {
+                if listing.min_flags.is_none() && term.is_none() {
+                    return Some(Ok(*key));
+                }
+                match self.record(key) {
+                    Ok(Some(record))
+                        if listing.min_flags.is_none_or(|min| record.size() >= min)
+                            && term.is_none_or(|term| record.contains(term)) =>
+                    {
+                        Some(Ok(*key))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if listing.order == Some(KeyOrder::Key) {
+            keys.sort_unstable();
+        }
+        keys.truncate(listing.limit.unwrap_or(usize::MAX));
+        Ok(keys)
+    }
+
+    /// Every term with its id and number of keys carrying it, ordered by id
+    pub fn term_usage(&self) -> Result<Vec<TermUsage>, StorageCorruption> {
+        let count = |term_id: TermId, scanned: &HashMap<TermId, usize>| match &self.columns {
+            Some(columns) => columns.count(term_id),
+            None => scanned.get(&term_id).copied().unwrap_or(0),
+        };
+
+        let mut scanned = HashMap::<TermId, usize>::new();
+        if self.columns.is_none() {
+            for record in self.records() {
+                let (_, record) = record?;
+                for term_id in record.term_ids() {
+                    *scanned.entry(term_id).or_default() += 1;
+                }
+            }
+        }
+
+        let mut usage = self
+            .terms
+            .iter()
+            .map(|(name, &id)| TermUsage {
+                name: name.clone(),
+                id,
+                key_count: count(id, &scanned),
+                evicted: self.evicted_term(id).map(String::from),
+            })
+            .collect::<Vec<_>>();
+        usage.sort_unstable_by_key(|term| term.id);
+        Ok(usage)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let records_per_tier = HashMap::from([
+            ("small".to_string(), self.small.keys().count()),
+            ("16".to_string(), self.tier16.keys().count()),
+            ("32".to_string(), self.tier32.keys().count()),
+            ("64".to_string(), self.tier64.keys().count()),
+            ("big".to_string(), self.big_storage.len()),
+            (
+                "spilled".to_string(),
+                self.big_storage
+                    .spill
+                    .as_ref()
+                    .map_or(0, |spill| spill.records.len()),
+            ),
+        ]);
+
+        Stats {
+            keys: self.index.len(),
+            terms: self.terms.len(),
+            aliases: self.aliases.len(),
+            remaining_term_capacity: self.remaining_term_capacity(),
+            records_per_tier,
+            memory: self.memory_usage(),
+            quotas: self.quota_utilization(),
+            last_snapshot: None,
+            handler_panics: 0,
+            query_cache: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::{KeyListing, KeyOrder, StorageClass};
+
+    #[test]
+    fn memory_usage_grows_with_records() {
+        let mut db = Database::<8>::default();
+        let empty = db.memory_usage().total;
+
+        for key in 1..=100 {
+            let key = Key::try_from(key).unwrap();
+            for term in 0..20 {
+                db.set_flag(key, &term.to_string()).unwrap();
+            }
+        }
+        let usage = db.memory_usage();
+
+        assert!(usage.total > empty);
+        assert!(usage.small_storage >= 100 * 32 * 2);
+        assert_eq!(
+            usage.total,
+            usage.small_storage + usage.big_storage + usage.terms + usage.index + usage.columns
+        );
+        assert_eq!(db.stats().records_per_tier["32"], 100);
+    }
+
+    #[test]
+    fn listed_keys_are_filtered_and_paged() {
+        let mut db = Database::<8>::default();
+        for key in 1..=6 {
+            let key = Key::try_from(key).unwrap();
+            for term in 0..key.get() * 15 {
+                db.set_flag(key, &term.to_string()).unwrap();
+            }
+        }
+        let keys = |listing: KeyListing| {
+            db.list_keys_filtered(&listing)
+                .unwrap()
+                .into_iter()
+                .map(Key::get)
+                .collect::<Vec<_>>()
+        };
+        let ordered = KeyListing {
+            order: Some(KeyOrder::Key),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            keys(KeyListing {
+                min_flags: Some(40),
+                ..ordered.clone()
+            }),
+            [3, 4, 5, 6]
+        );
+        assert_eq!(
+            keys(KeyListing {
+                storage: Some(StorageClass::Big),
+                ..ordered.clone()
+            }),
+            [5, 6]
+        );
+        assert_eq!(
+            keys(KeyListing {
+                has_term: Some("70".to_string()),
+                after: Key::new(5),
+                ..ordered.clone()
+            }),
+            [6]
+        );
+        assert_eq!(
+            keys(KeyListing {
+                after: Key::new(1),
+                limit: Some(2),
+                ..ordered
+            }),
+            [2, 3]
+        );
+        assert!(db
+            .list_keys_filtered(&KeyListing {
+                has_term: Some("missing".to_string()),
+                ..Default::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn item_info_reports_tier_and_remaining_capacity() {
+        let mut db = Database::<8>::default();
+        let (small, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        for term in 0..3 {
+            db.set_flag(small, &term.to_string()).unwrap();
+        }
+        for term in 0..70 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+
+        let info = db.item_info(small).unwrap();
+        assert_eq!(
+            (info.tier.as_str(), info.flags, info.remaining_capacity),
+            ("small", 3, Some(5))
+        );
+        let info = db.item_info(big).unwrap();
+        assert_eq!((info.tier.as_str(), info.capacity), ("big", None));
+        assert!(db.item_info(Key::try_from(3).unwrap()).is_none());
+    }
+
+    #[test]
+    fn term_usage_counts_keys() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(a, "x").unwrap();
+        db.set_flag(b, "x").unwrap();
+        db.set_flag(a, "y").unwrap();
+        db.add_term("unused").unwrap();
+
+        let counts = |db: &Database<8>| {
+            db.term_usage()
+                .unwrap()
+                .into_iter()
+                .map(|term| (term.name, term.key_count))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            ("x".to_string(), 2),
+            ("y".to_string(), 1),
+            ("unused".to_string(), 0),
+        ];
+        assert_eq!(counts(&db), expected);
+
+        db.enable_term_columns().unwrap();
+        assert_eq!(counts(&db), expected);
+    }
+}
@@ -1,12 +1,37 @@
-use super::doublemap::DoubleMap;
-use crate::smallset::{Smallset, SmallsetItem};
+use super::{
+    bigstore::{BigStorage, TermBitmap},
+    columns::TermColumns,
+    doublemap::DoubleMap,
+};
+use crate::{
+    config::TermEvictionPolicy,
+    latency::{Latencies, Operation},
+    plugins::{FlagValidator, WriteDenied},
+    quotas::{QuotaExceeded, QuotaState},
+    smallset::{Smallset, SmallsetItem},
+    soft_delete::DeletedRecord,
+    stats::StorageClass,
+    term_capacity::EvictionGuard,
+    triggers::{Trigger, TriggerCall},
+    versions::RecordVersions,
+};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
+use tokio::sync::mpsc::UnboundedSender;
 
 pub type Key = NonZeroU64;
 
+/// Identifier terms are stored under inside records
+pub type TermId = u16;
+
+/// Number of distinct terms database can hold, empty slot and tombstone ids are reserved by Smallset
+pub const MAX_TERMS: usize = TermId::MAX as usize - 1;
+
 const _: () = {
     assert!(
         std::mem::size_of::<Option<Key>>() == std::mem::size_of::<Key>(),
@@ -14,114 +39,988 @@ const _: () = {
     );
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(super) enum IndexLocation {
     /// Offset in number of elements (must be multiplied by size if offsetting into bytes)
     Small(usize),
+    Tier16(usize),
+    Tier32(usize),
+    Tier64(usize),
     Big,
 }
 
+/// Slab of fixed capacity records, slots freed by promotion are reused by later records
+#[derive(Clone, Default)]
+pub(super) struct SmallTier<const SIZE: usize> {
+    pub(super) keys: Vec<Option<Key>>,
+    pub(super) sets: Vec<Smallset<TermId, SIZE>>,
+    pub(super) holes: VecDeque<usize>,
+}
+
+impl<const SIZE: usize> SmallTier<SIZE> {
+    pub(super) fn get(&self, index: usize) -> Option<&Smallset<TermId, SIZE>> {
+        self.sets.get(index)
+    }
+
+    pub(super) fn get_mut(&mut self, index: usize) -> Option<&mut Smallset<TermId, SIZE>> {
+        self.sets.get_mut(index)
+    }
+
+    /// Store record with given items, reusing a hole if there is one. Items must fit into SIZE
+    fn allocate(&mut self, key: Key, items: &[TermId]) -> Result<usize, StorageCorruption> {
+        let items = items
+            .iter()
+            .map(|&item| {
+                SmallsetItem::new(item)
+                    .ok_or_else(|| corruption(format!("record {key} holds slot marker {item}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if items.len() > SIZE {
+            return Err(corruption(format!(
+                "record {key} with {} terms does not fit into tier of {SIZE}",
+                items.len()
+            )));
+        }
+
+        let index = match self.holes.pop_back() {
+            Some(hole) => {
+                self.keys[hole] = Some(key);
+                self.sets[hole].clear();
+                hole
+            }
+            None => {
+                self.keys.push(Some(key));
+                self.sets.push(Smallset::new_empty());
+                self.sets.len() - 1
+            }
+        };
+        for item in items {
+            self.sets[index].insert(item).map_err(|item| {
+                corruption(format!(
+                    "term {item} of record {key} does not fit into its set"
+                ))
+            })?;
+        }
+        Ok(index)
+    }
+
+    /// Free slot and return items that were stored in it
+    fn release(&mut self, index: usize) -> Vec<TermId> {
+        let items = self.sets[index].iter().collect();
+        self.keys[index] = None;
+        self.holes.push_back(index);
+        items
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (Key, &'_ Smallset<TermId, SIZE>)> {
+        self.keys
+            .iter()
+            .zip(self.sets.iter())
+            .filter_map(|(key, set)| Some(((*key)?, set)))
+    }
+
+    pub(super) fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.keys.iter().filter_map(|key| *key)
+    }
+}
+
+/// Record borrowed from whichever tier it currently lives in
+pub(super) enum RecordRef<'a, const SMALLSIZE: usize> {
+    Small(&'a Smallset<TermId, SMALLSIZE>),
+    Tier16(&'a Smallset<TermId, 16>),
+    Tier32(&'a Smallset<TermId, 32>),
+    Tier64(&'a Smallset<TermId, 64>),
+    Big(Cow<'a, TermBitmap>),
+}
+
+impl<const SMALLSIZE: usize> RecordRef<'_, SMALLSIZE> {
+    pub(super) fn contains(&self, item: SmallsetItem<TermId>) -> bool {
+        match self {
+            RecordRef::Small(set) => set.contains(item),
+            RecordRef::Tier16(set) => set.contains(item),
+            RecordRef::Tier32(set) => set.contains(item),
+            RecordRef::Tier64(set) => set.contains(item),
+            RecordRef::Big(set) => set.contains(item.get()),
+        }
+    }
+
+    /// How many of `items` the record holds, repeated items are counted each time
+    pub(super) fn count_contained(&self, items: &[SmallsetItem<TermId>]) -> usize {
+        match self {
+            RecordRef::Small(set) => set.contains_many(items) as usize,
+            RecordRef::Tier16(set) => set.contains_many(items) as usize,
+            RecordRef::Tier32(set) => set.contains_many(items) as usize,
+            RecordRef::Tier64(set) => set.contains_many(items) as usize,
+            RecordRef::Big(set) => items.iter().filter(|item| set.contains(item.get())).count(),
+        }
+    }
+
+    /// Number of terms set on the record
+    pub(super) fn size(&self) -> usize {
+        match self {
+            RecordRef::Small(set) => set.len(),
+            RecordRef::Tier16(set) => set.len(),
+            RecordRef::Tier32(set) => set.len(),
+            RecordRef::Tier64(set) => set.len(),
+            RecordRef::Big(set) => set.len(),
+        }
+    }
+
+    pub(super) fn term_ids(&self) -> Vec<TermId> {
+        match self {
+            RecordRef::Small(set) => set.iter().collect(),
+            RecordRef::Tier16(set) => set.iter().collect(),
+            RecordRef::Tier32(set) => set.iter().collect(),
+            RecordRef::Tier64(set) => set.iter().collect(),
+            RecordRef::Big(set) => set.iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TermError {
+    #[error("unknown term {0}")]
+    UnknownTerm(String),
+    #[error("term {0} already exists")]
+    AlreadyExists(String),
+    #[error("term {0} cannot be merged into itself")]
+    MergeIntoItself(String),
+    #[error("term id {0} is already taken")]
+    IdTaken(TermId),
+    #[error("term id {0} is out of range 1..={MAX_TERMS}")]
+    InvalidId(TermId),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("term table is full and cannot take more terms")]
+pub struct TermTableFull;
+
+/// Internal invariant of storage does not hold, e.g. indexed key has no record
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("storage is corrupted: {0}")]
+pub struct StorageCorruption(pub String);
+
+/// Report broken invariant, failing just the operation instead of taking the server down
+pub(super) fn corruption(message: String) -> StorageCorruption {
+    tracing::error!("storage is corrupted: {message}");
+    StorageCorruption(message)
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SetFlagError {
+    #[error(transparent)]
+    TermTableFull(#[from] TermTableFull),
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaExceeded),
+    #[error("unknown term id {0}")]
+    UnknownTermId(TermId),
+    #[error(transparent)]
+    Denied(#[from] WriteDenied),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
 #[derive(Default)]
 pub struct Database<const SMALLSIZE: usize> {
-    pub(super) terms: DoubleMap<String, u8>,
+    pub(super) terms: DoubleMap<String, TermId>,
+    /// Highest term id ever assigned. New terms are numbered past it, so that ids of removed
+    /// terms are never handed out again unless asked for explicitly or evicted to make room
+    pub(super) last_term_id: TermId,
+    /// Names of unused terms evicted since start, by id of the term that reused their id
+    pub(super) evicted_terms: HashMap<TermId, String>,
+    pub(super) aliases: HashMap<String, TermId>,
+    /// Optional string identifiers of keys
+    pub(super) key_aliases: DoubleMap<String, Key>,
+    /// Named sets of terms queries can refer to
+    pub(super) term_groups: BTreeMap<String, BTreeSet<TermId>>,
+    /// Groups of `term_groups` at most one term of which a record may carry
+    pub(super) exclusive_term_groups: BTreeSet<String>,
+    /// Prefixes of `term_groups`, terms starting with them join the group as they are added
+    pub(super) term_group_prefixes: BTreeMap<String, BTreeSet<String>>,
+    /// Rules run when flags get set, by name
+    pub(super) triggers: BTreeMap<String, Trigger>,
     pub(super) index: HashMap<Key, IndexLocation>,
-    pub(super) holes: VecDeque<usize>,
-    pub(super) small_keys: Vec<Option<Key>>,
-    pub(super) small_storage: Vec<Smallset<SMALLSIZE>>,
-    pub(super) big_storage: HashMap<Key, HashSet<u8>>,
+    pub(super) small: SmallTier<SMALLSIZE>,
+    pub(super) tier16: SmallTier<16>,
+    pub(super) tier32: SmallTier<32>,
+    pub(super) tier64: SmallTier<64>,
+    pub(super) big_storage: BigStorage,
+    /// Tombstones of soft-deleted records, kept until purged
+    pub(super) deleted: HashMap<Key, DeletedRecord>,
+    /// Term-major copy of records, maintained only when columnar layout is enabled
+    pub(super) columns: Option<TermColumns>,
+    /// Keys changed since last full snapshot, tracked only when incremental snapshots are enabled
+    pub(super) dirty: Option<Mutex<HashSet<Key>>>,
+    /// What to do when term table is full and a new term is added
+    pub(super) term_eviction: TermEvictionPolicy,
+    /// Limits on keys and flags along with their usage, enforced only when configured
+    pub(super) quotas: Option<QuotaState>,
+    /// Versions of records changed since start
+    pub(super) versions: RecordVersions,
+    /// Timings of operations since database was loaded
+    pub(super) latencies: Latencies,
+    /// Where calls of trigger webhooks go, they are not made without it
+    pub(super) trigger_webhooks: Option<UnboundedSender<TriggerCall>>,
+    /// Consulted before flags are set, such as WASM plugins
+    pub(super) validator: Option<Arc<dyn FlagValidator>>,
+    /// Set while writing flags validated beforehand, flags set by triggers are still validated
+    pub(super) validation_suspended: bool,
+    /// Keeps terms referenced outside of storage, such as locked ones, from being evicted
+    pub(super) eviction_guard: Option<Arc<dyn EvictionGuard>>,
 }
 
 impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
-    pub(super) fn get_smallset(&self, index: usize) -> Option<&Smallset<SMALLSIZE>> {
-        self.small_storage.get(index)
+    /// Record of `key`, failing only if it is big and cannot be read back
+    pub(super) fn record(
+        &self,
+        key: &Key,
+    ) -> Result<Option<RecordRef<'_, SMALLSIZE>>, StorageCorruption> {
+        let Some(&location) = self.index.get(key) else {
+            return Ok(None);
+        };
+        Ok(match location {
+            IndexLocation::Small(index) => self.small.get(index).map(RecordRef::Small),
+            IndexLocation::Tier16(index) => self.tier16.get(index).map(RecordRef::Tier16),
+            IndexLocation::Tier32(index) => self.tier32.get(index).map(RecordRef::Tier32),
+            IndexLocation::Tier64(index) => self.tier64.get(index).map(RecordRef::Tier64),
+            IndexLocation::Big => self.big_storage.get(key)?.map(RecordRef::Big),
+        })
+    }
+
+    /// Number of terms record of `key` carries, None if it does not exist
+    pub(super) fn record_size(&self, key: &Key) -> Option<usize> {
+        Some(match *self.index.get(key)? {
+            IndexLocation::Small(index) => self.small.get(index)?.len(),
+            IndexLocation::Tier16(index) => self.tier16.get(index)?.len(),
+            IndexLocation::Tier32(index) => self.tier32.get(index)?.len(),
+            IndexLocation::Tier64(index) => self.tier64.get(index)?.len(),
+            IndexLocation::Big => self.big_storage.len_of(key)?,
+        })
+    }
+
+    /// All records across every tier
+    pub(super) fn records(
+        &self,
+    ) -> impl Iterator<Item = Result<(Key, RecordRef<'_, SMALLSIZE>), StorageCorruption>> {
+        self.small_records().map(Ok).chain(self.big_records())
+    }
+
+    /// Records of smallset tiers
+    pub(super) fn small_records(&self) -> impl Iterator<Item = (Key, RecordRef<'_, SMALLSIZE>)> {
+        self.small
+            .iter()
+            .map(|(key, set)| (key, RecordRef::Small(set)))
+            .chain(
+                self.tier16
+                    .iter()
+                    .map(|(key, set)| (key, RecordRef::Tier16(set))),
+            )
+            .chain(
+                self.tier32
+                    .iter()
+                    .map(|(key, set)| (key, RecordRef::Tier32(set))),
+            )
+            .chain(
+                self.tier64
+                    .iter()
+                    .map(|(key, set)| (key, RecordRef::Tier64(set))),
+            )
+    }
+
+    pub(super) fn big_records(
+        &self,
+    ) -> impl Iterator<Item = Result<(Key, RecordRef<'_, SMALLSIZE>), StorageCorruption>> {
+        self.big_storage
+            .iter()
+            .map(|record| record.map(|(key, set)| (key, RecordRef::Big(set))))
     }
 
-    pub(super) fn get_smallset_mut(&mut self, index: usize) -> Option<&mut Smallset<SMALLSIZE>> {
-        self.small_storage.get_mut(index)
+    /// Storage class `key` is kept in, None if it does not exist
+    pub(super) fn storage_class(&self, key: &Key) -> Option<StorageClass> {
+        Some(match self.index.get(key)? {
+            IndexLocation::Big => StorageClass::Big,
+            _ => StorageClass::Small,
+        })
+    }
+
+    /// Time storage operations took so far
+    pub fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+
+    /// Add time elapsed since `started` to histogram of `key`'s storage class, if it exists
+    pub(super) fn observe_latency(&self, operation: Operation, key: &Key, started: Instant) {
+        if let Some(class) = self.storage_class(key) {
+            self.latencies.observe(operation, class, started.elapsed());
+        }
+    }
+
+    /// Note change of record, for versioning and incremental snapshots
+    pub(super) fn mark_dirty(&mut self, key: Key) {
+        self.versions.bump(key);
+        if let Some(dirty) = &mut self.dirty {
+            dirty
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(key);
+        }
     }
 
     /// Creates new key, indicates if it was inserted
-    pub fn create_record(&mut self, key: Key) -> bool {
+    pub fn create_record(&mut self, key: Key) -> Result<bool, QuotaExceeded> {
         if self.index.contains_key(&key) {
-            return false;
+            return Ok(false);
         }
+        self.check_quota_growth([(key, 0)])?;
 
-        if let Some(hole) = self.holes.pop_back() {
-            self.index.insert(key, IndexLocation::Small(hole));
-            self.get_smallset_mut(hole).unwrap().clear();
-            self.small_keys[hole] = Some(key);
-        } else {
-            self.index
-                .insert(key, IndexLocation::Small(self.small_storage.len()));
-            self.small_keys.push(Some(key));
-            self.small_storage.push(Smallset::new_empty())
+        let Ok(index) = self.small.allocate(key, &[]) else {
+            unreachable!("empty record fits into any tier");
+        };
+        self.index.insert(key, IndexLocation::Small(index));
+        if let Some(columns) = &mut self.columns {
+            columns.add_key(key);
         }
+        self.count_quota_usage(key, 1, 0);
+        self.mark_dirty(key);
+        Ok(true)
+    }
 
-        true
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.index.contains_key(key)
     }
 
-    pub fn get_term_id(&self, term: &str) -> Option<u8> {
-        self.terms.get_forward(term).cloned()
+    pub fn key_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Resolve term or one of its aliases into term id
+    pub fn get_term_id(&self, term: &str) -> Option<TermId> {
+        self.terms
+            .get_forward(term)
+            .or_else(|| self.aliases.get(term))
+            .cloned()
+    }
+
+    /// Name of the term `term` refers to, which differs from `term` only for aliases
+    pub fn canonical_name<'a>(&'a self, term: &'a str) -> &'a str {
+        self.aliases
+            .get(term)
+            .and_then(|&term_id| self.explain_term_id(term_id))
+            .unwrap_or(term)
+    }
+
+    fn name_is_taken(&self, name: &str) -> bool {
+        self.terms.contains_forward(name) || self.aliases.contains_key(name)
+    }
+
+    /// Register alternative name resolving to existing term
+    pub fn add_alias(&mut self, term: &str, alias: &str) -> Result<TermId, TermError> {
+        if self.name_is_taken(alias) {
+            return Err(TermError::AlreadyExists(alias.to_string()));
+        }
+        let term_id = *self
+            .terms
+            .get_forward(term)
+            .ok_or_else(|| TermError::UnknownTerm(term.to_string()))?;
+        self.aliases.insert(alias.to_string(), term_id);
+        Ok(term_id)
+    }
+
+    /// Drop alias, indicates if it existed
+    pub fn remove_alias(&mut self, alias: &str) -> bool {
+        self.aliases.remove(alias).is_some()
+    }
+
+    /// Pairs of alias and the term it resolves to
+    pub fn list_aliases(&self) -> impl Iterator<Item = (&'_ str, &'_ str)> {
+        self.aliases.iter().filter_map(|(alias, term_id)| {
+            Some((alias.as_str(), self.terms.get_backward(term_id)?.as_str()))
+        })
+    }
+
+    /// Tries to add Term, fails if it exceeds TermId capacity
+    pub fn add_term(&mut self, term: &str) -> Result<SmallsetItem<TermId>, TermTableFull> {
+        if let Some(loc) = self.get_term_id(term) {
+            return SmallsetItem::try_from(loc).map_err(|_| TermTableFull);
+        }
+        let mut evicted_id = None;
+        if self.remaining_term_capacity() == 0
+            && self.term_eviction == TermEvictionPolicy::EvictUnused
+        {
+            match self.evict_unused_term() {
+                Ok(Some(evicted)) => {
+                    tracing::warn!(
+                        evicted = evicted.name,
+                        id = evicted.id,
+                        term,
+                        "term table is full, evicted unused term and reused its id"
+                    );
+                    evicted_id = Some(evicted.id);
+                    self.evicted_terms.insert(evicted.id, evicted.name);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!("failed to evict unused term: {e}"),
+            }
+        }
+        let new_id = match evicted_id {
+            Some(id) => id,
+            None => self
+                .last_term_id
+                .checked_add(1)
+                .filter(|&id| id as usize <= MAX_TERMS)
+                .ok_or(TermTableFull)?,
+        };
+        let new_index = SmallsetItem::try_from(new_id).map_err(|_| TermTableFull)?;
+        self.terms.insert(term.to_string(), new_id);
+        self.last_term_id = self.last_term_id.max(new_id);
+        self.join_prefix_groups(term, new_id);
+        Ok(new_index)
+    }
+
+    /// Add term under given id, which may be one of a removed term
+    pub fn add_term_with_id(
+        &mut self,
+        term: &str,
+        id: TermId,
+    ) -> Result<SmallsetItem<TermId>, TermError> {
+        if self.name_is_taken(term) {
+            return Err(TermError::AlreadyExists(term.to_string()));
+        }
+        if self.terms.contains_backward(&id) {
+            return Err(TermError::IdTaken(id));
+        }
+        let new_index = SmallsetItem::try_from(id)
+            .ok()
+            .filter(|_| id as usize <= MAX_TERMS)
+            .ok_or(TermError::InvalidId(id))?;
+        self.terms.insert(term.to_string(), id);
+        self.last_term_id = self.last_term_id.max(id);
+        self.join_prefix_groups(term, id);
+        Ok(new_index)
     }
 
-    /// Tries to add Term, fails if it exceeds u8 capacity
-    pub fn add_term(&mut self, term: &str) -> Result<SmallsetItem, ()> {
-        if let Some(loc) = self.terms.get_forward(term) {
-            return SmallsetItem::try_from(*loc).map_err(|_| ());
+    /// Give existing term a new name while keeping its id, so stored records stay untouched
+    pub fn rename_term(&mut self, term: &str, new_name: &str) -> Result<TermId, TermError> {
+        if self.name_is_taken(new_name) {
+            return Err(TermError::AlreadyExists(new_name.to_string()));
         }
-        let new_index: SmallsetItem = (self.terms.len() as u8)
-            .checked_add(1)
-            .ok_or(())?
-            .try_into()
-            .map_err(|_| ())?;
-        self.terms.insert(term.to_string(), new_index.into());
-        SmallsetItem::try_from(*self.terms.get_forward(term).unwrap()).map_err(|_| ())
+        let term_id = self
+            .terms
+            .remove_forward(term)
+            .ok_or_else(|| TermError::UnknownTerm(term.to_string()))?;
+        self.terms.insert(new_name.to_string(), term_id);
+        Ok(term_id)
     }
 
     pub fn list_keys(&self) -> impl Iterator<Item = Key> + '_ {
         self.big_storage
             .keys()
-            .cloned()
-            .chain(self.small_keys.iter().filter_map(|item| *item))
+            .chain(self.small.keys())
+            .chain(self.tier16.keys())
+            .chain(self.tier32.keys())
+            .chain(self.tier64.keys())
+    }
+
+    /// Every record with names of terms it carries, in no particular order
+    pub fn iter_records(
+        &self,
+    ) -> impl Iterator<Item = Result<(Key, impl Iterator<Item = &'_ str>), StorageCorruption>> {
+        self.records().map(|record| {
+            let (key, record) = record?;
+            let terms = record
+                .term_ids()
+                .into_iter()
+                .filter_map(|term_id| self.explain_term_id(term_id));
+            Ok((key, terms))
+        })
+    }
+
+    /// Every flag as pair of key and term id, in no particular order
+    pub fn iter_flags_raw(
+        &self,
+    ) -> impl Iterator<Item = Result<(Key, TermId), StorageCorruption>> + '_ {
+        self.records().flat_map(|record| {
+            let (key, term_ids) = match record {
+                Ok((key, record)) => (key, record.term_ids()),
+                Err(e) => return vec![Err(e)],
+            };
+            term_ids
+                .into_iter()
+                .map(|term_id| Ok((key, term_id)))
+                .collect()
+        })
     }
 
     /// Add boolean flag to key
-    pub fn set_flag(&mut self, key: Key, term: &str) -> Result<bool, ()> {
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn set_flag(&mut self, key: Key, term: &str) -> Result<bool, SetFlagError> {
+        let started = Instant::now();
+        let existing = self
+            .get_term_id(term)
+            .and_then(|term_id| SmallsetItem::try_from(term_id).ok());
+        self.check_flag_quota(key, existing)?;
+        self.check_flag_allowed(key, term)?;
         let term_index = self.add_term(term)?;
-        self.create_record(key);
-
-        match self.index.get(&key).unwrap() {
-            &IndexLocation::Small(index) => {
-                let small_record = self.get_smallset_mut(index).unwrap();
-                match small_record.insert(term_index) {
-                    Ok(exists) => Ok(exists),
-                    Err(_) => {
-                        self.evict_into_large(key);
-                        self.set_flag(key, term)
-                    }
-                }
-            }
+        let inserted = self.insert_flag_firing_triggers(key, term_index);
+        self.observe_latency(Operation::SetFlag, &key, started);
+        inserted
+    }
+
+    /// Add flag of existing term given by id, sparing name lookups
+    pub fn set_flag_by_id(&mut self, key: Key, term_id: TermId) -> Result<bool, SetFlagError> {
+        let term_index = SmallsetItem::try_from(term_id)
+            .ok()
+            .filter(|_| self.terms.contains_backward(&term_id))
+            .ok_or(SetFlagError::UnknownTermId(term_id))?;
+        self.check_flag_quota(key, Some(term_index))?;
+        if self.validator.is_some() && !self.validation_suspended {
+            let term = self
+                .explain_term_id(term_id)
+                .unwrap_or_default()
+                .to_string();
+            self.check_flag_allowed(key, &term)?;
+        }
+        let started = Instant::now();
+        let inserted = self.insert_flag_firing_triggers(key, term_index);
+        self.observe_latency(Operation::SetFlag, &key, started);
+        inserted
+    }
+
+    /// Insert flag, clearing terms that share an exclusive group with it beforehand and firing
+    /// its triggers afterwards if it was not set before
+    fn insert_flag_firing_triggers(
+        &mut self,
+        key: Key,
+        term_index: SmallsetItem<TermId>,
+    ) -> Result<bool, SetFlagError> {
+        self.clear_exclusive_companions(key, term_index)?;
+        let inserted = self.insert_flag(key, term_index)?;
+        if inserted {
+            self.fire_triggers(key, term_index)?;
+        }
+        Ok(inserted)
+    }
+
+    /// Fails if adding flag of `term_index`, None for a term yet to be created, exceeds a quota
+    pub(super) fn check_flag_quota(
+        &self,
+        key: Key,
+        term_index: Option<SmallsetItem<TermId>>,
+    ) -> Result<(), SetFlagError> {
+        if self.quotas.is_none() {
+            return Ok(());
+        }
+        let flags = self.record(&key)?.map(|record| {
+            let is_set = term_index.is_some_and(|term_index| record.contains(term_index));
+            (record.size(), is_set)
+        });
+        match flags {
+            Some((_, true)) => Ok(()),
+            Some((size, false)) => Ok(self.check_quota_growth([(key, size + 1)])?),
+            None => Ok(self.check_quota_growth([(key, 1)])?),
+        }
+    }
+
+    pub(super) fn insert_flag(
+        &mut self,
+        key: Key,
+        term_index: SmallsetItem<TermId>,
+    ) -> Result<bool, SetFlagError> {
+        self.create_record(key)?;
+
+        let location = *self
+            .index
+            .get(&key)
+            .ok_or_else(|| corruption(format!("created record {key} is not indexed")))?;
+        let missing = || corruption(format!("record {key} is missing from {location:?}"));
+        let inserted = match location {
+            IndexLocation::Small(index) => self
+                .small
+                .get_mut(index)
+                .ok_or_else(missing)?
+                .insert(term_index),
+            IndexLocation::Tier16(index) => self
+                .tier16
+                .get_mut(index)
+                .ok_or_else(missing)?
+                .insert(term_index),
+            IndexLocation::Tier32(index) => self
+                .tier32
+                .get_mut(index)
+                .ok_or_else(missing)?
+                .insert(term_index),
+            IndexLocation::Tier64(index) => self
+                .tier64
+                .get_mut(index)
+                .ok_or_else(missing)?
+                .insert(term_index),
             IndexLocation::Big => Ok(self
                 .big_storage
-                .entry(key)
-                .or_default()
+                .get_or_insert_default(key)?
                 .insert(term_index.into())),
+        };
+
+        match inserted {
+            Ok(is_new) => {
+                if let (true, Some(columns)) = (is_new, &mut self.columns) {
+                    columns.set(key, term_index.get());
+                }
+                if is_new {
+                    self.count_quota_usage(key, 0, 1);
+                    self.mark_dirty(key);
+                }
+                Ok(is_new)
+            }
+            Err(_) => {
+                self.promote(key)?;
+                self.insert_flag(key, term_index)
+            }
         }
     }
 
-    fn evict_into_large(&mut self, key: Key) {
-        let small_index = match self.index.entry(key).or_insert(IndexLocation::Big) {
-            IndexLocation::Small(value) => *value,
-            IndexLocation::Big => return,
+    /// Remove boolean flag from key, indicates if it was set
+    pub fn unset_flag(&mut self, key: Key, term: &str) -> Result<bool, StorageCorruption> {
+        let Some(term_index) = self
+            .get_term_id(term)
+            .and_then(|term_id| SmallsetItem::try_from(term_id).ok())
+        else {
+            return Ok(false);
+        };
+        self.remove_flag(key, term_index)
+    }
+
+    pub(super) fn remove_flag(
+        &mut self,
+        key: Key,
+        term_index: SmallsetItem<TermId>,
+    ) -> Result<bool, StorageCorruption> {
+        let missing = |location| corruption(format!("record {key} is missing from {location:?}"));
+        let removed = match self.index.get(&key) {
+            Some(&location @ IndexLocation::Small(index)) => self
+                .small
+                .get_mut(index)
+                .ok_or_else(|| missing(location))?
+                .remove(term_index),
+            Some(&location @ IndexLocation::Tier16(index)) => self
+                .tier16
+                .get_mut(index)
+                .ok_or_else(|| missing(location))?
+                .remove(term_index),
+            Some(&location @ IndexLocation::Tier32(index)) => self
+                .tier32
+                .get_mut(index)
+                .ok_or_else(|| missing(location))?
+                .remove(term_index),
+            Some(&location @ IndexLocation::Tier64(index)) => self
+                .tier64
+                .get_mut(index)
+                .ok_or_else(|| missing(location))?
+                .remove(term_index),
+            Some(IndexLocation::Big) => self
+                .big_storage
+                .get_mut(&key)?
+                .is_some_and(|set| set.remove(term_index.into())),
+            None => false,
         };
+        if let (true, Some(columns)) = (removed, &mut self.columns) {
+            columns.unset(key, term_index.get());
+        }
+        if removed {
+            self.count_quota_usage(key, 0, -1);
+            self.mark_dirty(key);
+        }
+        Ok(removed)
+    }
+
+    /// Keep at most `cache_records` big records in memory, spilling the rest into scratch file at `path`
+    pub fn spill_big_records(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+        cache_records: usize,
+    ) -> std::io::Result<()> {
+        self.big_storage.spill_to(path, cache_records)
+    }
+
+    /// Replace contents with `other`, keeping runtime storage options such as columns and spilling
+    pub fn replace_with(&mut self, other: Self) -> std::io::Result<()> {
+        let columnar = self.columns.is_some();
+        let spill = self.big_storage.spill_settings();
+        let tracking = self.dirty.is_some();
+        let term_eviction = self.term_eviction;
+        let quotas = self.quotas.take();
+        let trigger_webhooks = self.trigger_webhooks.take();
+        let validator = self.validator.take();
+        let eviction_guard = self.eviction_guard.take();
+
+        *self = other;
+        self.term_eviction = term_eviction;
+        self.quotas = quotas;
+        self.trigger_webhooks = trigger_webhooks;
+        self.validator = validator;
+        self.eviction_guard = eviction_guard;
+        self.recount_quota_usage();
+        if columnar {
+            self.enable_term_columns().map_err(std::io::Error::other)?;
+        }
+        if tracking {
+            // nothing of the new contents is covered by previous snapshots
+            let keys = self.index.keys().copied().collect();
+            self.dirty = Some(Mutex::new(keys));
+        }
+        if let Some((path, cache_records)) = spill {
+            self.spill_big_records(path, cache_records)?;
+        }
+        Ok(())
+    }
+
+    /// How many more terms can be registered under new ids before term table is full
+    pub fn remaining_term_capacity(&self) -> usize {
+        MAX_TERMS.saturating_sub(self.last_term_id as usize)
+    }
 
-        let current_state = *self.get_smallset(small_index).unwrap();
+    /// Move record into the smallest tier that is larger than the one it currently occupies
+    fn promote(&mut self, key: Key) -> Result<(), StorageCorruption> {
+        let (items, capacity) = self
+            .detach(key)?
+            .ok_or_else(|| corruption(format!("record {key} to promote is missing")))?;
+        self.attach(key, &items, capacity + 1)
+    }
 
-        let big_set = self.big_storage.entry(key).or_default();
-        for item in current_state.iter() {
-            big_set.insert(item);
+    /// Number of terms record at this location can hold before it has to be promoted
+    pub(super) fn location_capacity(location: IndexLocation) -> usize {
+        match location {
+            IndexLocation::Small(_) => SMALLSIZE,
+            IndexLocation::Tier16(_) => 16,
+            IndexLocation::Tier32(_) => 32,
+            IndexLocation::Tier64(_) => 64,
+            IndexLocation::Big => usize::MAX,
+        }
+    }
+
+    /// Take record out of its tier, returning its items and capacity of that tier. Index is left stale
+    pub(super) fn detach(
+        &mut self,
+        key: Key,
+    ) -> Result<Option<(Vec<TermId>, usize)>, StorageCorruption> {
+        let Some(&location) = self.index.get(&key) else {
+            return Ok(None);
+        };
+        let items = match location {
+            IndexLocation::Small(index) => self.small.release(index),
+            IndexLocation::Tier16(index) => self.tier16.release(index),
+            IndexLocation::Tier32(index) => self.tier32.release(index),
+            IndexLocation::Tier64(index) => self.tier64.release(index),
+            IndexLocation::Big => match self.big_storage.remove(&key)? {
+                Some(set) => set.iter().collect(),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some((items, Self::location_capacity(location))))
+    }
+
+    /// Store record in the first tier holding at least `min_capacity` terms
+    pub(super) fn attach(
+        &mut self,
+        key: Key,
+        items: &[TermId],
+        min_capacity: usize,
+    ) -> Result<(), StorageCorruption> {
+        let min_capacity = min_capacity.max(items.len());
+        let location = if SMALLSIZE >= min_capacity {
+            IndexLocation::Small(self.small.allocate(key, items)?)
+        } else if 16 >= min_capacity {
+            IndexLocation::Tier16(self.tier16.allocate(key, items)?)
+        } else if 32 >= min_capacity {
+            IndexLocation::Tier32(self.tier32.allocate(key, items)?)
+        } else if 64 >= min_capacity {
+            IndexLocation::Tier64(self.tier64.allocate(key, items)?)
+        } else {
+            self.big_storage
+                .insert(key, items.iter().cloned().collect())?;
+            IndexLocation::Big
+        };
+        self.index.insert(key, location);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use proptest::prelude::*;
+
+    use super::{Database, IndexLocation, Key, SetFlagError, StorageCorruption, TermError};
+
+    #[test]
+    fn aliases_resolve_to_original_term() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.add_term("current").unwrap();
+        db.add_alias("current", "legacy").unwrap();
+
+        db.set_flag(key, "legacy").unwrap();
+        assert_eq!(db.get_term_id("legacy"), db.get_term_id("current"));
+        assert!(db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .contains("current"));
+        assert_eq!(db.terms.len(), 1);
+
+        assert!(matches!(
+            db.add_alias("current", "current"),
+            Err(TermError::AlreadyExists(_))
+        ));
+        assert!(db.remove_alias("legacy"));
+        assert_eq!(db.get_term_id("legacy"), None);
+    }
+
+    #[test]
+    fn broken_index_is_reported_as_corruption() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.index.insert(key, IndexLocation::Small(7));
+        assert_eq!(
+            db.set_flag(key, "x"),
+            Err(SetFlagError::Corruption(StorageCorruption(
+                "record 1 is missing from Small(7)".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn records_are_iterated_across_tiers() {
+        let mut db = Database::<8>::default();
+        let (small, big) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(small, "x").unwrap();
+        for term in 0..100 {
+            db.set_flag(big, &term.to_string()).unwrap();
+        }
+
+        let records = db
+            .iter_records()
+            .map(|record| record.map(|(key, terms)| (key, terms.collect::<HashSet<_>>())))
+            .collect::<Result<HashMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(records[&small], HashSet::from(["x"]));
+        assert_eq!(records[&big].len(), 100);
+
+        let flags = db.iter_flags_raw().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(flags.len(), 101);
+        assert!(flags.contains(&(small, db.get_term_id("x").unwrap())));
+    }
+
+    #[test]
+    fn renamed_term_keeps_id_and_records() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "typo").unwrap();
+        db.add_term("other").unwrap();
+
+        let old_id = db.get_term_id("typo").unwrap();
+        assert_eq!(db.rename_term("typo", "fixed").unwrap(), old_id);
+        assert_eq!(db.get_term_id("typo"), None);
+        assert_eq!(db.get_term_id("fixed"), Some(old_id));
+        assert!(db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .contains("fixed"));
+
+        assert!(matches!(
+            db.rename_term("fixed", "other"),
+            Err(TermError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            db.rename_term("typo", "x"),
+            Err(TermError::UnknownTerm(_))
+        ));
+    }
+
+    #[test]
+    fn records_graduate_through_tiers() {
+        let mut db = Database::<8>::default();
+        let (key, other) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(other, "0").unwrap();
+
+        let mut locations = vec![];
+        for term in 0..100 {
+            db.set_flag(key, &term.to_string()).unwrap();
+            let location = match db.index[&key] {
+                IndexLocation::Small(_) => "small",
+                IndexLocation::Tier16(_) => "16",
+                IndexLocation::Tier32(_) => "32",
+                IndexLocation::Tier64(_) => "64",
+                IndexLocation::Big => "big",
+            };
+            if locations.last() != Some(&location) {
+                locations.push(location);
+            }
+        }
+
+        assert_eq!(locations, ["small", "16", "32", "64", "big"]);
+        assert_eq!(db.horizontal_query(&key).unwrap().unwrap().len(), 100);
+        assert_eq!(db.list_keys().count(), 2);
+        assert!(db.unset_flag(key, "50").unwrap());
+        assert!(!db.horizontal_query(&key).unwrap().unwrap().contains("50"));
+
+        // slot freed by promotion is reused by the next small record
+        let third = Key::try_from(3).unwrap();
+        db.create_record(third).unwrap();
+        assert!(matches!(db.index[&third], IndexLocation::Small(1)));
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Set(u64, u16),
+        Unset(u64, u16),
+        Compact,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (1u64..6, 0u16..80).prop_map(|(key, term)| Op::Set(key, term)),
+            2 => (1u64..6, 0u16..80).prop_map(|(key, term)| Op::Unset(key, term)),
+            1 => Just(Op::Compact),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn records_match_reference_across_tiers(ops in prop::collection::vec(op(), 0..400)) {
+            let mut db = Database::<8>::default();
+            let mut reference = HashMap::<Key, HashSet<String>>::new();
+
+            for op in ops {
+                match op {
+                    Op::Set(key, term) => {
+                        let key = Key::try_from(key).unwrap();
+                        let is_new = reference.entry(key).or_default().insert(term.to_string());
+                        prop_assert_eq!(db.set_flag(key, &term.to_string()), Ok(is_new));
+                    }
+                    Op::Unset(key, term) => {
+                        let key = Key::try_from(key).unwrap();
+                        let was_set = reference
+                            .get_mut(&key)
+                            .is_some_and(|terms| terms.remove(&term.to_string()));
+                        prop_assert_eq!(db.unset_flag(key, &term.to_string()), Ok(was_set));
+                    }
+                    Op::Compact => {
+                        db.compact().unwrap();
+                    }
+                }
+            }
+
+            for (key, terms) in reference {
+                let stored = db.horizontal_query(&key).unwrap().unwrap();
+                prop_assert_eq!(
+                    stored.into_iter().map(str::to_string).collect::<HashSet<_>>(),
+                    terms
+                );
+            }
         }
-        self.index.insert(key, IndexLocation::Big);
-        self.holes.push_back(small_index);
-        self.small_keys[small_index] = None;
     }
 }
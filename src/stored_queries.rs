@@ -0,0 +1,197 @@
+//! Named queries saved with `PUT /queries/:name` and combined with `POST /query/combine`
+//!
+//! Queries are kept in a JSON file next to the data file, rewritten whole on every change,
+//! rather than in snapshots, since they describe how state is read and not state itself.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    query::{FilteredQuery, QueryError},
+    storage::{Database, StorageCorruption},
+    Key,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetOperation {
+    Union,
+    Intersection,
+    /// Keys of the first query matched by none of the others
+    Difference,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Combination {
+    pub op: SetOperation,
+    pub queries: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CombineError {
+    #[error("no stored query {0}")]
+    UnknownQuery(String),
+    #[error("query {name} failed: {message}")]
+    InvalidQuery { name: String, message: String },
+    #[error("combination needs at least one query")]
+    NoQueries,
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+pub struct StoredQueries {
+    path: PathBuf,
+    queries: RwLock<BTreeMap<String, FilteredQuery>>,
+}
+
+impl StoredQueries {
+    /// Queries are stored at `<data_file>.queries`
+    pub fn path_for(data_file: &Path) -> PathBuf {
+        let mut path = data_file.as_os_str().to_owned();
+        path.push(".queries");
+        path.into()
+    }
+
+    /// Load previously saved queries, starting empty if there is no file yet
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let queries = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            queries: RwLock::new(queries),
+        })
+    }
+
+    fn persist(&self, queries: &BTreeMap<String, FilteredQuery>) -> std::io::Result<()> {
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        std::fs::write(&partial, serde_json::to_vec_pretty(queries)?)?;
+        std::fs::rename(partial, &self.path)
+    }
+
+    pub fn list(&self) -> BTreeMap<String, FilteredQuery> {
+        self.queries.read().unwrap().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<FilteredQuery> {
+        self.queries.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns whether query was newly created rather than replaced
+    pub fn put(&self, name: String, query: FilteredQuery) -> std::io::Result<bool> {
+        let mut queries = self.queries.write().unwrap();
+        let previous = queries.insert(name.clone(), query);
+        if let Err(e) = self.persist(&queries) {
+            match previous {
+                Some(previous) => queries.insert(name, previous),
+                None => queries.remove(&name),
+            };
+            return Err(e);
+        }
+        Ok(previous.is_none())
+    }
+
+    /// Returns whether there was such query
+    pub fn remove(&self, name: &str) -> std::io::Result<bool> {
+        let mut queries = self.queries.write().unwrap();
+        let Some(previous) = queries.remove(name) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.persist(&queries) {
+            queries.insert(name.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Evaluate named queries and combine their results, keys come out in ascending order
+    pub fn combine<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        combination: &Combination,
+    ) -> Result<Vec<Key>, CombineError> {
+        let queries = combination
+            .queries
+            .iter()
+            .map(|name| {
+                self.get(name)
+                    .map(|query| (name, query))
+                    .ok_or_else(|| CombineError::UnknownQuery(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut results = queries.into_iter().map(|(name, query)| {
+            db.filtered_vertical_query(&query)
+                .map(BTreeSet::from_iter)
+                .map_err(|e| match e {
+                    QueryError::Invalid(message) => CombineError::InvalidQuery {
+                        name: name.clone(),
+                        message,
+                    },
+                    QueryError::Corruption(e) => e.into(),
+                })
+        });
+        let mut combined = results.next().ok_or(CombineError::NoQueries)??;
+        for result in results {
+            let result = result?;
+            match combination.op {
+                SetOperation::Union => combined.extend(result),
+                SetOperation::Intersection => combined.retain(|key| result.contains(key)),
+                SetOperation::Difference => combined.retain(|key| !result.contains(key)),
+            }
+        }
+        Ok(combined.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key};
+
+    use super::{Combination, StoredQueries};
+
+    #[test]
+    fn stored_queries_combine_and_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("elizadb-queries-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let queries = StoredQueries::open(path.clone()).unwrap();
+        let mut db = Database::<8>::default();
+        for key in 1..=6 {
+            let key = Key::try_from(key).unwrap();
+            if u64::from(key) % 2 == 0 {
+                db.set_flag(key, "even").unwrap();
+            }
+            if u64::from(key) <= 3 {
+                db.set_flag(key, "low").unwrap();
+            }
+        }
+        for (name, term) in [("even", "even"), ("low", "low")] {
+            let query = serde_json::from_str(&format!(r#"{{"type": "Simple", "term": "{term}"}}"#));
+            assert!(queries.put(name.to_string(), query.unwrap()).unwrap());
+        }
+
+        let combine = |op: &str| {
+            let combination: Combination =
+                serde_json::from_str(&format!(r#"{{"op": "{op}", "queries": ["even", "low"]}}"#))
+                    .unwrap();
+            let keys = queries.combine(&db, &combination).unwrap();
+            keys.into_iter().map(u64::from).collect::<Vec<_>>()
+        };
+        assert_eq!(combine("union"), [1, 2, 3, 4, 6]);
+        assert_eq!(combine("intersection"), [2]);
+        assert_eq!(combine("difference"), [4, 6]);
+
+        assert!(queries.remove("low").unwrap());
+        let reopened = StoredQueries::open(path.clone()).unwrap();
+        assert_eq!(reopened.list().keys().collect::<Vec<_>>(), ["even"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{FmtSpan, Writer},
+        time::{FormatTime, SystemTime},
+        writer::BoxMakeWriter,
+        FmtContext, FormatEvent, FormatFields,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    config::{Config, LogFormat},
+    log_file::{RotatingFile, Rotation},
+};
+
+/// Filter of installed subscriber, which can be replaced while running
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: Arc<Mutex<String>>,
+    /// Layer of filter not installed in any subscriber, kept alive so that it can still be set
+    _detached: Option<Arc<reload::Layer<EnvFilter, Registry>>>,
+}
+
+impl LogFilter {
+    /// Replace filter with `directives` in `RUST_LOG` syntax, e.g. `info,elizadb=debug`
+    pub fn set(&self, directives: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.handle.reload(EnvFilter::try_new(directives)?)?;
+        *self.directives.lock().unwrap() = directives.to_string();
+        Ok(())
+    }
+
+    pub fn directives(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    /// Filter that does not affect logging, for servers run in tests alongside each other
+    pub fn detached() -> Self {
+        let directives = "info".to_string();
+        let (layer, handle) = reload::Layer::new(EnvFilter::new(&directives));
+        Self {
+            handle,
+            directives: Arc::new(Mutex::new(directives)),
+            _detached: Some(Arc::new(layer)),
+        }
+    }
+}
+
+/// Install tracing subscriber logging to stderr or configured log file, filtered by `RUST_LOG`
+/// (defaults to `info`). Spans of queries and snapshot operations are emitted on `debug` level
+/// along with their durations
+pub fn init(config: &Config) -> Result<LogFilter, Box<dyn std::error::Error>> {
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .filter(|directives| EnvFilter::try_new(directives).is_ok())
+        .unwrap_or_else(|| "info".to_string());
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&directives));
+    let log_filter = LogFilter {
+        handle,
+        directives: Arc::new(Mutex::new(directives)),
+        _detached: None,
+    };
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer(config)?);
+    log_panics();
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &config.otlp_endpoint {
+        registry.with(otlp_layer(endpoint)?).try_init()?;
+        return Ok(log_filter);
+    }
+    #[cfg(not(feature = "otlp"))]
+    let _ = config;
+
+    registry.try_init()?;
+    Ok(log_filter)
+}
+
+fn fmt_layer<S>(config: &Config) -> std::io::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let writer = match &config.log_file {
+        Some(path) => {
+            let rotation = Rotation {
+                max_bytes: config.log_rotate_bytes,
+                interval: config.log_rotate_secs.map(Duration::from_secs),
+                keep: config.log_keep_files,
+            };
+            BoxMakeWriter::new(Arc::new(RotatingFile::open(path, rotation)?))
+        }
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_span_events(FmtSpan::CLOSE);
+    Ok(match config.log_format {
+        // colors would end up as escape codes in the file
+        LogFormat::Plain => Box::new(layer.with_ansi(config.log_file.is_none())),
+        LogFormat::Json => Box::new(layer.event_format(JsonFormat)),
+    })
+}
+
+/// Event as one JSON object per line, with its fields and names of spans it happened in
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| span.name())
+            .collect::<Vec<_>>();
+        let metadata = event.metadata();
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        writeln!(writer, "{line}")
+    }
+}
+
+#[derive(Default)]
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl tracing::field::Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Route panic messages through tracing along with backtrace of where they happened, since
+/// panicking request handlers are caught and would otherwise leave nothing to debug them by
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        tracing::error!("{info}\n{backtrace}");
+    }));
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<impl tracing_subscriber::Layer<S>, Box<dyn std::error::Error>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", "elizadb"),
+        ]))
+        .build();
+    let tracer = provider.tracer("elizadb");
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -0,0 +1,164 @@
+//! API keys confined to a namespace, a named range of keys, as set up in `tenants` of access policy
+//!
+//! [`confine_tenants`] runs before handlers: it turns tenants away from routes outside of
+//! [`TENANT_ROUTES`], which concern the whole instance, counts their requests for `/metrics` and
+//! hands their [`Tenant`] on to handlers as request extension. [`ItemKey`](crate::extract::ItemKey)
+//! then rejects keys outside of namespace of the caller, [`Access`](crate::access::Access) does
+//! the same for keys given in bodies and confines queries and listings to the namespace.
+//!
+//! Namespaces share one database, so a single `GET /admin/snapshot/stream` backs all of them up
+//! at the same point in time and `POST /admin/restore` brings them back together.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{
+    access::{bearer_api_key, AccessError, AccessPolicy},
+    error::ApiError,
+    query::KeyRange,
+    storage::Key,
+};
+
+/// Route prefixes tenants may use
+pub const TENANT_ROUTES: &[&str] = &["/items", "/query", "/bulk/query", "/transactions", "/batch"];
+
+/// Routes under [`TENANT_ROUTES`] that still look past a single namespace
+const INSTANCE_ROUTES: &[&str] = &["/query/combine"];
+
+/// Namespace API key is confined to
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tenant {
+    pub namespace: String,
+    #[serde(flatten)]
+    pub range: KeyRange,
+}
+
+impl Tenant {
+    pub fn check_key(&self, key: Key) -> Result<(), AccessError> {
+        if self.range.contains(key) {
+            Ok(())
+        } else {
+            Err(AccessError::OutsideNamespace {
+                key,
+                namespace: self.namespace.clone(),
+            })
+        }
+    }
+
+    /// Part of `range` within namespace
+    pub fn confine(&self, range: KeyRange) -> KeyRange {
+        KeyRange {
+            key_min: range.key_min.max(self.range.key_min),
+            key_max: match (range.key_max, self.range.key_max) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+/// Requests of tenants by namespace and status class, as reported by `/metrics`
+#[derive(Debug, Default)]
+pub struct TenantMetrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+}
+
+impl TenantMetrics {
+    fn record(&self, namespace: &str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((namespace.to_string(), status / 100))
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        const NAME: &str = "elizadb_tenant_requests_total";
+        let mut out = String::new();
+        writeln!(out, "# HELP {NAME} Requests made with tenant API keys").unwrap();
+        writeln!(out, "# TYPE {NAME} counter").unwrap();
+        for ((namespace, class), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "{NAME}{{tenant=\"{namespace}\",status=\"{class}xx\"}} {count}"
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Resolve tenant of calling API key, keeping it to [`TENANT_ROUTES`]. Meant as route layer,
+/// since it needs the matched route
+pub async fn confine_tenants(
+    State(policy): State<Arc<AccessPolicy>>,
+    State(metrics): State<Arc<TenantMetrics>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let tenant = bearer_api_key(request.headers())
+        .and_then(|api_key| policy.tenants.get(api_key))
+        .cloned();
+    let Some(tenant) = tenant else {
+        return next.run(request).await;
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    let allowed = TENANT_ROUTES
+        .iter()
+        .any(|prefix| route == *prefix || route.starts_with(&format!("{prefix}/")))
+        && !INSTANCE_ROUTES.contains(&route);
+    let namespace = tenant.namespace.clone();
+    let response = if allowed {
+        request.extensions_mut().insert(tenant);
+        next.run(request).await
+    } else {
+        ApiError::from(AccessError::NotForTenants(route.to_string())).into_response()
+    };
+    metrics.record(&namespace, response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{query::KeyRange, storage::Key};
+
+    use super::Tenant;
+
+    #[test]
+    fn tenants_stay_in_namespace() {
+        let key = |key| Key::try_from(key).unwrap();
+        let tenant = Tenant {
+            namespace: "team".to_string(),
+            range: KeyRange {
+                key_min: Some(key(100)),
+                key_max: Some(key(199)),
+            },
+        };
+        assert!(tenant.check_key(key(150)).is_ok());
+        assert!(tenant.check_key(key(200)).is_err());
+
+        let confined = tenant.confine(KeyRange {
+            key_min: Some(key(50)),
+            key_max: Some(key(150)),
+        });
+        assert_eq!(confined.key_min, Some(key(100)));
+        assert_eq!(confined.key_max, Some(key(150)));
+        let confined = tenant.confine(KeyRange::default());
+        assert_eq!(confined.key_max, Some(key(199)));
+    }
+}
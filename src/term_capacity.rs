@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use crate::{
+    config::TermEvictionPolicy,
+    smallset::SmallsetItem,
+    stats::TermUsage,
+    storage::{Database, Key, StorageCorruption, TermError, TermId},
+};
+
+/// Terms referenced from outside of storage, which eviction must leave alone even when unused
+pub trait EvictionGuard: Send + Sync {
+    fn keeps(&self, term: &str) -> bool;
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    pub fn set_term_eviction(&mut self, policy: TermEvictionPolicy) {
+        self.term_eviction = policy;
+    }
+
+    /// Consult `guard` before evicting terms from now on
+    pub fn set_eviction_guard(&mut self, guard: Arc<dyn EvictionGuard>) {
+        self.eviction_guard = Some(guard);
+    }
+
+    /// Terms carried by fewest keys first, ties broken by id
+    pub fn least_used_terms(&self, limit: usize) -> Result<Vec<TermUsage>, StorageCorruption> {
+        let mut usage = self.term_usage()?;
+        usage.sort_unstable_by_key(|term| (term.key_count, term.id));
+        usage.truncate(limit);
+        Ok(usage)
+    }
+
+    fn keys_carrying(&self, term_id: TermId) -> Result<Vec<Key>, StorageCorruption> {
+        let Ok(item) = SmallsetItem::try_from(term_id) else {
+            return Ok(vec![]);
+        };
+        self.records()
+            .filter(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |(_, record)| record.contains(item))
+            })
+            .map(|record| record.map(|(key, _)| key))
+            .collect()
+    }
+
+    /// Replace `from` with `to` in record, columns are left for the caller to update
+    fn retag(&mut self, key: Key, from: TermId, to: TermId) -> Result<(), StorageCorruption> {
+        let Some((mut items, _)) = self.detach(key)? else {
+            return Ok(());
+        };
+        items.retain(|&item| item != from && item != to);
+        items.push(to);
+        self.mark_dirty(key);
+        self.attach(key, &items, 0)
+    }
+
+    /// Forget term no record carries anymore, along with its aliases. Ids of other terms are left
+    /// as they are
+    fn remove_term_id(&mut self, term_id: TermId) {
+        self.terms.remove_backward(&term_id);
+        self.evicted_terms.remove(&term_id);
+        self.aliases.retain(|_, target| *target != term_id);
+        for members in self.term_groups.values_mut() {
+            members.remove(&term_id);
+        }
+        self.untrigger_term(term_id);
+        if let Some(columns) = &mut self.columns {
+            columns.remove_column(term_id);
+        }
+    }
+
+    /// Move every key carrying `term` over to `into` and free the id of `term`, which stays
+    /// usable as an alias of `into`. Returns number of keys that carried `term`
+    pub fn merge_term(&mut self, term: &str, into: &str) -> Result<usize, TermError> {
+        let from = self
+            .get_term_id(term)
+            .ok_or_else(|| TermError::UnknownTerm(term.to_string()))?;
+        let to = self
+            .get_term_id(into)
+            .ok_or_else(|| TermError::UnknownTerm(into.to_string()))?;
+        if from == to {
+            return Err(TermError::MergeIntoItself(term.to_string()));
+        }
+        let name = self.terms.get_backward(&from).unwrap().clone();
+
+        let keys = self.keys_carrying(from)?;
+        for &key in &keys {
+            self.retag(key, from, to)?;
+        }
+        if let Some(columns) = &mut self.columns {
+            columns.move_column(from, to);
+        }
+        for target in self.aliases.values_mut() {
+            if *target == from {
+                *target = to;
+            }
+        }
+        self.regroup_term(from, to);
+        self.retrigger_term(from, to);
+
+        self.remove_term_id(from);
+        self.aliases.insert(name, to);
+        self.recount_quota_usage();
+        Ok(keys.len())
+    }
+
+    /// Name of unused term evicted to free id of `term_id` since start, if it was evicted
+    pub fn evicted_term(&self, term_id: TermId) -> Option<&'_ str> {
+        self.evicted_terms.get(&term_id).map(String::as_str)
+    }
+
+    /// Whether term is referenced by an alias, term group, trigger or eviction guard, which would
+    /// silently lose it if it were evicted
+    fn is_referenced(&self, term: &TermUsage) -> bool {
+        self.aliases.values().any(|&target| target == term.id)
+            || self
+                .term_groups
+                .values()
+                .any(|members| members.contains(&term.id))
+            || self
+                .triggers
+                .values()
+                .any(|trigger| trigger.when == term.id || trigger.set.contains(&term.id))
+            || self
+                .eviction_guard
+                .as_ref()
+                .is_some_and(|guard| guard.keeps(&term.name))
+    }
+
+    /// Free id of the lowest numbered term no key carries and nothing references, if there is one
+    pub(super) fn evict_unused_term(&mut self) -> Result<Option<TermUsage>, StorageCorruption> {
+        let Some(unused) = self
+            .term_usage()?
+            .into_iter()
+            .find(|term| term.key_count == 0 && !self.is_referenced(term))
+        else {
+            return Ok(None);
+        };
+        self.remove_term_id(unused.id);
+        Ok(Some(unused))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        config::TermEvictionPolicy,
+        storage::{Database, Key, TermError, MAX_TERMS},
+    };
+
+    use super::EvictionGuard;
+
+    #[test]
+    fn merged_term_becomes_alias_and_frees_its_id() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(a, "colour").unwrap();
+        db.set_flag(b, "colour").unwrap();
+        db.set_flag(b, "color").unwrap();
+        for term in 0..70 {
+            db.set_flag(b, &term.to_string()).unwrap();
+        }
+        db.add_alias("colour", "tint").unwrap();
+        db.enable_term_columns().unwrap();
+
+        assert_eq!(db.merge_term("colour", "color").unwrap(), 2);
+
+        assert_eq!(db.terms.len(), 71);
+        assert_eq!(db.get_term_id("colour"), db.get_term_id("color"));
+        assert_eq!(db.get_term_id("tint"), db.get_term_id("color"));
+        assert_eq!(db.horizontal_query(&a).unwrap(), Some(["color"].into()));
+        assert_eq!(db.horizontal_query(&b).unwrap().unwrap().len(), 71);
+        assert_eq!(db.least_used_terms(1).unwrap()[0].key_count, 1);
+        assert!(db.check_consistency().consistent);
+    }
+
+    #[test]
+    fn term_ids_are_stable_and_not_reused() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        for term in ["a", "b", "c"] {
+            db.set_flag(key, term).unwrap();
+        }
+        db.merge_term("a", "b").unwrap();
+        assert_eq!(db.get_term_id("c"), Some(3));
+        assert_eq!(db.add_term("d").unwrap().get(), 4);
+
+        assert!(matches!(
+            db.add_term_with_id("e", 4),
+            Err(TermError::IdTaken(4))
+        ));
+        assert!(matches!(
+            db.add_term_with_id("e", 0),
+            Err(TermError::InvalidId(0))
+        ));
+        db.add_term_with_id("e", 1).unwrap();
+        db.add_term_with_id("f", 10).unwrap();
+        assert_eq!(db.add_term("g").unwrap().get(), 11);
+
+        let mut snapshot = vec![];
+        db.dump(&mut snapshot).unwrap();
+        let loaded = Database::<8>::load(&mut snapshot.as_slice()).unwrap();
+        let imported = Database::<8>::from_json_export(&db.export_json().unwrap()).unwrap();
+        for db in [loaded, imported] {
+            assert_eq!(db.get_term_id("e"), Some(1));
+            assert_eq!(db.get_term_id("f"), Some(10));
+            assert_eq!(db.horizontal_query(&key).unwrap(), Some(["b", "c"].into()));
+            assert_eq!(db.remaining_term_capacity(), MAX_TERMS - 11);
+        }
+    }
+
+    #[test]
+    fn full_table_evicts_unused_term_when_configured() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "used").unwrap();
+        for term in 1..MAX_TERMS {
+            db.add_term(&term.to_string()).unwrap();
+        }
+        assert!(db.add_term("new").is_err());
+
+        db.set_term_eviction(TermEvictionPolicy::EvictUnused);
+        db.add_term("new").unwrap();
+
+        assert!(db.get_term_id("1").is_none());
+        let new = db.get_term_id("new").unwrap();
+        assert_eq!(db.evicted_term(new), Some("1"));
+        let usage = db.term_usage().unwrap();
+        let new_usage = usage.iter().find(|term| term.id == new).unwrap();
+        assert_eq!(new_usage.evicted.as_deref(), Some("1"));
+        assert_eq!(db.horizontal_query(&key).unwrap(), Some(["used"].into()));
+        assert!(db.check_consistency().consistent);
+
+        // merging frees the id, which stops naming the evicted term
+        db.merge_term("new", "used").unwrap();
+        assert!(db.evicted_term(new).is_none());
+    }
+
+    #[test]
+    fn referenced_terms_are_not_evicted() {
+        struct Keep(&'static str);
+
+        impl EvictionGuard for Keep {
+            fn keeps(&self, term: &str) -> bool {
+                term == self.0
+            }
+        }
+
+        let mut db = Database::<8>::default();
+        for term in 1..=MAX_TERMS {
+            db.add_term(&term.to_string()).unwrap();
+        }
+        db.add_alias("1", "first").unwrap();
+        db.set_term_group("group", &["2".to_string()], true)
+            .unwrap();
+        db.set_trigger("trigger", "3", &["4".to_string()], None)
+            .unwrap();
+        db.set_eviction_guard(Arc::new(Keep("5")));
+        db.set_term_eviction(TermEvictionPolicy::EvictUnused);
+
+        let new = db.add_term("new").unwrap().get();
+        assert_eq!(db.evicted_term(new), Some("6"));
+        for term in ["first", "2", "3", "4", "5"] {
+            assert!(db.get_term_id(term).is_some());
+        }
+        assert!(db.trigger("trigger").is_some());
+    }
+}
@@ -0,0 +1,341 @@
+//! Named groups of terms, assigned with `POST /term-groups`
+//!
+//! Members given as `prefix*` take in every term starting with `prefix`. Setting a term of an
+//! exclusive group clears flags of its other terms.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    smallset::SmallsetItem,
+    storage::{Database, Key, StorageCorruption, TermError, TermId},
+};
+
+/// How many terms of a group a record has to carry to match
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupMatch {
+    #[default]
+    Any,
+    All,
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Make `terms` the members of group `name`, replacing members it had. Terms may be given
+    /// by alias or as `prefix*`, fails without changing anything if one of them does not exist
+    pub fn set_term_group(
+        &mut self,
+        name: &str,
+        terms: &[String],
+        exclusive: bool,
+    ) -> Result<(), TermError> {
+        let prefixes = terms
+            .iter()
+            .filter_map(|term| term.strip_suffix('*'))
+            .map(String::from)
+            .collect::<BTreeSet<_>>();
+        let mut members = terms
+            .iter()
+            .filter(|term| !term.ends_with('*'))
+            .map(|term| {
+                self.get_term_id(term)
+                    .ok_or_else(|| TermError::UnknownTerm(term.clone()))
+            })
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        members.extend(
+            self.terms
+                .iter()
+                .filter(|(term, _)| prefixes.iter().any(|prefix| term.starts_with(prefix)))
+                .map(|(_, &term_id)| term_id),
+        );
+        self.term_groups.insert(name.to_string(), members);
+        if prefixes.is_empty() {
+            self.term_group_prefixes.remove(name);
+        } else {
+            self.term_group_prefixes.insert(name.to_string(), prefixes);
+        }
+        if exclusive {
+            self.exclusive_term_groups.insert(name.to_string());
+        } else {
+            self.exclusive_term_groups.remove(name);
+        }
+        Ok(())
+    }
+
+    /// Drop group, indicates if it existed
+    pub fn remove_term_group(&mut self, name: &str) -> bool {
+        self.exclusive_term_groups.remove(name);
+        self.term_group_prefixes.remove(name);
+        self.term_groups.remove(name).is_some()
+    }
+
+    pub fn is_exclusive_term_group(&self, name: &str) -> bool {
+        self.exclusive_term_groups.contains(name)
+    }
+
+    /// Prefixes group was given as `prefix*`, None if there is no such group
+    pub fn term_group_prefixes(&self, name: &str) -> Option<Vec<&'_ str>> {
+        self.term_groups.get(name)?;
+        Some(
+            self.term_group_prefixes
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect(),
+        )
+    }
+
+    /// Add newly added term to groups having a prefix of it
+    pub(super) fn join_prefix_groups(&mut self, term: &str, term_id: TermId) {
+        for (name, prefixes) in &self.term_group_prefixes {
+            if prefixes.iter().any(|prefix| term.starts_with(prefix)) {
+                if let Some(members) = self.term_groups.get_mut(name) {
+                    members.insert(term_id);
+                }
+            }
+        }
+    }
+
+    /// Prefixes of exclusive groups having any of `term_ids`, terms added later with these
+    /// prefixes become their companions
+    pub fn exclusive_prefixes(&self, term_ids: &[TermId]) -> Vec<&'_ str> {
+        self.exclusive_term_groups
+            .iter()
+            .filter(|name| {
+                self.term_groups
+                    .get(*name)
+                    .is_some_and(|members| term_ids.iter().any(|id| members.contains(id)))
+            })
+            .filter_map(|name| self.term_group_prefixes.get(name))
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Flags carried by `key` that setting flag of `term` clears, as terms of exclusive groups
+    /// it belongs to or, if it does not exist yet, would join
+    pub fn carried_exclusive_companions(
+        &self,
+        key: Key,
+        term: &str,
+    ) -> Result<Vec<&'_ str>, StorageCorruption> {
+        if self.exclusive_term_groups.is_empty() {
+            return Ok(vec![]);
+        }
+        let companions = match self.get_term_id(term) {
+            Some(term_id) => self.exclusive_companions(term_id),
+            None => self
+                .exclusive_term_groups
+                .iter()
+                .filter(|name| {
+                    self.term_group_prefixes
+                        .get(*name)
+                        .is_some_and(|prefixes| prefixes.iter().any(|p| term.starts_with(p)))
+                })
+                .filter_map(|name| self.term_groups.get(name))
+                .flatten()
+                .copied()
+                .collect(),
+        };
+        let carried = self.horizontal_query(&key)?.unwrap_or_default();
+        Ok(companions
+            .into_iter()
+            .filter_map(|term_id| self.explain_term_id(term_id))
+            .filter(|term| carried.contains(term))
+            .collect())
+    }
+
+    /// Other terms of exclusive groups `term_id` belongs to
+    pub fn exclusive_companions(&self, term_id: TermId) -> Vec<TermId> {
+        let mut companions = self
+            .exclusive_term_groups
+            .iter()
+            .filter_map(|name| self.term_groups.get(name))
+            .filter(|members| members.contains(&term_id))
+            .flatten()
+            .copied()
+            .filter(|&member| member != term_id)
+            .collect::<Vec<_>>();
+        companions.sort_unstable();
+        companions.dedup();
+        companions
+    }
+
+    /// Unset flags of terms sharing an exclusive group with the one about to be set on `key`
+    pub(super) fn clear_exclusive_companions(
+        &mut self,
+        key: Key,
+        term_index: SmallsetItem<TermId>,
+    ) -> Result<(), StorageCorruption> {
+        if self.exclusive_term_groups.is_empty() {
+            return Ok(());
+        }
+        for companion in self.exclusive_companions(term_index.get()) {
+            if let Ok(companion) = SmallsetItem::try_from(companion) {
+                self.remove_flag(key, companion)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids of terms in group, None if there is no such group
+    pub fn term_group_ids(&self, name: &str) -> Option<Vec<TermId>> {
+        Some(self.term_groups.get(name)?.iter().copied().collect())
+    }
+
+    /// Names of terms in group in id order, None if there is no such group
+    pub fn term_group(&self, name: &str) -> Option<Vec<&'_ str>> {
+        Some(
+            self.term_groups
+                .get(name)?
+                .iter()
+                .filter_map(|term_id| self.explain_term_id(*term_id))
+                .collect(),
+        )
+    }
+
+    /// Pairs of group name and names of its terms, ordered by group name
+    pub fn list_term_groups(&self) -> impl Iterator<Item = (&'_ str, Vec<&'_ str>)> {
+        self.term_groups
+            .keys()
+            .filter_map(|name| Some((name.as_str(), self.term_group(name)?)))
+    }
+
+    /// Replace `from` with `to` in every group, called when `from` is merged into `to`
+    pub(super) fn regroup_term(&mut self, from: TermId, to: TermId) {
+        for members in self.term_groups.values_mut() {
+            if members.remove(&from) {
+                members.insert(to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        query::Query,
+        storage::{Database, Key, TermError},
+    };
+
+    use super::GroupMatch;
+
+    #[test]
+    fn group_queries_match_any_or_all_members() {
+        let mut db = Database::<8>::default();
+        let (a, b, c) = (
+            Key::try_from(1).unwrap(),
+            Key::try_from(2).unwrap(),
+            Key::try_from(3).unwrap(),
+        );
+        db.set_flag(a, "eu").unwrap();
+        db.set_flag(b, "eu").unwrap();
+        db.set_flag(b, "us").unwrap();
+        db.set_flag(c, "apac").unwrap();
+        db.add_alias("us", "america").unwrap();
+
+        assert!(matches!(
+            db.set_term_group("region", &["eu".to_string(), "mars".to_string()], false),
+            Err(TermError::UnknownTerm(term)) if term == "mars"
+        ));
+        db.set_term_group("region", &["eu".to_string(), "america".to_string()], false)
+            .unwrap();
+        assert_eq!(db.term_group("region").unwrap(), ["eu", "us"]);
+
+        let query = |mode| Query::Group {
+            group: "region".to_string(),
+            mode,
+        };
+        let mut any = db.vertical_query(&query(GroupMatch::Any)).unwrap();
+        any.sort();
+        assert_eq!(any, [a, b]);
+        assert_eq!(db.vertical_query(&query(GroupMatch::All)).unwrap(), [b]);
+
+        db.rename_term("eu", "europe").unwrap();
+        db.merge_term("us", "apac").unwrap();
+        assert_eq!(db.term_group("region").unwrap(), ["europe", "apac"]);
+        let mut any = db.vertical_query(&query(GroupMatch::Any)).unwrap();
+        any.sort();
+        assert_eq!(any, [a, b, c]);
+
+        assert!(db.remove_term_group("region"));
+        assert!(db.vertical_query(&query(GroupMatch::Any)).is_err());
+    }
+
+    #[test]
+    fn setting_term_of_exclusive_group_clears_the_others() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "status:open").unwrap();
+        db.set_flag(key, "status:closed").unwrap();
+        db.set_flag(key, "priority").unwrap();
+        let statuses = ["status:open", "status:closed", "status:archived"]
+            .map(String::from)
+            .to_vec();
+        db.add_term("status:archived").unwrap();
+        db.set_term_group("status", &statuses, true).unwrap();
+        assert_eq!(db.horizontal_query(&key).unwrap().unwrap().len(), 3);
+
+        db.set_flag(key, "status:archived").unwrap();
+        let mut terms = db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        terms.sort();
+        assert_eq!(terms, ["priority", "status:archived"]);
+
+        let open = db.get_term_id("status:open").unwrap();
+        db.set_flag_by_id(key, open).unwrap();
+        let mut terms = db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        terms.sort();
+        assert_eq!(terms, ["priority", "status:open"]);
+
+        db.set_term_group("status", &statuses, false).unwrap();
+        db.set_flag(key, "status:closed").unwrap();
+        assert_eq!(db.horizontal_query(&key).unwrap().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn prefix_groups_take_in_new_terms() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "status:open").unwrap();
+        db.set_flag(key, "priority").unwrap();
+        db.set_term_group("status", &["status:*".to_string()], true)
+            .unwrap();
+        assert_eq!(db.term_group("status").unwrap(), ["status:open"]);
+        assert_eq!(db.term_group_prefixes("status").unwrap(), ["status:"]);
+        assert_eq!(
+            db.carried_exclusive_companions(key, "status:closed")
+                .unwrap(),
+            ["status:open"]
+        );
+
+        db.set_flag(key, "status:closed").unwrap();
+        assert_eq!(
+            db.term_group("status").unwrap(),
+            ["status:open", "status:closed"]
+        );
+        let mut terms = db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        terms.sort();
+        assert_eq!(terms, ["priority", "status:closed"]);
+
+        assert!(db.remove_term_group("status"));
+        db.add_term("status:archived").unwrap();
+        assert!(db.term_group_prefixes("status").is_none());
+    }
+}
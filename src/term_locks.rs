@@ -0,0 +1,155 @@
+//! Terms admins lock with `POST /terms/:term/lock`, whose flags only admins may set or unset
+//!
+//! Meant for system-managed terms that clients, bulk imports in particular, should not change.
+//! Locks are kept in a JSON file next to the data file like stored queries, and checked by
+//! handlers and imports rather than storage, so replication and recovery are not affected.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{storage::Database, term_capacity::EvictionGuard};
+
+#[derive(Debug, thiserror::Error)]
+#[error("term {0} is locked, only admins may change its flags")]
+pub struct TermLocked(pub String);
+
+pub struct TermLocks {
+    path: PathBuf,
+    locked: RwLock<BTreeSet<String>>,
+}
+
+impl TermLocks {
+    /// Locks are stored at `<data_file>.locks`
+    pub fn path_for(data_file: &Path) -> PathBuf {
+        let mut path = data_file.as_os_str().to_owned();
+        path.push(".locks");
+        path.into()
+    }
+
+    /// Load previously locked terms, starting with none if there is no file yet
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let locked = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            locked: RwLock::new(locked),
+        })
+    }
+
+    fn persist(&self, locked: &BTreeSet<String>) -> std::io::Result<()> {
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        std::fs::write(&partial, serde_json::to_vec_pretty(locked)?)?;
+        std::fs::rename(partial, &self.path)
+    }
+
+    /// Apply `change` to locked terms, keeping them as they were if they cannot be persisted
+    fn update<R>(&self, change: impl FnOnce(&mut BTreeSet<String>) -> R) -> std::io::Result<R> {
+        let mut locked = self.locked.write().unwrap();
+        let mut updated = locked.clone();
+        let result = change(&mut updated);
+        if updated != *locked {
+            self.persist(&updated)?;
+            *locked = updated;
+        }
+        Ok(result)
+    }
+
+    pub fn list(&self) -> BTreeSet<String> {
+        self.locked.read().unwrap().clone()
+    }
+
+    /// Returns whether term was not locked before
+    pub fn lock(&self, term: &str) -> std::io::Result<bool> {
+        self.update(|locked| locked.insert(term.to_string()))
+    }
+
+    /// Returns whether term was locked
+    pub fn unlock(&self, term: &str) -> std::io::Result<bool> {
+        self.update(|locked| locked.remove(term))
+    }
+
+    /// Keep lock of renamed term
+    pub fn rename(&self, term: &str, new_name: &str) -> std::io::Result<()> {
+        self.update(|locked| {
+            if locked.remove(term) {
+                locked.insert(new_name.to_string());
+            }
+        })
+    }
+
+    pub fn check<'t>(&self, terms: impl IntoIterator<Item = &'t str>) -> Result<(), TermLocked> {
+        let locked = self.locked.read().unwrap();
+        if locked.is_empty() {
+            return Ok(());
+        }
+        match terms.into_iter().find(|term| locked.contains(*term)) {
+            Some(term) => Err(TermLocked(term.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl EvictionGuard for TermLocks {
+    fn keeps(&self, term: &str) -> bool {
+        self.locked.read().unwrap().contains(term)
+    }
+}
+
+/// Locks as they apply to a caller, admins are not held by them
+#[derive(Clone)]
+pub struct LockCheck {
+    locks: Arc<TermLocks>,
+    admin: bool,
+}
+
+impl LockCheck {
+    pub fn new(locks: Arc<TermLocks>, admin: bool) -> Self {
+        Self { locks, admin }
+    }
+
+    /// Fails if any of `terms` is locked, unless caller is an admin. Terms given by alias are
+    /// checked under their canonical name as well
+    pub fn check<'t, const SMALLSIZE: usize>(
+        &self,
+        db: &'t Database<SMALLSIZE>,
+        terms: impl IntoIterator<Item = &'t str>,
+    ) -> Result<(), TermLocked> {
+        if self.admin {
+            return Ok(());
+        }
+        self.locks.check(
+            terms
+                .into_iter()
+                .flat_map(|term| [term, db.canonical_name(term)]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TermLocks;
+
+    #[test]
+    fn locks_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("elizadb-locks-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let locks = TermLocks::open(path.clone()).unwrap();
+        assert!(locks.lock("system").unwrap());
+        assert!(!locks.lock("system").unwrap());
+        locks.lock("other").unwrap();
+        locks.unlock("other").unwrap();
+        locks.rename("system", "managed").unwrap();
+
+        let reopened = TermLocks::open(path.clone()).unwrap();
+        assert!(reopened.check(["x", "managed"]).is_err());
+        assert!(reopened.check(["x", "system", "other"]).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! Helpers for integration tests of applications embedding the database
+//!
+//! `DatabaseBuilder` fills a database without going through the API, `TestClient` sends
+//! requests straight to the router, so no TCP server has to be started.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use clap::Parser;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::RwLock;
+use tower::ServiceExt;
+
+use crate::{
+    api::{self, AppState, DBState},
+    config::Config,
+    jobs::Jobs,
+    snapshots::Snapshotter,
+    storage::{Database, Key},
+    telemetry::LogFilter,
+};
+
+/// Builds database state for tests, panics on input the database rejects
+#[derive(Default)]
+pub struct DatabaseBuilder {
+    db: Database<8>,
+}
+
+impl DatabaseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_terms<I>(mut self, terms: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        for term in terms {
+            self.db.add_term(term.as_ref()).unwrap();
+        }
+        self
+    }
+
+    /// Create record with flags set for `terms`, adding terms that do not exist yet
+    pub fn with_record<I>(mut self, key: u64, terms: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let key = Key::new(key).expect("keys are nonzero");
+        self.db.create_record(key).unwrap();
+        for term in terms {
+            self.db.set_flag(key, term.as_ref()).unwrap();
+        }
+        self
+    }
+
+    pub fn with_alias(mut self, term: &str, alias: &str) -> Self {
+        self.db.add_alias(term, alias).unwrap();
+        self
+    }
+
+    pub fn build(self) -> Database<8> {
+        self.db
+    }
+}
+
+/// Response body of a request sent by `TestClient`
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// Panics if body is not valid json for `T`
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("response is not expected json: {e}, body: {}", self.text()))
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// API router over given database, with files it writes kept in a temporary directory removed
+/// on drop. Must be created inside tokio runtime.
+pub struct TestClient {
+    router: Router,
+    db: DBState,
+    dir: PathBuf,
+}
+
+impl TestClient {
+    pub fn new(db: Database<8>) -> Self {
+        Self::with_args(db, std::iter::empty::<&str>())
+    }
+
+    /// Client of server started with additional command line `args`, panics if they are invalid
+    pub fn with_args<I>(db: Database<8>, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        static CLIENTS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "elizadb-test-{}-{}",
+            std::process::id(),
+            CLIENTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut argv: Vec<std::ffi::OsString> = vec!["elizadb".into(), "--data-file".into()];
+        argv.push(dir.join("data.elz").into());
+        argv.extend(args.into_iter().map(Into::into));
+        let config = Config::try_parse_from(argv).unwrap_or_else(|e| panic!("{e}"));
+
+        let db = Arc::new(RwLock::new(db));
+        let jobs = Arc::new(Jobs::default());
+        let snapshotter = Snapshotter::new(db.clone(), jobs.clone(), &config).unwrap();
+        let state = AppState::new(
+            db.clone(),
+            snapshotter,
+            jobs,
+            LogFilter::detached(),
+            &config,
+        )
+        .unwrap();
+        Self {
+            router: api::build_router(state),
+            db,
+            dir,
+        }
+    }
+
+    /// Database the router serves, to inspect or modify it directly
+    pub fn db(&self) -> &DBState {
+        &self.db
+    }
+
+    pub async fn get(&self, uri: &str) -> TestResponse {
+        self.send(Method::GET, uri, Body::empty()).await
+    }
+
+    pub async fn post(&self, uri: &str, body: &impl Serialize) -> TestResponse {
+        self.send(Method::POST, uri, json_body(body)).await
+    }
+
+    pub async fn put(&self, uri: &str, body: &impl Serialize) -> TestResponse {
+        self.send(Method::PUT, uri, json_body(body)).await
+    }
+
+    pub async fn delete(&self, uri: &str) -> TestResponse {
+        self.send(Method::DELETE, uri, Body::empty()).await
+    }
+
+    pub async fn send(&self, method: Method, uri: &str, body: Body) -> TestResponse {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        TestResponse {
+            status,
+            body: body.to_vec(),
+        }
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn json_body(body: &impl Serialize) -> Body {
+    Body::from(serde_json::to_vec(body).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::{DatabaseBuilder, TestClient};
+
+    #[tokio::test]
+    async fn client_serves_built_database() {
+        let db = DatabaseBuilder::new()
+            .with_terms(["x", "y"])
+            .with_record(1, ["x"])
+            .with_record(2, ["x", "y"])
+            .with_alias("y", "why")
+            .build();
+        let client = TestClient::new(db);
+
+        let response = client
+            .post(
+                "/query",
+                &serde_json::json!({"type": "Simple", "term": "why"}),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.json::<Vec<u64>>(), [2]);
+
+        assert!(client.post("/items/3", &"y").await.status.is_success());
+        let response = client.get("/items/3").await;
+        assert_eq!(response.json::<Vec<String>>(), ["y"]);
+        assert!(client.db().read().await.get_term_id("why").is_some());
+    }
+}
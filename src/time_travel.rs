@@ -0,0 +1,140 @@
+//! Reconstruction of past state for `POST /query?as_of=<milliseconds since epoch>`
+//!
+//! Audit log serves as write-ahead log. State starts from the newest retained snapshot written
+//! before the requested time, or from an empty database if there is none, and audit entries
+//! recorded since shortly before that snapshot up to the requested time are replayed over it.
+//! Replayed changes that no longer apply are skipped, so the result is exact only if audit log
+//! covers the whole period and state was not replaced wholesale (restores, imports) meanwhile.
+//! Queries that would replay more than [`MAX_REPLAYED_ENTRIES`] entries are rejected.
+
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{audit::AuditLog, serde::rotation, storage::Database};
+
+/// Snapshots record no sequence number, so entries of this period before a snapshot was written
+/// are replayed too in case they did not make it into it
+const REPLAY_OVERLAP_MS: u64 = 60_000;
+
+/// Most audit entries replayed for one query, as all of them are held in memory at once
+pub const MAX_REPLAYED_ENTRIES: usize = 1_000_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimeTravelError {
+    #[error("failed to read audit log: {0}")]
+    Audit(#[from] std::io::Error),
+    #[error("failed to load snapshot {}: {message}", .path.display())]
+    Snapshot { path: PathBuf, message: String },
+    #[error("more than {MAX_REPLAYED_ENTRIES} audit entries would have to be replayed")]
+    TooManyEntries,
+}
+
+/// Database as it was at `as_of` milliseconds since unix epoch, see module docs
+pub async fn state_as_of<const SMALLSIZE: usize>(
+    data_file: &Path,
+    audit: &AuditLog,
+    as_of: u64,
+) -> Result<Database<SMALLSIZE>, TimeTravelError> {
+    let data_file = data_file.to_owned();
+    let (written_at, mut db) =
+        tokio::task::spawn_blocking(move || base_snapshot::<SMALLSIZE>(&data_file, as_of))
+            .await
+            .expect("loading snapshot does not panic")?;
+    let since = written_at.map_or(0, |at| at.saturating_sub(REPLAY_OVERLAP_MS));
+    let entries = audit
+        .read_between(since, as_of, MAX_REPLAYED_ENTRIES + 1)
+        .await?;
+    if entries.len() > MAX_REPLAYED_ENTRIES {
+        return Err(TimeTravelError::TooManyEntries);
+    }
+    Ok(tokio::task::spawn_blocking(move || {
+        for entry in entries {
+            let _ = entry.change.apply(&mut db);
+        }
+        db
+    })
+    .await
+    .expect("replaying changes does not panic"))
+}
+
+/// Newest snapshot last modified at or before `as_of` with its modification time, empty
+/// database if there is none
+fn base_snapshot<const SMALLSIZE: usize>(
+    data_file: &Path,
+    as_of: u64,
+) -> Result<(Option<u64>, Database<SMALLSIZE>), TimeTravelError> {
+    let newest = rotation::slot_paths(data_file)
+        .into_iter()
+        .chain([data_file.to_owned()])
+        .filter_map(|path| Some((modified_at(&path)?, path)))
+        .filter(|(written_at, _)| *written_at <= as_of)
+        .max_by_key(|(written_at, _)| *written_at);
+    let Some((written_at, path)) = newest else {
+        return Ok((None, Database::default()));
+    };
+    let loaded = if path == data_file {
+        std::fs::read(&path).map_err(|e| e.to_string())
+    } else {
+        rotation::read_slot(&path, true)
+            .map(|(_, payload)| payload)
+            .map_err(|e| e.to_string())
+    }
+    .and_then(|bytes| Database::load(&mut bytes.as_slice()).map_err(|e| e.to_string()));
+    match loaded {
+        Ok(db) => Ok((Some(written_at), db)),
+        Err(message) => Err(TimeTravelError::Snapshot { path, message }),
+    }
+}
+
+fn modified_at(path: &Path) -> Option<u64> {
+    let modified = path.metadata().ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::{audit::AuditLog, changes::Change, query::Query, storage::Key};
+
+    use super::state_as_of;
+
+    #[tokio::test]
+    async fn past_state_is_replayed_from_audit_log() {
+        let dir = std::env::temp_dir().join(format!("elizadb-time-travel-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit = AuditLog::open(dir.join("audit.log")).unwrap();
+        let key = Key::try_from(1).unwrap();
+        let set = |term: &str| Change::SetFlag {
+            key,
+            term: term.to_string(),
+        };
+
+        audit.log(0, &set("x"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let before_y = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        audit.log(1, &set("y"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let data_file = dir.join("data.elz");
+        let query = |term: &str| Query::Simple {
+            term: term.to_string(),
+        };
+        let past = state_as_of::<8>(&data_file, &audit, before_y)
+            .await
+            .unwrap();
+        assert_eq!(past.vertical_query(&query("x")).unwrap(), [key]);
+        assert!(past.vertical_query(&query("y")).is_err());
+        let now = state_as_of::<8>(&data_file, &audit, u64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(now.vertical_query(&query("y")).unwrap(), [key]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::{hash_map::Entry, BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    changes::Change,
+    plugins::WriteDenied,
+    quotas::QuotaExceeded,
+    smallset::SmallsetItem,
+    storage::{Database, Key, SetFlagError, StorageCorruption, TermId, TermTableFull},
+};
+
+/// Changes listed individually in [`DryRunReport`], the rest are only counted
+const MAX_LISTED_CHANGES: usize = 1000;
+
+/// Short names `create`, `set` and `unset` are accepted as well, as used by client-side queues
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    #[serde(alias = "create")]
+    CreateRecord {
+        #[serde(with = "crate::keys::flexible")]
+        key: Key,
+    },
+    #[serde(alias = "set")]
+    SetFlag {
+        #[serde(with = "crate::keys::flexible")]
+        key: Key,
+        term: String,
+    },
+    #[serde(alias = "unset")]
+    UnsetFlag {
+        #[serde(with = "crate::keys::flexible")]
+        key: Key,
+        term: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationResult {
+    Created,
+    AlreadyExists,
+    Set,
+    AlreadySet,
+    Unset,
+    NotSet,
+}
+
+impl Operation {
+    /// Term whose flag operation sets or unsets
+    pub fn term(&self) -> Option<&str> {
+        match self {
+            Self::CreateRecord { .. } => None,
+            Self::SetFlag { term, .. } | Self::UnsetFlag { term, .. } => Some(term),
+        }
+    }
+}
+
+impl OperationResult {
+    /// Whether operation modified database, as opposed to finding it already in desired state
+    pub fn changed_state(&self) -> bool {
+        matches!(self, Self::Created | Self::Set | Self::Unset)
+    }
+}
+
+impl From<Operation> for Change {
+    fn from(value: Operation) -> Self {
+        match value {
+            Operation::CreateRecord { key } => Change::CreateRecord { key },
+            Operation::SetFlag { key, term } => Change::SetFlag { key, term },
+            Operation::UnsetFlag { key, term } => Change::UnsetFlag { key, term },
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    #[error("transaction needs {required} new terms, but term table has room for {available}")]
+    TermCapacityExceeded { required: usize, available: usize },
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaExceeded),
+    #[error(transparent)]
+    Denied(#[from] WriteDenied),
+    #[error(transparent)]
+    TermTableFull(#[from] TermTableFull),
+    #[error("unknown term id {0}")]
+    UnknownTermId(TermId),
+    /// Operations before the failed one stay applied
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+impl From<SetFlagError> for TransactionError {
+    fn from(error: SetFlagError) -> Self {
+        match error {
+            SetFlagError::TermTableFull(e) => e.into(),
+            SetFlagError::QuotaExceeded(e) => e.into(),
+            SetFlagError::UnknownTermId(term_id) => Self::UnknownTermId(term_id),
+            SetFlagError::Denied(e) => e.into(),
+            SetFlagError::Corruption(e) => e.into(),
+        }
+    }
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Apply all operations in order, or none of them if they are rejected by term capacity,
+    /// quota or validation checks made upfront. Corrupted storage found partway through leaves
+    /// operations before the failing one applied
+    pub fn apply_transaction(
+        &mut self,
+        operations: &[Operation],
+    ) -> Result<Vec<OperationResult>, TransactionError> {
+        // term table running out of space is the only way for operation on consistent storage
+        // to fail, so checking it upfront leaves only corruption to interrupt the transaction
+        let new_terms = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::SetFlag { term, .. } if self.get_term_id(term).is_none() => {
+                    Some(term.as_str())
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        if new_terms.len() > self.remaining_term_capacity() {
+            return Err(TransactionError::TermCapacityExceeded {
+                required: new_terms.len(),
+                available: self.remaining_term_capacity(),
+            });
+        }
+
+        // quotas only care about how records end up, which is checked as a whole so that
+        // operations freeing room may come after the ones taking it
+        self.check_quota_growth(self.record_sizes_after(operations)?)?;
+
+        // every flag is validated against records as they were before the transaction
+        for operation in operations {
+            if let Operation::SetFlag { key, term } = operation {
+                self.check_flag_allowed(*key, term)?;
+            }
+        }
+
+        self.with_quotas_suspended(|db| {
+            db.with_validation_suspended(|db| db.apply_checked(operations))
+        })
+    }
+
+    /// Number of flags each record touched by `operations` ends up with
+    fn record_sizes_after(
+        &self,
+        operations: &[Operation],
+    ) -> Result<HashMap<Key, usize>, StorageCorruption> {
+        let mut flags = HashMap::<(Key, &str), bool>::new();
+        let mut sizes = HashMap::new();
+        for operation in operations {
+            let key = match operation {
+                Operation::CreateRecord { key }
+                | Operation::SetFlag { key, .. }
+                | Operation::UnsetFlag { key, .. } => *key,
+            };
+            let size = sizes
+                .entry(key)
+                .or_insert_with(|| self.record_size(&key).unwrap_or(0) as isize);
+            let Some(term) = operation.term() else {
+                continue;
+            };
+            let term_id = self.get_term_id(term);
+            let term = term_id
+                .and_then(|term_id| self.explain_term_id(term_id))
+                .unwrap_or(term);
+            let is_set = match flags.entry((key, term)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let item = term_id.and_then(|term_id| SmallsetItem::try_from(term_id).ok());
+                    let stored = match item {
+                        Some(item) => self.record(&key)?.is_some_and(|r| r.contains(item)),
+                        None => false,
+                    };
+                    entry.insert(stored)
+                }
+            };
+            let set = matches!(operation, Operation::SetFlag { .. });
+            if *is_set != set {
+                *is_set = set;
+                *size += if set { 1 } else { -1 };
+            }
+        }
+        Ok(sizes
+            .into_iter()
+            .map(|(key, size)| (key, size.max(0) as usize))
+            .collect())
+    }
+
+    fn apply_checked(
+        &mut self,
+        operations: &[Operation],
+    ) -> Result<Vec<OperationResult>, TransactionError> {
+        operations
+            .iter()
+            .map(|operation| Ok(self.apply_operation(operation)?))
+            .collect()
+    }
+
+    /// Apply single operation on its own, as `/batch` does
+    pub fn apply_operation(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<OperationResult, SetFlagError> {
+        Ok(match operation {
+            Operation::CreateRecord { key } => {
+                if self.create_record(*key)? {
+                    OperationResult::Created
+                } else {
+                    OperationResult::AlreadyExists
+                }
+            }
+            Operation::SetFlag { key, term } => {
+                if self.set_flag(*key, term)? {
+                    OperationResult::Set
+                } else {
+                    OperationResult::AlreadySet
+                }
+            }
+            Operation::UnsetFlag { key, term } => {
+                if self.unset_flag(*key, term)? {
+                    OperationResult::Unset
+                } else {
+                    OperationResult::NotSet
+                }
+            }
+        })
+    }
+
+    /// Make `terms` the exact flag set of `key`, creating the key if needed. Applied like
+    /// [`Database::apply_transaction`]
+    pub fn replace_flags(
+        &mut self,
+        key: Key,
+        terms: &[String],
+    ) -> Result<FlagReplacement, TransactionError> {
+        let operations = self.replacement_operations(key, terms)?;
+        self.apply_flag_operations(key, operations)
+    }
+
+    /// Set all of `terms` on `key`, creating the key and terms if needed. Flags already set are
+    /// kept and not reported, flags of exclusive companions cleared along the way are reported as
+    /// unset. Applied like [`Database::apply_transaction`]
+    pub fn upsert_flags(
+        &mut self,
+        key: Key,
+        terms: &[String],
+    ) -> Result<FlagReplacement, TransactionError> {
+        let carried = |db: &Self| -> Result<BTreeSet<String>, StorageCorruption> {
+            let terms = db.horizontal_query(&key)?.unwrap_or_default();
+            Ok(terms.into_iter().map(String::from).collect())
+        };
+        let before = carried(self)?;
+        let operations = upsert_operations(key, terms);
+        let mut upsert = self.apply_flag_operations(key, operations)?;
+        upsert.unset = before.difference(&carried(self)?).cloned().collect();
+        Ok(upsert)
+    }
+
+    fn apply_flag_operations(
+        &mut self,
+        key: Key,
+        operations: Vec<Operation>,
+    ) -> Result<FlagReplacement, TransactionError> {
+        let results = self.apply_transaction(&operations)?;
+        let mut replacement = FlagReplacement::default();
+        for (operation, result) in operations.into_iter().zip(results) {
+            match (operation, result) {
+                (Operation::CreateRecord { .. }, OperationResult::Created) => {
+                    replacement.created = true
+                }
+                (Operation::SetFlag { term, .. }, OperationResult::Set) => {
+                    replacement.set.push(term)
+                }
+                (Operation::UnsetFlag { term, .. }, OperationResult::Unset) => {
+                    replacement.unset.push(term)
+                }
+                _ => {}
+            }
+        }
+        replacement.version = self.record_version(key);
+        Ok(replacement)
+    }
+
+    /// Operations taking flag set of `key` to exactly `terms`
+    pub fn replacement_operations(
+        &self,
+        key: Key,
+        terms: &[String],
+    ) -> Result<Vec<Operation>, StorageCorruption> {
+        let current = self.record_term_ids(&key)?.unwrap_or_default();
+        let desired = terms
+            .iter()
+            .filter_map(|term| self.get_term_id(term))
+            .collect::<HashSet<_>>();
+
+        let mut operations = vec![Operation::CreateRecord { key }];
+        operations.extend(
+            current
+                .iter()
+                .filter(|term_id| !desired.contains(term_id))
+                .filter_map(|&term_id| self.explain_term_id(term_id))
+                .map(|term| Operation::UnsetFlag {
+                    key,
+                    term: term.to_string(),
+                }),
+        );
+        operations.extend(terms.iter().map(|term| Operation::SetFlag {
+            key,
+            term: term.clone(),
+        }));
+        Ok(operations)
+    }
+}
+
+/// Operations setting all of `terms` on `key`, creating it first
+pub fn upsert_operations(key: Key, terms: &[String]) -> Vec<Operation> {
+    let mut operations = vec![Operation::CreateRecord { key }];
+    operations.extend(terms.iter().map(|term| Operation::SetFlag {
+        key,
+        term: term.clone(),
+    }));
+    operations
+}
+
+/// Works out what operations would do to database without changing it, for `?dry_run=true`
+#[derive(Debug, Default)]
+pub struct DryRun {
+    created: HashSet<Key>,
+    /// Flags as operations so far left them, keyed by term name or by the name alias points to
+    flags: HashMap<(Key, String), bool>,
+    new_terms: Vec<String>,
+    change_count: usize,
+    changes: Vec<Change>,
+}
+
+/// What request sent with `?dry_run=true` would have changed
+#[derive(Clone, Debug, Serialize)]
+pub struct DryRunReport {
+    pub change_count: usize,
+    /// First changes in the order they would be applied
+    pub changes: Vec<Change>,
+    pub new_terms: Vec<String>,
+    /// Room left in term table after adding new terms
+    pub remaining_term_capacity: usize,
+    pub term_capacity_exceeded: bool,
+}
+
+impl DryRun {
+    pub fn apply<const SMALLSIZE: usize>(
+        &mut self,
+        db: &Database<SMALLSIZE>,
+        operation: Operation,
+    ) -> Result<OperationResult, StorageCorruption> {
+        let result = match &operation {
+            Operation::CreateRecord { key } => {
+                if self.exists(db, key) {
+                    OperationResult::AlreadyExists
+                } else {
+                    self.created.insert(*key);
+                    OperationResult::Created
+                }
+            }
+            Operation::SetFlag { key, term } => {
+                if db.get_term_id(term).is_none() && !self.new_terms.contains(term) {
+                    self.new_terms.push(term.clone());
+                }
+                self.created.insert(*key);
+                if self.replace_flag(db, *key, term, true)? {
+                    OperationResult::AlreadySet
+                } else {
+                    OperationResult::Set
+                }
+            }
+            Operation::UnsetFlag { key, term } => {
+                if self.replace_flag(db, *key, term, false)? {
+                    OperationResult::Unset
+                } else {
+                    OperationResult::NotSet
+                }
+            }
+        };
+        if result.changed_state() {
+            self.change_count += 1;
+            if self.changes.len() < MAX_LISTED_CHANGES {
+                self.changes.push(operation.into());
+            }
+        }
+        Ok(result)
+    }
+
+    fn exists<const SMALLSIZE: usize>(&self, db: &Database<SMALLSIZE>, key: &Key) -> bool {
+        self.created.contains(key) || db.contains_key(key)
+    }
+
+    /// Returns whether flag was set before
+    fn replace_flag<const SMALLSIZE: usize>(
+        &mut self,
+        db: &Database<SMALLSIZE>,
+        key: Key,
+        term: &str,
+        value: bool,
+    ) -> Result<bool, StorageCorruption> {
+        let term_id = db.get_term_id(term);
+        let name = term_id
+            .and_then(|term_id| db.explain_term_id(term_id))
+            .unwrap_or(term)
+            .to_string();
+        let stored = || {
+            let item = term_id.and_then(|term_id| SmallsetItem::try_from(term_id).ok());
+            Ok(match (db.record(&key)?, item) {
+                (Some(record), Some(item)) => record.contains(item),
+                _ => false,
+            })
+        };
+        let was_set = match self.flags.get(&(key, name.clone())) {
+            Some(&set) => set,
+            None => stored()?,
+        };
+        self.flags.insert((key, name), value);
+        Ok(was_set)
+    }
+
+    /// Fails like [`Database::apply_transaction`] would if new terms do not fit
+    pub fn check_term_capacity<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+    ) -> Result<(), TransactionError> {
+        if self.new_terms.len() > db.remaining_term_capacity() {
+            return Err(TransactionError::TermCapacityExceeded {
+                required: self.new_terms.len(),
+                available: db.remaining_term_capacity(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn report<const SMALLSIZE: usize>(self, db: &Database<SMALLSIZE>) -> DryRunReport {
+        let available = db.remaining_term_capacity();
+        DryRunReport {
+            change_count: self.change_count,
+            changes: self.changes,
+            remaining_term_capacity: available.saturating_sub(self.new_terms.len()),
+            term_capacity_exceeded: self.new_terms.len() > available,
+            new_terms: self.new_terms,
+        }
+    }
+}
+
+/// What `replace_flags` or `upsert_flags` had to change to reach requested flag set
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FlagReplacement {
+    pub created: bool,
+    pub set: Vec<String>,
+    pub unset: Vec<String>,
+    /// Version record is at afterwards, to be sent as `expected_version` of the next replacement
+    pub version: u64,
+}
+
+impl FlagReplacement {
+    /// Changes to record in change feed, in the order they were applied
+    pub fn changes(&self, key: Key) -> impl Iterator<Item = Change> + '_ {
+        let created = self.created.then_some(Change::CreateRecord { key });
+        created
+            .into_iter()
+            .chain(self.unset.iter().map(move |term| Change::UnsetFlag {
+                key,
+                term: term.clone(),
+            }))
+            .chain(self.set.iter().map(move |term| Change::SetFlag {
+                key,
+                term: term.clone(),
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Database, Key, MAX_TERMS};
+
+    use super::{DryRun, Operation, OperationResult};
+
+    #[test]
+    fn operations_report_their_effect() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        let operations = [
+            Operation::CreateRecord { key },
+            Operation::SetFlag {
+                key,
+                term: "a".to_string(),
+            },
+            Operation::SetFlag {
+                key,
+                term: "a".to_string(),
+            },
+            Operation::UnsetFlag {
+                key,
+                term: "a".to_string(),
+            },
+            Operation::UnsetFlag {
+                key,
+                term: "b".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            db.apply_transaction(&operations).unwrap(),
+            [
+                OperationResult::Created,
+                OperationResult::Set,
+                OperationResult::AlreadySet,
+                OperationResult::Unset,
+                OperationResult::NotSet,
+            ]
+        );
+        assert!(db.horizontal_query(&key).unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn transaction_exceeding_term_capacity_is_not_applied() {
+        let mut db = Database::<8>::default();
+        for i in 0..MAX_TERMS - 1 {
+            db.add_term(&i.to_string()).unwrap();
+        }
+        let key = Key::try_from(1).unwrap();
+        let operations = [
+            Operation::CreateRecord { key },
+            Operation::SetFlag {
+                key,
+                term: "new".to_string(),
+            },
+            Operation::SetFlag {
+                key,
+                term: "another".to_string(),
+            },
+        ];
+
+        assert!(db.apply_transaction(&operations).is_err());
+        assert!(!db.contains_key(&key));
+    }
+
+    #[test]
+    fn batch_operations_fail_one_by_one() {
+        let mut db = Database::<8>::default();
+        for i in 0..MAX_TERMS - 1 {
+            db.add_term(&i.to_string()).unwrap();
+        }
+        let operations: Vec<Operation> = serde_json::from_str(
+            r#"[
+                {"op": "create", "key": 1},
+                {"op": "set", "key": 1, "term": "new"},
+                {"op": "set", "key": 1, "term": "another"},
+                {"op": "unset", "key": 1, "term": "new"}
+            ]"#,
+        )
+        .unwrap();
+
+        let results = operations
+            .iter()
+            .map(|operation| db.apply_operation(operation).ok())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                Some(OperationResult::Created),
+                Some(OperationResult::Set),
+                None,
+                Some(OperationResult::Unset),
+            ]
+        );
+    }
+
+    #[test]
+    fn replace_flags_sets_exact_flag_set() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        for term in ["a", "b", "c"] {
+            db.set_flag(key, term).unwrap();
+        }
+        db.add_term("x").unwrap();
+        db.add_alias("x", "x-alias").unwrap();
+
+        let replacement = db
+            .replace_flags(
+                key,
+                &["b".to_string(), "x-alias".to_string(), "new".to_string()],
+            )
+            .unwrap();
+        assert!(!replacement.created);
+        assert_eq!(replacement.set, ["x-alias", "new"]);
+        let mut unset = replacement.unset.clone();
+        unset.sort();
+        assert_eq!(unset, ["a", "c"]);
+
+        let flags = db.horizontal_query(&key).unwrap().unwrap();
+        assert_eq!(flags, ["b", "x", "new"].into_iter().collect());
+
+        let fresh = Key::try_from(2).unwrap();
+        let replacement = db.replace_flags(fresh, &[]).unwrap();
+        assert!(replacement.created);
+        assert!(db.contains_key(&fresh));
+    }
+
+    #[test]
+    fn upsert_flags_reports_only_new_flags() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        let terms = |terms: &[&str]| {
+            terms
+                .iter()
+                .map(|term| term.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let upsert = db.upsert_flags(key, &terms(&["a", "b"])).unwrap();
+        assert!(upsert.created);
+        assert_eq!(upsert.set, ["a", "b"]);
+
+        let upsert = db.upsert_flags(key, &terms(&["b", "c", "c"])).unwrap();
+        assert!(!upsert.created);
+        assert_eq!(upsert.set, ["c"]);
+        assert!(upsert.unset.is_empty());
+        assert_eq!(
+            db.horizontal_query(&key).unwrap().unwrap(),
+            ["a", "b", "c"].into_iter().collect()
+        );
+
+        db.set_flag(key, "open").unwrap();
+        db.add_term("closed").unwrap();
+        db.set_term_group("status", &terms(&["open", "closed"]), true)
+            .unwrap();
+        let upsert = db.upsert_flags(key, &terms(&["closed"])).unwrap();
+        assert_eq!(upsert.set, ["closed"]);
+        assert_eq!(upsert.unset, ["open"]);
+    }
+
+    #[test]
+    fn dry_run_reports_effect_without_applying_it() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        db.set_flag(key, "a").unwrap();
+        db.add_alias("a", "a-alias").unwrap();
+        let operations = db.replacement_operations(key, &["b".to_string()]).unwrap();
+
+        let mut dry_run = DryRun::default();
+        let results: Vec<_> = operations
+            .into_iter()
+            .chain([
+                Operation::SetFlag {
+                    key,
+                    term: "a-alias".to_string(),
+                },
+                Operation::SetFlag {
+                    key,
+                    term: "b".to_string(),
+                },
+            ])
+            .map(|operation| dry_run.apply(&db, operation).unwrap())
+            .collect();
+        assert_eq!(
+            results,
+            [
+                OperationResult::AlreadyExists,
+                OperationResult::Unset,
+                OperationResult::Set,
+                OperationResult::Set,
+                OperationResult::AlreadySet,
+            ]
+        );
+
+        let report = dry_run.report(&db);
+        assert_eq!(report.change_count, 3);
+        assert_eq!(report.new_terms, ["b"]);
+        assert_eq!(report.remaining_term_capacity, MAX_TERMS - 2);
+        assert_eq!(db.horizontal_query(&key).unwrap(), Some(["a"].into()));
+    }
+}
@@ -0,0 +1,375 @@
+//! Rules run when a flag gets set, managed at `/triggers`
+//!
+//! A trigger sets flags of other terms on the same record, calls a webhook, or both. Every term
+//! fires at most once per write, so triggers referring to each other do not loop.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    keys::ApiKey,
+    smallset::SmallsetItem,
+    storage::{Database, Key, SetFlagError, TermError, TermId},
+};
+
+/// How long webhook may take to accept a call before it is given up on
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trigger {
+    pub when: TermId,
+    #[serde(default)]
+    pub set: BTreeSet<TermId>,
+    /// Called with [`TriggerCall`] as JSON body
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+/// Trigger with terms given by name, as API and JSON exports show it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerInfo {
+    pub when: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub set: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TriggerError {
+    #[error(transparent)]
+    Term(#[from] TermError),
+    #[error("webhook {0} is not an http or https url")]
+    InvalidWebhook(String),
+    #[error("trigger neither sets terms nor calls a webhook")]
+    NoAction,
+}
+
+/// Body posted to webhook of trigger
+#[derive(Clone, Debug, Serialize)]
+pub struct TriggerCall {
+    pub trigger: String,
+    pub key: ApiKey,
+    pub term: String,
+    #[serde(skip)]
+    pub webhook: String,
+}
+
+/// Whether `webhook` can be called, plain http or https
+pub(crate) fn is_webhook_url(webhook: &str) -> bool {
+    webhook.starts_with("http://") || webhook.starts_with("https://")
+}
+
+/// Start task calling webhooks of triggers, sender is meant for
+/// [`Database::set_trigger_webhooks`]. Failed calls are logged and not retried
+pub fn deliver_webhooks() -> mpsc::UnboundedSender<TriggerCall> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<TriggerCall>();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        while let Some(call) = receiver.recv().await {
+            let delivered = client
+                .post(&call.webhook)
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(&call)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(e) = delivered {
+                warn!(
+                    trigger = call.trigger,
+                    webhook = call.webhook,
+                    "calling webhook failed: {e}"
+                );
+            }
+        }
+    });
+    sender
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    /// Create trigger or replace the one named `name`. Terms may be given by alias, fails without
+    /// changing anything if one of them does not exist
+    pub fn set_trigger(
+        &mut self,
+        name: &str,
+        when: &str,
+        set: &[String],
+        webhook: Option<&str>,
+    ) -> Result<(), TriggerError> {
+        if let Some(webhook) = webhook.filter(|webhook| !is_webhook_url(webhook)) {
+            return Err(TriggerError::InvalidWebhook(webhook.to_string()));
+        }
+        if set.is_empty() && webhook.is_none() {
+            return Err(TriggerError::NoAction);
+        }
+        let term_id = |term: &str| {
+            self.get_term_id(term)
+                .ok_or_else(|| TermError::UnknownTerm(term.to_string()))
+        };
+        let when = term_id(when)?;
+        let set = set
+            .iter()
+            .map(|term| term_id(term))
+            .filter(|term_id| !matches!(term_id, Ok(term_id) if *term_id == when))
+            .collect::<Result<_, _>>()?;
+        self.triggers.insert(
+            name.to_string(),
+            Trigger {
+                when,
+                set,
+                webhook: webhook.map(String::from),
+            },
+        );
+        Ok(())
+    }
+
+    /// Drop trigger, indicates if it existed
+    pub fn remove_trigger(&mut self, name: &str) -> bool {
+        self.triggers.remove(name).is_some()
+    }
+
+    /// Trigger with its terms named, None if there is no such trigger
+    pub fn trigger(&self, name: &str) -> Option<TriggerInfo> {
+        let trigger = self.triggers.get(name)?;
+        let explain = |term_id| self.explain_term_id(term_id).map(String::from);
+        Some(TriggerInfo {
+            when: explain(trigger.when)?,
+            set: trigger
+                .set
+                .iter()
+                .filter_map(|&term_id| explain(term_id))
+                .collect(),
+            webhook: trigger.webhook.clone(),
+        })
+    }
+
+    /// Pairs of trigger name and trigger, ordered by name
+    pub fn list_triggers(&self) -> impl Iterator<Item = (&'_ str, TriggerInfo)> {
+        self.triggers
+            .keys()
+            .filter_map(|name| Some((name.as_str(), self.trigger(name)?)))
+    }
+
+    /// Calls of trigger webhooks are sent to `webhooks` from now on
+    pub fn set_trigger_webhooks(&mut self, webhooks: mpsc::UnboundedSender<TriggerCall>) {
+        self.trigger_webhooks = Some(webhooks);
+    }
+
+    /// Terms whose flags, once set, end up setting flag of `term_id` through triggers
+    pub fn trigger_sources(&self, term_id: TermId) -> Vec<TermId> {
+        let mut sources = HashSet::from([term_id]);
+        let mut pending = vec![term_id];
+        while let Some(term_id) = pending.pop() {
+            for trigger in self.triggers.values() {
+                if trigger.set.contains(&term_id) && sources.insert(trigger.when) {
+                    pending.push(trigger.when);
+                }
+            }
+        }
+        sources.remove(&term_id);
+        sources.into_iter().collect()
+    }
+
+    /// Fire triggers of flag `term_index` that was just set on `key`
+    pub(super) fn fire_triggers(
+        &mut self,
+        key: Key,
+        term_index: SmallsetItem<TermId>,
+    ) -> Result<(), SetFlagError> {
+        if self.triggers.is_empty() {
+            return Ok(());
+        }
+        let mut fired = HashSet::from([term_index.get()]);
+        let mut pending = vec![term_index.get()];
+        while let Some(term_id) = pending.pop() {
+            let triggers = self
+                .triggers
+                .iter()
+                .filter(|(_, trigger)| trigger.when == term_id)
+                .map(|(name, trigger)| (name.clone(), trigger.clone()))
+                .collect::<Vec<_>>();
+            for (name, trigger) in triggers {
+                if let (Some(webhook), Some(webhooks)) = (trigger.webhook, &self.trigger_webhooks) {
+                    let term = self
+                        .explain_term_id(term_id)
+                        .unwrap_or_default()
+                        .to_string();
+                    let _ = webhooks.send(TriggerCall {
+                        trigger: name.clone(),
+                        key: ApiKey(key),
+                        term,
+                        webhook,
+                    });
+                }
+                for derived in trigger.set {
+                    if !fired.insert(derived) {
+                        continue;
+                    }
+                    let Ok(derived_index) = SmallsetItem::try_from(derived) else {
+                        continue;
+                    };
+                    // flags derived over quota or denied by validator are skipped rather than
+                    // failing the write that fired them, which already took place
+                    let allowed = self
+                        .check_flag_quota(key, Some(derived_index))
+                        .and_then(|()| {
+                            let term = self.explain_term_id(derived).unwrap_or_default();
+                            self.check_derived_flag_allowed(key, term)
+                        });
+                    match allowed {
+                        Ok(()) => {}
+                        Err(SetFlagError::Corruption(e)) => return Err(e.into()),
+                        Err(e) => {
+                            warn!(trigger = name, %key, "flag set by trigger skipped: {e}");
+                            continue;
+                        }
+                    }
+                    self.clear_exclusive_companions(key, derived_index)?;
+                    if self.insert_flag(key, derived_index)? {
+                        pending.push(derived);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `from` with `to` in every trigger, called when `from` is merged into `to`
+    pub(super) fn retrigger_term(&mut self, from: TermId, to: TermId) {
+        for trigger in self.triggers.values_mut() {
+            if trigger.when == from {
+                trigger.when = to;
+            }
+            if trigger.set.remove(&from) {
+                trigger.set.insert(to);
+            }
+            let when = trigger.when;
+            trigger.set.remove(&when);
+        }
+        self.triggers
+            .retain(|_, trigger| !trigger.set.is_empty() || trigger.webhook.is_some());
+    }
+
+    /// Drop removed term from triggers, along with triggers fired by it
+    pub(super) fn untrigger_term(&mut self, term_id: TermId) {
+        self.triggers.retain(|_, trigger| {
+            trigger.set.remove(&term_id);
+            trigger.when != term_id && (!trigger.set.is_empty() || trigger.webhook.is_some())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        plugins::{FlagValidator, WriteDenied},
+        storage::{Database, Key},
+    };
+
+    use super::TriggerError;
+
+    #[test]
+    fn triggers_set_derived_flags_without_looping() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        for term in ["paid", "active", "billable", "unrelated"] {
+            db.add_term(term).unwrap();
+        }
+        assert!(matches!(
+            db.set_trigger("empty", "paid", &[], None),
+            Err(TriggerError::NoAction)
+        ));
+        assert!(matches!(
+            db.set_trigger("call", "paid", &[], Some("ftp://example.com")),
+            Err(TriggerError::InvalidWebhook(_))
+        ));
+        db.set_trigger("call", "paid", &[], Some("https://example.com"))
+            .unwrap();
+        assert!(db.remove_trigger("call"));
+        db.set_trigger("activate", "paid", &["active".to_string()], None)
+            .unwrap();
+        db.set_trigger("bill", "active", &["billable".to_string()], None)
+            .unwrap();
+        db.set_trigger("back", "billable", &["paid".to_string()], None)
+            .unwrap();
+
+        assert!(db.set_flag(key, "paid").unwrap());
+        let mut terms = db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        terms.sort();
+        assert_eq!(terms, ["active", "billable", "paid"]);
+
+        let paid = db.get_term_id("paid").unwrap();
+        let mut sources = db.trigger_sources(db.get_term_id("billable").unwrap());
+        sources.sort();
+        assert_eq!(sources, [paid, db.get_term_id("active").unwrap()]);
+
+        // already set flags fire nothing
+        db.unset_flag(key, "active").unwrap();
+        assert!(!db.set_flag(key, "paid").unwrap());
+        assert!(!db
+            .horizontal_query(&key)
+            .unwrap()
+            .unwrap()
+            .contains(&"active"));
+
+        db.merge_term("billable", "unrelated").unwrap();
+        assert_eq!(db.trigger("bill").unwrap().set, ["unrelated"]);
+    }
+
+    struct DenyTerm(&'static str);
+
+    impl FlagValidator for DenyTerm {
+        fn validate(&self, key: Key, term: &str, _flags: &[&str]) -> Result<(), WriteDenied> {
+            if term != self.0 {
+                return Ok(());
+            }
+            Err(WriteDenied {
+                plugin: "deny".to_string(),
+                key,
+                term: term.to_string(),
+                reason: "denied".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn derived_flags_denied_by_validator_are_skipped() {
+        let mut db = Database::<8>::default();
+        let key = Key::try_from(1).unwrap();
+        for term in ["paid", "active", "billable"] {
+            db.add_term(term).unwrap();
+        }
+        db.set_trigger(
+            "activate",
+            "paid",
+            &["active".to_string(), "billable".to_string()],
+            None,
+        )
+        .unwrap();
+        db.set_flag_validator(Arc::new(DenyTerm("billable")));
+
+        assert!(db.set_flag(key, "paid").unwrap());
+        let terms = db.horizontal_query(&key).unwrap().unwrap();
+        assert!(terms.contains("active") && !terms.contains("billable"));
+
+        // writes validated beforehand still have their derived flags validated
+        let other = Key::try_from(2).unwrap();
+        db.with_validation_suspended(|db| db.set_flag(other, "paid"))
+            .unwrap();
+        let terms = db.horizontal_query(&other).unwrap().unwrap();
+        assert!(terms.contains("active") && !terms.contains("billable"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Per-record versions for optimistic concurrency of `PUT /items/:key`
+//!
+//! Version of a record is a number that changes whenever the record does. Versions are taken from
+//! a sequence seeded by current time rather than stored in snapshots, so that a number handed out
+//! before restart is never handed out again. Records not changed since start are at version 0.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::storage::{Database, Key, StorageCorruption};
+
+#[derive(Clone, Debug, Default)]
+pub struct RecordVersions {
+    last: u64,
+    changed: HashMap<Key, u64>,
+}
+
+impl RecordVersions {
+    pub(crate) fn bump(&mut self, key: Key) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        self.last = (self.last + 1).max(now);
+        self.changed.insert(key, self.last);
+    }
+
+    pub(crate) fn forget(&mut self, key: Key) {
+        self.changed.remove(&key);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("record {key} is at version {current}, not {expected}")]
+pub struct VersionMismatch {
+    pub key: Key,
+    pub expected: u64,
+    pub current: u64,
+    /// Flags record currently carries, so that caller can reconcile without another request
+    pub terms: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VersionError {
+    #[error(transparent)]
+    Mismatch(#[from] VersionMismatch),
+    #[error(transparent)]
+    Corruption(#[from] StorageCorruption),
+}
+
+impl<const SMALLSIZE: usize> Database<SMALLSIZE> {
+    pub fn record_version(&self, key: Key) -> u64 {
+        self.versions.changed.get(&key).copied().unwrap_or(0)
+    }
+
+    /// Fails unless record of `key` is at `expected` version
+    pub fn check_version(&self, key: Key, expected: u64) -> Result<(), VersionError> {
+        let current = self.record_version(key);
+        if current == expected {
+            return Ok(());
+        }
+        let mut terms = self
+            .horizontal_query(&key)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        terms.sort_unstable();
+        Err(VersionMismatch {
+            key,
+            expected,
+            current,
+            terms,
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionError;
+    use crate::storage::{Database, Key};
+
+    #[test]
+    fn versions_change_with_records() {
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        assert_eq!(db.record_version(a), 0);
+
+        db.set_flag(a, "x").unwrap();
+        let first = db.record_version(a);
+        assert_ne!(first, 0);
+        db.set_flag(a, "x").unwrap();
+        assert_eq!(db.record_version(a), first);
+        db.set_flag(b, "x").unwrap();
+        assert_eq!(db.record_version(a), first);
+
+        db.unset_flag(a, "x").unwrap();
+        assert!(db.record_version(a) > first);
+        let Err(VersionError::Mismatch(mismatch)) = db.check_version(a, first) else {
+            panic!("version check passed");
+        };
+        assert!(mismatch.terms.is_empty());
+        assert!(db.check_version(b, db.record_version(b)).is_ok());
+    }
+}
@@ -0,0 +1,375 @@
+//! Materialized views: queries whose matching keys are kept up to date as changes are recorded
+//!
+//! Every recorded change marks the key it touched, and a view re-evaluates its query over just
+//! the marked keys the next time it is read. Changes to terms a view mentions, and state being
+//! replaced wholesale, make the view evaluate its query over all records again instead.
+//! Definitions are kept in a JSON file next to the data file, matching keys only in memory.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    changes::Change,
+    deadline::Deadline,
+    query::{FilteredQuery, KeyRange, Query},
+    storage::Database,
+    Key,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ViewDefinition {
+    pub name: String,
+    pub query: Query,
+}
+
+/// View as listed by `GET /views`
+#[derive(Clone, Debug, Serialize)]
+pub struct ViewInfo {
+    pub name: String,
+    pub query: Query,
+    /// Last error evaluating the query, e.g. after a term it mentions was renamed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ViewError {
+    #[error("view {0} already exists")]
+    AlreadyExists(String),
+    #[error("no view {0}")]
+    UnknownView(String),
+    #[error("{0}")]
+    InvalidQuery(String),
+    #[error("failed to save view definitions: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+struct View {
+    query: Query,
+    keys: BTreeSet<Key>,
+    /// Keys changed since view was last brought up to date
+    touched: HashSet<Key>,
+    /// Query has to be evaluated over all records
+    stale: bool,
+    error: Option<String>,
+}
+
+impl View {
+    fn new(query: Query) -> Self {
+        Self {
+            query,
+            keys: BTreeSet::new(),
+            touched: HashSet::new(),
+            stale: true,
+            error: None,
+        }
+    }
+
+    fn mentions(&self, term: &str) -> bool {
+        match &self.query {
+            Query::Simple { term: mentioned } => mentioned == term,
+            Query::KofN { terms, .. } => terms.iter().any(|mentioned| mentioned == term),
+            // members are tracked by id, so changes of term names do not affect groups
+            Query::Group { .. } => false,
+        }
+    }
+
+    fn group(&self) -> Option<&str> {
+        match &self.query {
+            Query::Group { group, .. } => Some(group),
+            _ => None,
+        }
+    }
+
+    fn refresh<const SMALLSIZE: usize>(&mut self, db: &Database<SMALLSIZE>) {
+        if self.stale {
+            self.stale = false;
+            self.touched.clear();
+            match db.vertical_query(&self.query) {
+                Ok(keys) => {
+                    self.keys = keys.into_iter().collect();
+                    self.error = None;
+                }
+                Err(e) => {
+                    self.keys.clear();
+                    self.error = Some(e.to_string());
+                }
+            }
+            return;
+        }
+        if self.touched.is_empty() {
+            return;
+        }
+
+        let touched: Vec<Key> = self.touched.drain().collect();
+        let query = FilteredQuery {
+            query: self.query.clone(),
+            range: KeyRange::default(),
+            candidate_keys: Some(touched.clone()),
+            sample: None,
+            deadline: Deadline::default(),
+        };
+        let Ok(matching) = db.filtered_vertical_query(&query) else {
+            self.stale = true;
+            return self.refresh(db);
+        };
+        for key in touched {
+            self.keys.remove(&key);
+        }
+        self.keys.extend(matching);
+    }
+}
+
+pub struct Views {
+    path: PathBuf,
+    views: Mutex<BTreeMap<String, View>>,
+}
+
+impl Views {
+    /// Definitions are stored at `<data_file>.views`
+    pub fn path_for(data_file: &Path) -> PathBuf {
+        let mut path = data_file.as_os_str().to_owned();
+        path.push(".views");
+        path.into()
+    }
+
+    /// Load view definitions, their keys are worked out when first read
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let definitions: Vec<ViewDefinition> = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let views = definitions
+            .into_iter()
+            .map(|definition| (definition.name, View::new(definition.query)))
+            .collect();
+        Ok(Self {
+            path,
+            views: Mutex::new(views),
+        })
+    }
+
+    fn persist(&self, views: &BTreeMap<String, View>) -> std::io::Result<()> {
+        let definitions: Vec<ViewDefinition> = views
+            .iter()
+            .map(|(name, view)| ViewDefinition {
+                name: name.clone(),
+                query: view.query.clone(),
+            })
+            .collect();
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&definitions)?)?;
+        std::fs::rename(partial, &self.path)
+    }
+
+    /// Register view and evaluate its query right away, which must succeed
+    pub fn create<const SMALLSIZE: usize>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        definition: ViewDefinition,
+    ) -> Result<usize, ViewError> {
+        let mut views = self.views.lock().unwrap();
+        if views.contains_key(&definition.name) {
+            return Err(ViewError::AlreadyExists(definition.name));
+        }
+        let mut view = View::new(definition.query);
+        view.refresh(db);
+        if let Some(e) = view.error {
+            return Err(ViewError::InvalidQuery(e));
+        }
+        let count = view.keys.len();
+        views.insert(definition.name.clone(), view);
+        if let Err(e) = self.persist(&views) {
+            views.remove(&definition.name);
+            return Err(e.into());
+        }
+        Ok(count)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), ViewError> {
+        let mut views = self.views.lock().unwrap();
+        let view = views
+            .remove(name)
+            .ok_or_else(|| ViewError::UnknownView(name.to_string()))?;
+        if let Err(e) = self.persist(&views) {
+            views.insert(name.to_string(), view);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<ViewInfo> {
+        self.views
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, view)| ViewInfo {
+                name: name.clone(),
+                query: view.query.clone(),
+                error: view.error.clone(),
+            })
+            .collect()
+    }
+
+    pub fn query(&self, name: &str) -> Option<Query> {
+        let views = self.views.lock().unwrap();
+        views.get(name).map(|view| view.query.clone())
+    }
+
+    /// Bring view up to date with `db` and read it, `db` must be the state changes were observed on
+    pub fn read<const SMALLSIZE: usize, T>(
+        &self,
+        db: &Database<SMALLSIZE>,
+        name: &str,
+        read: impl FnOnce(&BTreeSet<Key>) -> T,
+    ) -> Result<T, ViewError> {
+        let mut views = self.views.lock().unwrap();
+        let view = views
+            .get_mut(name)
+            .ok_or_else(|| ViewError::UnknownView(name.to_string()))?;
+        view.refresh(db);
+        Ok(read(&view.keys))
+    }
+
+    /// Note change recorded in change feed, called while database write lock is held
+    pub fn observe(&self, change: &Change) {
+        let mut views = self.views.lock().unwrap();
+        match change {
+            Change::CreateRecord { key }
+            | Change::SetFlag { key, .. }
+            | Change::UnsetFlag { key, .. }
+            | Change::DeleteRecord { key, .. }
+            | Change::RestoreRecord { key } => {
+                for view in views.values_mut().filter(|view| !view.stale) {
+                    view.touched.insert(*key);
+                }
+            }
+            Change::AddTerm { term }
+            | Change::AddTermWithId { term, .. }
+            | Change::RemoveAlias { alias: term }
+            | Change::AddAlias { alias: term, .. } => {
+                for view in views.values_mut().filter(|view| view.mentions(term)) {
+                    view.stale = true;
+                }
+            }
+            Change::RenameTerm {
+                term,
+                new_name: other,
+            }
+            | Change::MergeTerm { term, into: other } => {
+                let merge = matches!(change, Change::MergeTerm { .. });
+                for view in views.values_mut() {
+                    // merged terms are replaced in every group they were in
+                    if view.mentions(term)
+                        || view.mentions(other)
+                        || (merge && view.group().is_some())
+                    {
+                        view.stale = true;
+                    }
+                }
+            }
+            Change::SetTermGroup { name, .. } | Change::RemoveTermGroup { name } => {
+                for view in views.values_mut().filter(|view| view.group() == Some(name)) {
+                    view.stale = true;
+                }
+            }
+            Change::SetKeyAlias { .. }
+            | Change::RemoveKeyAlias { .. }
+            | Change::SetTrigger { .. }
+            | Change::RemoveTrigger { .. }
+            | Change::PurgeRecord { .. } => {}
+        }
+    }
+
+    /// State was replaced wholesale, every view has to be evaluated again
+    pub fn invalidate_all(&self) {
+        for view in self.views.lock().unwrap().values_mut() {
+            view.stale = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        changes::Change,
+        query::Query,
+        storage::{Database, Key},
+    };
+
+    use super::{ViewDefinition, Views};
+
+    #[test]
+    fn view_follows_observed_changes() {
+        let path = std::env::temp_dir().join(format!("elizadb-views-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let views = Views::open(path.clone()).unwrap();
+        let mut db = Database::<8>::default();
+        let (a, b) = (Key::try_from(1).unwrap(), Key::try_from(2).unwrap());
+        db.set_flag(a, "x").unwrap();
+        db.add_term("y").unwrap();
+        let query = Query::KofN {
+            terms: vec!["x".to_string(), "y".to_string()],
+            bound: 2,
+        };
+        let definition = ViewDefinition {
+            name: "both".to_string(),
+            query,
+        };
+        assert_eq!(views.create(&db, definition).unwrap(), 0);
+
+        let apply = |db: &mut Database<8>, change: Change| {
+            change.apply(db).unwrap();
+            views.observe(&change);
+        };
+        apply(
+            &mut db,
+            Change::SetFlag {
+                key: a,
+                term: "y".to_string(),
+            },
+        );
+        apply(
+            &mut db,
+            Change::SetFlag {
+                key: b,
+                term: "x".to_string(),
+            },
+        );
+        assert_eq!(views.read(&db, "both", |keys| keys.len()).unwrap(), 1);
+
+        apply(
+            &mut db,
+            Change::SetFlag {
+                key: b,
+                term: "y".to_string(),
+            },
+        );
+        apply(
+            &mut db,
+            Change::UnsetFlag {
+                key: a,
+                term: "x".to_string(),
+            },
+        );
+        apply(
+            &mut db,
+            Change::RenameTerm {
+                term: "y".to_string(),
+                new_name: "z".to_string(),
+            },
+        );
+        assert!(views.read(&db, "both", |keys| keys.is_empty()).unwrap());
+        assert!(views.list()[0].error.is_some());
+
+        let reopened = Views::open(path.clone()).unwrap();
+        assert_eq!(reopened.list()[0].name, "both");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{api::DBState, audit, storage::Database};
+
+type WriteJob = Box<dyn FnOnce(&mut Database<8>) + Send>;
+
+/// Applies mutations either directly under database write lock or, when batching is enabled,
+/// by handing them to a single writer task that applies queued jobs in batches under one lock
+#[derive(Clone)]
+pub struct Writer {
+    db: DBState,
+    queue: Option<mpsc::Sender<WriteJob>>,
+}
+
+impl Writer {
+    pub fn direct(db: DBState) -> Self {
+        Self { db, queue: None }
+    }
+
+    /// Start writer task. Submitters wait once `capacity` jobs are queued
+    pub fn batching(db: DBState, capacity: usize, max_batch: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        tokio::spawn(apply_batches(db.clone(), receiver, max_batch.max(1)));
+        Self {
+            db,
+            queue: Some(sender),
+        }
+    }
+
+    /// Run `job` with exclusive access to database and return its result
+    pub async fn run<R: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Database<8>) -> R + Send + 'static,
+    ) -> R {
+        let Some(queue) = &self.queue else {
+            return job(&mut *self.db.write().await);
+        };
+
+        let actor = audit::current_actor();
+        let (sender, receiver) = oneshot::channel();
+        let job: WriteJob = Box::new(move |db| {
+            let _ = sender.send(audit::with_actor(actor, || job(db)));
+        });
+        queue.send(job).await.expect("writer task has stopped");
+        receiver.await.expect("write job panicked")
+    }
+}
+
+async fn apply_batches(db: DBState, mut receiver: mpsc::Receiver<WriteJob>, max_batch: usize) {
+    let mut batch = Vec::with_capacity(max_batch);
+    while receiver.recv_many(&mut batch, max_batch).await > 0 {
+        let mut db = db.write().await;
+        for job in batch.drain(..) {
+            // dropped result sender reports failure to the submitter, other jobs carry on
+            let _ = catch_unwind(AssertUnwindSafe(|| job(&mut db)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::RwLock;
+
+    use crate::storage::{Database, Key};
+
+    use super::Writer;
+
+    #[tokio::test]
+    async fn queued_writes_are_applied() {
+        let db = Arc::new(RwLock::new(Database::<8>::default()));
+        let writer = Writer::batching(db.clone(), 4, 2);
+
+        let handles = (1..=10)
+            .map(|key| {
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    writer
+                        .run(move |db| db.set_flag(Key::try_from(key).unwrap(), "x"))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Ok(true));
+        }
+
+        assert_eq!(db.read().await.list_keys().count(), 10);
+    }
+}
@@ -0,0 +1,695 @@
+//! End-to-end tests of the HTTP API, served on an ephemeral port and driven over real connections
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use clap::Parser;
+use elizadb::{
+    api,
+    config::{BigRecordDecoding, Config},
+    jobs::Jobs,
+    serde,
+    snapshots::Snapshotter,
+    telemetry::LogFilter,
+    Database,
+};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+struct TestServer {
+    address: SocketAddr,
+    client: Client,
+    db: api::DBState,
+    dir: PathBuf,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl TestServer {
+    async fn start(db: Database<8>) -> Self {
+        static SERVERS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "elizadb-api-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self::start_with(db, dir, &[]).await
+    }
+
+    async fn start_in(db: Database<8>, dir: PathBuf) -> Self {
+        Self::start_with(db, dir, &[]).await
+    }
+
+    /// Start with command line arguments `args` added to configuration
+    async fn start_with(db: Database<8>, dir: PathBuf, args: &[&str]) -> Self {
+        let data_file = dir.join("data.elz");
+        let config = Config::parse_from(
+            [
+                "elizadb".as_ref(),
+                "--data-file".as_ref(),
+                data_file.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(|arg| arg.as_ref())),
+        );
+        let db = Arc::new(RwLock::new(db));
+        let jobs = Arc::new(Jobs::default());
+        let snapshotter = Snapshotter::new(db.clone(), jobs.clone(), &config).unwrap();
+        let state = api::AppState::new(
+            db.clone(),
+            snapshotter,
+            jobs,
+            LogFilter::detached(),
+            &config,
+        )
+        .unwrap();
+        let router = api::build_router(state);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+        Self {
+            address,
+            client: Client::new(),
+            db,
+            dir,
+            server,
+        }
+    }
+
+    fn data_file(&self) -> PathBuf {
+        self.dir.join("data.elz")
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.address)
+    }
+
+    async fn get(&self, path: &str) -> (StatusCode, Value) {
+        let response = self.client.get(self.url(path)).send().await.unwrap();
+        (
+            response.status(),
+            response.json().await.unwrap_or(Value::Null),
+        )
+    }
+
+    async fn post(&self, path: &str, body: Value) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .post(self.url(path))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        (
+            response.status(),
+            response.json().await.unwrap_or(Value::Null),
+        )
+    }
+
+    async fn put(&self, path: &str, body: Value) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .put(self.url(path))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        (
+            response.status(),
+            response.json().await.unwrap_or(Value::Null),
+        )
+    }
+
+    async fn delete(&self, path: &str) -> StatusCode {
+        let response = self.client.delete(self.url(path)).send().await.unwrap();
+        response.status()
+    }
+
+    async fn query(&self, query: Value) -> Vec<u64> {
+        let (status, keys) = self.post("/query", query).await;
+        assert_eq!(status, StatusCode::OK, "{keys}");
+        serde_json::from_value(keys).unwrap()
+    }
+
+    /// Stop serving, keeping data directory for a server started after it
+    fn stop(mut self) -> PathBuf {
+        std::mem::take(&mut self.dir)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.abort();
+        if !self.dir.as_os_str().is_empty() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[tokio::test]
+async fn records_are_created_flagged_and_queried() {
+    let server = TestServer::start(Database::default()).await;
+
+    let (status, _) = server.post("/items", json!(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, error) = server.post("/items", json!(1)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["code"], "key_exists");
+
+    assert!(server.post("/items/1", json!("x")).await.0.is_success());
+    assert!(server.post("/items/2", json!("x")).await.0.is_success());
+    assert!(server.post("/items/2", json!("y")).await.0.is_success());
+    let (_, terms) = server.get("/items/2").await;
+    let mut terms: Vec<String> = serde_json::from_value(terms).unwrap();
+    terms.sort();
+    assert_eq!(terms, ["x", "y"]);
+
+    let both = json!({"type": "KofN", "terms": ["x", "y"], "bound": 2});
+    assert_eq!(
+        server.query(json!({"type": "Simple", "term": "x"})).await,
+        [1, 2]
+    );
+    assert_eq!(server.query(both.clone()).await, [2]);
+    let (status, count) = server.post("/query/count", both.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(count["count"], 1);
+
+    assert!(server.put("/items/2", json!(["y"])).await.0.is_success());
+    assert_eq!(server.query(both).await, Vec::<u64>::new());
+
+    assert!(server.delete("/items/1").await.is_success());
+    let (status, error) = server.get("/items/1").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(error["code"].is_string());
+
+    let (status, error) = server.get("/no/such/route").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error["code"], "route_not_found");
+}
+
+#[tokio::test]
+async fn bulk_writes_are_visible_to_queries() {
+    let server = TestServer::start(Database::default()).await;
+
+    let keys: Vec<u64> = (1..=100).collect();
+    let (status, _) = server.post("/bulk/items", json!(keys)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, error) = server.post("/bulk/items", json!([100, 101])).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["detail"], json!([100]));
+
+    let even: Vec<u64> = keys.iter().copied().filter(|key| key % 2 == 0).collect();
+    let (status, report) = server
+        .post("/bulk/keys", json!({"term": "even", "keys": even}))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report.as_array().unwrap().len(), 50);
+    assert!(report
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|report| report["result"] == "applied"));
+
+    assert_eq!(
+        server
+            .query(json!({"type": "Simple", "term": "even"}))
+            .await,
+        even
+    );
+    let (status, results) = server
+        .post(
+            "/bulk/query",
+            json!([
+                {"type": "Simple", "term": "even"},
+                {"type": "Simple", "term": "odd"},
+            ]),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(results[0]["Ok"], json!(even));
+    assert_eq!(results[1]["Err"]["code"], "invalid_query");
+    assert!(results[1]["Err"]["error"].is_string());
+
+    let (status, parallel) = server
+        .post(
+            "/bulk/query?parallel=true",
+            json!([
+                {"type": "Simple", "term": "even"},
+                {"type": "Simple", "term": "odd"},
+            ]),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parallel, results);
+}
+
+#[tokio::test]
+async fn saved_state_survives_restart() {
+    let server = TestServer::start(Database::default()).await;
+    server.post("/bulk/items", json!([1, 2, 3])).await;
+    server.post("/items/1", json!("x")).await;
+    server.post("/items/3", json!("x")).await;
+    server
+        .post("/aliases", json!({"term": "x", "alias": "ex"}))
+        .await;
+    let (status, _) = server.post("/service/save", Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let data_file = server.data_file();
+    let dir = server.stop();
+    let db = serde::load_possibly_missing::<8>(&data_file, BigRecordDecoding::Eager).unwrap();
+    let server = TestServer::start_in(db, dir).await;
+    assert_eq!(
+        server.query(json!({"type": "Simple", "term": "ex"})).await,
+        [1, 3]
+    );
+    assert_eq!(server.get("/items/2").await, (StatusCode::OK, json!([])));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_bulk_writes_and_queries_agree() {
+    const WRITERS: u64 = 8;
+    const KEYS: u64 = 500;
+    let server = Arc::new(TestServer::start(Database::default()).await);
+    let keys: Vec<u64> = (1..=KEYS).collect();
+    server.post("/bulk/items", json!(keys)).await;
+
+    let writers = (0..WRITERS).map(|writer| {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let keys: Vec<u64> = (1..=KEYS).filter(|key| key % WRITERS == writer).collect();
+            let term = format!("w{writer}");
+            let (status, _) = server
+                .post("/bulk/keys", json!({"term": term, "keys": keys}))
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = server
+                .post("/bulk/keys", json!({"term": "all", "keys": keys}))
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        })
+    });
+    let readers = (0..WRITERS).map(|_| {
+        let server = server.clone();
+        tokio::spawn(async move {
+            for _ in 0..10 {
+                let (status, keys) = server
+                    .post("/query", json!({"type": "Simple", "term": "all"}))
+                    .await;
+                // term may not exist yet when first queries arrive
+                if status == StatusCode::OK {
+                    let keys: Vec<u64> = serde_json::from_value(keys).unwrap();
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            }
+        })
+    });
+    let tasks: Vec<_> = writers.chain(readers).collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert_eq!(
+        server.query(json!({"type": "Simple", "term": "all"})).await,
+        keys
+    );
+    let terms: Vec<String> = (0..WRITERS).map(|writer| format!("w{writer}")).collect();
+    let any = json!({"type": "KofN", "terms": terms, "bound": 1});
+    assert_eq!(server.query(any).await, keys);
+    let (_, check) = server.get("/admin/check").await;
+    assert_eq!(check["consistent"], true);
+}
+
+#[tokio::test]
+async fn saturated_route_groups_shed_requests() {
+    let dir = std::env::temp_dir().join(format!("elizadb-api-test-{}-shed", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let server =
+        TestServer::start_with(Database::default(), dir, &["--max-concurrent-reads", "1"]).await;
+    let lock = server.db.write().await;
+    let in_flight = tokio::spawn(server.client.get(server.url("/items")).send());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let shed = server
+        .client
+        .get(server.url("/terms"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(shed.headers()["retry-after"], "1");
+    let body: Value = shed.json().await.unwrap();
+    assert_eq!(body["code"], "overloaded");
+
+    let (status, config) = server
+        .post("/admin/config", json!({"max_concurrent_reads": 0}))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(config["max_concurrent_reads"], Value::Null);
+    let admitted = tokio::spawn(server.client.get(server.url("/terms")).send());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!admitted.is_finished());
+
+    drop(lock);
+    assert_eq!(in_flight.await.unwrap().unwrap().status(), StatusCode::OK);
+    assert_eq!(admitted.await.unwrap().unwrap().status(), StatusCode::OK);
+    assert_eq!(server.get("/terms").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn verify_reports_keys_differing_from_server() {
+    let mut served = Database::default();
+    let mut snapshot = Database::<8>::default();
+    for key in 1..=20u64 {
+        let key = key.try_into().unwrap();
+        served.set_flag(key, "a").unwrap();
+        snapshot.set_flag(key, "a").unwrap();
+    }
+    let (changed, missing) = (3.try_into().unwrap(), 30.try_into().unwrap());
+    snapshot.set_flag(changed, "b").unwrap();
+    snapshot.set_flag(missing, "a").unwrap();
+    assert_eq!(snapshot.sample_keys(100).len(), 21);
+    let server = TestServer::start(served).await;
+    let snapshot_file = server.dir.join("verified.bin");
+    serde::two_phase_save(&snapshot, &snapshot_file).unwrap();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_elizadb-cli"))
+        .arg("verify")
+        .args(["--snapshot", snapshot_file.to_str().unwrap()])
+        .args(["--server", &server.url("")])
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    let mismatches: Vec<Value> = lines[..2]
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(mismatches[0]["key"], 3);
+    assert_eq!(mismatches[1]["key"], 30);
+    assert_eq!(mismatches[1]["server"], Value::Null);
+    assert_eq!(lines[2], "checked 21 keys, 2 mismatched");
+}
+
+#[tokio::test]
+async fn admin_routes_and_term_locks_need_admin_role() {
+    let dir = std::env::temp_dir().join(format!("elizadb-api-test-{}-admin", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let policy = dir.join("policy.json");
+    std::fs::write(
+        &policy,
+        r#"{"api_keys": {"root": ["admin"], "reader": []}}"#,
+    )
+    .unwrap();
+    let mut db = Database::default();
+    db.add_term("managed").unwrap();
+    let server =
+        TestServer::start_with(db, dir, &["--access-policy", policy.to_str().unwrap()]).await;
+
+    let export = |api_key: Option<&str>| {
+        let mut request = server.client.get(server.url("/admin/export"));
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        request.send()
+    };
+    assert_eq!(export(None).await.unwrap().status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        export(Some("reader")).await.unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(export(Some("root")).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(server.get("/terms").await.0, StatusCode::OK);
+
+    let lock = |api_key: &str| {
+        server
+            .client
+            .post(server.url("/terms/managed/lock"))
+            .bearer_auth(api_key)
+            .send()
+    };
+    assert_eq!(
+        lock("reader").await.unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(lock("root").await.unwrap().status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn locks_cover_aliases_of_locked_terms() {
+    let dir =
+        std::env::temp_dir().join(format!("elizadb-api-test-{}-lockalias", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let policy = dir.join("policy.json");
+    std::fs::write(&policy, r#"{"api_keys": {"root": ["admin"]}}"#).unwrap();
+    let mut db = Database::default();
+    db.add_term("plain").unwrap();
+    db.add_alias("plain", "pl").unwrap();
+    let server =
+        TestServer::start_with(db, dir, &["--access-policy", policy.to_str().unwrap()]).await;
+
+    let locked = server
+        .client
+        .post(server.url("/terms/pl/lock"))
+        .bearer_auth("root")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(locked.status(), StatusCode::CREATED);
+    assert_eq!(server.get("/terms/locked").await.1, json!(["plain"]));
+    for term in ["plain", "pl"] {
+        assert_eq!(
+            server.post("/items/2", json!(term)).await.0,
+            StatusCode::LOCKED
+        );
+    }
+    let (status, _) = server.post("/items/2/flags", json!(["pl"])).await;
+    assert_eq!(status, StatusCode::LOCKED);
+}
+
+#[tokio::test]
+async fn triggers_need_write_access_to_their_terms() {
+    let dir =
+        std::env::temp_dir().join(format!("elizadb-api-test-{}-triggers", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let policy = dir.join("policy.json");
+    std::fs::write(
+        &policy,
+        r#"{
+            "api_keys": {"root": ["admin"], "clerk": []},
+            "rules": [{"prefix": "secret:", "write": ["admin"]}]
+        }"#,
+    )
+    .unwrap();
+    let mut db = Database::default();
+    for term in ["paid", "secret:vip", "active"] {
+        db.add_term(term).unwrap();
+    }
+    db.add_alias("secret:vip", "vip").unwrap();
+    let server =
+        TestServer::start_with(db, dir, &["--access-policy", policy.to_str().unwrap()]).await;
+
+    let set_trigger = |api_key: &str, set: &str| {
+        server
+            .client
+            .post(server.url("/triggers"))
+            .bearer_auth(api_key)
+            .json(&json!({"name": "promote", "when": "paid", "set": [set]}))
+            .send()
+    };
+    // aliases are checked under canonical names as well
+    for set in ["secret:vip", "vip"] {
+        assert_eq!(
+            set_trigger("clerk", set).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    assert_eq!(
+        set_trigger("clerk", "active").await.unwrap().status(),
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        set_trigger("root", "vip").await.unwrap().status(),
+        StatusCode::CREATED
+    );
+}
+
+#[tokio::test]
+async fn aliases_do_not_get_around_access_policy() {
+    let dir = std::env::temp_dir().join(format!("elizadb-api-test-{}-aliases", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let policy = dir.join("policy.json");
+    std::fs::write(
+        &policy,
+        r#"{
+            "api_keys": {"root": ["admin"]},
+            "rules": [{"prefix": "pii:", "read": ["admin"], "write": ["admin"]}]
+        }"#,
+    )
+    .unwrap();
+    let mut db = Database::default();
+    db.set_flag(1.try_into().unwrap(), "pii:ssn").unwrap();
+    db.add_alias("pii:ssn", "innocent").unwrap();
+    let server =
+        TestServer::start_with(db, dir, &["--access-policy", policy.to_str().unwrap()]).await;
+
+    let (status, _) = server
+        .post("/query", json!({"type": "Simple", "term": "innocent"}))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        server.post("/items/2", json!("innocent")).await.0,
+        StatusCode::FORBIDDEN
+    );
+    let alias = json!({"term": "pii:ssn", "alias": "harmless"});
+    assert_eq!(
+        server.post("/aliases", alias.clone()).await.0,
+        StatusCode::FORBIDDEN
+    );
+    let created = server
+        .client
+        .post(server.url("/aliases"))
+        .bearer_auth("root")
+        .json(&alias)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(created.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn setting_exclusive_term_checks_locks_of_cleared_companions() {
+    let dir = std::env::temp_dir().join(format!(
+        "elizadb-api-test-{}-companions",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let policy = dir.join("policy.json");
+    std::fs::write(&policy, r#"{"api_keys": {"root": ["admin"], "clerk": []}}"#).unwrap();
+    let mut db = Database::default();
+    db.set_flag(1.try_into().unwrap(), "status:open").unwrap();
+    db.set_term_group("status", &["status:*".to_string()], true)
+        .unwrap();
+    let server =
+        TestServer::start_with(db, dir, &["--access-policy", policy.to_str().unwrap()]).await;
+    let lock = server
+        .client
+        .post(server.url("/terms/status:open/lock"))
+        .bearer_auth("root")
+        .send();
+    assert_eq!(lock.await.unwrap().status(), StatusCode::CREATED);
+
+    let set_closed = |api_key: &str| {
+        server
+            .client
+            .post(server.url("/items/1"))
+            .bearer_auth(api_key)
+            .json(&json!("status:closed"))
+            .send()
+    };
+    assert_eq!(
+        set_closed("clerk").await.unwrap().status(),
+        StatusCode::LOCKED
+    );
+    let upsert_closed = |api_key: &str, path: &str| {
+        server
+            .client
+            .post(server.url(path))
+            .bearer_auth(api_key)
+            .json(&json!(["status:closed"]))
+            .send()
+    };
+    for path in ["/items/1/flags", "/items/1/flags?dry_run=true"] {
+        assert_eq!(
+            upsert_closed("clerk", path).await.unwrap().status(),
+            StatusCode::LOCKED
+        );
+    }
+    let upsert = upsert_closed("root", "/items/1/flags").await.unwrap();
+    assert_eq!(upsert.status(), StatusCode::OK);
+    let upsert: Value = upsert.json().await.unwrap();
+    assert_eq!(upsert["unset"], json!(["status:open"]));
+    let (_, group) = server.get("/term-groups/status").await;
+    assert_eq!(group["terms"], json!(["status:open", "status:closed"]));
+    assert_eq!(group["prefixes"], json!(["status:"]));
+}
+
+#[tokio::test]
+async fn writes_are_rejected_once_memory_estimate_exceeds_limit() {
+    let db = Database::default();
+    let limit = db.memory_usage().total + 64;
+    let dir = std::env::temp_dir().join(format!("elizadb-api-test-{}-memory", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let server = TestServer::start_with(db, dir, &["--max-memory-bytes", &limit.to_string()]).await;
+
+    let keys: Vec<u64> = (1..=1000).collect();
+    assert_eq!(
+        server.post("/bulk/items", json!(keys)).await.0,
+        StatusCode::CREATED
+    );
+    // usage is not estimated again right away
+    assert_eq!(
+        server.post("/items", json!(1001)).await.0,
+        StatusCode::CREATED
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (status, error) = server.post("/items", json!(1002)).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(error["code"], "memory_limit_exceeded");
+    assert_eq!(server.delete("/items/1").await, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn read_only_mode_rejects_admin_writes() {
+    let server = TestServer::start(Database::default()).await;
+    let (status, _) = server.post("/admin/readonly", json!(true)).await;
+    assert_eq!(status, StatusCode::OK);
+    for path in ["/admin/deleted/purge", "/admin/compact"] {
+        let (status, error) = server.post(path, json!(null)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error["code"], "read_only");
+    }
+
+    server.post("/admin/readonly", json!(false)).await;
+    let (status, purge) = server.post("/admin/deleted/purge", json!(null)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(purge["purged"], 0);
+}
+
+#[tokio::test]
+async fn standby_takes_writes_once_promoted() {
+    let dir = std::env::temp_dir().join(format!("elizadb-api-test-{}-standby", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // nothing listens there, standby keeps retrying in the background
+    let server = TestServer::start_with(
+        Database::default(),
+        dir,
+        &["--follow", "http://127.0.0.1:9"],
+    )
+    .await;
+
+    let (status, error) = server.post("/items", json!(1)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error["code"], "standby");
+
+    let (status, promotion) = server.post("/admin/promote", json!(null)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(promotion["previous_leader"], "http://127.0.0.1:9");
+    assert_eq!(server.post("/items", json!(1)).await.0, StatusCode::CREATED);
+    assert_eq!(
+        server.post("/admin/promote", json!(null)).await.0,
+        StatusCode::CONFLICT
+    );
+}